@@ -0,0 +1,142 @@
+// OBVHとCompressedOBVHのノードのメモリ使用量とトラバース速度を比較する。
+// OBVH+TrianglePackは葉に三角形を8個ずつまとめたもの。
+//   cargo run --release --example obvh_layouts [OBJ_FILE]
+// OBJ_FILEを省略すると、凹凸のある球を約100万個の三角形で生成して使う。
+
+use rand::prng::XorShiftRng;
+use rand::{Rng, SeedableRng};
+use ray::aliases::Vec3;
+use ray::hitable::bvh::BVH;
+use ray::hitable::obvh::compressed::CompressedOBVH;
+use ray::hitable::obvh::OBVH;
+use ray::hitable::triangle::Triangle;
+use ray::hitable::triangle_pack::TrianglePack;
+use ray::hitable::Hitable;
+use ray::material::lambertian::Lambertian;
+use ray::material::Material;
+use ray::obj_file::ObjFile;
+use ray::ray::Ray;
+use ray::texture::constant::ConstantTexture;
+use ray::util::duration_to_secs;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Instant;
+
+const RAY_CNT: usize = 1_000_000;
+
+fn bumpy_sphere(material: Arc<Material>) -> Vec<Triangle> {
+    const N_THETA: usize = 500;
+    const N_PHI: usize = 1000;
+    let point = |i: usize, j: usize| {
+        let theta = std::f32::consts::PI * i as f32 / N_THETA as f32;
+        let phi = 2.0 * std::f32::consts::PI * j as f32 / N_PHI as f32;
+        let r = 1.0 + 0.05 * (theta * 40.0).sin() * (phi * 30.0).cos();
+        Vec3::new(
+            r * theta.sin() * phi.cos(),
+            r * theta.cos(),
+            r * theta.sin() * phi.sin(),
+        )
+    };
+    let mut triangles = Vec::with_capacity(2 * N_THETA * N_PHI);
+    for i in 0..N_THETA {
+        for j in 0..N_PHI {
+            let (a, b) = (point(i, j), point(i + 1, j));
+            let (c, d) = (point(i + 1, j + 1), point(i, j + 1));
+            for tri in [[a, b, c], [a, c, d]].iter() {
+                // 極では退化した三角形ができるので除く
+                if (tri[1] - tri[0]).cross(&(tri[2] - tri[0])).norm() > 0.0 {
+                    triangles.push(Triangle::new(tri, &None, material.clone()));
+                }
+            }
+        }
+    }
+    triangles
+}
+
+/// 各レイの最初の交点のtの和（結果の比較用）とかかった秒数。
+fn measure(hitable: &Hitable, rays: &[Ray], is_hit: bool) -> (f64, f64) {
+    let start_time = Instant::now();
+    let mut checksum = 0.0f64;
+    for ray in rays {
+        if is_hit {
+            checksum += hitable.is_hit(ray, 0.0001, std::f32::MAX) as u32 as f64;
+        } else if let Some(rec) = hitable.hit(ray, 0.0001, std::f32::MAX) {
+            checksum += rec.t as f64;
+        }
+    }
+    (checksum, duration_to_secs(&start_time.elapsed()))
+}
+
+fn main() {
+    let material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+        1.0, 1.0, 1.0,
+    ))));
+    let make_triangles = || match std::env::args().nth(1) {
+        Some(path) => {
+            let obj = &mut ObjFile::from_file(Path::new(&path)).unwrap().groups[0];
+            obj.to_triangles(material.clone())
+        }
+        None => bumpy_sphere(material.clone()),
+    };
+    let obvh = OBVH::from_bvh(BVH::new_binned(make_triangles(), 0.0, 1.0, 4));
+    let compressed = CompressedOBVH::from_obvh(OBVH::from_bvh(BVH::new_binned(
+        make_triangles(),
+        0.0,
+        1.0,
+        4,
+    )));
+    let packed = OBVH::from_bvh(BVH::new_binned(
+        TrianglePack::pack(make_triangles(), 8, 0.0, 1.0, 4),
+        0.0,
+        1.0,
+        4,
+    ));
+    let bbox = obvh.bounding_box(0.0, 1.0).unwrap();
+    let center = bbox.center();
+    let radius = (bbox.max - bbox.min).norm() * 0.5;
+    // バウンディングボックスを囲む球面上から、ボックス内の点に向かうレイ
+    let mut rng = XorShiftRng::seed_from_u64(0);
+    let mut random_in_box = || {
+        let u = Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+        bbox.min + (bbox.max - bbox.min).component_mul(&u)
+    };
+    let rays: Vec<Ray> = (0..RAY_CNT)
+        .map(|_| {
+            let dir = (random_in_box() - center).normalize();
+            let origin = center + dir * radius * 2.0;
+            Ray::new(&origin, &(random_in_box() - origin), 0.0)
+        })
+        .collect();
+    println!(
+        "{:<20}{:>16}{:>16}{:>16}",
+        "layout", "node MiB", "hit Mrays/s", "is_hit Mrays/s"
+    );
+    let mut checksums = vec![];
+    for (name, hitable, node_bytes) in [
+        ("OBVH", &obvh as &Hitable, obvh.node_bytes()),
+        ("CompressedOBVH", &compressed, compressed.node_bytes()),
+        ("OBVH+TrianglePack", &packed, packed.node_bytes()),
+    ]
+    .iter()
+    {
+        let (hit_sum, hit_secs) = measure(*hitable, &rays, false);
+        let (is_hit_sum, is_hit_secs) = measure(*hitable, &rays, true);
+        checksums.push((hit_sum, is_hit_sum));
+        println!(
+            "{:<20}{:>16.2}{:>16.2}{:>16.2}",
+            name,
+            *node_bytes as f64 / (1024.0 * 1024.0),
+            RAY_CNT as f64 / hit_secs * 1.0e-6,
+            RAY_CNT as f64 / is_hit_secs * 1.0e-6
+        );
+    }
+    // 共有された辺の上では、どちらの三角形が見つかるかがレイアウトによって変わりうるので、わずかな差は許す
+    let is_close = |a: f64, b: f64| (a - b).abs() <= 1.0e-4 * a.abs().max(1.0);
+    assert!(
+        checksums
+            .windows(2)
+            .all(|w| is_close(w[0].0, w[1].0) && is_close(w[0].1, w[1].1)),
+        "The layouts disagree: {:?}",
+        checksums
+    );
+}
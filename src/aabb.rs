@@ -34,6 +34,9 @@ impl fmt::Display for Aabb {
 impl fmt::Debug for Aabb {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use crate::util::pretty_print_f32;
+        if self.is_empty() {
+            return write!(f, "(empty)");
+        }
         write!(
             f,
             "(min: ({},{},{}), max: ({},{},{})), center: ({},{},{})",
@@ -57,6 +60,7 @@ impl default::Default for Aabb {
 }
 
 impl Aabb {
+    #[inline]
     pub fn new(min: &Vec3, max: &Vec3) -> Self {
         debug_assert!(
             compare_vec3_le(&min, &max)
@@ -73,6 +77,7 @@ impl Aabb {
             max: *max,
         }
     }
+    #[inline]
     pub fn empty() -> Self {
         Aabb::new(
             &Vec3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY),
@@ -83,6 +88,7 @@ impl Aabb {
             ),
         )
     }
+    #[inline]
     pub fn is_empty(&self) -> bool {
         self.min == Vec3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY)
             && self.max
@@ -111,9 +117,11 @@ impl Aabb {
         }
         return true;
     }
+    #[inline]
     pub fn unite(lhs: &Aabb, rhs: &Aabb) -> Aabb {
         Aabb::new(&min_vec3(&lhs.min, &rhs.min), &max_vec3(&lhs.max, &rhs.max))
     }
+    #[inline]
     pub fn append_point(&mut self, point: Vec3) {
         self.min = min_vec3(&self.min, &point);
         self.max = max_vec3(&self.max, &point);
@@ -149,6 +157,7 @@ impl Aabb {
             })
             .collect()
     }
+    #[inline]
     pub fn area(&self) -> f32 {
         if self.is_empty() {
             return 0.0;
@@ -165,6 +174,7 @@ impl Aabb {
             .partial_cmp(&rhs_center)
             .unwrap_or(std::cmp::Ordering::Equal)
     }
+    #[inline]
     pub fn center(&self) -> Vec3 {
         debug_assert!(!self.is_empty());
         0.5 * (self.min + self.max)
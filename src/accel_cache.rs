@@ -0,0 +1,625 @@
+// 三角形メッシュと、それに対して構築したBVH/OBVHのノード配列のバイナリキャッシュ。
+// ファイルの構成（数値はすべてリトルエンディアン）:
+//   MAGIC, FORMAT_VERSION (u32), ソースファイルの内容のハッシュ (u64), 前処理のタグのハッシュ (u64),
+//   BuildParams, 三角形の配列, BVHのノード配列, OBVHのノード配列
+// ソースファイルの内容、前処理のタグか構築パラメータが変わると、キャッシュは無効になり作り直される。
+// キャッシュのファイル名にはソースファイルの絶対パスのハッシュを含めるので、同名の別のファイルとは衝突しない。
+// マテリアルは保存しないので、読み込む側が与える。
+
+use crate::aabb::Aabb;
+use crate::aliases::{Vec2, Vec3, Vec4};
+use crate::hitable::bvh::BVH;
+use crate::hitable::obvh::OBVH;
+use crate::hitable::triangle::Triangle;
+use crate::material::Material;
+use crate::util::duration_to_secs;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+const MAGIC: [u8; 8] = *b"RAYACCEL";
+/// Incremented whenever the layout of the cache file changes.
+pub const FORMAT_VERSION: u32 = 3;
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    NotCache,                 // the file does not start with MAGIC
+    IncompatibleVersion(u32), // the version of the file
+    Stale,                    // the source, preprocessing or build parameters have changed
+    Corrupted(String),
+}
+
+impl From<io::Error> for Error {
+    fn from(io_e: io::Error) -> Self {
+        Error::IO(io_e)
+    }
+}
+
+/// Which BVH builder is used.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Builder {
+    Sorted,                             // BVH::new
+    Binned,                             // BVH::new_binned
+    Sbvh { spatial_split_budget: f32 }, // BVH::new_sbvh
+}
+
+/// Parameters which affect the built structure. A cache is used only if they are equal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BuildParams {
+    pub builder: Builder,
+    pub time_0: f32,
+    pub time_1: f32,
+}
+
+impl BuildParams {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        match self.builder {
+            Builder::Sorted => {
+                write_u32(writer, 0)?;
+                write_f32(writer, 0.0)?;
+            }
+            Builder::Binned => {
+                write_u32(writer, 1)?;
+                write_f32(writer, 0.0)?;
+            }
+            Builder::Sbvh {
+                spatial_split_budget,
+            } => {
+                write_u32(writer, 2)?;
+                write_f32(writer, spatial_split_budget)?;
+            }
+        }
+        write_f32(writer, self.time_0)?;
+        write_f32(writer, self.time_1)
+    }
+    fn read(reader: &mut impl Read) -> Result<Self, Error> {
+        let tag = read_u32(reader)?;
+        let budget = read_f32(reader)?;
+        let builder = match tag {
+            0 => Builder::Sorted,
+            1 => Builder::Binned,
+            2 => Builder::Sbvh {
+                spatial_split_budget: budget,
+            },
+            _ => return Err(Error::Corrupted(format!("Unknown builder {}.", tag))),
+        };
+        Ok(BuildParams {
+            builder: builder,
+            time_0: read_f32(reader)?,
+            time_1: read_f32(reader)?,
+        })
+    }
+    fn build(&self, triangles: Vec<Triangle>, thread_cnt: usize) -> BVH<Triangle> {
+        match self.builder {
+            Builder::Sorted => BVH::new(triangles, self.time_0, self.time_1),
+            Builder::Binned => BVH::new_binned(triangles, self.time_0, self.time_1, thread_cnt),
+            Builder::Sbvh {
+                spatial_split_budget,
+            } => BVH::new_sbvh(triangles, self.time_0, self.time_1, spatial_split_budget),
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Unlike std::collections::hash_map::DefaultHasher, the value is stable across Rust versions.
+pub fn content_hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// A cache file of the triangles loaded from a source file (e.g., an OBJ file).
+pub struct MeshCache {
+    path: PathBuf,
+    source_hash: u64,
+    preprocess_hash: u64,
+    params: BuildParams,
+}
+
+impl MeshCache {
+    /// The cache of source is stored in cache_dir. Reads source to compute its hash.
+    /// * `preprocess` - describes how the triangles are made from source (e.g., "unify_vertex, set_smooth_normals").
+    ///   Change it whenever the preprocessing changes, so that caches made by the old one are not used.
+    pub fn new(
+        cache_dir: &Path,
+        source: &Path,
+        preprocess: &str,
+        params: BuildParams,
+    ) -> Result<Self, Error> {
+        let source_hash = content_hash(&fs::read(source)?);
+        let path_hash = content_hash(fs::canonicalize(source)?.to_string_lossy().as_bytes());
+        let file_name = source
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        Ok(MeshCache {
+            path: cache_dir.join(format!("{}-{:016x}.accel", file_name, path_hash)),
+            source_hash: source_hash,
+            preprocess_hash: content_hash(preprocess.as_bytes()),
+            params: params,
+        })
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// Loads the OBVH from the cache, or builds it by make_triangles() and params and stores it.
+    /// Triangles loaded from the cache have the given material.
+    pub fn obvh_or_build<F>(
+        &self,
+        material: Arc<Material>,
+        thread_cnt: usize,
+        make_triangles: F,
+    ) -> OBVH<Triangle>
+    where
+        F: FnOnce() -> Vec<Triangle>,
+    {
+        match self.load_obvh(material) {
+            Ok(obvh) => return obvh,
+            Err(Error::IO(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => println!("[MeshCache] rebuilding {}: {:?}", self.path.display(), e),
+        }
+        let obvh = self.build_and_store(make_triangles(), thread_cnt);
+        obvh.unwrap_or_else(|(obvh, e)| {
+            println!(
+                "[MeshCache] failed to write {}: {:?}",
+                self.path.display(),
+                e
+            );
+            obvh
+        })
+    }
+    /// Builds BVH and OBVH over triangles, and writes them to the cache file.
+    /// The OBVH is returned even if writing fails.
+    pub fn build_and_store(
+        &self,
+        triangles: Vec<Triangle>,
+        thread_cnt: usize,
+    ) -> Result<OBVH<Triangle>, (OBVH<Triangle>, Error)> {
+        let bvh = self.params.build(triangles, thread_cnt);
+        // OBVH::from_bvhはbvhを消費するので、先にBVHまでをバッファに書いておく。
+        let mut buf: Vec<u8> = vec![];
+        self.write_header(&mut buf)
+            .and_then(|_| write_triangles(&mut buf, &bvh.leaves))
+            .and_then(|_| bvh.write_nodes(&mut buf))
+            .unwrap();
+        let obvh = OBVH::from_bvh(bvh);
+        let res = obvh
+            .write_nodes(&mut buf)
+            .and_then(|_| self.write_file(&buf));
+        match res {
+            Ok(()) => Ok(obvh),
+            Err(e) => Err((obvh, Error::from(e))),
+        }
+    }
+    /// Same as obvh_or_build, but returns the BVH, e.g. to group its subtrees by TrianglePack::from_bvh.
+    pub fn bvh_or_build<F>(
+        &self,
+        material: Arc<Material>,
+        thread_cnt: usize,
+        make_triangles: F,
+    ) -> BVH<Triangle>
+    where
+        F: FnOnce() -> Vec<Triangle>,
+    {
+        match self.load_bvh(material) {
+            Ok(bvh) => return bvh,
+            Err(Error::IO(ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => println!("[MeshCache] rebuilding {}: {:?}", self.path.display(), e),
+        }
+        let bvh = self.params.build(make_triangles(), thread_cnt);
+        let mut buf: Vec<u8> = vec![];
+        let res = self
+            .write_header(&mut buf)
+            .and_then(|_| write_triangles(&mut buf, &bvh.leaves))
+            .and_then(|_| bvh.write_nodes(&mut buf))
+            .and_then(|_| OBVH::write_nodes_of_bvh(&bvh, &mut buf))
+            .and_then(|_| self.write_file(&buf));
+        if let Err(e) = res {
+            println!(
+                "[MeshCache] failed to write {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+        bvh
+    }
+    pub fn load_bvh(&self, material: Arc<Material>) -> Result<BVH<Triangle>, Error> {
+        let mut reader = self.open()?;
+        let triangles = read_triangles(&mut reader, material)?;
+        BVH::read_nodes(&mut reader, triangles)
+    }
+    pub fn load_obvh(&self, material: Arc<Material>) -> Result<OBVH<Triangle>, Error> {
+        let start_time = Instant::now();
+        let mut reader = self.open()?;
+        let triangles = read_triangles(&mut reader, material)?;
+        let bvh = BVH::read_nodes(&mut reader, triangles)?; // OBVHのノード配列まで読み飛ばす
+        let obvh = OBVH::read_nodes(&mut reader, bvh.leaves)?;
+        println!(
+            "[MeshCache] loaded {} ({:.3} secs)",
+            self.path.display(),
+            duration_to_secs(&start_time.elapsed())
+        );
+        Ok(obvh)
+    }
+    /// Writes a temporary file in the same directory and renames it to the cache file,
+    /// so that a crash or another process building the same cache never leaves a partly written file.
+    fn write_file(&self, buf: &[u8]) -> io::Result<()> {
+        static TEMP_CNT: AtomicUsize = AtomicUsize::new(0);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut temp_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(format!(
+            ".{}-{}.tmp",
+            std::process::id(),
+            TEMP_CNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = self.path.with_file_name(temp_name);
+        let res = File::create(&temp_path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            writer.write_all(buf)?;
+            writer.flush()
+        });
+        let res = res.and_then(|_| fs::rename(&temp_path, &self.path));
+        if res.is_err() {
+            let _ = fs::remove_file(&temp_path);
+        }
+        res
+    }
+    fn write_header(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&MAGIC)?;
+        write_u32(writer, FORMAT_VERSION)?;
+        write_u64(writer, self.source_hash)?;
+        write_u64(writer, self.preprocess_hash)?;
+        self.params.write(writer)
+    }
+    /// Opens the cache file and checks the header.
+    fn open(&self) -> Result<impl Read, Error> {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::NotCache);
+        }
+        let version = read_u32(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(Error::IncompatibleVersion(version));
+        }
+        if read_u64(&mut reader)? != self.source_hash
+            || read_u64(&mut reader)? != self.preprocess_hash
+            || BuildParams::read(&mut reader)? != self.params
+        {
+            return Err(Error::Stale);
+        }
+        Ok(reader)
+    }
+}
+
+// 三角形ごとに、頂点の後に持っている属性のフラグを書く
+const HAS_NORMALS: u32 = 1;
+const HAS_TEX_COORDS: u32 = 2;
+const HAS_TANGENTS: u32 = 4;
+
+fn write_triangles(writer: &mut impl Write, triangles: &[Triangle]) -> io::Result<()> {
+    write_u64(writer, triangles.len() as u64)?;
+    for tri in triangles {
+        for v in tri.vertices() {
+            write_vec3(writer, v)?;
+        }
+        let flags = tri.vertex_normals().map_or(0, |_| HAS_NORMALS)
+            | tri.tex_coords().map_or(0, |_| HAS_TEX_COORDS)
+            | tri.vertex_tangents().map_or(0, |_| HAS_TANGENTS);
+        write_u32(writer, flags)?;
+        if let Some(normals) = tri.vertex_normals() {
+            for n in normals {
+                write_vec3(writer, n)?;
+            }
+        }
+        if let Some(tex_coords) = tri.tex_coords() {
+            for uv in tex_coords {
+                write_f32(writer, uv[0])?;
+                write_f32(writer, uv[1])?;
+            }
+        }
+        if let Some(tangents) = tri.vertex_tangents() {
+            for t in tangents {
+                for a in 0..4 {
+                    write_f32(writer, t[a])?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_triangles(reader: &mut impl Read, material: Arc<Material>) -> Result<Vec<Triangle>, Error> {
+    let cnt = read_u64(reader)? as usize;
+    let mut triangles = Vec::with_capacity(cnt.min(1 << 24));
+    for _ in 0..cnt {
+        let vertices = [read_vec3(reader)?, read_vec3(reader)?, read_vec3(reader)?];
+        let flags = read_u32(reader)?;
+        if flags & !(HAS_NORMALS | HAS_TEX_COORDS | HAS_TANGENTS) != 0 {
+            return Err(Error::Corrupted(format!(
+                "Invalid attribute flags {}.",
+                flags
+            )));
+        }
+        let normals = if flags & HAS_NORMALS != 0 {
+            Some([read_vec3(reader)?, read_vec3(reader)?, read_vec3(reader)?])
+        } else {
+            None
+        };
+        let mut tri = Triangle::new(&vertices, &normals, material.clone());
+        if flags & HAS_TEX_COORDS != 0 {
+            let mut tex_coords = [Vec2::zeros(); 3];
+            for uv in &mut tex_coords {
+                *uv = Vec2::new(read_f32(reader)?, read_f32(reader)?);
+            }
+            tri.set_tex_coords(Some(tex_coords));
+        }
+        if flags & HAS_TANGENTS != 0 {
+            let mut tangents = [Vec4::zeros(); 3];
+            for t in &mut tangents {
+                *t = Vec4::new(
+                    read_f32(reader)?,
+                    read_f32(reader)?,
+                    read_f32(reader)?,
+                    read_f32(reader)?,
+                );
+            }
+            tri.set_vertex_tangents(Some(tangents));
+        }
+        triangles.push(tri);
+    }
+    Ok(triangles)
+}
+
+pub fn write_u32(writer: &mut impl Write, x: u32) -> io::Result<()> {
+    writer.write_all(&x.to_le_bytes())
+}
+
+pub fn write_u64(writer: &mut impl Write, x: u64) -> io::Result<()> {
+    writer.write_all(&x.to_le_bytes())
+}
+
+pub fn write_f32(writer: &mut impl Write, x: f32) -> io::Result<()> {
+    writer.write_all(&x.to_le_bytes())
+}
+
+pub fn write_vec3(writer: &mut impl Write, v: &Vec3) -> io::Result<()> {
+    for a in 0..3 {
+        write_f32(writer, v[a])?;
+    }
+    Ok(())
+}
+
+pub fn write_aabb(writer: &mut impl Write, bbox: &Aabb) -> io::Result<()> {
+    write_vec3(writer, &bbox.min)?;
+    write_vec3(writer, &bbox.max)
+}
+
+pub fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+pub fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    Ok(f32::from_bits(read_u32(reader)?))
+}
+
+pub fn read_vec3(reader: &mut impl Read) -> io::Result<Vec3> {
+    Ok(Vec3::new(
+        read_f32(reader)?,
+        read_f32(reader)?,
+        read_f32(reader)?,
+    ))
+}
+
+/// The box may be empty.
+pub fn read_aabb(reader: &mut impl Read) -> io::Result<Aabb> {
+    let min = read_vec3(reader)?;
+    let max = read_vec3(reader)?;
+    Ok(Aabb { min: min, max: max })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::accel_cache::{BuildParams, Builder, Error, MeshCache, FORMAT_VERSION};
+    use crate::aliases::{Vec2, Vec3};
+    use crate::hit_record::HitRecord;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::obvh::OBVH;
+    use crate::hitable::test_util;
+    use crate::hitable::triangle::Triangle;
+    use crate::hitable::Hitable;
+    use rand::prng::XorShiftRng;
+    use rand::SeedableRng;
+    use std::fs;
+    #[test]
+    fn cache_round_trip_and_invalidation() {
+        let material = test_util::white();
+        let dir = std::env::temp_dir().join(format!("ray_accel_cache_{}", std::process::id()));
+        fs::create_dir_all(dir.join("other")).unwrap();
+        let source = dir.join("mesh.obj");
+        fs::write(&source, "v 0 0 0\n").unwrap();
+        let mut rng = XorShiftRng::seed_from_u64(5);
+        let vertices: Vec<[Vec3; 3]> = (0..300)
+            .map(|_| {
+                let p = test_util::random_vec(&mut rng) * 10.0;
+                [
+                    p,
+                    p + test_util::random_vec(&mut rng),
+                    p + test_util::random_vec(&mut rng),
+                ]
+            })
+            .collect();
+        // 法線・テクスチャ座標のある三角形とない三角形を混ぜる
+        let make_triangles = || -> Vec<Triangle> {
+            vertices
+                .iter()
+                .enumerate()
+                .map(|(k, v)| {
+                    let normals = if k % 2 == 0 {
+                        Some([Vec3::new(0.0, 1.0, 0.0); 3])
+                    } else {
+                        None
+                    };
+                    let mut tri = Triangle::new(v, &normals, material.clone());
+                    if k % 3 == 0 {
+                        tri.set_tex_coords(Some([
+                            Vec2::new(0.0, 0.0),
+                            Vec2::new(1.0, 0.0),
+                            Vec2::new(0.0, 1.0),
+                        ]));
+                    }
+                    tri
+                })
+                .collect()
+        };
+        let params = BuildParams {
+            builder: Builder::Sbvh {
+                spatial_split_budget: 0.5,
+            },
+            time_0: 0.0,
+            time_1: 1.0,
+        };
+        let cache = MeshCache::new(&dir, &source, "none", params).unwrap();
+        match cache.load_obvh(material.clone()) {
+            Err(Error::IO(_)) => {}
+            _ => panic!("The cache should not exist yet."),
+        }
+        let built = cache.obvh_or_build(material.clone(), 1, make_triangles);
+        let loaded = cache.load_obvh(material.clone()).unwrap();
+        let loaded_bvh = cache.load_bvh(material.clone()).unwrap();
+        assert_eq!(built.sah_cost(), loaded.sah_cost());
+        for ray in test_util::random_rays(&mut rng, 300, 20.0) {
+            let t_and_uv = |rec: HitRecord| (rec.t, rec.tex_coord);
+            let expected = built.hit(&ray, 0.0001, std::f32::MAX).map(t_and_uv);
+            assert_eq!(
+                expected,
+                loaded.hit(&ray, 0.0001, std::f32::MAX).map(t_and_uv)
+            );
+            assert_eq!(
+                expected,
+                loaded_bvh.hit(&ray, 0.0001, std::f32::MAX).map(t_and_uv)
+            );
+        }
+        // 別のディレクトリにある同名のファイルのキャッシュとは衝突しない。
+        let other_source = dir.join("other").join("mesh.obj");
+        fs::write(&other_source, "v 0 0 0\n").unwrap();
+        let other = MeshCache::new(&dir, &other_source, "none", params).unwrap();
+        assert_ne!(cache.path(), other.path());
+        // bvh_or_buildが書いたキャッシュからもOBVHを読める。
+        let other_bvh = other.bvh_or_build(material.clone(), 1, make_triangles);
+        let other_obvh = other.load_obvh(material.clone()).unwrap();
+        assert_eq!(built.sah_cost(), other_obvh.sah_cost());
+        for ray in test_util::random_rays(&mut rng, 100, 20.0) {
+            assert_eq!(
+                other_bvh.hit(&ray, 0.0001, std::f32::MAX).map(|rec| rec.t),
+                other_obvh.hit(&ray, 0.0001, std::f32::MAX).map(|rec| rec.t)
+            );
+        }
+        // 構築パラメータ、前処理かソースファイルが変わると無効になる。
+        let other_params = BuildParams {
+            builder: Builder::Binned,
+            ..params
+        };
+        match MeshCache::new(&dir, &source, "none", other_params)
+            .unwrap()
+            .load_obvh(material.clone())
+        {
+            Err(Error::Stale) => {}
+            _ => panic!("Build parameters are not checked."),
+        }
+        match MeshCache::new(&dir, &source, "set_smooth_normals", params)
+            .unwrap()
+            .load_obvh(material.clone())
+        {
+            Err(Error::Stale) => {}
+            _ => panic!("The preprocessing is not checked."),
+        }
+        fs::write(&source, "v 0 0 1\n").unwrap();
+        let modified = MeshCache::new(&dir, &source, "none", params).unwrap();
+        match modified.load_obvh(material.clone()) {
+            Err(Error::Stale) => {}
+            _ => panic!("The source file is not checked."),
+        }
+        // バージョンが異なるファイルは読まない。
+        let mut bytes = fs::read(cache.path()).unwrap();
+        bytes[8..12].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        fs::write(cache.path(), &bytes[..100]).unwrap();
+        match modified.load_obvh(material.clone()) {
+            Err(Error::IncompatibleVersion(v)) => assert_eq!(v, FORMAT_VERSION + 1),
+            _ => panic!("The version is not checked."),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn node_order() {
+        let mut rng = XorShiftRng::seed_from_u64(7);
+        let vertices: Vec<[Vec3; 3]> = (0..200)
+            .map(|_| {
+                let p = test_util::random_vec(&mut rng) * 10.0;
+                [
+                    p,
+                    p + Vec3::new(1.0, 0.0, 0.0),
+                    p + Vec3::new(0.0, 1.0, 0.0),
+                ]
+            })
+            .collect();
+        // どの構築方法でも子は親より後ろにあり、読み直せる
+        for &builder in &[
+            Builder::Sorted,
+            Builder::Binned,
+            Builder::Sbvh {
+                spatial_split_budget: 0.5,
+            },
+        ] {
+            let params = BuildParams {
+                builder: builder,
+                time_0: 0.0,
+                time_1: 1.0,
+            };
+            let bvh = params.build(test_util::make_triangles(&vertices), 4);
+            let mut buf = Vec::new();
+            bvh.write_nodes(&mut buf).unwrap();
+            let leaves = test_util::make_triangles(&vertices);
+            assert!(BVH::read_nodes(&mut &buf[..], leaves).is_ok());
+            let mut obvh_buf = Vec::new();
+            OBVH::write_nodes_of_bvh(&bvh, &mut obvh_buf).unwrap();
+            let leaves = test_util::make_triangles(&vertices);
+            assert!(OBVH::read_nodes(&mut &obvh_buf[..], leaves).is_ok());
+
+            // 根の最初の子を根自身にすると、循環するので読まない
+            // BVH: bbox (24バイト)、ノード数 (8)、子0のbbox (24)の後に子0
+            buf[56..60].copy_from_slice(&0u32.to_le_bytes());
+            let leaves = test_util::make_triangles(&vertices);
+            match BVH::read_nodes(&mut &buf[..], leaves) {
+                Err(Error::Stale) => {}
+                _ => panic!("A cyclic BVH must be rejected."),
+            }
+            // OBVH: bbox (24)、ノード数 (8)、8個のbbox (192)の後に子0
+            obvh_buf[224..228].copy_from_slice(&0u32.to_le_bytes());
+            let leaves = test_util::make_triangles(&vertices);
+            match OBVH::read_nodes(&mut &obvh_buf[..], leaves) {
+                Err(Error::Stale) => {}
+                _ => panic!("A cyclic OBVH must be rejected."),
+            }
+        }
+    }
+}
@@ -25,6 +25,16 @@ impl Affine {
             a_det: a.determinant(),
         }
     }
+    /// x -> a x + b, or None if a is not a (nonzero) scalar multiple of an orthogonal matrix.
+    pub fn similarity(a: &Mat3, b: &Vec3) -> Option<Self> {
+        let ata = a.transpose() * a;
+        let s2 = ata.trace() / 3.0;
+        if s2.is_nan() || s2 <= 0.0 || (ata - Mat3::from_diagonal_element(s2)).amax() > 1.0e-4 * s2
+        {
+            return None;
+        }
+        Some(Affine::new(a, b))
+    }
     /// Linear transformation.
     fn linear(linear: &Mat3, origin: &Vec3) -> Self {
         Affine::new(linear, &(-linear * origin + origin))
@@ -45,6 +55,10 @@ impl Affine {
             a_det: 1.0 / self.a_det,
         }
     }
+    /// The determinant of the linear part.
+    pub fn determinant(&self) -> f32 {
+        self.a_det
+    }
     /// Act on a point.
     pub fn act_point(&self, pt: &Vec3) -> Vec3 {
         self.a * pt + self.b
@@ -65,5 +79,13 @@ impl Affine {
     pub fn translate(diff: &Vec3) -> Self {
         Affine::new(&Mat3::from_diagonal(&Vec3::new(1.0, 1.0, 1.0)), diff)
     }
-    // ToDo: implement composition
+    /// The composition self ∘ other, i.e., x -> self(other(x)).
+    pub fn compose(&self, other: &Affine) -> Affine {
+        Affine {
+            a: self.a * other.a,
+            b: self.a * other.b + self.b,
+            a_inv: other.a_inv * self.a_inv,
+            a_det: self.a_det * other.a_det,
+        }
+    }
 }
@@ -1,8 +1,8 @@
 use nalgebra as na;
-use rand;
 
 pub type Vec3 = na::Vector3<f32>;
 pub type Vec2 = na::Vector2<f32>;
+pub type Vec4 = na::Vector4<f32>;
 pub type Mat4 = na::Matrix4<f32>;
 pub type Mat3 = na::Matrix3<f32>;
-pub type RandGen = rand::prelude::ThreadRng;
+pub use crate::rand_gen::RandGen;
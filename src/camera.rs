@@ -1,4 +1,4 @@
-use crate::aliases::{RandGen, Vec3};
+use crate::aliases::{RandGen, Vec2, Vec3};
 use crate::pdf::rnd_in_unit_disc;
 use crate::ray::Ray;
 use rand::Rng;
@@ -12,12 +12,20 @@ pub struct Camera {
     lens_radius: f32,
     u: Vec3, // a unit vector directing right
     v: Vec3, // a unit vector directing up
-    #[allow(dead_code)]
     w: Vec3, // u.cross(v)
+    focus_dist: f32,
     time_0: f32, // shutter open
     time_1: f32, // shutter close
 }
 
+/// A point on the lens connected to a point in the scene (used for light tracing).
+pub struct LensSample {
+    pub lens_point: Vec3,
+    pub film_position: Vec2, // (u, v) such that get_ray(u, v) can generate the ray from lens_point
+    pub importance: f32,     // the importance function We of the ray from lens_point
+    pub density: f32, // density of lens_point w.r.t. the solid angle seen from the given point
+}
+
 impl Camera {
     pub fn new_time(
         look_from: &Vec3,
@@ -49,6 +57,7 @@ impl Camera {
             u: u,
             v: v,
             w: w,
+            focus_dist: focus_dist,
             time_0: time_0,
             time_1: time_1,
         }
@@ -77,7 +86,7 @@ impl Camera {
     pub fn get_ray(&self, u: f32, v: f32, rng: &mut RandGen) -> Ray {
         let r = self.lens_radius * rnd_in_unit_disc(rng);
         let offset = r.x * self.u + r.y * self.v;
-        let time = self.time_0 + rng.gen::<f32>() * (self.time_1 - self.time_0);
+        let time = self.sample_time(rng);
         Ray::new(
             &(self.origin + offset),
             &(self.lower_left_corner + u * self.horizontal + v * self.vertical
@@ -86,4 +95,79 @@ impl Camera {
             time,
         )
     }
+    /// A random time while the shutter is open.
+    pub fn sample_time(&self, rng: &mut RandGen) -> f32 {
+        self.time_0 + rng.gen::<f32>() * (self.time_1 - self.time_0)
+    }
+    /// The unit vector directing forward.
+    pub fn forward(&self) -> Vec3 {
+        -self.w
+    }
+    /// Area of the lens. Returns 1.0 for a pinhole camera (treated as a delta distribution).
+    fn lens_area(&self) -> f32 {
+        if self.lens_radius > 0.0 {
+            PI * self.lens_radius * self.lens_radius
+        } else {
+            1.0
+        }
+    }
+    /// Area of the film placed at unit distance from the lens.
+    fn film_area(&self) -> f32 {
+        self.horizontal.norm() * self.vertical.norm() / (self.focus_dist * self.focus_dist)
+    }
+    /// Calculates (u, v) such that get_ray(u, v) generates a ray from lens_point along dir.
+    /// * `return` - None if the ray does not pass through the film.
+    pub fn film_position(&self, lens_point: &Vec3, dir: &Vec3) -> Option<Vec2> {
+        let dir = dir.normalize();
+        let cosine = dir.dot(&self.forward());
+        if cosine <= 0.0 {
+            return None;
+        }
+        let rel = lens_point + dir * (self.focus_dist / cosine) - self.lower_left_corner;
+        let u = rel.dot(&self.horizontal) / self.horizontal.norm_squared();
+        let v = rel.dot(&self.vertical) / self.vertical.norm_squared();
+        if 0.0 <= u && u < 1.0 && 0.0 <= v && v < 1.0 {
+            Some(Vec2::new(u, v))
+        } else {
+            None
+        }
+    }
+    /// Importance function We of a ray starting from the lens.
+    /// Normalized so that We * cosine / (pdf_pos * pdf_dir) = 1 for rays generated by get_ray,
+    /// i.e., a pixel value is the average of the radiances carried by camera rays.
+    pub fn importance(&self, ray: &Ray) -> f32 {
+        if self.film_position(&ray.origin, &ray.direction).is_none() {
+            return 0.0;
+        }
+        let cosine = ray.direction.normalize().dot(&self.forward());
+        1.0 / (self.film_area() * self.lens_area() * cosine.powi(4))
+    }
+    /// Densities of the origin (w.r.t. the lens area) and the direction (w.r.t. the solid angle)
+    /// of a ray generated by get_ray.
+    /// * `return` - (pdf_pos, pdf_dir)
+    pub fn ray_density(&self, ray: &Ray) -> (f32, f32) {
+        if self.film_position(&ray.origin, &ray.direction).is_none() {
+            return (0.0, 0.0);
+        }
+        let cosine = ray.direction.normalize().dot(&self.forward());
+        (
+            1.0 / self.lens_area(),
+            1.0 / (self.film_area() * cosine.powi(3)),
+        )
+    }
+    /// Samples a point on the lens from which the point is visible.
+    /// * `return` - None if the point is out of the field of view.
+    pub fn sample_lens_toward(&self, point: &Vec3, rng: &mut RandGen) -> Option<LensSample> {
+        let r = self.lens_radius * rnd_in_unit_disc(rng);
+        let lens_point = self.origin + r.x * self.u + r.y * self.v;
+        let dir = point - lens_point;
+        let film_position = self.film_position(&lens_point, &dir)?;
+        let cosine = dir.normalize().dot(&self.forward());
+        Some(LensSample {
+            lens_point: lens_point,
+            film_position: film_position,
+            importance: self.importance(&Ray::new(&lens_point, &dir, 0.0)),
+            density: dir.norm_squared() / (cosine * self.lens_area()),
+        })
+    }
 }
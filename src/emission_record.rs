@@ -0,0 +1,12 @@
+use crate::aliases::Vec3;
+use crate::hit_record::HitRecord;
+use crate::ray::Ray;
+
+/// Informations of a ray emitted from a light source.
+pub struct EmissionRecord<'a> {
+    pub ray: Ray,           // emitted ray. ray.origin is on the surface of the light.
+    pub rec: HitRecord<'a>, // the point on the light from which the ray is emitted.
+    pub radiance: Vec3,     // radiance carried by the ray.
+    pub pdf_pos: f32,       // density of rec.point w.r.t. the surface area.
+    pub pdf_dir: f32,       // density of ray.direction w.r.t. the solid angle.
+}
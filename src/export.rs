@@ -0,0 +1,492 @@
+// シーンを三角形メッシュにしてOBJ+MTLやglTFに書き出す（他のツールでジオメトリを確認するため）。
+// ・Hitable::tessellateで、Transform・Instanceの変換を掛けたワールド座標の三角形をExportMeshに集める。
+//   球はUV球にし、テッセレーションできないもの（MovingSphereなど）はskippedに数える。
+// ・マテリアルはポインタで区別し、最初の三角形の重心で評価したalbedoとemittedだけを書き出す。
+//   DiffuseLightはKd = 0、Ke = 放射輝度とし、mtl_fileやgltf_fileで読み込むと光源に戻る。
+// ・glTFのUVは上下を反転して書き出す（gltf_fileの読み込みと対になる）。
+
+use crate::affine::Affine;
+use crate::aliases::{Vec2, Vec3};
+use crate::hit_record::HitRecord;
+use crate::hitable::Hitable;
+use crate::json::Json;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::util::{max_vec3, min_vec3};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// Triangles of a scene in world coordinates, grouped by material.
+pub struct ExportMesh {
+    pub positions: Vec<Vec3>,
+    pub normals: Vec<Vec3>,
+    pub tex_coords: Vec<Vec2>,
+    pub groups: Vec<ExportGroup>,
+    pub sphere_segments: usize,      // UV球の経度方向の分割数
+    pub sphere_rings: usize,         // UV球の緯度方向の分割数
+    pub skipped: usize,              // テッセレーションできなかったHitableの数
+    transforms: Vec<Affine>,         // transforms[i]は外側からi+1個の変換の合成
+    materials: Vec<Arc<Material>>,   // Instanceによるマテリアルの上書き
+    group_of: HashMap<usize, usize>, // マテリアルのアドレス -> groupsの添字
+}
+
+/// Triangles sharing a material.
+pub struct ExportGroup {
+    pub albedo: Vec3,
+    pub emission: Vec3,
+    pub indices: Vec<[u32; 3]>,
+}
+
+impl Default for ExportMesh {
+    fn default() -> Self {
+        ExportMesh::new()
+    }
+}
+
+impl ExportMesh {
+    pub fn new() -> Self {
+        ExportMesh {
+            positions: Vec::new(),
+            normals: Vec::new(),
+            tex_coords: Vec::new(),
+            groups: Vec::new(),
+            sphere_segments: 32,
+            sphere_rings: 16,
+            skipped: 0,
+            transforms: Vec::new(),
+            materials: Vec::new(),
+            group_of: HashMap::new(),
+        }
+    }
+    /// Tessellates hitable into a new ExportMesh.
+    pub fn from_hitable(hitable: &Hitable) -> Self {
+        let mut mesh = ExportMesh::new();
+        hitable.tessellate(&mut mesh);
+        mesh
+    }
+    pub fn triangle_cnt(&self) -> usize {
+        self.groups.iter().map(|g| g.indices.len()).sum()
+    }
+    /// Applies tr to the vertices added until pop_transform().
+    pub fn push_transform(&mut self, tr: &Affine) {
+        let tr = match self.transforms.last() {
+            Some(outer) => outer.compose(tr),
+            None => *tr,
+        };
+        self.transforms.push(tr);
+    }
+    pub fn pop_transform(&mut self) {
+        self.transforms.pop();
+    }
+    /// Uses material for the triangles added until pop_material(), instead of their own materials.
+    pub fn push_material(&mut self, material: Arc<Material>) {
+        self.materials.push(material);
+    }
+    pub fn pop_material(&mut self) {
+        self.materials.pop();
+    }
+    /// Adds a vertex in the current coordinates and returns its index.
+    pub fn add_vertex(&mut self, position: &Vec3, normal: &Vec3, tex_coord: &Vec2) -> u32 {
+        let (position, normal) = match self.transforms.last() {
+            Some(tr) => (tr.act_point(position), tr.act_2_vec(normal)),
+            None => (*position, *normal),
+        };
+        self.positions.push(position);
+        self.normals.push(normal.normalize());
+        self.tex_coords.push(*tex_coord);
+        (self.positions.len() - 1) as u32
+    }
+    /// Adds a triangle of the vertices added by add_vertex().
+    /// The front side is the one from which the vertices are seen counterclockwise.
+    /// Transforms keep the order, so a reflection turns the surface inside out as Transform does.
+    pub fn add_triangle(&mut self, indices: [u32; 3], material: &Material) {
+        let overridden = self.materials.last().cloned();
+        let material = match overridden {
+            Some(ref m) => m.as_ref(),
+            None => material,
+        };
+        let key = material as *const Material as *const u8 as usize;
+        let group = match self.group_of.get(&key) {
+            Some(&group) => group,
+            None => {
+                let (albedo, emission) = self.evaluate_material(&indices, material);
+                self.groups.push(ExportGroup {
+                    albedo: albedo,
+                    emission: emission,
+                    indices: Vec::new(),
+                });
+                self.group_of.insert(key, self.groups.len() - 1);
+                self.groups.len() - 1
+            }
+        };
+        self.groups[group].indices.push(indices);
+    }
+    /// (albedo, emitted radiance) at the centroid of the triangle.
+    fn evaluate_material(&self, indices: &[u32; 3], material: &Material) -> (Vec3, Vec3) {
+        let [a, b, c] = indices.map(|i| i as usize);
+        let point = (self.positions[a] + self.positions[b] + self.positions[c]) / 3.0;
+        let normal = (self.normals[a] + self.normals[b] + self.normals[c]).normalize();
+        let tex_coord = (self.tex_coords[a] + self.tex_coords[b] + self.tex_coords[c]) / 3.0;
+        let normal = if normal.iter().all(|x| x.is_finite()) {
+            normal
+        } else {
+            Vec3::new(0.0, 0.0, 1.0)
+        };
+        let rec = HitRecord {
+            t: 1.0,
+            point: point,
+            tex_coord: tex_coord,
+            normal: normal,
+            geometric_normal: normal,
+            barycentric: None,
+            dpdu: self.positions[b] - self.positions[a],
+            dpdv: self.positions[c] - self.positions[a],
+            material: material,
+        };
+        let emission = material.emitted(&Ray::new(&(point + normal), &(-normal), 0.0), &rec);
+        if emission == Vec3::new(0.0, 0.0, 0.0) {
+            (material.albedo(&rec), emission)
+        } else {
+            (Vec3::new(0.0, 0.0, 0.0), emission)
+        }
+    }
+    /// Writes path (.obj) and the material library with the extension .mtl.
+    pub fn write_obj(&self, path: &Path) -> io::Result<()> {
+        let mtl_path = path.with_extension("mtl");
+        let mut mtl = BufWriter::new(fs::File::create(&mtl_path)?);
+        for (i, group) in self.groups.iter().enumerate() {
+            let (kd, ke) = (group.albedo, group.emission);
+            writeln!(mtl, "newmtl material_{}", i)?;
+            writeln!(mtl, "Kd {} {} {}", kd[0], kd[1], kd[2])?;
+            if ke != Vec3::new(0.0, 0.0, 0.0) {
+                writeln!(mtl, "Ke {} {} {}", ke[0], ke[1], ke[2])?;
+            }
+            writeln!(mtl, "illum 1")?;
+        }
+        mtl.flush()?;
+
+        let mut obj = BufWriter::new(fs::File::create(path)?);
+        writeln!(
+            obj,
+            "mtllib {}",
+            mtl_path.file_name().unwrap().to_string_lossy()
+        )?;
+        for p in &self.positions {
+            writeln!(obj, "v {} {} {}", p[0], p[1], p[2])?;
+        }
+        for n in &self.normals {
+            writeln!(obj, "vn {} {} {}", n[0], n[1], n[2])?;
+        }
+        for uv in &self.tex_coords {
+            writeln!(obj, "vt {} {}", uv[0], uv[1])?;
+        }
+        for (i, group) in self.groups.iter().enumerate() {
+            writeln!(obj, "usemtl material_{}", i)?;
+            for t in &group.indices {
+                let [a, b, c] = t.map(|i| i + 1);
+                writeln!(obj, "f {0}/{0}/{0} {1}/{1}/{1} {2}/{2}/{2}", a, b, c)?;
+            }
+        }
+        obj.flush()
+    }
+    /// Writes path (.gltf) and the buffer with the extension .bin.
+    /// An empty mesh is an error, since glTF allows neither empty buffers nor meshes without primitives.
+    pub fn write_gltf(&self, path: &Path) -> io::Result<()> {
+        fn number(x: f32) -> Json {
+            Json::Number(x as f64)
+        }
+        fn vec3(v: &Vec3) -> Json {
+            Json::Array(v.iter().map(|&x| number(x)).collect())
+        }
+        fn object(members: Vec<(&str, Json)>) -> Json {
+            Json::Object(
+                members
+                    .into_iter()
+                    .map(|(k, v)| (k.to_string(), v))
+                    .collect(),
+            )
+        }
+        fn usize(n: usize) -> Json {
+            Json::Number(n as f64)
+        }
+        const FLOAT: usize = 5126;
+        const UNSIGNED_INT: usize = 5125;
+        if self.triangle_cnt() == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "No triangles to export.",
+            ));
+        }
+
+        let mut bin = Vec::new();
+        let mut views = Vec::new();
+        let mut accessors = Vec::new();
+        let mut add_view = |bin: &mut Vec<u8>, data: Vec<[u8; 4]>, target: usize| {
+            views.push(object(vec![
+                ("buffer", usize(0)),
+                ("byteOffset", usize(bin.len())),
+                ("byteLength", usize(4 * data.len())),
+                ("target", usize(target)),
+            ]));
+            bin.extend(data.iter().flatten());
+            views.len() - 1
+        };
+        let vertex_cnt = self.positions.len();
+        let flatten3 = |vs: &[Vec3]| vs.iter().flatten().map(|x| x.to_le_bytes()).collect();
+        let mut min = Vec3::repeat(std::f32::MAX);
+        let mut max = Vec3::repeat(-std::f32::MAX);
+        for p in &self.positions {
+            min = min_vec3(&min, p);
+            max = max_vec3(&max, p);
+        }
+        let attributes = [
+            ("POSITION", flatten3(&self.positions), "VEC3"),
+            ("NORMAL", flatten3(&self.normals), "VEC3"),
+            (
+                "TEXCOORD_0",
+                self.tex_coords
+                    .iter()
+                    .flat_map(|uv| [uv[0], 1.0 - uv[1]])
+                    .map(f32::to_le_bytes)
+                    .collect(),
+                "VEC2",
+            ),
+        ];
+        let mut attribute_members = Vec::new();
+        for (name, data, ty) in attributes {
+            let view = add_view(&mut bin, data, 34962);
+            let mut accessor = vec![
+                ("bufferView", usize(view)),
+                ("componentType", usize(FLOAT)),
+                ("count", usize(vertex_cnt)),
+                ("type", Json::String(ty.to_string())),
+            ];
+            if name == "POSITION" {
+                accessor.push(("min", vec3(&min)));
+                accessor.push(("max", vec3(&max)));
+            }
+            accessors.push(object(accessor));
+            attribute_members.push((name, usize(accessors.len() - 1)));
+        }
+        let mut primitives = Vec::new();
+        let mut materials = Vec::new();
+        for (i, group) in self.groups.iter().enumerate() {
+            let indices = group
+                .indices
+                .iter()
+                .flatten()
+                .map(|i| i.to_le_bytes())
+                .collect();
+            let view = add_view(&mut bin, indices, 34963);
+            accessors.push(object(vec![
+                ("bufferView", usize(view)),
+                ("componentType", usize(UNSIGNED_INT)),
+                ("count", usize(3 * group.indices.len())),
+                ("type", Json::String("SCALAR".to_string())),
+            ]));
+            primitives.push(object(vec![
+                ("attributes", object(attribute_members.clone())),
+                ("indices", usize(accessors.len() - 1)),
+                ("material", usize(i)),
+            ]));
+            // emissiveFactorは[0, 1]なので、1を超える分はKHR_materials_emissive_strengthで表す
+            let strength = group.emission.amax().max(1.0);
+            let mut material = vec![
+                ("name", Json::String(format!("material_{}", i))),
+                (
+                    "pbrMetallicRoughness",
+                    object(vec![
+                        (
+                            "baseColorFactor",
+                            Json::Array(
+                                group
+                                    .albedo
+                                    .iter()
+                                    .chain(&[1.0])
+                                    .map(|&x| number(x))
+                                    .collect(),
+                            ),
+                        ),
+                        ("metallicFactor", number(0.0)),
+                        ("roughnessFactor", number(1.0)),
+                    ]),
+                ),
+            ];
+            if group.emission != Vec3::new(0.0, 0.0, 0.0) {
+                material.push(("emissiveFactor", vec3(&(group.emission / strength))));
+            }
+            if strength > 1.0 {
+                material.push((
+                    "extensions",
+                    object(vec![(
+                        "KHR_materials_emissive_strength",
+                        object(vec![("emissiveStrength", number(strength))]),
+                    )]),
+                ));
+            }
+            materials.push(object(material));
+        }
+
+        let bin_path = path.with_extension("bin");
+        let uses_strength = self.groups.iter().any(|g| g.emission.amax() > 1.0);
+        let mut root = vec![
+            (
+                "asset",
+                object(vec![("version", Json::String("2.0".to_string()))]),
+            ),
+            ("scene", usize(0)),
+            (
+                "scenes",
+                Json::Array(vec![object(vec![("nodes", Json::Array(vec![usize(0)]))])]),
+            ),
+            ("nodes", Json::Array(vec![object(vec![("mesh", usize(0))])])),
+            (
+                "meshes",
+                Json::Array(vec![object(vec![("primitives", Json::Array(primitives))])]),
+            ),
+            ("materials", Json::Array(materials)),
+            ("accessors", Json::Array(accessors)),
+            ("bufferViews", Json::Array(views)),
+            (
+                "buffers",
+                Json::Array(vec![object(vec![
+                    (
+                        "uri",
+                        Json::String(bin_path.file_name().unwrap().to_string_lossy().into_owned()),
+                    ),
+                    ("byteLength", usize(bin.len())),
+                ])]),
+            ),
+        ];
+        if uses_strength {
+            root.push((
+                "extensionsUsed",
+                Json::Array(vec![Json::String(
+                    "KHR_materials_emissive_strength".to_string(),
+                )]),
+            ));
+        }
+        fs::write(&bin_path, &bin)?;
+        fs::write(path, object(root).to_string())
+    }
+}
+
+/// Appends the triangles of a rectangle (origin, origin + edge_0, origin + edge_0 + edge_1, origin + edge_1),
+/// whose front side is the side of edge_0 x edge_1.
+pub fn tessellate_rectangle(
+    mesh: &mut ExportMesh,
+    origin: &Vec3,
+    edge_0: &Vec3,
+    edge_1: &Vec3,
+    material: &Material,
+) {
+    let normal = edge_0.cross(edge_1);
+    let corners = [
+        (*origin, Vec2::new(0.0, 0.0)),
+        (origin + edge_0, Vec2::new(1.0, 0.0)),
+        (origin + edge_0 + edge_1, Vec2::new(1.0, 1.0)),
+        (origin + edge_1, Vec2::new(0.0, 1.0)),
+    ];
+    let v = corners.map(|(p, uv)| mesh.add_vertex(&p, &normal, &uv));
+    mesh.add_triangle([v[0], v[1], v[2]], material);
+    mesh.add_triangle([v[0], v[2], v[3]], material);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine;
+    use crate::aliases::Vec3;
+    use crate::export::ExportMesh;
+    use crate::gltf_file::GltfFile;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::cube_rectangles;
+    use crate::hitable::hitable_list::HitableList;
+    use crate::hitable::sphere::{MovingSphere, Sphere};
+    use crate::hitable::transform::Transform;
+    use crate::hitable::Hitable;
+    use crate::material::diffuse_light::DiffuseLight;
+    use crate::material::lambertian::Lambertian;
+    use crate::material::Material;
+    use crate::obj_file::ObjFile;
+    use crate::ray::Ray;
+    use crate::texture::constant::ConstantTexture;
+    use std::fs;
+    use std::sync::Arc;
+    #[test]
+    fn export_and_import() {
+        let red: Arc<Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(
+            &Vec3::new(0.8, 0.1, 0.1),
+        ))));
+        let light = Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
+            &Vec3::new(4.0, 4.0, 4.0),
+        ))));
+        let cube = BVH::new(
+            cube_rectangles(&Vec3::zeros(), &Vec3::new(1.0, 1.0, 1.0), red.clone(), 0),
+            0.0,
+            1.0,
+        );
+        // 鏡映を含む変換でも、法線と頂点の順序は整合する
+        let tr = Affine::translate(&Vec3::new(2.0, 0.0, 0.0))
+            .compose(&Affine::scale(-1.0, &Vec3::zeros()));
+        let scene: Vec<Arc<Hitable>> = vec![
+            Arc::new(Transform::new(Arc::new(cube), &tr, 0.0, 1.0)),
+            Arc::new(Sphere::new(&Vec3::new(0.0, 3.0, 0.0), 0.5, light)),
+            Arc::new(MovingSphere::new(
+                &Vec3::zeros(),
+                &Vec3::zeros(),
+                1.0,
+                Box::new(Lambertian::new(Arc::new(ConstantTexture::new(
+                    &Vec3::zeros(),
+                )))),
+            )),
+        ];
+        let mut mesh = ExportMesh::new();
+        mesh.sphere_segments = 8;
+        mesh.sphere_rings = 4;
+        HitableList::new(scene).tessellate(&mut mesh);
+        // 立方体12 + 球（極の周りは1枚ずつ）8 * (2 + 2 * 2)
+        assert_eq!(mesh.triangle_cnt(), 12 + 48);
+        assert_eq!(mesh.groups.len(), 2);
+        assert_eq!(mesh.skipped, 1);
+        for group in &mesh.groups {
+            for t in &group.indices {
+                let [a, b, c] = t.map(|i| mesh.positions[i as usize]);
+                let n = mesh.normals[t[0] as usize];
+                assert!((b - a).cross(&(c - a)).dot(&n) > 0.0);
+            }
+        }
+
+        let dir = std::env::temp_dir().join(format!("ray-export-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        mesh.write_obj(&dir.join("scene.obj")).unwrap();
+        let meshes = ObjFile::load_meshes(&dir.join("scene.obj"), red.clone()).unwrap();
+        assert_eq!(meshes.iter().map(|m| m.triangle_cnt()).sum::<usize>(), 60);
+        mesh.write_gltf(&dir.join("scene.gltf")).unwrap();
+        let scene = GltfFile::from_file(&dir.join("scene.gltf"))
+            .unwrap()
+            .to_scene(1.0)
+            .unwrap()
+            .scene;
+        let bbox = scene.hitables.bounding_box(0.0, 1.0).unwrap();
+        assert!((bbox.min - Vec3::new(-0.5, -1.0, -1.0)).amax() < 1.0e-3);
+        assert!((bbox.max - Vec3::new(2.0, 3.5, 0.5)).amax() < 1.0e-3);
+        // 球光源の放射は読み直してもScene::lightになる
+        let ray = Ray::new(&Vec3::new(0.0, 3.0, 3.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = scene
+            .light
+            .as_ref()
+            .unwrap()
+            .hit(&ray, 0.0, std::f32::MAX)
+            .unwrap();
+        assert!((rec.material.emitted(&ray, &rec) - Vec3::new(4.0, 4.0, 4.0)).amax() < 1.0e-3);
+        // 空のメッシュは正しいglTFにならない
+        let empty = ExportMesh::from_hitable(&HitableList::new(vec![]));
+        assert!(empty.write_gltf(&dir.join("empty.gltf")).is_err());
+        assert!(!dir.join("empty.gltf").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
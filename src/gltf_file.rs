@@ -0,0 +1,1079 @@
+// glTF 2.0 (.gltf + .bin、.glb)からSceneを作る。
+// 参考：https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html
+// ・ノードのワールド変換が相似変換ならメッシュのOBVHを共有するInstanceとしてTlasに入れ、
+//   そうでなければ（非一様スケールなど）頂点に変換を焼き込む。
+// ・マテリアルはmtl_fileと同じ方針で、Emissive -> DiffuseLight、透過 -> Glass、
+//   metallic >= 0.5 -> Metal、それ以外 -> LBP とし、normalTextureがあればNormalMapで包む。
+//   baseColorTextureは（Glassでは屈折光の色として）全てに使い、テクスチャがある場合はbaseColorFactorを掛けない。
+//   metallicRoughnessTextureとemissiveTextureは無視して警告を出す。
+// ・Emissiveなプリミティブは、ノードごとにワールド座標に焼き込んでScene::lightに加える（BLASには入れない）。
+// ・KHR_lights_punctualの点光源とスポットライトは、同じ光度の小さな球光源で近似する（スポットの円錐は無視して警告を出す）。
+//   平行光源は背景のDirectionalLightにする。
+// ・カメラはシーンの最初のperspectiveカメラを使い、無ければシーン全体を正面から見るカメラを作る。
+// ・UVは上下を反転してImageTextureの向き（vが上向き）に合わせ、接線はTANGENTを使わずに計算し直す。
+
+use crate::affine::Affine;
+use crate::aliases::{Mat3, Vec2, Vec3};
+use crate::background::{AmbientLight, Background, DirectionalLight, WeightedBg};
+use crate::camera::Camera;
+use crate::hitable::bvh::BVH;
+use crate::hitable::hitable_list::HitableList;
+use crate::hitable::instance::{Instance, Tlas};
+use crate::hitable::obvh::OBVH;
+use crate::hitable::sphere::Sphere;
+use crate::hitable::triangle_mesh::{MeshTriangle, TriangleMesh};
+use crate::hitable::Hitable;
+use crate::json;
+use crate::json::Json;
+use crate::material::diffuse_light::DiffuseLight;
+use crate::material::glass::Glass;
+use crate::material::lambertian::Lambertian;
+use crate::material::lbp::{exponent_from_roughness, LBP};
+use crate::material::metal::Metal;
+use crate::material::normal_map::NormalMap;
+use crate::material::Material;
+use crate::scene::Scene;
+use crate::texture::constant::ConstantTexture;
+use crate::texture::image::ImageTexture;
+use crate::texture::Texture;
+use nalgebra as na;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The radius of the spheres approximating point lights and spot lights.
+pub const POINT_LIGHT_RADIUS: f32 = 0.01;
+
+// bufferViewの無いアクセサ（全て0）の要素数は、壊れたファイルで巨大な確保をしないようにここまでにする
+const MAX_ZERO_ELEMENTS: usize = 1 << 24;
+
+/// A scene read from a glTF file.
+pub struct GltfScene {
+    pub scene: Scene,
+    pub warnings: Vec<String>, // ignored properties
+}
+
+pub struct GltfFile {
+    json: Json,
+    buffers: Vec<Vec<u8>>,
+    dir: PathBuf, // URIs are relative to this
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    Json(json::Error),
+    Format(String),      // the file is not valid glTF
+    Unsupported(String), // e.g., sparse accessors and orthographic cameras
+    Image(String, image::ImageError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "{}", e),
+            Error::Json(e) => write!(f, "JSON {}", e),
+            Error::Format(message) => write!(f, "{}", message),
+            Error::Unsupported(feature) => write!(f, "{} is not supported.", feature),
+            Error::Image(image, e) => write!(f, "{}: {}", image, e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(io_e: io::Error) -> Self {
+        Error::IO(io_e)
+    }
+}
+
+fn format_error<T>(message: String) -> Result<T, Error> {
+    Err(Error::Format(message))
+}
+
+/// An affine map x -> a x + b, which may not be a similarity unlike Affine.
+#[derive(Clone, Copy)]
+struct Matrix {
+    a: Mat3,
+    b: Vec3,
+}
+
+impl Matrix {
+    fn compose(&self, other: &Matrix) -> Matrix {
+        Matrix {
+            a: self.a * other.a,
+            b: self.a * other.b + self.b,
+        }
+    }
+}
+
+impl GltfFile {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let data = fs::read(path)?;
+        Self::from_slice(&data, path.parent().unwrap_or_else(|| Path::new("")))
+    }
+    /// Reads .gltf (JSON) or .glb (binary) data. dir is the directory where the external files are.
+    pub fn from_slice(data: &[u8], dir: &Path) -> Result<Self, Error> {
+        let (text, bin) = if data.starts_with(b"glTF") {
+            split_glb(data)?
+        } else {
+            (data, None)
+        };
+        let text = match std::str::from_utf8(text) {
+            Ok(text) => text.trim_start_matches('\u{feff}'),
+            Err(_) => return format_error("The JSON is not UTF-8.".to_string()),
+        };
+        let json = Json::parse(text).map_err(Error::Json)?;
+        let version = json
+            .get("asset")
+            .and_then(|a| a.get("version"))
+            .and_then(Json::as_str);
+        if !version.is_some_and(|v| v.starts_with("2.")) {
+            return Err(Error::Unsupported(format!("glTF version {:?}", version)));
+        }
+        let mut buffers = Vec::new();
+        for (i, buffer) in array(&json, "buffers").iter().enumerate() {
+            let data = match buffer.get("uri").and_then(Json::as_str) {
+                Some(uri) => read_uri(uri, dir)?,
+                None if i == 0 && bin.is_some() => bin.unwrap().to_vec(),
+                None => return format_error(format!("buffers[{}] has no data.", i)),
+            };
+            let length = buffer
+                .get("byteLength")
+                .and_then(Json::as_usize)
+                .unwrap_or(0);
+            if data.len() < length {
+                return format_error(format!("buffers[{}] is shorter than byteLength.", i));
+            }
+            buffers.push(data);
+        }
+        Ok(GltfFile {
+            json: json,
+            buffers: buffers,
+            dir: dir.to_path_buf(),
+        })
+    }
+    /// Builds the default scene (or the first one).
+    /// * `aspect` - width over height of the image, which is used instead of aspectRatio of the camera.
+    pub fn to_scene(&self, aspect: f32) -> Result<GltfScene, Error> {
+        let roots: Vec<usize> = match self.json.get("scenes") {
+            Some(_) => {
+                let scene = self.json.get("scene").and_then(Json::as_usize).unwrap_or(0);
+                self.get("scenes", scene)?
+                    .get("nodes")
+                    .map(|nodes| indices(nodes, "scene.nodes"))
+                    .transpose()?
+                    .unwrap_or_default()
+            }
+            // シーンが無ければ、どのノードの子でもないノードを全て使う
+            None => {
+                let mut is_child = vec![false; array(&self.json, "nodes").len()];
+                for node in array(&self.json, "nodes") {
+                    if let Some(children) = node.get("children") {
+                        for child in indices(children, "node.children")? {
+                            if let Some(c) = is_child.get_mut(child) {
+                                *c = true;
+                            }
+                        }
+                    }
+                }
+                (0..is_child.len()).filter(|&i| !is_child[i]).collect()
+            }
+        };
+        let mut builder = SceneBuilder {
+            file: self,
+            materials: HashMap::new(),
+            textures: HashMap::new(),
+            meshes: HashMap::new(),
+            instances: Vec::new(),
+            baked: Vec::new(),
+            lights: Vec::new(),
+            backgrounds: Vec::new(),
+            camera: None,
+            warnings: Vec::new(),
+        };
+        let identity = Matrix {
+            a: Mat3::identity(),
+            b: Vec3::zeros(),
+        };
+        for root in roots {
+            builder.add_node(root, &identity, 0)?;
+        }
+        builder.build(aspect)
+    }
+    /// The index-th item of the top-level array kind, e.g., get("meshes", 0).
+    fn get(&self, kind: &str, index: usize) -> Result<&Json, Error> {
+        match array(&self.json, kind).get(index) {
+            Some(item) => Ok(item),
+            None => format_error(format!("{}[{}] does not exist.", kind, index)),
+        }
+    }
+    fn buffer_view(&self, index: usize) -> Result<(&[u8], Option<usize>), Error> {
+        let view = self.get("bufferViews", index)?;
+        let buffer = view.get("buffer").and_then(Json::as_usize);
+        let buffer = match buffer.and_then(|b| self.buffers.get(b)) {
+            Some(buffer) => buffer,
+            None => return format_error(format!("bufferViews[{}] has no buffer.", index)),
+        };
+        let offset = view.get("byteOffset").and_then(Json::as_usize).unwrap_or(0);
+        let length = view.get("byteLength").and_then(Json::as_usize).unwrap_or(0);
+        let end = match offset.checked_add(length) {
+            Some(end) => end,
+            None => return format_error(format!("bufferViews[{}] is out of the buffer.", index)),
+        };
+        match buffer.get(offset..end) {
+            Some(data) => Ok((data, view.get("byteStride").and_then(Json::as_usize))),
+            None => format_error(format!("bufferViews[{}] is out of the buffer.", index)),
+        }
+    }
+    /// The elements of an accessor, each of which has the returned number of components.
+    fn accessor(&self, index: usize) -> Result<(Vec<f64>, usize), Error> {
+        let accessor = self.get("accessors", index)?;
+        if accessor.get("sparse").is_some() {
+            return Err(Error::Unsupported("Sparse accessor".to_string()));
+        }
+        let error = |message: &str| format_error(format!("accessors[{}]: {}", index, message));
+        let count = accessor.get("count").and_then(Json::as_usize).unwrap_or(0);
+        let components = match accessor.get("type").and_then(Json::as_str) {
+            Some("SCALAR") => 1,
+            Some("VEC2") => 2,
+            Some("VEC3") => 3,
+            Some("VEC4") => 4,
+            Some("MAT2") => 4,
+            Some("MAT3") => 9,
+            Some("MAT4") => 16,
+            _ => return error("Invalid type."),
+        };
+        let component_type = accessor.get("componentType").and_then(Json::as_usize);
+        let size = match component_type {
+            Some(5120) | Some(5121) => 1,
+            Some(5122) | Some(5123) => 2,
+            Some(5125) | Some(5126) => 4,
+            _ => return error("Invalid componentType."),
+        };
+        let normalized = accessor
+            .get("normalized")
+            .and_then(Json::as_bool)
+            .unwrap_or(false);
+        let view = match accessor.get("bufferView").and_then(Json::as_usize) {
+            Some(view) => view,
+            None if count <= MAX_ZERO_ELEMENTS => {
+                return Ok((vec![0.0; count * components], components)); // 全て0
+            }
+            None => return error("Too many elements without bufferView."),
+        };
+        let (data, stride) = self.buffer_view(view)?;
+        let offset = accessor
+            .get("byteOffset")
+            .and_then(Json::as_usize)
+            .unwrap_or(0);
+        let stride = stride.unwrap_or(size * components);
+        if stride < size * components {
+            return error("byteStride is smaller than an element.");
+        }
+        // 要素がビューに収まれば、countはビューの長さで抑えられる
+        let end = count.checked_sub(1).map_or(Some(0), |last| {
+            stride
+                .checked_mul(last)
+                .and_then(|p| p.checked_add(offset))
+                .and_then(|p| p.checked_add(size * components))
+        });
+        match end {
+            Some(end) if end <= data.len() => {}
+            _ => return error("Out of the buffer view."),
+        }
+        let mut values = Vec::with_capacity(count * components);
+        for i in 0..count {
+            for c in 0..components {
+                let p = offset + stride * i + size * c;
+                let b = &data[p..p + size];
+                let (x, max) = match component_type.unwrap() {
+                    5120 => (b[0] as i8 as f64, 127.0),
+                    5121 => (b[0] as f64, 255.0),
+                    5122 => (i16::from_le_bytes([b[0], b[1]]) as f64, 32767.0),
+                    5123 => (u16::from_le_bytes([b[0], b[1]]) as f64, 65535.0),
+                    5125 => (u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+                    _ => (f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64, 1.0),
+                };
+                values.push(if normalized { (x / max).max(-1.0) } else { x });
+            }
+        }
+        Ok((values, components))
+    }
+    fn vec3_accessor(&self, index: usize) -> Result<Vec<Vec3>, Error> {
+        match self.accessor(index)? {
+            (values, 3) => Ok(values
+                .chunks(3)
+                .map(|v| Vec3::new(v[0] as f32, v[1] as f32, v[2] as f32))
+                .collect()),
+            _ => format_error(format!("accessors[{}] is not VEC3.", index)),
+        }
+    }
+    /// The contents of an image given by its URI or by a buffer view.
+    fn image_data(&self, index: usize) -> Result<Vec<u8>, Error> {
+        let image = self.get("images", index)?;
+        if let Some(uri) = image.get("uri").and_then(Json::as_str) {
+            return read_uri(uri, &self.dir);
+        }
+        match image.get("bufferView").and_then(Json::as_usize) {
+            Some(view) => Ok(self.buffer_view(view)?.0.to_vec()),
+            None => format_error(format!("images[{}] has no data.", index)),
+        }
+    }
+}
+
+/// Splits a .glb file into the JSON chunk and the BIN chunk.
+fn split_glb(data: &[u8]) -> Result<(&[u8], Option<&[u8]>), Error> {
+    let u32_at = |i: usize| -> Option<usize> {
+        let b = data.get(i..i + 4)?;
+        Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize)
+    };
+    if u32_at(4) != Some(2) {
+        return Err(Error::Unsupported(format!("GLB version {:?}", u32_at(4))));
+    }
+    let length = u32_at(8).unwrap_or(0).min(data.len());
+    let mut chunks = Vec::new();
+    let mut p = 12;
+    while p + 8 <= length {
+        let chunk_length = u32_at(p).unwrap();
+        let chunk_type = u32_at(p + 4).unwrap();
+        match data.get(p + 8..p + 8 + chunk_length) {
+            Some(chunk) => chunks.push((chunk_type, chunk)),
+            None => return format_error("GLB chunk is out of the file.".to_string()),
+        }
+        p += 8 + chunk_length;
+    }
+    const JSON: usize = 0x4E4F_534A;
+    const BIN: usize = 0x004E_4942;
+    match chunks.first() {
+        Some(&(JSON, text)) => Ok((text, chunks.get(1).filter(|c| c.0 == BIN).map(|c| c.1))),
+        _ => format_error("GLB has no JSON chunk.".to_string()),
+    }
+}
+
+/// The data of a URI, which is a relative path (percent-encoded) or a base64 data URI.
+fn read_uri(uri: &str, dir: &Path) -> Result<Vec<u8>, Error> {
+    if uri.starts_with("data:") {
+        return match uri.find(";base64,") {
+            Some(i) => match decode_base64(&uri[i + 8..]) {
+                Some(data) => Ok(data),
+                None => format_error("Invalid base64 data URI.".to_string()),
+            },
+            None => Err(Error::Unsupported("Data URI not in base64".to_string())),
+        };
+    }
+    let mut path = Vec::with_capacity(uri.len());
+    let bytes = uri.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|h| std::str::from_utf8(h).ok())
+            .and_then(|h| u8::from_str_radix(h, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(c)) => {
+                path.push(c);
+                i += 3;
+            }
+            (c, _) => {
+                path.push(c);
+                i += 1;
+            }
+        }
+    }
+    match String::from_utf8(path) {
+        Ok(path) => Ok(fs::read(dir.join(path))?),
+        Err(_) => format_error(format!("Invalid URI {}.", uri)),
+    }
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::with_capacity(text.len() / 4 * 3);
+    let mut bits = 0u32;
+    let mut bit_cnt = 0;
+    for c in text.bytes().take_while(|&c| c != b'=') {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        bit_cnt += 6;
+        if 8 <= bit_cnt {
+            bit_cnt -= 8;
+            data.push((bits >> bit_cnt) as u8);
+        }
+    }
+    Some(data)
+}
+
+/// The top-level array kind, or an empty slice if it does not exist.
+fn array<'a>(json: &'a Json, kind: &str) -> &'a [Json] {
+    json.get(kind).and_then(Json::as_array).unwrap_or(&[])
+}
+
+fn indices(json: &Json, name: &str) -> Result<Vec<usize>, Error> {
+    let indices: Option<Vec<usize>> = json
+        .as_array()
+        .and_then(|a| a.iter().map(Json::as_usize).collect());
+    match indices {
+        Some(indices) => Ok(indices),
+        None => format_error(format!("Invalid {}.", name)),
+    }
+}
+
+/// The (first three) numbers of the property of json, or default if it does not exist.
+fn vec3_or(json: &Json, key: &str, default: Vec3) -> Result<Vec3, Error> {
+    match json.get(key).map(Json::as_f32_array) {
+        None => Ok(default),
+        Some(Some(ref v)) if 3 <= v.len() => Ok(Vec3::new(v[0], v[1], v[2])),
+        Some(_) => format_error(format!("Invalid {}.", key)),
+    }
+}
+
+fn f32_or(json: &Json, key: &str, default: f32) -> f32 {
+    json.get(key).and_then(Json::as_f32).unwrap_or(default)
+}
+
+fn local_matrix(node: &Json) -> Result<Matrix, Error> {
+    if let Some(matrix) = node.get("matrix") {
+        return match matrix.as_f32_array() {
+            // 列優先
+            Some(ref m) if m.len() == 16 => Ok(Matrix {
+                a: Mat3::new(m[0], m[4], m[8], m[1], m[5], m[9], m[2], m[6], m[10]),
+                b: Vec3::new(m[12], m[13], m[14]),
+            }),
+            _ => format_error("Invalid matrix.".to_string()),
+        };
+    }
+    let translation = vec3_or(node, "translation", Vec3::zeros())?;
+    let scale = vec3_or(node, "scale", Vec3::new(1.0, 1.0, 1.0))?;
+    let rotation = match node.get("rotation").map(Json::as_f32_array) {
+        None => Mat3::identity(),
+        Some(Some(ref q)) if q.len() == 4 => {
+            *na::UnitQuaternion::from_quaternion(na::Quaternion::new(q[3], q[0], q[1], q[2]))
+                .to_rotation_matrix()
+                .matrix()
+        }
+        Some(_) => return format_error("Invalid rotation.".to_string()),
+    };
+    Ok(Matrix {
+        a: rotation * Mat3::from_diagonal(&scale),
+        b: translation,
+    })
+}
+
+/// The acceleration structure of the primitives of a glTF mesh, shared by the instances.
+type Blas = OBVH<MeshTriangle>;
+
+/// The primitives of a glTF mesh.
+struct Primitives {
+    meshes: Vec<Arc<TriangleMesh>>,
+    blas: Option<Arc<Blas>>,     // of meshes
+    emissive: Vec<TriangleMesh>, // lights, which are baked for each node instead of the blas
+}
+
+struct SceneBuilder<'a> {
+    file: &'a GltfFile,
+    materials: HashMap<usize, (Arc<Material>, bool)>, // (material, is emissive)
+    textures: HashMap<usize, Arc<Texture>>,
+    meshes: HashMap<usize, Primitives>,
+    instances: Vec<Instance<Blas>>,
+    baked: Vec<MeshTriangle>, // meshes under non-similarity transformations
+    lights: Vec<Arc<Hitable>>,
+    backgrounds: Vec<Box<Background>>,
+    camera: Option<(Matrix, f32)>, // (world transformation, vertical field of view in degrees)
+    warnings: Vec<String>,
+}
+
+impl<'a> SceneBuilder<'a> {
+    fn add_node(&mut self, index: usize, parent: &Matrix, depth: usize) -> Result<(), Error> {
+        if depth > array(&self.file.json, "nodes").len() {
+            return format_error("The node hierarchy has a cycle.".to_string());
+        }
+        let node = self.file.get("nodes", index)?;
+        let world = parent.compose(&local_matrix(node)?);
+        if let Some(mesh) = node.get("mesh").and_then(Json::as_usize) {
+            self.add_mesh(mesh, &world)?;
+        }
+        if let Some(camera) = node.get("camera").and_then(Json::as_usize) {
+            if self.camera.is_none() {
+                let camera = self.file.get("cameras", camera)?;
+                match camera.get("perspective") {
+                    Some(perspective) => {
+                        let yfov = f32_or(perspective, "yfov", 0.8);
+                        self.camera = Some((world, yfov.to_degrees()));
+                    }
+                    None => return Err(Error::Unsupported("Orthographic camera".to_string())),
+                }
+            }
+        }
+        let light = node
+            .get("extensions")
+            .and_then(|e| e.get("KHR_lights_punctual"))
+            .and_then(|e| e.get("light"))
+            .and_then(Json::as_usize);
+        if let Some(light) = light {
+            self.add_light(light, &world)?;
+        }
+        if let Some(children) = node.get("children") {
+            for child in indices(children, "node.children")? {
+                self.add_node(child, &world, depth + 1)?;
+            }
+        }
+        Ok(())
+    }
+    fn add_mesh(&mut self, index: usize, world: &Matrix) -> Result<(), Error> {
+        if !self.meshes.contains_key(&index) {
+            let mut meshes = Vec::new();
+            let mut emissive = Vec::new();
+            for (mesh, is_emissive) in self.load_mesh(index)? {
+                if is_emissive {
+                    emissive.push(mesh);
+                } else {
+                    meshes.push(Arc::new(mesh));
+                }
+            }
+            let leaves: Vec<MeshTriangle> =
+                meshes.iter().flat_map(TriangleMesh::triangles).collect();
+            let blas = if leaves.is_empty() {
+                None
+            } else {
+                Some(Arc::new(OBVH::from_bvh(BVH::new_binned(
+                    leaves, 0.0, 0.0, 1,
+                ))))
+            };
+            let primitives = Primitives {
+                meshes: meshes,
+                blas: blas,
+                emissive: emissive,
+            };
+            self.meshes.insert(index, primitives);
+        }
+        let primitives = &self.meshes[&index];
+        for mesh in &primitives.emissive {
+            if let Some(baked) = mesh.transformed(&world.a, &world.b) {
+                self.lights
+                    .push(Arc::new(TriangleMesh::light(&Arc::new(baked))));
+            }
+        }
+        let blas = match &primitives.blas {
+            Some(blas) => blas,
+            None => return Ok(()),
+        };
+        match Affine::similarity(&world.a, &world.b) {
+            Some(tr) => self
+                .instances
+                .push(Instance::new(blas.clone(), &tr, None, 0.0, 0.0)),
+            None => {
+                for mesh in &primitives.meshes {
+                    if let Some(baked) = mesh.transformed(&world.a, &world.b) {
+                        self.baked.extend(TriangleMesh::triangles(&Arc::new(baked)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    /// One TriangleMesh for each primitive with whether its material is emissive.
+    /// Primitives of points and lines are ignored.
+    fn load_mesh(&mut self, index: usize) -> Result<Vec<(TriangleMesh, bool)>, Error> {
+        let file = self.file;
+        let mut meshes = Vec::new();
+        for primitive in array(file.get("meshes", index)?, "primitives") {
+            let mode = primitive.get("mode").and_then(Json::as_usize).unwrap_or(4);
+            if mode < 4 {
+                continue;
+            }
+            let attributes = primitive.get("attributes");
+            let attribute = |name| {
+                attributes
+                    .and_then(|a| a.get(name))
+                    .and_then(Json::as_usize)
+            };
+            let positions = match attribute("POSITION") {
+                Some(accessor) => file.vec3_accessor(accessor)?,
+                None => return format_error(format!("meshes[{}] has no POSITION.", index)),
+            };
+            let vertex_cnt = positions.len();
+            let normals = attribute("NORMAL")
+                .map(|accessor| file.vec3_accessor(accessor))
+                .transpose()?;
+            let tex_coords = match attribute("TEXCOORD_0") {
+                Some(accessor) => match file.accessor(accessor)? {
+                    (values, 2) => Some(
+                        values
+                            .chunks(2)
+                            .map(|uv| Vec2::new(uv[0] as f32, 1.0 - uv[1] as f32))
+                            .collect::<Vec<_>>(),
+                    ),
+                    _ => return format_error(format!("accessors[{}] is not VEC2.", accessor)),
+                },
+                None => None,
+            };
+            if normals.as_ref().is_some_and(|n| n.len() != vertex_cnt)
+                || tex_coords.as_ref().is_some_and(|t| t.len() != vertex_cnt)
+            {
+                return format_error(format!(
+                    "meshes[{}] has attributes of different lengths.",
+                    index
+                ));
+            }
+            let vertices: Vec<u32> = match primitive.get("indices").and_then(Json::as_usize) {
+                Some(accessor) => file
+                    .accessor(accessor)?
+                    .0
+                    .iter()
+                    .map(|&i| i as u32)
+                    .collect(),
+                None => (0..vertex_cnt as u32).collect(),
+            };
+            if vertices.iter().any(|&i| vertex_cnt <= i as usize) {
+                return format_error(format!("meshes[{}] has an invalid index.", index));
+            }
+            let mut triangles: Vec<[u32; 3]> = match mode {
+                4 => vertices
+                    .chunks_exact(3)
+                    .map(|t| [t[0], t[1], t[2]])
+                    .collect(),
+                5 => (2..vertices.len())
+                    .map(|i| {
+                        let t = [vertices[i - 2], vertices[i - 1], vertices[i]];
+                        if i % 2 == 0 {
+                            t
+                        } else {
+                            [t[1], t[0], t[2]]
+                        }
+                    })
+                    .collect(),
+                6 => (2..vertices.len())
+                    .map(|i| [vertices[0], vertices[i - 1], vertices[i]])
+                    .collect(),
+                _ => return format_error(format!("meshes[{}] has an invalid mode.", index)),
+            };
+            triangles.retain(|t| t[0] != t[1] && t[1] != t[2] && t[2] != t[0]);
+            let (material, is_emissive) = match primitive.get("material").and_then(Json::as_usize) {
+                Some(material) => self.material(material)?,
+                None => (
+                    Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+                        0.8, 0.8, 0.8,
+                    )))) as Arc<Material>,
+                    false,
+                ),
+            };
+            let mut mesh =
+                TriangleMesh::new(positions, normals, tex_coords, None, triangles, material);
+            mesh.generate_tangents();
+            meshes.push((mesh, is_emissive));
+        }
+        Ok(meshes)
+    }
+    /// The material and whether it is emissive.
+    fn material(&mut self, index: usize) -> Result<(Arc<Material>, bool), Error> {
+        if let Some(material) = self.materials.get(&index) {
+            return Ok(material.clone());
+        }
+        let json = self.file.get("materials", index)?;
+        let extension = |name: &str| json.get("extensions").and_then(|e| e.get(name));
+        let emissive_strength = extension("KHR_materials_emissive_strength")
+            .map_or(1.0, |e| f32_or(e, "emissiveStrength", 1.0));
+        let emissive = emissive_strength * vec3_or(json, "emissiveFactor", Vec3::zeros())?;
+        let pbr = json.get("pbrMetallicRoughness");
+        let base_color = match pbr
+            .and_then(|p| p.get("baseColorFactor"))
+            .map(Json::as_f32_array)
+        {
+            None => [1.0; 4],
+            Some(Some(ref c)) if c.len() == 4 => [c[0], c[1], c[2], c[3]],
+            Some(_) => return format_error("Invalid baseColorFactor.".to_string()),
+        };
+        let metallic = pbr.map_or(1.0, |p| f32_or(p, "metallicFactor", 1.0));
+        let roughness = pbr.map_or(1.0, |p| f32_or(p, "roughnessFactor", 1.0));
+        let transmission = extension("KHR_materials_transmission")
+            .map_or(0.0, |e| f32_or(e, "transmissionFactor", 0.0));
+        let blend = json.get("alphaMode").and_then(Json::as_str) == Some("BLEND");
+        let ior = extension("KHR_materials_ior").map_or(1.5, |e| f32_or(e, "ior", 1.5));
+        let color = Vec3::new(base_color[0], base_color[1], base_color[2]);
+        let is_emissive = emissive != Vec3::zeros();
+        for (parent, texture) in &[
+            (pbr, "metallicRoughnessTexture"),
+            (Some(json), "emissiveTexture"),
+        ] {
+            if parent.and_then(|p| p.get(texture)).is_some() {
+                self.warnings
+                    .push(format!("materials[{}]: {} is ignored.", index, texture));
+            }
+        }
+        let base_texture = pbr
+            .and_then(|p| p.get("baseColorTexture"))
+            .and_then(|t| t.get("index"))
+            .and_then(Json::as_usize);
+        let base: Arc<Texture> = match base_texture {
+            Some(texture) if !is_emissive => self.texture(texture)?,
+            _ => Arc::new(ConstantTexture::new(&color)),
+        };
+        let material: Arc<Material> = if is_emissive {
+            Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(&emissive))))
+        } else if 0.5 <= transmission || (blend && base_color[3] < 1.0) {
+            Arc::new(Glass::with_tint(ior, roughness, base))
+        } else if 0.5 <= metallic {
+            Arc::new(Metal::with_texture(base, roughness))
+        } else {
+            Arc::new(LBP::new(
+                base,
+                Arc::new(ConstantTexture::rgb(0.04, 0.04, 0.04)),
+                exponent_from_roughness(roughness),
+                0.0,
+            ))
+        };
+        let normal_texture = json.get("normalTexture");
+        let material = match normal_texture
+            .and_then(|t| t.get("index"))
+            .and_then(Json::as_usize)
+        {
+            Some(texture) if !is_emissive => Arc::new(NormalMap::new(
+                material,
+                self.texture(texture)?,
+                f32_or(normal_texture.unwrap(), "scale", 1.0),
+            )),
+            _ => material,
+        };
+        self.materials
+            .insert(index, (material.clone(), is_emissive));
+        Ok((material, is_emissive))
+    }
+    fn texture(&mut self, index: usize) -> Result<Arc<Texture>, Error> {
+        if let Some(texture) = self.textures.get(&index) {
+            return Ok(texture.clone());
+        }
+        let source = self
+            .file
+            .get("textures", index)?
+            .get("source")
+            .and_then(Json::as_usize);
+        let source = match source {
+            Some(source) => source,
+            None => {
+                return Err(Error::Unsupported(format!(
+                    "textures[{}] without source",
+                    index
+                )))
+            }
+        };
+        let image = image::load_from_memory(&self.file.image_data(source)?)
+            .map_err(|e| Error::Image(format!("images[{}]", source), e))?;
+        let texture: Arc<Texture> = Arc::new(ImageTexture::from_image(&image));
+        self.textures.insert(index, texture.clone());
+        Ok(texture)
+    }
+    fn add_light(&mut self, index: usize, world: &Matrix) -> Result<(), Error> {
+        let lights = self
+            .file
+            .json
+            .get("extensions")
+            .and_then(|e| e.get("KHR_lights_punctual"))
+            .map_or(&[][..], |e| array(e, "lights"));
+        let light = match lights.get(index) {
+            Some(light) => light,
+            None => return format_error(format!("lights[{}] does not exist.", index)),
+        };
+        let color =
+            f32_or(light, "intensity", 1.0) * vec3_or(light, "color", Vec3::new(1.0, 1.0, 1.0))?;
+        match light.get("type").and_then(Json::as_str) {
+            Some("directional") => {
+                // 光は-Z方向に進む
+                let direction = world.a * Vec3::new(0.0, 0.0, 1.0);
+                self.backgrounds
+                    .push(Box::new(DirectionalLight::new(&direction, &color)));
+            }
+            Some(ty @ "point") | Some(ty @ "spot") => {
+                if ty == "spot" {
+                    self.warnings.push(format!(
+                        "lights[{}]: The cone of the spot light is ignored.",
+                        index
+                    ));
+                }
+                // 光度I [cd]の点光源を、放射輝度I / (πr^2)の球で近似する
+                let r = POINT_LIGHT_RADIUS;
+                let radiance = color / (PI * r * r);
+                self.lights.push(Arc::new(Sphere::new(
+                    &world.b,
+                    r,
+                    Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(&radiance)))),
+                )));
+            }
+            ty => return Err(Error::Unsupported(format!("Light type {:?}", ty))),
+        }
+        Ok(())
+    }
+    fn build(self, aspect: f32) -> Result<GltfScene, Error> {
+        let mut hitables: Vec<Arc<Hitable>> = Vec::new();
+        if !self.instances.is_empty() {
+            hitables.push(Arc::new(Tlas::new(self.instances, 0.0, 0.0)));
+        }
+        if !self.baked.is_empty() {
+            hitables.push(Arc::new(OBVH::from_bvh(BVH::new_binned(
+                self.baked, 0.0, 0.0, 1,
+            ))));
+        }
+        hitables.extend(self.lights.iter().cloned());
+        let hitables: Arc<Hitable> = Arc::new(HitableList::new(hitables));
+        let light: Option<Arc<Hitable>> = match self.lights.len() {
+            0 => None,
+            1 => Some(self.lights[0].clone()),
+            _ => Some(Arc::new(HitableList::new(self.lights))),
+        };
+        let camera = match self.camera {
+            Some((world, vfov)) => {
+                let look_from = world.b;
+                let forward = world.a * Vec3::new(0.0, 0.0, -1.0);
+                let up = world.a * Vec3::new(0.0, 1.0, 0.0);
+                Camera::new(
+                    &look_from,
+                    &(look_from + forward),
+                    &up,
+                    vfov,
+                    aspect,
+                    0.0,
+                    1.0,
+                )
+            }
+            None => {
+                // シーン全体を+Z方向から見る
+                let (center, size) = match hitables.bounding_box(0.0, 0.0) {
+                    Some(bbox) => (0.5 * (bbox.min + bbox.max), (bbox.max - bbox.min).norm()),
+                    None => (Vec3::zeros(), 1.0),
+                };
+                let look_from = center + Vec3::new(0.0, 0.0, 1.5 * size);
+                Camera::new(
+                    &look_from,
+                    &center,
+                    &Vec3::new(0.0, 1.0, 0.0),
+                    40.0,
+                    aspect,
+                    0.0,
+                    1.0,
+                )
+            }
+        };
+        let mut backgrounds = self.backgrounds.into_iter();
+        let first: Box<Background> = match backgrounds.next() {
+            Some(bg) => bg,
+            None => Box::new(AmbientLight::new(&Vec3::zeros())),
+        };
+        let bg = backgrounds.fold(first, |a, b| Box::new(WeightedBg::new(1.0, a, 1.0, b)));
+        Ok(GltfScene {
+            scene: Scene {
+                hitables: hitables,
+                light: light,
+                camera: camera,
+                bg: Arc::from(bg),
+            },
+            warnings: self.warnings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::gltf_file::{Error, GltfFile, GltfScene};
+    use crate::ray::Ray;
+    use std::fs;
+    use std::path::Path;
+    #[test]
+    fn import_scene() {
+        // z = 0上の正方形[-1, 1]^2
+        let mut bin = Vec::new();
+        for p in &[
+            [-1.0f32, -1.0, 0.0],
+            [1.0, -1.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [-1.0, 1.0, 0.0],
+        ] {
+            for x in p {
+                bin.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        for _ in 0..4 {
+            for x in &[0.0f32, 0.0, 1.0] {
+                bin.extend_from_slice(&x.to_le_bytes());
+            }
+        }
+        for i in &[0u16, 1, 2, 0, 2, 3] {
+            bin.extend_from_slice(&i.to_le_bytes());
+        }
+        let json = |uri: &str| {
+            format!(
+                r#"{{
+  "asset": {{"version": "2.0"}},
+  "extensionsUsed": ["KHR_lights_punctual"],
+  "extensions": {{"KHR_lights_punctual": {{"lights": [{{"type": "point", "intensity": 2.0}}, {{"type": "spot"}}]}}}},
+  "scene": 0,
+  "scenes": [{{"nodes": [0, 3, 4, 5, 6, 7]}}],
+  "nodes": [
+    {{"translation": [0, 0, -5], "children": [1, 2]}},
+    {{"mesh": 0, "scale": [2, 2, 2]}},
+    {{"mesh": 0, "translation": [5, 0, 0], "scale": [1, 2, 1]}},
+    {{"camera": 0, "translation": [0, 0, 5], "rotation": [0, 0.7071068, 0, 0.7071068]}},
+    {{"translation": [0, 3, 0], "extensions": {{"KHR_lights_punctual": {{"light": 0}}}}}},
+    {{"mesh": 1, "translation": [-10, 0, -5]}},
+    {{"mesh": 2, "translation": [10, 0, -5]}},
+    {{"translation": [20, 3, 0], "extensions": {{"KHR_lights_punctual": {{"light": 1}}}}}}
+  ],
+  "cameras": [{{"type": "perspective", "perspective": {{"yfov": 0.5, "znear": 0.1}}}}],
+  "meshes": [
+    {{"primitives": [{{"attributes": {{"POSITION": 0, "NORMAL": 1}}, "indices": 2, "material": 0}}]}},
+    {{"primitives": [{{"attributes": {{"POSITION": 0, "NORMAL": 1}}, "indices": 2, "material": 1}}]}},
+    {{"primitives": [{{"attributes": {{"POSITION": 0, "NORMAL": 1}}, "indices": 2, "material": 2}}]}}
+  ],
+  "materials": [
+    {{"pbrMetallicRoughness": {{"baseColorFactor": [1, 0, 0, 1], "metallicFactor": 0.0}}}},
+    {{"emissiveFactor": [1, 1, 1], "emissiveTexture": {{"index": 0}},
+      "extensions": {{"KHR_materials_emissive_strength": {{"emissiveStrength": 3.0}}}}}},
+    {{"pbrMetallicRoughness": {{"baseColorTexture": {{"index": 0}}, "metallicRoughnessTexture": {{"index": 0}}}}}}
+  ],
+  "textures": [{{"source": 0}}],
+  "images": [{{"uri": "green.png"}}],
+  "accessors": [
+    {{"bufferView": 0, "componentType": 5126, "count": 4, "type": "VEC3", "min": [-1, -1, 0], "max": [1, 1, 0]}},
+    {{"bufferView": 0, "byteOffset": 48, "componentType": 5126, "count": 4, "type": "VEC3"}},
+    {{"bufferView": 1, "componentType": 5123, "count": 6, "type": "SCALAR"}}
+  ],
+  "bufferViews": [
+    {{"buffer": 0, "byteLength": 96}},
+    {{"buffer": 0, "byteOffset": 96, "byteLength": 12}}
+  ],
+  "buffers": [{{{}"byteLength": 108}}]
+}}"#,
+                uri
+            )
+        };
+        let dir = std::env::temp_dir().join(format!("ray-gltf-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("quad data.bin"), &bin).unwrap();
+        image::RgbImage::from_pixel(2, 2, image::Rgb([0, 255, 0]))
+            .save(dir.join("green.png"))
+            .unwrap();
+        fs::write(dir.join("a.gltf"), json(r#""uri": "quad%20data.bin", "#)).unwrap();
+        let gltf = GltfFile::from_file(&dir.join("a.gltf")).unwrap();
+
+        let mut text = json("").into_bytes();
+        text.resize((text.len() + 3) / 4 * 4, b' ');
+        bin.resize(112, 0);
+        let mut glb = b"glTF".to_vec();
+        glb.extend_from_slice(&2u32.to_le_bytes());
+        glb.extend_from_slice(&(12 + 8 + text.len() as u32 + 8 + 112).to_le_bytes());
+        glb.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        glb.extend_from_slice(b"JSON");
+        glb.extend_from_slice(&text);
+        glb.extend_from_slice(&112u32.to_le_bytes());
+        glb.extend_from_slice(b"BIN\0");
+        glb.extend_from_slice(&bin);
+        let glb = GltfFile::from_slice(&glb, &dir).unwrap();
+
+        for file in &[gltf, glb] {
+            let GltfScene { scene, warnings } = file.to_scene(1.0).unwrap();
+            let hit = |origin: Vec3| {
+                let ray = Ray::new(&origin, &Vec3::new(0.0, 0.0, -1.0), 0.0);
+                scene
+                    .hitables
+                    .hit(&ray, 0.0, std::f32::MAX)
+                    .map(|rec| (rec.t, rec.material.albedo(&rec), rec.normal))
+            };
+            // スケール2の正方形（インスタンス）
+            let (t, albedo, normal) = hit(Vec3::new(1.9, -1.9, 0.0)).unwrap();
+            assert!((t - 5.0).abs() < 1.0e-4);
+            assert_eq!(albedo, Vec3::new(1.0, 0.0, 0.0));
+            assert!((normal - Vec3::new(0.0, 0.0, 1.0)).norm() < 1.0e-4);
+            assert!(hit(Vec3::new(2.1, 0.0, 0.0)).is_none());
+            // 非一様スケールの正方形（焼き込み）
+            assert!(hit(Vec3::new(5.9, 1.9, 0.0)).is_some());
+            assert!(hit(Vec3::new(6.1, 1.9, 0.0)).is_none());
+            assert!(hit(Vec3::new(5.9, 2.1, 0.0)).is_none());
+            // baseColorTextureを使うMetal
+            let (_, albedo, _) = hit(Vec3::new(10.0, 0.0, 0.0)).unwrap();
+            assert_eq!(albedo, Vec3::new(0.0, 1.0, 0.0));
+            // 点光源
+            let light = scene.light.as_ref().unwrap();
+            let ray = Ray::new(&Vec3::new(0.0, 0.0, 0.0), &Vec3::new(0.0, 1.0, 0.0), 0.0);
+            let rec = light.hit(&ray, 0.0, std::f32::MAX).unwrap();
+            assert!((rec.t - 2.99).abs() < 1.0e-4);
+            assert!(rec.material.emitted(&ray, &rec)[0] > 0.0);
+            // Emissiveなプリミティブも光源になる
+            let ray = Ray::new(&Vec3::new(-10.0, 0.0, 0.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let rec = light.hit(&ray, 0.0, std::f32::MAX).unwrap();
+            assert!((rec.t - 5.0).abs() < 1.0e-4);
+            assert_eq!(rec.material.emitted(&ray, &rec), Vec3::new(3.0, 3.0, 3.0));
+            assert!(hit(Vec3::new(-10.0, 0.0, 0.0)).is_some());
+            assert_eq!(warnings.len(), 3);
+            assert!(warnings
+                .iter()
+                .any(|w| w.contains("metallicRoughnessTexture")));
+            assert!(warnings.iter().any(|w| w.contains("emissiveTexture")));
+            assert!(warnings.iter().any(|w| w.contains("spot")));
+            // Y軸周りに90度回転したカメラは-X方向を向く
+            assert!((scene.camera.forward() - Vec3::new(-1.0, 0.0, 0.0)).norm() < 1.0e-4);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        match GltfFile::from_slice(br#"{"asset": {"version": "1.0"}}"#, Path::new("")) {
+            Err(Error::Unsupported(_)) => {}
+            _ => panic!("glTF 1.0 must be unsupported."),
+        }
+    }
+    #[test]
+    fn reject_huge_accessors() {
+        // 12バイトの0のバッファに、大きすぎる要素数やオフセットを持つアクセサ
+        let json = |accessor: &str, view: &str| {
+            format!(
+                r#"{{
+  "asset": {{"version": "2.0"}},
+  "nodes": [{{"mesh": 0}}],
+  "meshes": [{{"primitives": [{{"attributes": {{"POSITION": 0}}}}]}}],
+  "accessors": [{{"componentType": 5126, "type": "VEC3", {}}}],
+  "bufferViews": [{{"buffer": 0, {}}}],
+  "buffers": [{{"uri": "data:application/octet-stream;base64,AAAAAAAAAAAAAAAA", "byteLength": 12}}]
+}}"#,
+                accessor, view
+            )
+        };
+        let cases = [
+            (r#""count": 1e30"#, r#""byteLength": 12"#),
+            (r#""count": 1e30, "bufferView": 0"#, r#""byteLength": 12"#),
+            (
+                r#""count": 1, "bufferView": 0"#,
+                r#""byteOffset": 1e30, "byteLength": 1e30"#,
+            ),
+            (
+                r#""count": 2, "bufferView": 0, "byteOffset": 1e30"#,
+                r#""byteLength": 12"#,
+            ),
+            (
+                r#""count": 1e30, "bufferView": 0"#,
+                r#""byteLength": 12, "byteStride": 0"#,
+            ),
+        ];
+        for (accessor, view) in &cases {
+            let file =
+                GltfFile::from_slice(json(accessor, view).as_bytes(), Path::new("")).unwrap();
+            match file.to_scene(1.0) {
+                Err(Error::Format(_)) => {}
+                _ => panic!("{} {} must be an error.", accessor, view),
+            }
+        }
+        let file = GltfFile::from_slice(
+            json(r#""count": 1, "bufferView": 0"#, r#""byteLength": 12"#).as_bytes(),
+            Path::new(""),
+        )
+        .unwrap();
+        assert!(file.to_scene(1.0).is_ok());
+    }
+}
@@ -7,17 +7,29 @@ pub struct HitRecord<'a> {
     pub t: f32,
     pub point: Vec3,
     pub tex_coord: Vec2,
-    pub normal: Vec3,
+    pub normal: Vec3,              // normal used for shading (may be interpolated)
+    pub geometric_normal: Vec3,    // normal of the actual surface
+    pub barycentric: Option<Vec3>, // barycentric coordinates when the hitable is a triangle
+    pub dpdu: Vec3,                // ∂point/∂u, where (u, v) = tex_coord. Tangent of the shading frame
+    pub dpdv: Vec3,                // ∂point/∂v
     pub material: &'a Material,
 }
 
 impl<'a> HitRecord<'a> {
+    /// See Material::shading_record().
+    pub fn shaded(self) -> HitRecord<'a> {
+        self.material.shading_record(&self)
+    }
     pub fn get_transformed(&self, tr: &Affine) -> HitRecord<'a> {
         HitRecord {
             t: self.t,
             point: tr.act_point(&self.point),
             tex_coord: self.tex_coord,
             normal: tr.act_2_vec(&self.normal).normalize(),
+            geometric_normal: tr.act_2_vec(&self.geometric_normal).normalize(),
+            barycentric: self.barycentric,
+            dpdu: tr.act_vec(&self.dpdu),
+            dpdv: tr.act_vec(&self.dpdv),
             material: self.material,
         }
     }
@@ -1,9 +1,29 @@
 use crate::aabb::Aabb;
+use crate::accel_cache;
+use crate::accel_cache::{read_aabb, read_u32, read_u64, write_aabb, write_u32, write_u64};
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
 use crate::hitable::bvh_node::BvhNode;
 use crate::hitable::node_pointer::NodePointer;
-use crate::hitable::Hitable;
+use crate::hitable::{Hitable, TraversalStats};
 use crate::ray::Ray;
+use crate::util::duration_to_secs;
+use std::io;
+use std::io::{Read, Write};
+use std::time::Instant;
+
+/// Number of bins per axis used by BVH::new_binned and BVH::new_sbvh.
+const BIN_CNT: usize = 32;
+/// Spatial splits are tried only if the children of the object split overlap more than this (relative to the root area).
+const SPATIAL_SPLIT_ALPHA: f32 = 1.0e-5;
+/// Subtrees with fewer leaves than this are built on the current thread.
+const PARALLEL_LEAF_CNT: usize = 1024;
+/// Costs used by BVH::sah_cost.
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+/// BVH::refit_or_rebuild (and OBVH::refit_or_rebuild) rebuilds the tree
+/// if the SAH cost becomes larger than this times the cost just after the construction.
+pub const REBUILD_SAH_RATIO: f32 = 1.5;
 
 /// NodePointerのwrapper（newtypeパターン）
 #[derive(Clone, Copy)]
@@ -29,11 +49,29 @@ impl Default for Node {
     }
 }
 
+/// 中心座標のビンによる分割。ビンがbin未満の葉が左に入る。
+#[derive(Clone, Copy)]
+struct BinnedSplit {
+    axis: usize,
+    lo: f32,     // 中心座標の最小値
+    extent: f32, // 中心座標の幅（> 0）
+    bin: usize,
+    cost: f32, // (左の面積) * (左の葉数) + (右の面積) * (右の葉数)
+}
+
+impl BinnedSplit {
+    fn bin_of(&self, bbox: &Aabb) -> usize {
+        (((bbox.center()[self.axis] - self.lo) / self.extent * BIN_CNT as f32) as usize)
+            .min(BIN_CNT - 1)
+    }
+}
+
 // ToDo: OBVHから参照するためにpubにしている。
 pub struct BVH<L> {
     pub leaves: Vec<L>,    // leaf-nodes.
     pub inners: Vec<Node>, // inner nodes. inners[0] is the root node.
     pub bbox: Aabb,
+    built_sah_cost: f32, // 構築直後のsah_cost()
 }
 
 impl<L> BVH<L>
@@ -55,11 +93,196 @@ where
             .fold(Aabb::empty(), |accum, aabb| Aabb::unite(&accum, &aabb));
         let mut inners = Vec::<Node>::default();
         Self::construct(idx_bboxes, &mut inners);
-        Self {
+        Self::from_parts(leaves, inners, bbox)
+    }
+    fn from_parts(leaves: Vec<L>, inners: Vec<Node>, bbox: Aabb) -> Self {
+        let mut bvh = Self {
             leaves,
             inners,
             bbox,
+            built_sah_cost: 0.0,
+        };
+        bvh.built_sah_cost = bvh.sah_cost();
+        bvh
+    }
+    /// Builds the same structure as new() by the binned SAH, which does not sort the leaves.
+    /// Subtrees are built in parallel by at most thread_cnt threads.
+    pub fn new_binned(leaves: Vec<L>, time_0: f32, time_1: f32, thread_cnt: usize) -> Self {
+        let start_time = Instant::now();
+        let mut idx_bboxes: Vec<(usize, Aabb)> = leaves
+            .iter()
+            .enumerate()
+            .map(|(idx, leaf)| (idx, leaf.bounding_box(time_0, time_1).unwrap()))
+            .collect();
+        let bbox = idx_bboxes
+            .iter()
+            .fold(Aabb::empty(), |accum, leaf| Aabb::unite(&accum, &leaf.1));
+        let mut inners = Vec::<Node>::default();
+        // 2^parallel_depth >= thread_cnt
+        let parallel_depth = (thread_cnt.max(1) as f32).log2().ceil() as u32;
+        Self::construct_binned(&mut idx_bboxes, &mut inners, parallel_depth);
+        let bvh = Self::from_parts(leaves, inners, bbox);
+        println!(
+            "[BVH::new_binned] leaves={}, inners={}, {:.3} secs, SAH cost={:.3}",
+            bvh.leaves.len(),
+            bvh.inners.len(),
+            duration_to_secs(&start_time.elapsed()),
+            bvh.sah_cost()
+        );
+        bvh
+    }
+    /// Builds a BVH with spatial splits (Stich et al., "Spatial Splits in Bounding Volume Hierarchies").
+    /// A leaf may be referenced from several nodes, each of which bounds a part of the leaf (see Hitable::split_bounding_box).
+    /// Traversal needs no explicit deduplication: the second hit of the same leaf has the same t and is rejected by t < t_max.
+    /// * `spatial_split_budget` - the number of references may increase up to (1 + spatial_split_budget) * leaves.len().
+    pub fn new_sbvh(leaves: Vec<L>, time_0: f32, time_1: f32, spatial_split_budget: f32) -> Self {
+        let start_time = Instant::now();
+        let refs: Vec<(usize, Aabb)> = leaves
+            .iter()
+            .enumerate()
+            .map(|(idx, leaf)| (idx, leaf.bounding_box(time_0, time_1).unwrap()))
+            .collect();
+        let bbox = refs
+            .iter()
+            .fold(Aabb::empty(), |accum, leaf| Aabb::unite(&accum, &leaf.1));
+        let mut builder = SbvhBuilder {
+            leaves: &leaves,
+            min_overlap: SPATIAL_SPLIT_ALPHA * bbox.area(),
+            remaining_refs: (leaves.len() as f32 * spatial_split_budget.max(0.0)) as usize,
+            nodes: Vec::default(),
+        };
+        builder.construct(refs, &bbox);
+        let inners = builder.nodes;
+        let bvh = Self::from_parts(leaves, inners, bbox);
+        println!(
+            "[BVH::new_sbvh] leaves={}, references={}, inners={}, {:.3} secs, SAH cost={:.3}",
+            bvh.leaves.len(),
+            bvh.reference_cnt(),
+            bvh.inners.len(),
+            duration_to_secs(&start_time.elapsed()),
+            bvh.sah_cost()
+        );
+        bvh
+    }
+    /// Updates the bounding boxes bottom-up after the leaves have moved. The tree structure is kept.
+    /// Boxes of the leaves split by new_sbvh become the whole bounding boxes of the leaves.
+    /// If the tree has no root (inners is public and may be left empty), the root is built as construct does.
+    pub fn refit(&mut self, time_0: f32, time_1: f32) {
+        if self.inners.is_empty() {
+            let idx_bboxes = self
+                .leaves
+                .iter()
+                .enumerate()
+                .map(|(idx, leaf)| (idx, leaf.bounding_box(time_0, time_1).unwrap()))
+                .collect();
+            Self::construct(idx_bboxes, &mut self.inners);
+        }
+        self.bbox = self.refit_node(0, time_0, time_1);
+    }
+    /// * return - inners[idx]の2つの子のバウンディングボックスの和
+    fn refit_node(&mut self, idx: usize, time_0: f32, time_1: f32) -> Aabb {
+        let children = self.inners[idx].children;
+        let mut bboxes = [Aabb::empty(); 2];
+        for (bbox, child) in bboxes.iter_mut().zip(children.iter()) {
+            *bbox = if child.0.is_empty_leaf() {
+                Aabb::empty()
+            } else if child.0.is_leaf() {
+                self.leaves[child.0.index()]
+                    .bounding_box(time_0, time_1)
+                    .unwrap()
+            } else {
+                self.refit_node(child.0.index(), time_0, time_1)
+            };
+        }
+        self.inners[idx].bboxes = bboxes;
+        Aabb::unite(&bboxes[0], &bboxes[1])
+    }
+    /// Whether refits have degraded the tree so much that a full rebuild pays off.
+    pub fn needs_rebuild(&self) -> bool {
+        self.sah_cost() > self.built_sah_cost * REBUILD_SAH_RATIO
+    }
+    /// Refits the tree, and rebuilds it by new_binned if needs_rebuild().
+    /// Call this every frame after moving the leaves.
+    pub fn refit_or_rebuild(mut self, time_0: f32, time_1: f32, thread_cnt: usize) -> Self {
+        self.refit(time_0, time_1);
+        if self.needs_rebuild() {
+            Self::new_binned(self.leaves, time_0, time_1, thread_cnt)
+        } else {
+            self
+        }
+    }
+    /// Writes the inner nodes (not the leaves) for accel_cache.
+    pub fn write_nodes(&self, writer: &mut impl Write) -> io::Result<()> {
+        write_aabb(writer, &self.bbox)?;
+        write_u64(writer, self.inners.len() as u64)?;
+        for node in &self.inners {
+            for i in 0..2 {
+                write_aabb(writer, &node.bboxes[i])?;
+                write_u32(writer, node.children[i].0.to_raw())?;
+            }
+            write_u32(writer, u32::from(node.axis))?;
+        }
+        Ok(())
+    }
+    /// Reads the inner nodes written by write_nodes, which were built over leaves.
+    pub fn read_nodes(reader: &mut impl Read, leaves: Vec<L>) -> Result<Self, accel_cache::Error> {
+        let bbox = read_aabb(reader)?;
+        let inner_cnt = read_u64(reader)? as usize;
+        let mut inners = Vec::with_capacity(inner_cnt.min(1 << 24));
+        for idx in 0..inner_cnt {
+            let mut node = Node::default();
+            for i in 0..2 {
+                node.bboxes[i] = read_aabb(reader)?;
+                node.children[i] = BVHNodePointer(NodePointer::from_raw(read_u32(reader)?));
+                if !node.children[i].0.is_valid(leaves.len(), inner_cnt) {
+                    return Err(accel_cache::Error::Corrupted(
+                        "BVH node pointer out of range.".to_string(),
+                    ));
+                }
+                // 構築した木では子は親より後ろにあるので、そうでなければ（循環しうる）古いか壊れたファイル
+                if !node.children[i].0.follows(idx) {
+                    return Err(accel_cache::Error::Stale);
+                }
+            }
+            node.axis = read_u32(reader)?.min(2) as u8;
+            inners.push(node);
+        }
+        if inners.is_empty() {
+            return Err(accel_cache::Error::Corrupted(
+                "BVH has no root.".to_string(),
+            ));
+        }
+        Ok(Self::from_parts(leaves, inners, bbox))
+    }
+    /// 葉への参照の個数。空間分割がなければleaves.len()に等しい。
+    fn reference_cnt(&self) -> usize {
+        self.inners
+            .iter()
+            .flat_map(|node| node.children.iter())
+            .filter(|child| child.0.is_leaf() && !child.0.is_empty_leaf())
+            .count()
+    }
+    /// Expected cost of finding the closest hit of a ray which hits the root box,
+    /// i.e., the sum of (surface area of a node / that of the root) * (traversal or intersection cost).
+    pub fn sah_cost(&self) -> f32 {
+        let root_area = self.bbox.area();
+        if self.bbox.is_empty() || root_area <= 0.0 {
+            return 0.0;
+        }
+        let mut cost = TRAVERSAL_COST * root_area;
+        for node in &self.inners {
+            for (bbox, child) in node.bboxes.iter().zip(node.children.iter()) {
+                if child.0.is_empty_leaf() || bbox.is_empty() {
+                    continue;
+                }
+                if child.0.is_leaf() {
+                    cost += INTERSECTION_COST * bbox.area();
+                } else {
+                    cost += TRAVERSAL_COST * bbox.area();
+                }
+            }
         }
+        cost / root_area
     }
     fn sort_by_center(list: &mut Vec<(usize, Aabb)>, axis: usize) {
         list.sort_unstable_by(|a, b| {
@@ -138,6 +361,144 @@ where
         }
         new_node_idx
     }
+    /// constructと同様だが、分割位置を中心座標のビン分けによって求める。
+    /// * parallel_depth - この深さまでは左の部分木を別スレッドで構築する
+    fn construct_binned(
+        leaves: &mut [(usize, Aabb)],
+        nodes: &mut Vec<Node>,
+        parallel_depth: u32,
+    ) -> usize {
+        if leaves.len() <= 2 {
+            return Self::construct(leaves.to_vec(), nodes);
+        }
+        nodes.push(Node::default());
+        let new_node_idx = nodes.len() - 1;
+        let (axis, mid) = match Self::search_binned_split(leaves) {
+            Some(split) => {
+                // 左に入る葉を前に集める。
+                let mut mid = 0;
+                for i in 0..leaves.len() {
+                    if split.bin_of(&leaves[i].1) < split.bin {
+                        leaves.swap(i, mid);
+                        mid += 1;
+                    }
+                }
+                (split.axis, mid)
+            }
+            None => {
+                // 中心がすべて一致しているなど、ビンで分割できない場合。constructと同様に半分に分ける。
+                let mid = leaves.len() / 2;
+                leaves.select_nth_unstable_by(mid, |a, b| a.1.compare_center(&b.1, 0));
+                (0, mid)
+            }
+        };
+        let (left_leaves, right_leaves) = leaves.split_at_mut(mid);
+        let bbox_from_leaves = |leaves: &[(usize, Aabb)]| {
+            leaves
+                .iter()
+                .fold(Aabb::empty(), |accum, leaf| Aabb::unite(&accum, &leaf.1))
+        };
+        let left_bbox = bbox_from_leaves(left_leaves);
+        let right_bbox = bbox_from_leaves(right_leaves);
+        let (left_node_idx, right_node_idx) =
+            if parallel_depth > 0 && mid + right_leaves.len() >= PARALLEL_LEAF_CNT {
+                let (left_nodes, right_node_idx) = crossbeam::scope(|scope| {
+                    let left = scope.spawn(move |_| {
+                        let mut left_nodes = Vec::<Node>::default();
+                        Self::construct_binned(left_leaves, &mut left_nodes, parallel_depth - 1);
+                        left_nodes
+                    });
+                    let right_node_idx =
+                        Self::construct_binned(right_leaves, nodes, parallel_depth - 1);
+                    (left.join().unwrap(), right_node_idx)
+                })
+                .unwrap();
+                (Self::append_nodes(nodes, left_nodes), right_node_idx)
+            } else {
+                let left_node_idx = Self::construct_binned(left_leaves, nodes, parallel_depth);
+                let right_node_idx = Self::construct_binned(right_leaves, nodes, parallel_depth);
+                (left_node_idx, right_node_idx)
+            };
+        nodes[new_node_idx] = Node {
+            bboxes: [left_bbox, right_bbox],
+            children: [
+                BVHNodePointer(NodePointer::new_inner(left_node_idx)),
+                BVHNodePointer(NodePointer::new_inner(right_node_idx)),
+            ],
+            axis: axis as u8,
+        };
+        new_node_idx
+    }
+    /// 別に構築した木（根はsubtree[0]）をnodesの末尾に移し、根のインデックスを返す。
+    fn append_nodes(nodes: &mut Vec<Node>, subtree: Vec<Node>) -> usize {
+        let offset = nodes.len();
+        nodes.extend(subtree.into_iter().map(|mut node| {
+            for child in node.children.iter_mut() {
+                if child.0.is_inner() {
+                    *child = BVHNodePointer(NodePointer::new_inner(child.0.index() + offset));
+                }
+            }
+            node
+        }));
+        offset
+    }
+    /// SAHコストが最小となる分割を返す。左右の一方が空になる分割しかない場合はNone。
+    fn search_binned_split(leaves: &[(usize, Aabb)]) -> Option<BinnedSplit> {
+        let centroid_bbox = leaves.iter().fold(Aabb::empty(), |mut accum, leaf| {
+            accum.append_point(leaf.1.center());
+            accum
+        });
+        let mut best: Option<BinnedSplit> = None;
+        let mut min_cost = std::f32::MAX;
+        for axis in 0..3 {
+            let mut split = BinnedSplit {
+                axis: axis,
+                lo: centroid_bbox.min[axis],
+                extent: centroid_bbox.max[axis] - centroid_bbox.min[axis],
+                bin: 0,
+                cost: 0.0,
+            };
+            if !split.extent.is_finite() || split.extent <= 0.0 {
+                continue;
+            }
+            let mut bin_boxes = [Aabb::empty(); BIN_CNT];
+            let mut bin_counts = [0usize; BIN_CNT];
+            for leaf in leaves {
+                let bin = split.bin_of(&leaf.1);
+                bin_boxes[bin] = Aabb::unite(&bin_boxes[bin], &leaf.1);
+                bin_counts[bin] += 1;
+            }
+            // right_areas[i] = area of the union of bins [i, BIN_CNT)
+            let mut right_areas = [0.0f32; BIN_CNT];
+            let mut right_counts = [0usize; BIN_CNT];
+            let mut accum = Aabb::empty();
+            let mut count = 0;
+            for i in (1..BIN_CNT).rev() {
+                accum = Aabb::unite(&accum, &bin_boxes[i]);
+                count += bin_counts[i];
+                right_areas[i] = accum.area();
+                right_counts[i] = count;
+            }
+            let mut accum = Aabb::empty();
+            let mut count = 0;
+            for i in 1..BIN_CNT {
+                // split between bin i-1 and bin i.
+                accum = Aabb::unite(&accum, &bin_boxes[i - 1]);
+                count += bin_counts[i - 1];
+                if count == 0 || right_counts[i] == 0 {
+                    continue;
+                }
+                let cost = accum.area() * count as f32 + right_areas[i] * right_counts[i] as f32;
+                if cost < min_cost {
+                    min_cost = cost;
+                    split.bin = i;
+                    split.cost = cost;
+                    best = Some(split);
+                }
+            }
+        }
+        best
+    }
     fn hit_core<'s, 'r>(
         &'s self,
         bbox: &Aabb,
@@ -145,16 +506,19 @@ where
         ray: &'r Ray,
         t_min: f32,
         t_max: f32,
+        stats: &mut TraversalStats,
     ) -> Option<HitRecord<'s>> {
+        stats.aabb_tests += 1;
         if !bbox.hit(ray, t_min, t_max) {
             None
         } else if node_ptr.0.is_leaf() {
             if node_ptr.0.is_empty_leaf() {
                 None
             } else {
-                self.leaves[node_ptr.0.index()].hit(ray, t_min, t_max)
+                self.leaves[node_ptr.0.index()].hit_with_stats(ray, t_min, t_max, stats)
             }
         } else {
+            stats.node_visits += 1;
             let node = &self.inners[node_ptr.0.index()];
             let prior_idx = (ray.direction[node.axis as usize] < 0.0) as usize;
             if let Some(ref hit_left) = self.hit_core(
@@ -163,6 +527,7 @@ where
                 ray,
                 t_min,
                 t_max,
+                stats,
             ) {
                 if let Some(ref hit_right) = self.hit_core(
                     &node.bboxes[1 - prior_idx],
@@ -170,6 +535,7 @@ where
                     ray,
                     t_min,
                     hit_left.t,
+                    stats,
                 ) {
                     Some(*hit_right)
                 } else {
@@ -182,27 +548,372 @@ where
                     ray,
                     t_min,
                     t_max,
+                    stats,
                 )
             }
         }
     }
 }
 
+/// BVH::new_sbvhの作業領域。
+struct SbvhBuilder<'a, L> {
+    leaves: &'a [L],
+    min_overlap: f32,      // これより子の重なりの面積が小さければ空間分割を試さない
+    remaining_refs: usize, // 空間分割で増やせる参照の数
+    nodes: Vec<Node>,
+}
+
+/// 参照の列。(葉のインデックス, 葉のうちノードに属する部分のバウンディングボックス)
+type References = Vec<(usize, Aabb)>;
+
+/// 空間分割。軸axisの平面positionで参照を左右に分ける。
+#[derive(Clone, Copy)]
+struct SpatialSplit {
+    axis: usize,
+    lo: f32,
+    extent: f32,
+    bin: usize,
+    cost: f32,
+}
+
+impl SpatialSplit {
+    fn bin_of(&self, x: f32) -> usize {
+        (((x - self.lo) / self.extent * BIN_CNT as f32).max(0.0) as usize).min(BIN_CNT - 1)
+    }
+    /// ビンbinの下端の平面の位置。
+    fn plane(&self, bin: usize) -> f32 {
+        self.lo + self.extent * bin as f32 / BIN_CNT as f32
+    }
+    fn position(&self) -> f32 {
+        self.plane(self.bin)
+    }
+}
+
+impl<'a, L> SbvhBuilder<'a, L>
+where
+    L: Hitable,
+{
+    /// BVH::construct_binnedと同様だが、オブジェクト分割より空間分割の方がコストが低ければ参照を分割する。
+    /// 参照は(葉のインデックス, 葉のうちこのノードに属する部分のバウンディングボックス)。
+    /// 同じ葉への参照は同じノードに2つ以上含まれない（空間分割された2つの部分は異なる部分木に入る）。
+    fn construct(&mut self, mut refs: References, bbox: &Aabb) -> usize {
+        if refs.len() <= 2 {
+            return BVH::<L>::construct(refs, &mut self.nodes);
+        }
+        self.nodes.push(Node::default());
+        let new_node_idx = self.nodes.len() - 1;
+        let bbox_from_refs = |refs: &[(usize, Aabb)]| {
+            refs.iter()
+                .fold(Aabb::empty(), |accum, r| Aabb::unite(&accum, &r.1))
+        };
+        let object_split = BVH::<L>::search_binned_split(&refs);
+        let mut spatial_split = None;
+        if self.remaining_refs > 0 {
+            let overlap = match object_split {
+                Some(ref split) => {
+                    let mut left = Aabb::empty();
+                    let mut right = Aabb::empty();
+                    for r in &refs {
+                        if split.bin_of(&r.1) < split.bin {
+                            left = Aabb::unite(&left, &r.1);
+                        } else {
+                            right = Aabb::unite(&right, &r.1);
+                        }
+                    }
+                    left.intersect(&right).area()
+                }
+                None => std::f32::INFINITY,
+            };
+            if overlap > self.min_overlap {
+                let object_cost = object_split.map_or(std::f32::MAX, |split| split.cost);
+                spatial_split = self
+                    .search_spatial_split(&refs, bbox)
+                    .filter(|split| split.cost < object_cost);
+            }
+        }
+        let (axis, left_refs, right_refs) = match (spatial_split, object_split) {
+            (Some(split), _) => {
+                let (left, right) = self.split_refs(refs, &split);
+                (split.axis, left, right)
+            }
+            (None, Some(split)) => {
+                let (left, right): (Vec<_>, Vec<_>) = refs
+                    .into_iter()
+                    .partition(|r| split.bin_of(&r.1) < split.bin);
+                (split.axis, left, right)
+            }
+            (None, None) => {
+                // 中心がすべて一致している場合。
+                let mid = refs.len() / 2;
+                refs.select_nth_unstable_by(mid, |a, b| a.1.compare_center(&b.1, 0));
+                let right = refs.split_off(mid);
+                (0, refs, right)
+            }
+        };
+        let left_bbox = bbox_from_refs(&left_refs);
+        let right_bbox = bbox_from_refs(&right_refs);
+        let left_node_idx = self.construct(left_refs, &left_bbox);
+        let right_node_idx = self.construct(right_refs, &right_bbox);
+        self.nodes[new_node_idx] = Node {
+            bboxes: [left_bbox, right_bbox],
+            children: [
+                BVHNodePointer(NodePointer::new_inner(left_node_idx)),
+                BVHNodePointer(NodePointer::new_inner(right_node_idx)),
+            ],
+            axis: axis as u8,
+        };
+        new_node_idx
+    }
+    /// ノードのバウンディングボックスを各軸BIN_CNT個の等幅のビンに分け、SAHコストが最小の分割平面を探す。
+    /// 左右のどちらかがすべての参照を含む分割は除く。
+    fn search_spatial_split(&self, refs: &[(usize, Aabb)], bbox: &Aabb) -> Option<SpatialSplit> {
+        let mut best: Option<SpatialSplit> = None;
+        for axis in 0..3 {
+            let split = SpatialSplit {
+                axis: axis,
+                lo: bbox.min[axis],
+                extent: bbox.max[axis] - bbox.min[axis],
+                bin: 0,
+                cost: 0.0,
+            };
+            if !split.extent.is_finite() || split.extent <= 0.0 {
+                continue;
+            }
+            let mut bin_boxes = [Aabb::empty(); BIN_CNT];
+            let mut entries = [0usize; BIN_CNT]; // 参照が始まるビンごとの個数
+            let mut exits = [0usize; BIN_CNT]; // 参照が終わるビンごとの個数
+            for r in refs {
+                let first = split.bin_of(r.1.min[axis]);
+                let last = split.bin_of(r.1.max[axis]);
+                let mut rest = r.1;
+                for (bin, bin_box) in bin_boxes.iter_mut().enumerate().take(last).skip(first) {
+                    let (below, above) =
+                        self.leaves[r.0].split_bounding_box(&rest, axis, split.plane(bin + 1));
+                    *bin_box = Aabb::unite(bin_box, &below);
+                    rest = above;
+                }
+                bin_boxes[last] = Aabb::unite(&bin_boxes[last], &rest);
+                entries[first] += 1;
+                exits[last] += 1;
+            }
+            let mut right_areas = [0.0f32; BIN_CNT];
+            let mut right_counts = [0usize; BIN_CNT];
+            let mut accum = Aabb::empty();
+            let mut count = 0;
+            for i in (1..BIN_CNT).rev() {
+                accum = Aabb::unite(&accum, &bin_boxes[i]);
+                count += exits[i];
+                right_areas[i] = accum.area();
+                right_counts[i] = count;
+            }
+            let mut accum = Aabb::empty();
+            let mut count = 0;
+            for i in 1..BIN_CNT {
+                accum = Aabb::unite(&accum, &bin_boxes[i - 1]);
+                count += entries[i - 1];
+                if count == 0
+                    || right_counts[i] == 0
+                    || count == refs.len() && right_counts[i] == refs.len()
+                {
+                    continue;
+                }
+                let cost = accum.area() * count as f32 + right_areas[i] * right_counts[i] as f32;
+                if best.as_ref().is_none_or(|b| cost < b.cost) {
+                    best = Some(SpatialSplit {
+                        bin: i,
+                        cost: cost,
+                        ..split
+                    });
+                }
+            }
+        }
+        best
+    }
+    /// 空間分割にしたがって参照を左右に分ける。平面をまたぐ参照は両側に複製する。
+    fn split_refs(&mut self, refs: References, split: &SpatialSplit) -> (References, References) {
+        let mut left = Vec::with_capacity(refs.len());
+        let mut right = Vec::with_capacity(refs.len());
+        for r in refs {
+            let first = split.bin_of(r.1.min[split.axis]);
+            let last = split.bin_of(r.1.max[split.axis]);
+            if last < split.bin {
+                left.push(r);
+            } else if split.bin <= first {
+                right.push(r);
+            } else {
+                let (below, above) =
+                    self.leaves[r.0].split_bounding_box(&r.1, split.axis, split.position());
+                if !below.is_empty() {
+                    left.push((r.0, below));
+                }
+                if !above.is_empty() {
+                    right.push((r.0, above));
+                }
+                if !below.is_empty() && !above.is_empty() {
+                    self.remaining_refs = self.remaining_refs.saturating_sub(1);
+                }
+            }
+        }
+        (left, right)
+    }
+}
+
 impl<L> Hitable for BVH<L>
 where
     L: Hitable,
 {
     fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        self.hit_with_stats(ray, t_min, t_max, &mut TraversalStats::default())
+    }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
         self.hit_core(
             &self.bbox,
             BVHNodePointer(NodePointer::root()),
             ray,
             t_min,
             t_max,
+            stats,
         )
     }
     // ToDo: 効率的なis_hitを実装する
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
         Some(self.bbox)
     }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        for leaf in &self.leaves {
+            leaf.tessellate(mesh);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::obvh::OBVH;
+    use crate::hitable::test_util;
+    use crate::ray::Ray;
+    use rand::prng::XorShiftRng;
+    use rand::SeedableRng;
+    #[test]
+    fn binned_construction() {
+        let mut rng = XorShiftRng::seed_from_u64(1);
+        let spheres = test_util::random_spheres(&mut rng, 1500, 30.0, 0.1, 0.6);
+        let list = test_util::brute_force(test_util::make_spheres(&spheres));
+        let sorted = BVH::new(test_util::make_spheres(&spheres), 0.0, 1.0);
+        let binned = BVH::new_binned(test_util::make_spheres(&spheres), 0.0, 1.0, 4);
+        assert!(binned.sah_cost() < sorted.sah_cost() * 1.2);
+        let binned_obvh = OBVH::from_bvh(BVH::new_binned(
+            test_util::make_spheres(&spheres),
+            0.0,
+            1.0,
+            4,
+        ));
+        let rays = test_util::random_rays(&mut rng, 300, 40.0);
+        test_util::assert_same_hits(&list, &binned, &rays, "BVH");
+        test_util::assert_same_hits(&list, &binned_obvh, &rays, "OBVH");
+    }
+    #[test]
+    fn sbvh_construction() {
+        let mut rng = XorShiftRng::seed_from_u64(2);
+        // 細長い斜めの三角形は空間分割が効く
+        let triangles: Vec<[Vec3; 3]> = (0..200)
+            .map(|_| {
+                let p = test_util::random_vec(&mut rng) * 20.0;
+                let dir = test_util::random_vec(&mut rng) * 15.0;
+                [p, p + dir, p + dir + test_util::random_vec(&mut rng) * 0.3]
+            })
+            .collect();
+        let list = test_util::brute_force(test_util::make_triangles(&triangles));
+        let binned = BVH::new_binned(test_util::make_triangles(&triangles), 0.0, 1.0, 1);
+        let sbvh = BVH::new_sbvh(test_util::make_triangles(&triangles), 0.0, 1.0, 1.0);
+        assert!(sbvh.reference_cnt() > sbvh.leaves.len());
+        assert!(sbvh.reference_cnt() <= sbvh.leaves.len() * 2 + 1);
+        assert!(sbvh.sah_cost() < binned.sah_cost());
+        let sbvh_obvh = OBVH::from_bvh(BVH::new_sbvh(
+            test_util::make_triangles(&triangles),
+            0.0,
+            1.0,
+            1.0,
+        ));
+        let rays = test_util::random_rays(&mut rng, 500, 40.0);
+        test_util::assert_same_hits(&list, &sbvh, &rays, "BVH");
+        test_util::assert_same_hits(&list, &sbvh_obvh, &rays, "OBVH");
+    }
+    #[test]
+    fn refit_and_rebuild() {
+        let mut rng = XorShiftRng::seed_from_u64(3);
+        let mut spheres = test_util::random_spheres(&mut rng, 300, 20.0, 0.5, 0.5);
+        let mut bvh = BVH::new_binned(test_util::make_spheres(&spheres), 0.0, 1.0, 1);
+        let mut obvh = OBVH::from_bvh(BVH::new_binned(
+            test_util::make_spheres(&spheres),
+            0.0,
+            1.0,
+            1,
+        ));
+        for frame in 0..2 {
+            // 1フレーム目は少しだけ動かし、2フレーム目は位置を入れ替えて木の質を劣化させる
+            if frame == 0 {
+                for sphere in spheres.iter_mut() {
+                    sphere.0 += test_util::random_vec(&mut rng) * 0.5;
+                }
+            } else {
+                spheres.reverse();
+            }
+            bvh.leaves = test_util::make_spheres(&spheres);
+            for (leaf, sphere) in obvh
+                .leaves_mut()
+                .iter_mut()
+                .zip(test_util::make_spheres(&spheres))
+            {
+                *leaf = sphere;
+            }
+            bvh.refit(0.0, 1.0);
+            obvh.refit(0.0, 1.0);
+            assert_eq!(bvh.needs_rebuild(), frame == 1);
+            assert_eq!(obvh.needs_rebuild(), frame == 1);
+            let list = test_util::brute_force(test_util::make_spheres(&spheres));
+            let rays = test_util::random_rays(&mut rng, 200, 40.0);
+            test_util::assert_same_hits(&list, &bvh, &rays, "BVH");
+            test_util::assert_same_hits(&list, &obvh, &rays, "OBVH");
+        }
+        let degraded_cost = bvh.sah_cost();
+        let bvh = bvh.refit_or_rebuild(0.0, 1.0, 1);
+        assert!(!bvh.needs_rebuild());
+        assert!(bvh.sah_cost() < degraded_cost);
+        let obvh = obvh.refit_or_rebuild(0.0, 1.0, 1);
+        assert!(!obvh.needs_rebuild());
+    }
+    #[test]
+    fn refit_small_trees() {
+        // 葉が0個・1個の木や、根のない木もrefitできる
+        for leaf_cnt in 0..3 {
+            let spheres: Vec<(Vec3, f32)> = (0..leaf_cnt)
+                .map(|i| (Vec3::new(i as f32, 0.0, 0.0), 0.5))
+                .collect();
+            let mut bvh = BVH::new_binned(test_util::make_spheres(&spheres), 0.0, 1.0, 1);
+            bvh.refit(0.0, 1.0);
+            let mut obvh = OBVH::from_bvh(BVH::new(test_util::make_spheres(&spheres), 0.0, 1.0));
+            obvh.refit(0.0, 1.0);
+            let mut rootless = BVH::new(test_util::make_spheres(&spheres), 0.0, 1.0);
+            rootless.inners.clear();
+            rootless.refit(0.0, 1.0);
+            let list = test_util::brute_force(test_util::make_spheres(&spheres));
+            let rays = [Ray::new(
+                &Vec3::new(0.0, 0.0, -5.0),
+                &Vec3::new(0.0, 0.0, 1.0),
+                0.0,
+            )];
+            test_util::assert_same_hits(&list, &bvh, &rays, "BVH");
+            test_util::assert_same_hits(&list, &obvh, &rays, "OBVH");
+            test_util::assert_same_hits(&list, &rootless, &rays, "rootless BVH");
+            assert_eq!(bvh.bbox.is_empty(), leaf_cnt == 0);
+        }
+    }
 }
@@ -1,8 +1,9 @@
 use crate::aabb::Aabb;
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
 use crate::hitable::empty::Empty;
 use crate::hitable::hitable_list::HitableList;
-use crate::hitable::Hitable;
+use crate::hitable::{Hitable, TraversalStats};
 use crate::ray::Ray;
 use std::sync::Arc;
 
@@ -207,26 +208,41 @@ impl BvhNode {
 
 impl Hitable for BvhNode {
     fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        self.hit_with_stats(ray, t_min, t_max, &mut TraversalStats::default())
+    }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        stats.aabb_tests += 1;
         if !self.aabb.hit(ray, t_min, t_max) {
             return None;
         }
+        stats.node_visits += 1;
         // decide the order of traverse.
         let (first, second) = if ray.direction[self.axis] >= 0.0 {
             (&self.left, &self.right)
         } else {
             (&self.right, &self.left)
         };
-        if let Some(ref hit_left) = first.hit(ray, t_min, t_max) {
-            if let Some(ref hit_right) = second.hit(ray, t_min, hit_left.t) {
+        if let Some(ref hit_left) = first.hit_with_stats(ray, t_min, t_max, stats) {
+            if let Some(ref hit_right) = second.hit_with_stats(ray, t_min, hit_left.t, stats) {
                 Some(*hit_right)
             } else {
                 Some(*hit_left)
             }
         } else {
-            second.hit(ray, t_min, t_max)
+            second.hit_with_stats(ray, t_min, t_max, stats)
         }
     }
     fn bounding_box(&self, _t0: f32, _t1: f32) -> Option<Aabb> {
         Some(self.aabb)
     }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        self.left.tessellate(mesh);
+        self.right.tessellate(mesh);
+    }
 }
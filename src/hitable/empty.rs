@@ -1,6 +1,7 @@
 use crate::aabb::Aabb;
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
-use crate::hitable::Hitable;
+use crate::hitable::{Hitable, TraversalStats};
 use crate::ray::Ray;
 
 pub struct Empty;
@@ -15,10 +16,20 @@ impl Hitable for Empty {
     fn hit(&self, _ray: &Ray, _t_min: f32, _t_max: f32) -> Option<HitRecord> {
         None
     }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        _ray: &'r Ray,
+        _t_min: f32,
+        _t_max: f32,
+        _stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        None
+    }
     fn is_hit<'s, 'r>(&'s self, _ray: &'r Ray, _t_min: f32, _t_max: f32) -> bool {
         false
     }
     fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
         Some(Aabb::empty())
     }
+    fn tessellate(&self, _mesh: &mut ExportMesh) {}
 }
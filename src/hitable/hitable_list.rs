@@ -1,7 +1,8 @@
 use crate::aabb::Aabb;
 use crate::aliases::{RandGen, Vec3};
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
-use crate::hitable::Hitable;
+use crate::hitable::{Hitable, TraversalStats};
 use crate::ray::Ray;
 use rand::Rng;
 use std::sync::Arc;
@@ -28,6 +29,23 @@ impl Hitable for HitableList {
         }
         return res;
     }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        let mut res: Option<HitRecord<'s>> = None;
+        let mut closest_so_far = t_max;
+        for obj in &self.list {
+            if let Some(ref tmp_rec) = obj.hit_with_stats(ray, t_min, closest_so_far, stats) {
+                closest_so_far = tmp_rec.t;
+                res = Some(*tmp_rec);
+            }
+        }
+        res
+    }
     fn is_hit(&self, ray: &Ray, t_min: f32, t_max: f32) -> bool {
         for obj in &self.list {
             if obj.is_hit(ray, t_min, t_max) {
@@ -36,6 +54,31 @@ impl Hitable for HitableList {
         }
         false
     }
+    fn hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &mut [f32],
+        recs: &mut [Option<HitRecord<'s>>],
+    ) {
+        for obj in &self.list {
+            obj.hit_packet(rays, t_min, t_max, recs);
+        }
+    }
+    fn is_hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &[f32],
+        is_hits: &mut [bool],
+    ) {
+        for obj in &self.list {
+            if is_hits.iter().all(|&is_hit| is_hit) {
+                break;
+            }
+            obj.is_hit_packet(rays, t_min, t_max, is_hits);
+        }
+    }
     fn bounding_box(&self, time_0: f32, time_1: f32) -> Option<Aabb> {
         if self.list.len() == 0 {
             return Some(Aabb::empty());
@@ -61,4 +104,26 @@ impl Hitable for HitableList {
             .sum();
         sum / self.list.len() as f32
     }
+    /// Chooses an element with probability proportional to its area,
+    /// so that the generated points are uniform on the whole surface.
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        let areas: Vec<f32> = self.list.iter().map(|o| o.surface_area()).collect();
+        let total: f32 = areas.iter().sum();
+        let mut threshold = rng.gen::<f32>() * total;
+        for (obj, area) in self.list.iter().zip(areas.iter()) {
+            if threshold < *area {
+                return obj.random_point_on_surface(rng);
+            }
+            threshold -= area;
+        }
+        self.list.last().unwrap().random_point_on_surface(rng)
+    }
+    fn surface_area(&self) -> f32 {
+        self.list.iter().map(|o| o.surface_area()).sum()
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        for obj in &self.list {
+            obj.tessellate(mesh);
+        }
+    }
 }
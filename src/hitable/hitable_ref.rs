@@ -1,8 +1,9 @@
 use crate::aabb::Aabb;
 use crate::aliases::RandGen;
 use crate::aliases::Vec3;
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
-use crate::hitable::Hitable;
+use crate::hitable::{Hitable, TraversalStats};
 use crate::Ray;
 use std::sync::Arc;
 
@@ -13,6 +14,15 @@ impl Hitable for HitableRef {
     fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
         self.0.hit(ray, t_min, t_max)
     }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        self.0.hit_with_stats(ray, t_min, t_max, stats)
+    }
     fn is_hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> bool {
         self.0.hit(ray, t_min, t_max).is_some()
     }
@@ -25,6 +35,15 @@ impl Hitable for HitableRef {
     fn direction_density(&self, origin: &Vec3, dir: &Vec3) -> f32 {
         self.0.direction_density(origin, dir)
     }
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        self.0.random_point_on_surface(rng)
+    }
+    fn surface_area(&self) -> f32 {
+        self.0.surface_area()
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        self.0.tessellate(mesh)
+    }
 }
 
 impl HitableRef {
@@ -0,0 +1,268 @@
+// 2レベルの加速構造（インスタンシング）。
+// 下位の加速構造（BLAS。OBVH<Triangle>など）をArcで共有し、インスタンスごとにはアフィン変換と
+// マテリアルの上書きだけを持つ。上位の加速構造（TLAS）はインスタンスのバウンディングボックスに対するOBVH。
+
+use crate::aabb::Aabb;
+use crate::affine::Affine;
+use crate::aliases::{RandGen, Vec3};
+use crate::export::ExportMesh;
+use crate::hit_record::HitRecord;
+use crate::hitable::bvh::BVH;
+use crate::hitable::obvh::OBVH;
+use crate::hitable::{Hitable, TraversalStats};
+use crate::material::Material;
+use crate::ray::Ray;
+use std::sync::Arc;
+
+/// A placement of a shared bottom-level acceleration structure.
+pub struct Instance<B: ?Sized> {
+    blas: Arc<B>,
+    transform: Affine,
+    inv_transform: Affine,
+    material: Option<Arc<Material>>, // replaces the materials of blas if Some
+    bbox: Option<Aabb>,
+}
+
+impl<B> Instance<B>
+where
+    B: Hitable + ?Sized,
+{
+    /// time_0, time_1 is used for moving hitables in blas.
+    pub fn new(
+        blas: Arc<B>,
+        tr: &Affine,
+        material: Option<Arc<Material>>,
+        time_0: f32,
+        time_1: f32,
+    ) -> Self {
+        let bbox = blas
+            .bounding_box(time_0, time_1)
+            .map(|bbox| bbox.get_transformed(tr));
+        Instance {
+            blas: blas,
+            transform: *tr,
+            inv_transform: tr.inverse(),
+            material: material,
+            bbox: bbox,
+        }
+    }
+    fn to_world<'s>(&'s self, rec: HitRecord<'s>) -> HitRecord<'s> {
+        let mut rec = rec.get_transformed(&self.transform);
+        if let Some(ref material) = self.material {
+            rec.material = material.as_ref();
+        }
+        rec
+    }
+}
+
+impl<B> Hitable for Instance<B>
+where
+    B: Hitable + ?Sized,
+{
+    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        self.blas
+            .hit(&ray.get_transformed(&self.inv_transform), t_min, t_max)
+            .map(|rec| self.to_world(rec))
+    }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        self.blas
+            .hit_with_stats(
+                &ray.get_transformed(&self.inv_transform),
+                t_min,
+                t_max,
+                stats,
+            )
+            .map(|rec| self.to_world(rec))
+    }
+    fn is_hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> bool {
+        self.blas
+            .is_hit(&ray.get_transformed(&self.inv_transform), t_min, t_max)
+    }
+    fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
+        self.bbox
+    }
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        let rec = self.blas.random_point_on_surface(rng);
+        self.to_world(rec)
+    }
+    fn surface_area(&self) -> f32 {
+        // Affine is a similarity transformation, which scales areas uniformly.
+        self.blas.surface_area() * self.transform.determinant().abs().powf(2.0 / 3.0)
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        mesh.push_transform(&self.transform);
+        if let Some(ref material) = self.material {
+            mesh.push_material(material.clone());
+        }
+        self.blas.tessellate(mesh);
+        if self.material.is_some() {
+            mesh.pop_material();
+        }
+        mesh.pop_transform();
+    }
+}
+
+/// Top-level acceleration structure over instances.
+/// The memory is that of the shared BLASes plus one Instance per placement.
+pub struct Tlas<B: ?Sized> {
+    instances: OBVH<Instance<B>>,
+    instance_cnt: usize,
+}
+
+impl<B> Tlas<B>
+where
+    B: Hitable + ?Sized,
+{
+    /// Every instance must have a bounding box.
+    pub fn new(instances: Vec<Instance<B>>, time_0: f32, time_1: f32) -> Self {
+        let instance_cnt = instances.len();
+        Tlas {
+            instances: OBVH::from_bvh(BVH::new_binned(instances, time_0, time_1, 1)),
+            instance_cnt: instance_cnt,
+        }
+    }
+    pub fn instance_cnt(&self) -> usize {
+        self.instance_cnt
+    }
+}
+
+impl<B> Hitable for Tlas<B>
+where
+    B: Hitable + ?Sized,
+{
+    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        self.instances.hit(ray, t_min, t_max)
+    }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        self.instances.hit_with_stats(ray, t_min, t_max, stats)
+    }
+    fn is_hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> bool {
+        self.instances.is_hit(ray, t_min, t_max)
+    }
+    fn hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &mut [f32],
+        recs: &mut [Option<HitRecord<'s>>],
+    ) {
+        self.instances.hit_packet(rays, t_min, t_max, recs)
+    }
+    fn is_hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &[f32],
+        is_hits: &mut [bool],
+    ) {
+        self.instances.is_hit_packet(rays, t_min, t_max, is_hits)
+    }
+    fn bounding_box(&self, time_0: f32, time_1: f32) -> Option<Aabb> {
+        self.instances.bounding_box(time_0, time_1)
+    }
+    fn random_direction_from(&self, _origin: &Vec3, _rng: &mut RandGen) -> Vec3 {
+        panic!("random_direction_from called for Tlas");
+    }
+    fn direction_density(&self, _origin: &Vec3, _dir: &Vec3) -> f32 {
+        panic!("direction_density called for Tlas");
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        self.instances.tessellate(mesh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::affine::Affine;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::hitable_list::HitableList;
+    use crate::hitable::instance::{Instance, Tlas};
+    use crate::hitable::obvh::OBVH;
+    use crate::hitable::test_util;
+    use crate::hitable::transform::Transform;
+    use crate::hitable::Hitable;
+    use crate::material::lambertian::Lambertian;
+    use crate::material::Material;
+    use crate::texture::constant::ConstantTexture;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::Arc;
+    #[test]
+    fn instances_agree_with_transforms() {
+        let override_material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(
+            ConstantTexture::rgb(1.0, 0.0, 0.0),
+        )));
+        let mut rng = XorShiftRng::seed_from_u64(4);
+        // 小さな球のクラスタを1つだけ構築し、200か所に配置する
+        let cluster = test_util::random_spheres(&mut rng, 20, 1.0, 0.3, 0.3);
+        let blas = Arc::new(OBVH::from_bvh(BVH::new(
+            test_util::make_spheres(&cluster),
+            0.0,
+            1.0,
+        )));
+        let transforms: Vec<Affine> = (0..200)
+            .map(|k| {
+                let origin = test_util::random_vec(&mut rng) * 30.0;
+                match k % 3 {
+                    0 => Affine::translate(&origin),
+                    1 => Affine::rotation(&(test_util::random_vec(&mut rng) * 3.0), &origin),
+                    _ => Affine::scale(0.5 + rng.gen::<f32>(), &origin),
+                }
+            })
+            .collect();
+        let tlas = Tlas::new(
+            transforms
+                .iter()
+                .enumerate()
+                .map(|(k, tr)| {
+                    let material = if k % 2 == 0 {
+                        Some(override_material.clone())
+                    } else {
+                        None
+                    };
+                    Instance::new(blas.clone(), tr, material, 0.0, 1.0)
+                })
+                .collect(),
+            0.0,
+            1.0,
+        );
+        assert_eq!(tlas.instance_cnt(), 200);
+        let list = HitableList::new(
+            transforms
+                .iter()
+                .map(|tr| -> Arc<Hitable> { Arc::new(Transform::new(blas.clone(), tr, 0.0, 1.0)) })
+                .collect(),
+        );
+        let rays = test_util::random_rays(&mut rng, 500, 40.0);
+        test_util::assert_same_hits(&list, &tlas, &rays, "TLAS");
+        // 交点と法線も一致し、半分のインスタンスではマテリアルが上書きされる
+        let mut overridden = 0;
+        for ray in &rays {
+            let expected = list.hit(ray, 0.0001, std::f32::MAX);
+            let actual = tlas.hit(ray, 0.0001, std::f32::MAX);
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                assert!((expected.point - actual.point).norm() < 1.0e-3);
+                assert!((expected.normal - actual.normal).norm() < 1.0e-3);
+                if !std::ptr::eq(
+                    actual.material as *const Material as *const u8,
+                    expected.material as *const Material as *const u8,
+                ) {
+                    overridden += 1;
+                }
+            }
+        }
+        assert!(overridden > 0);
+    }
+}
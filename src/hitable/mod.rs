@@ -3,35 +3,119 @@ pub mod bvh_node;
 pub mod empty;
 pub mod hitable_list;
 pub mod hitable_ref;
+pub mod instance;
 mod node_pointer;
 pub mod obvh;
 pub mod rectangle;
 pub mod sphere;
+#[cfg(test)]
+pub mod test_util;
 pub mod transform;
 pub mod triangle;
+pub mod triangle_mesh;
+pub mod triangle_pack;
 
 use crate::aabb::Aabb;
 use crate::aliases::{RandGen, Vec3};
+use crate::emission_record::EmissionRecord;
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
 use crate::hitable::hitable_list::HitableList;
 use crate::hitable::rectangle::Rectangle;
 use crate::material::Material;
+use crate::pdf::cosine::CosinePdf;
+use crate::pdf::Pdf;
 use crate::ray::Ray;
-use std::ops::Shl;
+use std::ops::{AddAssign, Shl};
 use std::sync::Arc;
 
+/// Counters of the work done while finding the closest hit of a ray.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TraversalStats {
+    pub node_visits: u32,     // inner nodes of acceleration structures whose children are examined
+    pub aabb_tests: u32,      // ray-box tests (an OBVH node tests 8 boxes at once)
+    pub primitive_tests: u32, // calls of hit() of hitables which are not acceleration structures
+}
+
+impl AddAssign for TraversalStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.node_visits += rhs.node_visits;
+        self.aabb_tests += rhs.aabb_tests;
+        self.primitive_tests += rhs.primitive_tests;
+    }
+}
+
 pub trait Hitable: Send + Sync {
     /// Calculates HitRecord.
     fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>>;
+    /// Same as hit(), and adds the work done to stats.
+    /// Acceleration structures and containers should override this; the default counts self as a primitive.
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        stats.primitive_tests += 1;
+        self.hit(ray, t_min, t_max)
+    }
     /// Judge the ray hits self or not.
     /// Each Hitable should provide a more efficient implementation than the default.
     fn is_hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> bool {
         self.hit(ray, t_min, t_max).is_some()
     }
+    /// hit() for each rays[i] with t_max[i]. Acceleration structures traverse coherent rays
+    /// (camera rays of neighboring pixels, shadow rays toward a light) together.
+    /// If rays[i] hits self before t_max[i], recs[i] and t_max[i] are replaced by the hit; otherwise they are left as is,
+    /// so that the buffers can be passed to several Hitables in turn without allocations.
+    /// The result must be the same as hit() for each ray, which the default calls one by one.
+    fn hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &mut [f32],
+        recs: &mut [Option<HitRecord<'s>>],
+    ) {
+        debug_assert_eq!(rays.len(), t_max.len());
+        debug_assert_eq!(rays.len(), recs.len());
+        for ((ray, t_max), rec) in rays.iter().zip(t_max.iter_mut()).zip(recs.iter_mut()) {
+            if let Some(hit) = self.hit(ray, t_min, *t_max) {
+                *t_max = hit.t;
+                *rec = Some(hit);
+            }
+        }
+    }
+    /// is_hit() version of hit_packet(). is_hits[i] is set to true if rays[i] hits self;
+    /// the rays with is_hits[i] already true are skipped.
+    fn is_hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &[f32],
+        is_hits: &mut [bool],
+    ) {
+        debug_assert_eq!(rays.len(), t_max.len());
+        debug_assert_eq!(rays.len(), is_hits.len());
+        for ((ray, &t_max), is_hit) in rays.iter().zip(t_max).zip(is_hits.iter_mut()) {
+            *is_hit = *is_hit || self.is_hit(ray, t_min, t_max);
+        }
+    }
     /// Axis-aligned bounding box of this Hitable.
     /// Returns None if this does not have bounding box (e.g., infinite plane)
     /// For moving objects, returns the unite of all boxes while the time interval [t0, t1]
     fn bounding_box(&self, time_0: f32, time_1: f32) -> Option<Aabb>;
+    /// Splits the part of this Hitable inside bbox by the plane {p | p[axis] = position},
+    /// and returns the bounding boxes of the two parts (below the plane, above the plane).
+    /// Used for spatial splits in BVH construction. The default just cuts bbox, which is valid but loose.
+    fn split_bounding_box(&self, bbox: &Aabb, axis: usize, position: f32) -> (Aabb, Aabb) {
+        let inf = std::f32::INFINITY;
+        let mut below = Aabb::new(&Vec3::new(-inf, -inf, -inf), &Vec3::new(inf, inf, inf));
+        let mut above = below;
+        below.max[axis] = position;
+        above.min[axis] = position;
+        (bbox.intersect(&below), bbox.intersect(&above))
+    }
     /// Generate a random direction vector from a specified point to this hitable.
     fn random_direction_from(&self, _origin: &Vec3, _rng: &mut RandGen) -> Vec3 {
         unimplemented!()
@@ -40,6 +124,45 @@ pub trait Hitable: Send + Sync {
     fn direction_density(&self, _origin: &Vec3, _dir: &Vec3) -> f32 {
         unimplemented!()
     }
+    /// Generate a random point on the surface of this hitable uniformly w.r.t. the surface area.
+    /// The field t of the returned HitRecord is meaningless.
+    fn random_point_on_surface<'s>(&'s self, _rng: &mut RandGen) -> HitRecord<'s> {
+        unimplemented!()
+    }
+    /// Total surface area of this hitable.
+    fn surface_area(&self) -> f32 {
+        unimplemented!()
+    }
+    /// Generate a random ray emitted from this hitable (regarded as a light source).
+    /// The origin is sampled by random_point_on_surface() and the direction by the cosine-weighted pdf.
+    /// Returns None if the sampled ray carries no radiance.
+    fn sample_emission<'s>(&'s self, time: f32, rng: &mut RandGen) -> Option<EmissionRecord<'s>> {
+        let rec = self.random_point_on_surface(rng);
+        let dir_pdf = CosinePdf::new(&rec.normal);
+        let dir = dir_pdf.generate(rng);
+        let pdf_dir = dir_pdf.density(&dir);
+        if pdf_dir <= 0.0 {
+            return None;
+        }
+        let radiance = rec
+            .material
+            .emitted(&Ray::new(&(rec.point + dir), &(-dir), time), &rec);
+        if radiance == Vec3::new(0.0, 0.0, 0.0) {
+            return None;
+        }
+        Some(EmissionRecord {
+            ray: Ray::new(&rec.point, &dir, time),
+            rec: rec,
+            radiance: radiance,
+            pdf_pos: 1.0 / self.surface_area(),
+            pdf_dir: pdf_dir,
+        })
+    }
+    /// Appends triangles approximating this hitable to mesh, for exporting the scene.
+    /// Containers should tessellate their children; the default counts self as skipped.
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        mesh.skipped += 1;
+    }
 }
 
 pub fn cube(size: &Vec3, material: Arc<Material>) -> impl Hitable {
@@ -46,4 +46,26 @@ impl NodePointer {
     pub fn new_inner(index: usize) -> Self {
         Self::new(false, index)
     }
+    /// The raw representation, used to write caches.
+    pub fn to_raw(self) -> u32 {
+        self.info
+    }
+    pub fn from_raw(info: u32) -> Self {
+        NodePointer { info: info }
+    }
+    /// Whether this is a leaf or an inner node after the parent_index-th one,
+    /// which holds in built trees and guarantees that traversal terminates.
+    pub fn follows(&self, parent_index: usize) -> bool {
+        self.is_leaf() || parent_index < self.index()
+    }
+    /// Whether this pointer can be dereferenced in arrays of the given lengths.
+    pub fn is_valid(&self, leaf_cnt: usize, inner_cnt: usize) -> bool {
+        if self.is_empty_leaf() {
+            true
+        } else if self.is_leaf() {
+            self.index() < leaf_cnt
+        } else {
+            self.index() < inner_cnt
+        }
+    }
 }
@@ -1,17 +1,29 @@
+pub mod compressed;
+pub mod packet;
+
 use crate::aabb::Aabb;
+use crate::accel_cache;
+use crate::accel_cache::{
+    read_aabb, read_f32, read_u32, read_u64, write_aabb, write_f32, write_u32, write_u64,
+};
 use crate::aliases::RandGen;
 use crate::aliases::Vec3;
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
 use crate::hitable::bvh::BVHNodePointer;
 use crate::hitable::bvh::BVH;
+use crate::hitable::bvh::REBUILD_SAH_RATIO;
 use crate::hitable::node_pointer::NodePointer;
-use crate::hitable::Hitable;
+use crate::hitable::obvh::packet::PACKET_SIZE;
+use crate::hitable::{Hitable, TraversalStats};
 use crate::ray::Ray;
 #[cfg(target_arch = "x86")]
 use std::arch::x86::*;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 use std::fmt;
+use std::io;
+use std::io::{Read, Write};
 use std::ops::Shl;
 use std::ops::Shr;
 
@@ -19,6 +31,8 @@ use std::ops::Shr;
 #[derive(Clone, Copy)]
 struct OBVHNodePointer(pub NodePointer);
 
+// bboxes[min_or_max][axis][child_id]。AVXでは各[f32; 8]を__m256として、SSEでは前半と後半をそれぞれ__m128としてロードする。
+#[derive(Clone, Copy)]
 #[repr(align(32))]
 struct BBoxesArray([[[f32; 8]; 3]; 2]);
 
@@ -31,31 +45,141 @@ impl BBoxesArray {
             ],
         }
     }
+    /// レイがぞれぞれのバウンディングボックスにヒットしているか返す。
+    /// * `return` - 各i in [0..8)に対し、returnの第iビット =「レイがi番目にヒットしているかどうか（true=1, false=0）」
+    fn hit(&self, ray: &RayInfo, t_min: f32, t_max: f32, backend: TraversalBackend) -> i32 {
+        match backend {
+            // OBVH::set_backendで利用可能であることを確認済み。
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TraversalBackend::Avx => unsafe { self.hit_avx(ray, t_min, t_max) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TraversalBackend::Sse => unsafe { self.hit_sse(ray, t_min, t_max) },
+            _ => self.hit_scalar(ray, t_min, t_max),
+        }
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx")]
+    unsafe fn hit_avx(&self, ray: &RayInfo, t_min: f32, t_max: f32) -> i32 {
+        let mut t_min = _mm256_set1_ps(t_min);
+        let mut t_max = _mm256_set1_ps(t_max);
+        for a in 0..3 {
+            // a = Axis
+            let origin = _mm256_set1_ps(ray.origin[a]);
+            let inv_dir = _mm256_set1_ps(ray.inv_dir[a]);
+            let near = _mm256_load_ps(self.0[1 - ray.dir_sign[a]][a].as_ptr());
+            let far = _mm256_load_ps(self.0[ray.dir_sign[a]][a].as_ptr());
+            t_min = _mm256_max_ps(t_min, _mm256_mul_ps(inv_dir, _mm256_sub_ps(near, origin)));
+            t_max = _mm256_min_ps(t_max, _mm256_mul_ps(inv_dir, _mm256_sub_ps(far, origin)));
+        }
+        _mm256_movemask_ps(_mm256_cmp_ps(
+            t_min, t_max, _CMP_LE_OS, /* Ordered, Signaling. */
+        ))
+    }
+    /// 子0..4と子4..8の2回に分けて4個ずつ判定する。
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "sse")]
+    unsafe fn hit_sse(&self, ray: &RayInfo, t_min: f32, t_max: f32) -> i32 {
+        let mut res = 0;
+        for half in 0..2 {
+            let mut t_min = _mm_set1_ps(t_min);
+            let mut t_max = _mm_set1_ps(t_max);
+            for a in 0..3 {
+                let origin = _mm_set1_ps(ray.origin[a]);
+                let inv_dir = _mm_set1_ps(ray.inv_dir[a]);
+                let near = _mm_load_ps(self.0[1 - ray.dir_sign[a]][a][half * 4..].as_ptr());
+                let far = _mm_load_ps(self.0[ray.dir_sign[a]][a][half * 4..].as_ptr());
+                t_min = _mm_max_ps(t_min, _mm_mul_ps(inv_dir, _mm_sub_ps(near, origin)));
+                t_max = _mm_min_ps(t_max, _mm_mul_ps(inv_dir, _mm_sub_ps(far, origin)));
+            }
+            res |= _mm_movemask_ps(_mm_cmple_ps(t_min, t_max)) << (half * 4);
+        }
+        res
+    }
+    fn hit_scalar(&self, ray: &RayInfo, t_min: f32, t_max: f32) -> i32 {
+        // NaN（例えば 0 * inf）の扱いをmaxps, minpsに合わせる。
+        fn max(a: f32, b: f32) -> f32 {
+            if a > b {
+                a
+            } else {
+                b
+            }
+        }
+        fn min(a: f32, b: f32) -> f32 {
+            if a < b {
+                a
+            } else {
+                b
+            }
+        }
+        let mut res = 0;
+        for child_id in 0..8 {
+            let mut t_min = t_min;
+            let mut t_max = t_max;
+            for a in 0..3 {
+                let near = self.0[1 - ray.dir_sign[a]][a][child_id];
+                let far = self.0[ray.dir_sign[a]][a][child_id];
+                t_min = max(t_min, ray.inv_dir[a] * (near - ray.origin[a]));
+                t_max = min(t_max, ray.inv_dir[a] * (far - ray.origin[a]));
+            }
+            if t_min <= t_max {
+                res |= 1 << child_id;
+            }
+        }
+        res
+    }
 }
 
-struct RayAVXInfo {
-    origin: [__m256; 3],  // origin[axis_idx] = 8 copies of ray.origin[axis_idx]
-    inv_dir: [__m256; 3], // origin[axis_idx] = 8 copies of 1.0 / ray.direction[axis_idx]
+#[derive(Clone, Copy, Default)]
+struct RayInfo {
+    origin: [f32; 3],     // ray.origin
+    inv_dir: [f32; 3],    // inv_dir[axis_idx] = 1.0 / ray.direction[axis_idx]
     dir_sign: [usize; 3], // dir_sign[axis_idx] = 1 (resp. 0) if ray.direction[axis_idx] >= 0 (resp. < 0)
 }
 
-impl RayAVXInfo {
+impl RayInfo {
     fn from_ray(ray: &Ray) -> Self {
         let calc_sign = |axis| (ray.direction[axis] >= 0.0) as usize;
-        unsafe {
-            Self {
-                origin: [
-                    _mm256_set1_ps(ray.origin[0]),
-                    _mm256_set1_ps(ray.origin[1]),
-                    _mm256_set1_ps(ray.origin[2]),
-                ],
-                inv_dir: [
-                    _mm256_set1_ps(1.0 / ray.direction[0]),
-                    _mm256_set1_ps(1.0 / ray.direction[1]),
-                    _mm256_set1_ps(1.0 / ray.direction[2]),
-                ],
-                dir_sign: [calc_sign(0), calc_sign(1), calc_sign(2)],
-            }
+        Self {
+            origin: [ray.origin[0], ray.origin[1], ray.origin[2]],
+            inv_dir: [
+                1.0 / ray.direction[0],
+                1.0 / ray.direction[1],
+                1.0 / ray.direction[2],
+            ],
+            dir_sign: [calc_sign(0), calc_sign(1), calc_sign(2)],
+        }
+    }
+}
+
+/// Implementations of the ray-box tests of the 8 children of an OBVH node.
+/// Every backend uses the same node layout and returns the same result.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraversalBackend {
+    Avx,
+    Sse, // 4-wide
+    Scalar,
+}
+
+impl TraversalBackend {
+    /// The fastest backend supported by the running CPU.
+    pub fn detect() -> Self {
+        if TraversalBackend::Avx.is_available() {
+            TraversalBackend::Avx
+        } else if TraversalBackend::Sse.is_available() {
+            TraversalBackend::Sse
+        } else {
+            TraversalBackend::Scalar
+        }
+    }
+    pub fn is_available(&self) -> bool {
+        match self {
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TraversalBackend::Avx => is_x86_feature_detected!("avx"),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TraversalBackend::Sse => is_x86_feature_detected!("sse"),
+            TraversalBackend::Scalar => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
         }
     }
 }
@@ -63,8 +187,10 @@ impl RayAVXInfo {
 /// Octa Bounding Volume Hierarchy
 pub struct OBVH<L> {
     bbox: Aabb,
-    leaves: Vec<L>,    // leaf-nodes.
-    inners: Vec<Node>, // inner nodes. inners[0] is the root node.
+    backend: TraversalBackend,
+    leaves: Vec<L>,      // leaf-nodes.
+    inners: Vec<Node>,   // inner nodes. inners[0] is the root node.
+    built_sah_cost: f32, // 構築直後のsah_cost()
 }
 
 struct Node {
@@ -80,10 +206,9 @@ struct Node {
     // ある分岐点より左（resp. 右）に格納されている子ノードたちは、軸axisに関して座標値が小さい（resp. 大きい）側である。
 
     // 各子ノードのバウンディングボックス情報。
-    // __m256と[f32; 8]を同一視し、bboxesフィールドの型が[[[f32; 8]; 3]; 2]であると考えるとき、
-    // bboxes[min_or_max][axis][child_id]は、子chiild_idのバウンディングボックスの、軸axisに沿った座標値の
+    // bboxes.0[min_or_max][axis][child_id]は、子chiild_idのバウンディングボックスの、軸axisに沿った座標値の
     // 最小値（min_or_max=0のとき）か最大値（min_or_max=1のとき）。
-    bboxes: [[__m256; 3]; 2],
+    bboxes: BBoxesArray,
     children: [OBVHNodePointer; 8],
     // 各分岐点における分割軸情報。
     // axis_bit_i（i=0,1）をサイズ8のu8配列とみなすとする（リトルエンディアン）。
@@ -100,9 +225,8 @@ struct Node {
 
 impl fmt::Debug for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let bboxes_array = Self::store_bboxes(&self.bboxes);
         let mut aabbs: [Aabb; 8] = Default::default();
-        Self::eight_aabb_from_array_layout(&mut aabbs, &bboxes_array);
+        Self::eight_aabb_from_array_layout(&mut aabbs, &self.bboxes.0);
         write!(
             f,
             "axis: {axis_top}
@@ -169,6 +293,42 @@ right:
     }
 }
 
+/// OBVH::Nodeのaxis_bits_0, axis_bits_1から、それぞれの子ノードのトラバースにおける優先度を求める。
+/// `return` - リトルエンディアンで[u8; 8]と同一視するとき、return[child_id] = 子child_idの優先度（[0..8)）
+#[inline(always)]
+fn calc_traverse_priority(axis_bits_0: u64, axis_bits_1: u64, ray_is_pos: &[usize; 3]) -> u64 {
+    const CHILD_IDS: u64 = (0b111u64 << 8 * 0)
+        | (0b011u64 << 8 * 1)
+        | (0b101u64 << 8 * 2)
+        | (0b001u64 << 8 * 3)
+        | (0b110u64 << 8 * 4)
+        | (0b010u64 << 8 * 5)
+        | (0b100u64 << 8 * 6)
+        | (0b000u64 << 8 * 7);
+    // >>
+    const MASK: u64 = (0b111u64 << 8 * 0)
+        | (0b111u64 << 8 * 1)
+        | (0b111u64 << 8 * 2)
+        | (0b111u64 << 8 * 3)
+        | (0b111u64 << 8 * 4)
+        | (0b111u64 << 8 * 5)
+        | (0b111u64 << 8 * 6)
+        | (0b111u64 << 8 * 7);
+    // >>
+    let mut mapped: u64 = 0;
+    if ray_is_pos[0b00] == 1 {
+        mapped |= !axis_bits_1 & !axis_bits_0 & MASK;
+    }
+    if ray_is_pos[0b01] == 1 {
+        mapped |= !axis_bits_1 & axis_bits_0;
+    }
+    if ray_is_pos[0b10] == 1 {
+        mapped |= axis_bits_1 & !axis_bits_0;
+    }
+    debug_assert!((mapped ^ CHILD_IDS) & MASK == mapped ^ CHILD_IDS);
+    mapped ^ CHILD_IDS
+}
+
 const NODE_STACK_UPPER_BOUND: usize = 64;
 
 struct NodeStack {
@@ -218,11 +378,21 @@ impl<L> Hitable for OBVH<L>
 where
     L: Hitable,
 {
-    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, mut t_max: f32) -> Option<HitRecord<'s>> {
+    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        self.hit_with_stats(ray, t_min, t_max, &mut TraversalStats::default())
+    }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        mut t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        stats.aabb_tests += 1;
         if !self.bbox.hit(ray, t_min, t_max) {
             return None;
         }
-        let ray_avx = RayAVXInfo::from_ray(ray);
+        let ray_info = RayInfo::from_ray(ray);
         let mut node_stack = NodeStack::empty();
         node_stack.push(OBVHNodePointer(NodePointer::root()));
         let mut hit_record: Option<HitRecord<'s>> = None;
@@ -234,7 +404,7 @@ where
                 }
                 HitRecord::replace_to_some_min(
                     &mut hit_record,
-                    &self.leaves[node_ptr.0.index()].hit(ray, t_min, t_max),
+                    &self.leaves[node_ptr.0.index()].hit_with_stats(ray, t_min, t_max, stats),
                 );
                 hit_record.map(|ref hr| {
                     debug_assert!(t_min <= hr.t && hr.t <= t_max);
@@ -242,10 +412,12 @@ where
                 });
             } else {
                 // if an inner node,
+                stats.node_visits += 1;
+                stats.aabb_tests += 8;
                 let node = &self.inners[node_ptr.0.index()];
-                let hit_bits = node.hit(&ray_avx, t_min, t_max);
+                let hit_bits = node.hit(&ray_info, t_min, t_max, self.backend);
                 debug_assert!(hit_bits < 256);
-                let priorities = node.calc_traverse_priority(&ray_avx.dir_sign);
+                let priorities = node.calc_traverse_priority(&ray_info.dir_sign);
                 let mut ordered = [0usize; 8];
                 for child_id in 0..8 {
                     ordered[priorities.shr(child_id * 8) as u8 as usize] = child_id;
@@ -262,7 +434,7 @@ where
         hit_record
     }
     fn is_hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> bool {
-        let ray_avx = RayAVXInfo::from_ray(ray);
+        let ray_info = RayInfo::from_ray(ray);
         let mut node_stack = NodeStack::empty();
         node_stack.push(OBVHNodePointer(NodePointer::root()));
         while !node_stack.is_empty() {
@@ -277,7 +449,7 @@ where
             } else {
                 // if an inner node,
                 let node = &self.inners[node_ptr.0.index()];
-                let hit_bits = node.hit(&ray_avx, t_min, t_max);
+                let hit_bits = node.hit(&ray_info, t_min, t_max, self.backend);
                 debug_assert!(hit_bits <= 255);
                 for bit in 0..8 {
                     if hit_bits & 1i32.shl(bit) != 0 {
@@ -289,6 +461,40 @@ where
         }
         false
     }
+    fn hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &mut [f32],
+        recs: &mut [Option<HitRecord<'s>>],
+    ) {
+        debug_assert_eq!(rays.len(), t_max.len());
+        debug_assert_eq!(rays.len(), recs.len());
+        for ((rays, t_max), recs) in rays
+            .chunks(PACKET_SIZE)
+            .zip(t_max.chunks_mut(PACKET_SIZE))
+            .zip(recs.chunks_mut(PACKET_SIZE))
+        {
+            self.hit_packet_chunk(rays, t_min, t_max, recs);
+        }
+    }
+    fn is_hit_packet<'s, 'r>(
+        &'s self,
+        rays: &'r [Ray],
+        t_min: f32,
+        t_max: &[f32],
+        is_hits: &mut [bool],
+    ) {
+        debug_assert_eq!(rays.len(), t_max.len());
+        debug_assert_eq!(rays.len(), is_hits.len());
+        for ((rays, t_max), is_hits) in rays
+            .chunks(PACKET_SIZE)
+            .zip(t_max.chunks(PACKET_SIZE))
+            .zip(is_hits.chunks_mut(PACKET_SIZE))
+        {
+            self.is_hit_packet_chunk(rays, t_min, t_max, is_hits);
+        }
+    }
     fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
         // ToDo: self.bboxは事前計算しておいたもの。そのときに使ったtime_0とtime_1と一致していないとうまく動かない。
         Some(self.bbox)
@@ -299,87 +505,32 @@ where
     fn direction_density(&self, _origin: &Vec3, _dir: &Vec3) -> f32 {
         panic!("direction_density called for OBHV");
     }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        for leaf in &self.leaves {
+            leaf.tessellate(mesh);
+        }
+    }
 }
 
 impl Node {
-    /// レイがぞれぞれのバウンディングボックスにヒットしているか返す。
-    /// * `return` - 各i in [0..8)に対し、returnの第iビット =「レイがi番目にヒットしているかどうか（true=1, false=0）」
-    unsafe fn hit_core(&self, ray: &RayAVXInfo, t_min: f32, t_max: f32) -> i32 {
-        let mut t_min = _mm256_set1_ps(t_min);
-        let mut t_max = _mm256_set1_ps(t_max);
-        for a in 0..3 {
-            // a = Axis
-            t_min = _mm256_max_ps(
-                t_min,
-                _mm256_mul_ps(
-                    ray.inv_dir[a],
-                    _mm256_sub_ps(self.bboxes[1 - ray.dir_sign[a]][a], ray.origin[a]),
-                ),
-            );
-            t_max = _mm256_min_ps(
-                t_max,
-                _mm256_mul_ps(
-                    ray.inv_dir[a],
-                    _mm256_sub_ps(self.bboxes[ray.dir_sign[a]][a], ray.origin[a]),
-                ),
-            );
-        }
-        _mm256_movemask_ps(_mm256_cmp_ps(
-            t_min, t_max, _CMP_LE_OS, /* Ordered, Signaling. */
-        ))
-    }
-    fn hit(&self, ray: &RayAVXInfo, t_min: f32, t_max: f32) -> i32 {
-        unsafe { self.hit_core(ray, t_min, t_max) }
+    /// self.bboxesに対するBBoxesArray::hit。
+    fn hit(&self, ray: &RayInfo, t_min: f32, t_max: f32, backend: TraversalBackend) -> i32 {
+        self.bboxes.hit(ray, t_min, t_max, backend)
     }
     #[inline(always)]
-    /// それぞれの子ノードのトラバースにおける優先度を求める。
-    /// `return` - リトルエンディアンで[u8; 8]と同一視するとき、return[child_id] = 子child_idの優先度（[0..8)）
+    /// このノードの分割軸に対するcalc_traverse_priority。
     fn calc_traverse_priority(&self, ray_is_pos: &[usize; 3]) -> u64 {
-        const CHILD_IDS: u64 = (0b111u64 << 8 * 0)
-            | (0b011u64 << 8 * 1)
-            | (0b101u64 << 8 * 2)
-            | (0b001u64 << 8 * 3)
-            | (0b110u64 << 8 * 4)
-            | (0b010u64 << 8 * 5)
-            | (0b100u64 << 8 * 6)
-            | (0b000u64 << 8 * 7);
-        // >>
-        const MASK: u64 = (0b111u64 << 8 * 0)
-            | (0b111u64 << 8 * 1)
-            | (0b111u64 << 8 * 2)
-            | (0b111u64 << 8 * 3)
-            | (0b111u64 << 8 * 4)
-            | (0b111u64 << 8 * 5)
-            | (0b111u64 << 8 * 6)
-            | (0b111u64 << 8 * 7);
-        // >>
-        let mut mapped: u64 = 0;
-        if ray_is_pos[0b00] == 1 {
-            mapped |= !self.axis_bits_1 & !self.axis_bits_0 & MASK;
-        }
-        if ray_is_pos[0b01] == 1 {
-            mapped |= !self.axis_bits_1 & self.axis_bits_0;
-        }
-        if ray_is_pos[0b10] == 1 {
-            mapped |= self.axis_bits_1 & !self.axis_bits_0;
-        }
-        debug_assert!((mapped ^ CHILD_IDS) & MASK == mapped ^ CHILD_IDS);
-        mapped ^ CHILD_IDS
+        calc_traverse_priority(self.axis_bits_0, self.axis_bits_1, ray_is_pos)
     }
     fn empty() -> Self {
-        unsafe {
-            Node {
-                bboxes: [
-                    [_mm256_set1_ps(std::f32::INFINITY); 3],
-                    [_mm256_set1_ps(std::f32::NEG_INFINITY); 3],
-                ],
-                children: [OBVHNodePointer(NodePointer::empty_leaf()); 8],
-                // axis_top: 0,
-                // axis_child: [0; 2],
-                // axis_gson: [0; 4],
-                axis_bits_0: 0,
-                axis_bits_1: 0,
-            }
+        Node {
+            bboxes: BBoxesArray::empty(),
+            children: [OBVHNodePointer(NodePointer::empty_leaf()); 8],
+            // axis_top: 0,
+            // axis_child: [0; 2],
+            // axis_gson: [0; 4],
+            axis_bits_0: 0,
+            axis_bits_1: 0,
         }
     }
     pub fn from_bvh_node<L>(
@@ -404,7 +555,7 @@ impl Node {
             &mut axis_gson,
         );
         this.calc_axis_bits(axis_top, axis_child, axis_gson);
-        this.bboxes = Self::load_bboxes(&bboxes);
+        this.bboxes = bboxes;
         (this, children)
     }
     fn from_bvh_node_traverse<L>(
@@ -510,34 +661,6 @@ impl Node {
             }
         }
     }
-    fn load_bboxes(arr: &BBoxesArray) -> [[__m256; 3]; 2] {
-        unsafe {
-            let mut res = [
-                [_mm256_set1_ps(std::f32::INFINITY); 3],
-                [_mm256_set1_ps(std::f32::NEG_INFINITY); 3],
-            ];
-            for min_max in 0..2 {
-                for axis in 0..3 {
-                    res[min_max][axis] = _mm256_load_ps(arr.0[min_max][axis].as_ptr());
-                }
-            }
-            res
-        }
-    }
-    fn store_bboxes(src: &[[__m256; 3]; 2]) -> [[[f32; 8]; 3]; 2] {
-        unsafe {
-            let mut res = [
-                [[std::f32::INFINITY; 8]; 3],
-                [[std::f32::NEG_INFINITY; 8]; 3],
-            ];
-            for min_max in 0..2 {
-                for axis in 0..3 {
-                    _mm256_store_ps(res[min_max][axis].as_mut_ptr(), src[min_max][axis]);
-                }
-            }
-            res
-        }
-    }
 }
 
 #[derive(Clone, Copy)]
@@ -566,23 +689,195 @@ where
     L: Hitable,
 {
     pub fn from_bvh(bvh: BVH<L>) -> Self {
-        let mut inners: Vec<Node> = Vec::default();
-        Self::add_inner(
-            &bvh,
-            BVHNodePtrWithBBox::new(BVHNodePointer(NodePointer::root()), bvh.bbox),
-            &mut inners,
-        );
+        let inners = Self::inners_from_bvh(&bvh);
         println!("[OBVH::from_bvh] inners={}", inners.len());
         // [a]: 047a7caf47766e2d1bcadc166ed84686d9070883 から [b]: tdfa4b904573eb6bc38ef650dadbe3a39a1a03181 でOBVHが
         // 劣化してしまった。
         // teapot.rsで
         // [b], inners=2360
         // [a], inners=2332
-        Self {
+        let mut obvh = Self {
             bbox: bvh.bbox, // ToDo: 数字は適当。
+            backend: TraversalBackend::detect(),
             inners: inners,
             leaves: bvh.leaves,
+            built_sah_cost: 0.0,
+        };
+        obvh.built_sah_cost = obvh.sah_cost();
+        obvh
+    }
+    fn inners_from_bvh(bvh: &BVH<L>) -> Vec<Node> {
+        let mut inners: Vec<Node> = Vec::default();
+        Self::add_inner(
+            bvh,
+            BVHNodePtrWithBBox::new(BVHNodePointer(NodePointer::root()), bvh.bbox),
+            &mut inners,
+        );
+        inners
+    }
+    /// Writes the inner nodes (not the leaves) for accel_cache.
+    pub fn write_nodes(&self, writer: &mut impl Write) -> io::Result<()> {
+        Self::write_inners(writer, &self.bbox, &self.inners)
+    }
+    /// Writes the inner nodes of the OBVH which from_bvh(bvh) would build, without consuming bvh.
+    pub fn write_nodes_of_bvh(bvh: &BVH<L>, writer: &mut impl Write) -> io::Result<()> {
+        Self::write_inners(writer, &bvh.bbox, &Self::inners_from_bvh(bvh))
+    }
+    fn write_inners(writer: &mut impl Write, bbox: &Aabb, inners: &[Node]) -> io::Result<()> {
+        write_aabb(writer, bbox)?;
+        write_u64(writer, inners.len() as u64)?;
+        for node in inners {
+            for x in node
+                .bboxes
+                .0
+                .iter()
+                .flat_map(|a| a.iter())
+                .flat_map(|a| a.iter())
+            {
+                write_f32(writer, *x)?;
+            }
+            for child in &node.children {
+                write_u32(writer, child.0.to_raw())?;
+            }
+            write_u64(writer, node.axis_bits_0)?;
+            write_u64(writer, node.axis_bits_1)?;
         }
+        Ok(())
+    }
+    /// Reads the inner nodes written by write_nodes, which were built over leaves.
+    pub fn read_nodes(reader: &mut impl Read, leaves: Vec<L>) -> Result<Self, accel_cache::Error> {
+        let bbox = read_aabb(reader)?;
+        let inner_cnt = read_u64(reader)? as usize;
+        let mut inners = Vec::with_capacity(inner_cnt.min(1 << 24));
+        for idx in 0..inner_cnt {
+            let mut node = Node::empty();
+            for x in node
+                .bboxes
+                .0
+                .iter_mut()
+                .flat_map(|a| a.iter_mut())
+                .flat_map(|a| a.iter_mut())
+            {
+                *x = read_f32(reader)?;
+            }
+            for child in node.children.iter_mut() {
+                *child = OBVHNodePointer(NodePointer::from_raw(read_u32(reader)?));
+                if !child.0.is_valid(leaves.len(), inner_cnt) {
+                    return Err(accel_cache::Error::Corrupted(
+                        "OBVH node pointer out of range.".to_string(),
+                    ));
+                }
+                // BVH::read_nodesと同じく、循環しうるファイルを拒否する
+                if !child.0.follows(idx) {
+                    return Err(accel_cache::Error::Stale);
+                }
+            }
+            node.axis_bits_0 = read_u64(reader)?;
+            node.axis_bits_1 = read_u64(reader)?;
+            inners.push(node);
+        }
+        if inners.is_empty() {
+            return Err(accel_cache::Error::Corrupted(
+                "OBVH has no root.".to_string(),
+            ));
+        }
+        let mut obvh = Self {
+            bbox: bbox,
+            backend: TraversalBackend::detect(),
+            inners: inners,
+            leaves: leaves,
+            built_sah_cost: 0.0,
+        };
+        obvh.built_sah_cost = obvh.sah_cost();
+        Ok(obvh)
+    }
+    /// Bytes used by the inner nodes.
+    pub fn node_bytes(&self) -> usize {
+        self.inners.len() * std::mem::size_of::<Node>()
+    }
+    /// Leaves can be replaced (e.g., moved) through this, followed by refit() or refit_or_rebuild().
+    pub fn leaves_mut(&mut self) -> &mut [L] {
+        &mut self.leaves
+    }
+    /// Same as BVH::sah_cost. A node counts as one traversal step although it tests 8 boxes.
+    pub fn sah_cost(&self) -> f32 {
+        let root_area = self.bbox.area();
+        if self.bbox.is_empty() || root_area <= 0.0 {
+            return 0.0;
+        }
+        let mut cost = root_area;
+        let mut bboxes = [Aabb::empty(); 8];
+        for node in &self.inners {
+            Node::eight_aabb_from_array_layout(&mut bboxes, &node.bboxes.0);
+            for (bbox, child) in bboxes.iter().zip(node.children.iter()) {
+                if !child.0.is_empty_leaf() && !bbox.is_empty() {
+                    cost += bbox.area();
+                }
+            }
+        }
+        cost / root_area
+    }
+    /// Updates the packed bounding boxes bottom-up after the leaves have moved, like BVH::refit.
+    /// If the tree has no root, it is rebuilt by BVH::new as from_bvh does.
+    pub fn refit(&mut self, time_0: f32, time_1: f32) {
+        if self.inners.is_empty() {
+            let backend = self.backend;
+            let leaves = std::mem::take(&mut self.leaves);
+            *self = Self::from_bvh(BVH::new(leaves, time_0, time_1));
+            self.backend = backend;
+            return;
+        }
+        self.bbox = self.refit_node(0, time_0, time_1);
+    }
+    /// * return - inners[idx]の8つの子のバウンディングボックスの和
+    fn refit_node(&mut self, idx: usize, time_0: f32, time_1: f32) -> Aabb {
+        let children = self.inners[idx].children;
+        let mut united = Aabb::empty();
+        for (child_id, child) in children.iter().enumerate() {
+            let bbox = if child.0.is_empty_leaf() {
+                Aabb::empty()
+            } else if child.0.is_leaf() {
+                self.leaves[child.0.index()]
+                    .bounding_box(time_0, time_1)
+                    .unwrap()
+            } else {
+                self.refit_node(child.0.index(), time_0, time_1)
+            };
+            Node::set_bboxes_array_layout(&mut self.inners[idx].bboxes, child_id as u8, &bbox);
+            united = Aabb::unite(&united, &bbox);
+        }
+        united
+    }
+    /// Whether refits have degraded the tree so much that a full rebuild pays off.
+    pub fn needs_rebuild(&self) -> bool {
+        self.sah_cost() > self.built_sah_cost * REBUILD_SAH_RATIO
+    }
+    /// Refits the tree, and rebuilds it from BVH::new_binned if needs_rebuild().
+    /// The backend is kept.
+    pub fn refit_or_rebuild(mut self, time_0: f32, time_1: f32, thread_cnt: usize) -> Self {
+        self.refit(time_0, time_1);
+        if self.needs_rebuild() {
+            let backend = self.backend;
+            let mut rebuilt =
+                Self::from_bvh(BVH::new_binned(self.leaves, time_0, time_1, thread_cnt));
+            rebuilt.backend = backend;
+            rebuilt
+        } else {
+            self
+        }
+    }
+    pub fn backend(&self) -> TraversalBackend {
+        self.backend
+    }
+    /// Replaces the backend chosen by TraversalBackend::detect().
+    /// Panics if the running CPU does not support the backend.
+    pub fn set_backend(&mut self, backend: TraversalBackend) {
+        assert!(
+            backend.is_available(),
+            "{:?} is not supported by the CPU",
+            backend
+        );
+        self.backend = backend;
     }
     // ToDo: NodePointerがBVHとOBVHの両方にあってわかりづらいのでnewtypeパターンする
     /// BVHを平坦化して、OBVHノードを構築する。
@@ -620,12 +915,16 @@ mod tests {
     use super::Node;
     use super::NodePointer;
     use super::OBVHNodePointer;
+    use super::TraversalBackend;
     use super::BVH;
     use super::OBVH;
+    use crate::hitable::test_util;
     use crate::material::lambertian::Lambertian;
     use crate::obj_file::ObjFile;
     use crate::texture::constant::ConstantTexture;
     use itertools::iproduct;
+    use rand::prng::XorShiftRng;
+    use rand::SeedableRng;
     #[cfg(target_arch = "x86")]
     use std::arch::x86::*;
     #[cfg(target_arch = "x86_64")]
@@ -633,11 +932,15 @@ mod tests {
     use std::path::Path;
     use std::sync::Arc;
     #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     fn load_and_movemask_order_compatibility() {
+        if !TraversalBackend::Avx.is_available() {
+            return;
+        }
         unsafe {
             let vec_a = _mm256_set1_ps(0.0);
             let b_arr = [4.0, 3.0, 2.0, 1.0, 0.0, -1.0, -2.0, -3.0];
-            let vec_b = _mm256_load_ps(b_arr.as_ptr());
+            let vec_b = _mm256_loadu_ps(b_arr.as_ptr());
             let movemask = _mm256_movemask_ps(_mm256_cmp_ps(
                 vec_a, vec_b, _CMP_LE_OS, /* Ordered, Signaling. */
             ));
@@ -734,7 +1037,7 @@ mod tests {
         {
             for (ray_is_pos_x, ray_is_pos_y, ray_is_pos_z) in iproduct!(0..2, 0..2, 0..2) {
                 let mut node = Node {
-                    bboxes: Node::load_bboxes(&BBoxesArray::empty()),
+                    bboxes: BBoxesArray::empty(),
                     children: [OBVHNodePointer(NodePointer::empty_leaf()); 8],
                     axis_bits_0: 0,
                     axis_bits_1: 0,
@@ -762,10 +1065,32 @@ mod tests {
             }
         }
     }
+    #[test]
+    fn backends_agree_with_brute_force() {
+        let mut rng = XorShiftRng::seed_from_u64(0);
+        let spheres = test_util::random_spheres(&mut rng, 200, 10.0, 0.1, 1.1);
+        let list = test_util::brute_force(test_util::make_spheres(&spheres));
+        let mut obvh = OBVH::from_bvh(BVH::new(test_util::make_spheres(&spheres), 0.0, 1.0));
+        let mut rays = test_util::random_rays(&mut rng, 1000, 15.0);
+        // 軸に平行なレイも含める（inv_dirが無限大になる）。
+        for (i, ray) in rays.iter_mut().enumerate().step_by(4) {
+            ray.direction[i / 4 % 3] = 0.0;
+        }
+        let backends = [
+            TraversalBackend::Avx,
+            TraversalBackend::Sse,
+            TraversalBackend::Scalar,
+        ];
+        for backend in backends.iter().filter(|b| b.is_available()) {
+            obvh.set_backend(*backend);
+            test_util::assert_same_hits(&list, &obvh, &rays, &format!("{:?}", backend));
+        }
+    }
 }
 
 impl<L> fmt::Debug for OBVH<L> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Backend: {:?}\n", self.backend).unwrap();
         write!(f, "Num of leaf nodes: {}\n", self.leaves.len()).unwrap();
         write!(f, "Num of inner nodes: {}\n", self.inners.len()).unwrap();
         for i in 0..self.inners.len() {
@@ -0,0 +1,326 @@
+// 子のバウンディングボックスを8bitに量子化したOBVH。
+// 各ノードは8つの子のバウンディングボックスの和（ノードのフレーム）の最小点originと、軸ごとの刻み幅scaleを持ち、
+// 子のボックスをorigin + q * scale（q in [0..256)）で表す。
+// 最小値は切り捨て、最大値は切り上げで量子化するので、復元したボックスは元のボックスを必ず含む。
+// ノードは256バイトから128バイトになる。トラバースでは復元したボックスに対してOBVHと同じバックエンドで判定する。
+
+use super::{
+    calc_traverse_priority, BBoxesArray, NodeStack, OBVHNodePointer, RayInfo, TraversalBackend,
+    NODE_STACK_UPPER_BOUND, OBVH,
+};
+use crate::aabb::Aabb;
+use crate::aliases::{RandGen, Vec3};
+use crate::export::ExportMesh;
+use crate::hit_record::HitRecord;
+use crate::hitable::node_pointer::NodePointer;
+use crate::hitable::{Hitable, TraversalStats};
+use crate::ray::Ray;
+use std::ops::Shl;
+use std::ops::Shr;
+
+const QUANTIZATION_MAX: u32 = 255;
+
+struct Node {
+    origin: [f32; 3],
+    scale: [f32; 3],
+    bounds: [[[u8; 8]; 3]; 2], // bounds[min_or_max][axis][child_id]
+    children: [OBVHNodePointer; 8],
+    axis_bits_0: u64, // OBVHのNodeと同じ
+    axis_bits_1: u64,
+    valid_mask: u8, // 第iビットが1 <=> 子iが空でない
+}
+
+impl Node {
+    fn from_obvh_node(node: &super::Node) -> Self {
+        let mut bboxes = [Aabb::empty(); 8];
+        super::Node::eight_aabb_from_array_layout(&mut bboxes, &node.bboxes.0);
+        let mut valid_mask = 0u8;
+        let mut frame = Aabb::empty();
+        for child_id in 0..8 {
+            if !node.children[child_id].0.is_empty_leaf() && !bboxes[child_id].is_empty() {
+                valid_mask |= 1u8.shl(child_id);
+                frame = Aabb::unite(&frame, &bboxes[child_id]);
+            }
+        }
+        let mut this = Node {
+            origin: [0.0; 3],
+            scale: [0.0; 3],
+            bounds: [[[0; 8]; 3]; 2],
+            children: node.children,
+            axis_bits_0: node.axis_bits_0,
+            axis_bits_1: node.axis_bits_1,
+            valid_mask: valid_mask,
+        };
+        if frame.is_empty() {
+            return this;
+        }
+        for a in 0..3 {
+            assert!(
+                frame.min[a].is_finite() && frame.max[a].is_finite(),
+                "CompressedOBVH cannot bound infinite boxes."
+            );
+            let origin = frame.min[a];
+            let mut scale = (frame.max[a] - origin) / QUANTIZATION_MAX as f32;
+            // origin + 255 * scaleがフレームの最大値を下回らないように、丸め誤差の分だけ大きくする。
+            while origin + QUANTIZATION_MAX as f32 * scale < frame.max[a] {
+                scale = f32::from_bits(scale.to_bits() + 1);
+            }
+            this.origin[a] = origin;
+            this.scale[a] = scale;
+            for child_id in 0..8 {
+                if valid_mask & 1u8.shl(child_id) == 0 {
+                    continue;
+                }
+                this.bounds[0][a][child_id] = quantize_min(bboxes[child_id].min[a], origin, scale);
+                this.bounds[1][a][child_id] = quantize_max(bboxes[child_id].max[a], origin, scale);
+            }
+        }
+        this
+    }
+    /// 子のバウンディングボックスを復元する。空の子は空のボックスになる。
+    #[inline(always)]
+    fn dequantize(&self, out: &mut BBoxesArray) {
+        for min_max in 0..2 {
+            for a in 0..3 {
+                for child_id in 0..8 {
+                    out.0[min_max][a][child_id] = dequantize(
+                        self.bounds[min_max][a][child_id],
+                        self.origin[a],
+                        self.scale[a],
+                    );
+                }
+            }
+        }
+    }
+    #[inline(always)]
+    fn hit(&self, ray: &RayInfo, t_min: f32, t_max: f32, backend: TraversalBackend) -> i32 {
+        let mut bboxes = BBoxesArray::empty();
+        self.dequantize(&mut bboxes);
+        bboxes.hit(ray, t_min, t_max, backend) & self.valid_mask as i32
+    }
+}
+
+#[inline(always)]
+fn dequantize(q: u8, origin: f32, scale: f32) -> f32 {
+    origin + q as f32 * scale
+}
+
+fn quantize_min(x: f32, origin: f32, scale: f32) -> u8 {
+    if scale <= 0.0 {
+        return 0;
+    }
+    let mut q = (((x - origin) / scale).floor().max(0.0) as u32).min(QUANTIZATION_MAX);
+    while q > 0 && dequantize(q as u8, origin, scale) > x {
+        q -= 1;
+    }
+    q as u8
+}
+
+fn quantize_max(x: f32, origin: f32, scale: f32) -> u8 {
+    if scale <= 0.0 {
+        return 0;
+    }
+    let mut q = (((x - origin) / scale).ceil().max(0.0) as u32).min(QUANTIZATION_MAX);
+    while q < QUANTIZATION_MAX && dequantize(q as u8, origin, scale) < x {
+        q += 1;
+    }
+    q as u8
+}
+
+/// OBVH whose nodes store the child bounds as 8-bit offsets in the frame of the node.
+/// Uses less memory than OBVH at the cost of the dequantization and looser boxes.
+pub struct CompressedOBVH<L> {
+    bbox: Aabb,
+    backend: TraversalBackend,
+    leaves: Vec<L>,    // leaf-nodes.
+    inners: Vec<Node>, // inner nodes. inners[0] is the root node.
+}
+
+impl<L> CompressedOBVH<L>
+where
+    L: Hitable,
+{
+    /// Panics if a node has an infinite bounding box.
+    pub fn from_obvh(obvh: OBVH<L>) -> Self {
+        let inners: Vec<Node> = obvh.inners.iter().map(Node::from_obvh_node).collect();
+        Self {
+            bbox: obvh.bbox,
+            backend: obvh.backend,
+            inners: inners,
+            leaves: obvh.leaves,
+        }
+    }
+    pub fn backend(&self) -> TraversalBackend {
+        self.backend
+    }
+    /// Same as OBVH::set_backend.
+    pub fn set_backend(&mut self, backend: TraversalBackend) {
+        assert!(
+            backend.is_available(),
+            "{:?} is not supported by the CPU",
+            backend
+        );
+        self.backend = backend;
+    }
+    /// Bytes used by the inner nodes (comparable with OBVH::node_bytes).
+    pub fn node_bytes(&self) -> usize {
+        self.inners.len() * std::mem::size_of::<Node>()
+    }
+}
+
+impl<L> Hitable for CompressedOBVH<L>
+where
+    L: Hitable,
+{
+    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        self.hit_with_stats(ray, t_min, t_max, &mut TraversalStats::default())
+    }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        mut t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        stats.aabb_tests += 1;
+        if !self.bbox.hit(ray, t_min, t_max) {
+            return None;
+        }
+        let ray_info = RayInfo::from_ray(ray);
+        let mut node_stack = NodeStack::empty();
+        node_stack.push(OBVHNodePointer(NodePointer::root()));
+        let mut hit_record: Option<HitRecord<'s>> = None;
+        while !node_stack.is_empty() {
+            let node_ptr = node_stack.pop();
+            if node_ptr.0.is_leaf() {
+                if node_ptr.0.is_empty_leaf() {
+                    continue;
+                }
+                HitRecord::replace_to_some_min(
+                    &mut hit_record,
+                    &self.leaves[node_ptr.0.index()].hit_with_stats(ray, t_min, t_max, stats),
+                );
+                if let Some(ref hr) = hit_record {
+                    t_max = hr.t;
+                }
+            } else {
+                stats.node_visits += 1;
+                stats.aabb_tests += 8;
+                let node = &self.inners[node_ptr.0.index()];
+                let hit_bits = node.hit(&ray_info, t_min, t_max, self.backend);
+                let priorities =
+                    calc_traverse_priority(node.axis_bits_0, node.axis_bits_1, &ray_info.dir_sign);
+                let mut ordered = [0usize; 8];
+                for child_id in 0..8 {
+                    ordered[priorities.shr(child_id * 8) as u8 as usize] = child_id;
+                }
+                for child_id in ordered.iter() {
+                    if hit_bits & (1i32.shl(*child_id)) != 0 {
+                        node_stack.push(node.children[*child_id]);
+                    }
+                }
+                debug_assert!(node_stack.len() <= NODE_STACK_UPPER_BOUND);
+            }
+        }
+        hit_record
+    }
+    fn is_hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> bool {
+        let ray_info = RayInfo::from_ray(ray);
+        let mut node_stack = NodeStack::empty();
+        node_stack.push(OBVHNodePointer(NodePointer::root()));
+        while !node_stack.is_empty() {
+            let node_ptr = node_stack.pop();
+            if node_ptr.0.is_leaf() {
+                if node_ptr.0.is_empty_leaf() {
+                    continue;
+                }
+                if self.leaves[node_ptr.0.index()].is_hit(ray, t_min, t_max) {
+                    return true;
+                }
+            } else {
+                let node = &self.inners[node_ptr.0.index()];
+                let hit_bits = node.hit(&ray_info, t_min, t_max, self.backend);
+                for bit in 0..8 {
+                    if hit_bits & 1i32.shl(bit) != 0 {
+                        node_stack.push(node.children[bit]);
+                    }
+                }
+                debug_assert!(node_stack.len() <= NODE_STACK_UPPER_BOUND);
+            }
+        }
+        false
+    }
+    fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
+        Some(self.bbox)
+    }
+    fn random_direction_from(&self, _origin: &Vec3, _rng: &mut RandGen) -> Vec3 {
+        panic!("random_direction_from called for CompressedOBVH");
+    }
+    fn direction_density(&self, _origin: &Vec3, _dir: &Vec3) -> f32 {
+        panic!("direction_density called for CompressedOBVH");
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        for leaf in &self.leaves {
+            leaf.tessellate(mesh);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quantize_max, quantize_min, CompressedOBVH, Node};
+    use crate::aliases::Vec3;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::obvh::{TraversalBackend, OBVH};
+    use crate::hitable::test_util;
+    use crate::ray::Ray;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
+    #[test]
+    fn compressed_obvh_is_conservative() {
+        let mut rng = XorShiftRng::seed_from_u64(6);
+        for _ in 0..1000 {
+            let origin = rng.gen::<f32>() * 200.0 - 100.0;
+            let scale = rng.gen::<f32>() * 0.1;
+            let x = origin + rng.gen::<f32>() * 255.0 * scale;
+            assert!(super::dequantize(quantize_min(x, origin, scale), origin, scale) <= x);
+            assert!(super::dequantize(quantize_max(x, origin, scale), origin, scale) >= x);
+        }
+        // 原点から離れた小さな三角形（量子化の誤差が出やすい）
+        let offset = Vec3::new(100.0, 0.0, 0.0);
+        let vertices: Vec<[Vec3; 3]> = (0..500)
+            .map(|_| {
+                let p = test_util::random_vec(&mut rng) * 10.0 + offset;
+                [
+                    p,
+                    p + test_util::random_vec(&mut rng) * 0.5,
+                    p + test_util::random_vec(&mut rng) * 0.5,
+                ]
+            })
+            .collect();
+        let list = test_util::brute_force(test_util::make_triangles(&vertices));
+        let obvh = OBVH::from_bvh(BVH::new_binned(
+            test_util::make_triangles(&vertices),
+            0.0,
+            1.0,
+            1,
+        ));
+        let full_bytes = obvh.node_bytes();
+        let mut compressed = CompressedOBVH::from_obvh(obvh);
+        assert!(compressed.node_bytes() * 2 <= full_bytes);
+        assert!(std::mem::size_of::<Node>() <= 128);
+        let rays: Vec<Ray> = test_util::random_rays(&mut rng, 200, 30.0)
+            .into_iter()
+            .map(|ray| Ray::new(&(ray.origin + offset), &ray.direction, 0.0))
+            .collect();
+        let backends = [
+            TraversalBackend::Avx,
+            TraversalBackend::Sse,
+            TraversalBackend::Scalar,
+        ];
+        for backend in backends.iter().filter(|b| b.is_available()) {
+            compressed.set_backend(*backend);
+            test_util::assert_same_hits(&list, &compressed, &rays, &format!("{:?}", backend));
+        }
+    }
+}
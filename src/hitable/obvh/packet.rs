@@ -0,0 +1,352 @@
+// 最大8本のレイをまとめてOBVHをトラバースする（パケットトラバーサル）。
+// カメラレイやNEEのシャドウレイのように原点と方向が近いレイは同じノードを訪れることが多いので、
+// ノードの読み込みとスタック操作をパケット全体で共有する。
+// AVXでは8本のレイを各レーンに入れ、子ノードのボックス1つずつに対して8本同時に判定する。
+// それ以外のbackendでは、レイ1本ずつBBoxesArray::hitを呼ぶ（判定結果は同じ）。
+
+use super::{BBoxesArray, OBVHNodePointer, RayInfo, TraversalBackend, OBVH};
+use crate::hit_record::HitRecord;
+use crate::hitable::node_pointer::NodePointer;
+use crate::hitable::Hitable;
+use crate::ray::Ray;
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::ops::Shr;
+
+/// The number of rays traversed together.
+pub const PACKET_SIZE: usize = 8;
+
+// パケットのレイをSoAで持つ。rays.len() < 8のとき、余ったレーンは使わない（マスクの外）。
+// 各[f32; 8]を__m256としてアラインされたロードをするので、フィールドを並べ替えさせない。
+#[repr(C, align(32))]
+struct RayPacket {
+    origin: [[f32; PACKET_SIZE]; 3],
+    inv_dir: [[f32; PACKET_SIZE]; 3],
+    dir_is_neg: [[u32; PACKET_SIZE]; 3], // ray.direction[axis] < 0 のレーンは全ビット1（blendvのマスク）
+    infos: [RayInfo; PACKET_SIZE],       // スカラーでの判定用
+}
+
+impl RayPacket {
+    fn new(rays: &[Ray]) -> Self {
+        debug_assert!(rays.len() <= PACKET_SIZE);
+        let mut packet = RayPacket {
+            origin: [[0.0; PACKET_SIZE]; 3],
+            inv_dir: [[0.0; PACKET_SIZE]; 3],
+            dir_is_neg: [[0; PACKET_SIZE]; 3],
+            infos: [RayInfo::default(); PACKET_SIZE],
+        };
+        for (lane, ray) in rays.iter().enumerate() {
+            let info = RayInfo::from_ray(ray);
+            packet.infos[lane] = info;
+            for a in 0..3 {
+                packet.origin[a][lane] = info.origin[a];
+                packet.inv_dir[a][lane] = info.inv_dir[a];
+                packet.dir_is_neg[a][lane] = if info.dir_sign[a] == 0 { !0 } else { 0 };
+            }
+        }
+        packet
+    }
+}
+
+/// maskの立っているレーン
+fn lanes(mask: u8) -> impl Iterator<Item = usize> {
+    (0..PACKET_SIZE).filter(move |lane| mask & (1 << lane) != 0)
+}
+
+impl BBoxesArray {
+    /// パケットのレイがぞれぞれのバウンディングボックスにヒットしているか返す。
+    /// * `return` - return[child_id]の第laneビット =「レーンlaneのレイが子child_idにヒットしているか」（maskの外は0）
+    fn hit_packet(
+        &self,
+        packet: &RayPacket,
+        t_min: f32,
+        t_max: &[f32; PACKET_SIZE],
+        mask: u8,
+        backend: TraversalBackend,
+    ) -> [u8; 8] {
+        match backend {
+            // OBVH::set_backendで利用可能であることを確認済み。
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TraversalBackend::Avx => unsafe { self.hit_packet_avx(packet, t_min, t_max, mask) },
+            _ => self.hit_packet_per_ray(packet, t_min, t_max, mask, backend),
+        }
+    }
+    /// BBoxesArray::hit_avxと同じ演算を、子とレイの役割を入れ替えて行う。
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx")]
+    unsafe fn hit_packet_avx(
+        &self,
+        packet: &RayPacket,
+        t_min: f32,
+        t_max: &[f32; PACKET_SIZE],
+        mask: u8,
+    ) -> [u8; 8] {
+        let mut origin = [_mm256_setzero_ps(); 3];
+        let mut inv_dir = [_mm256_setzero_ps(); 3];
+        let mut dir_is_neg = [_mm256_setzero_ps(); 3];
+        for a in 0..3 {
+            origin[a] = _mm256_load_ps(packet.origin[a].as_ptr());
+            inv_dir[a] = _mm256_load_ps(packet.inv_dir[a].as_ptr());
+            dir_is_neg[a] = _mm256_load_ps(packet.dir_is_neg[a].as_ptr() as *const f32);
+        }
+        let t_max = _mm256_loadu_ps(t_max.as_ptr());
+        let mut res = [0u8; 8];
+        for (child_id, r) in res.iter_mut().enumerate() {
+            let mut t_min = _mm256_set1_ps(t_min);
+            let mut t_max = t_max;
+            for a in 0..3 {
+                let lo = _mm256_set1_ps(self.0[0][a][child_id]);
+                let hi = _mm256_set1_ps(self.0[1][a][child_id]);
+                let near = _mm256_blendv_ps(lo, hi, dir_is_neg[a]);
+                let far = _mm256_blendv_ps(hi, lo, dir_is_neg[a]);
+                t_min = _mm256_max_ps(
+                    t_min,
+                    _mm256_mul_ps(inv_dir[a], _mm256_sub_ps(near, origin[a])),
+                );
+                t_max = _mm256_min_ps(
+                    t_max,
+                    _mm256_mul_ps(inv_dir[a], _mm256_sub_ps(far, origin[a])),
+                );
+            }
+            *r = _mm256_movemask_ps(_mm256_cmp_ps(t_min, t_max, _CMP_LE_OS)) as u8 & mask;
+        }
+        res
+    }
+    fn hit_packet_per_ray(
+        &self,
+        packet: &RayPacket,
+        t_min: f32,
+        t_max: &[f32; PACKET_SIZE],
+        mask: u8,
+        backend: TraversalBackend,
+    ) -> [u8; 8] {
+        let mut res = [0u8; 8];
+        for lane in lanes(mask) {
+            let hit_bits = self.hit(&packet.infos[lane], t_min, t_max[lane], backend);
+            for (child_id, r) in res.iter_mut().enumerate() {
+                if hit_bits & (1 << child_id) != 0 {
+                    *r |= 1 << lane;
+                }
+            }
+        }
+        res
+    }
+}
+
+const PACKET_STACK_UPPER_BOUND: usize = super::NODE_STACK_UPPER_BOUND;
+
+// NodeStackのパケット版。ノードと、そのノードを訪れるレーンのマスクを積む。
+struct PacketNodeStack {
+    data: [(OBVHNodePointer, u8); PACKET_STACK_UPPER_BOUND],
+    end: usize,
+}
+
+impl PacketNodeStack {
+    fn empty() -> Self {
+        PacketNodeStack {
+            data: [(OBVHNodePointer(NodePointer::empty_leaf()), 0); PACKET_STACK_UPPER_BOUND],
+            end: 0,
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.end == 0
+    }
+    fn push(&mut self, node_ptr: OBVHNodePointer, mask: u8) {
+        self.data[self.end] = (node_ptr, mask);
+        self.end += 1;
+        debug_assert!(self.end <= PACKET_STACK_UPPER_BOUND);
+    }
+    fn pop(&mut self) -> (OBVHNodePointer, u8) {
+        debug_assert!(!self.is_empty());
+        self.end -= 1;
+        self.data[self.end]
+    }
+    /// 内部ノードのうち、masksが0でない子を積む。
+    /// 順序はパケットの先頭のレイに対するcalc_traverse_priorityに従う（結果には影響しない）。
+    fn push_children(&mut self, node: &super::Node, masks: &[u8; 8], leader: &RayInfo) {
+        let priorities = node.calc_traverse_priority(&leader.dir_sign);
+        let mut ordered = [0usize; 8];
+        for child_id in 0..8 {
+            ordered[priorities.shr(child_id * 8) as u8 as usize] = child_id;
+        }
+        for &child_id in ordered.iter() {
+            if masks[child_id] != 0 {
+                self.push(node.children[child_id], masks[child_id]);
+            }
+        }
+    }
+}
+
+impl<L> OBVH<L>
+where
+    L: Hitable,
+{
+    /// Hitable::hit_packetの、PACKET_SIZE本以下のレイに対する実装。
+    pub(super) fn hit_packet_chunk<'s>(
+        &'s self,
+        rays: &[Ray],
+        t_min: f32,
+        t_max: &mut [f32],
+        recs: &mut [Option<HitRecord<'s>>],
+    ) {
+        let packet = RayPacket::new(rays);
+        let mut lane_t_max = [std::f32::MAX; PACKET_SIZE];
+        lane_t_max[..rays.len()].copy_from_slice(t_max);
+        let mut root_mask = 0u8;
+        for (lane, ray) in rays.iter().enumerate() {
+            if self.bbox.hit(ray, t_min, t_max[lane]) {
+                root_mask |= 1 << lane;
+            }
+        }
+        let mut node_stack = PacketNodeStack::empty();
+        if root_mask != 0 {
+            node_stack.push(OBVHNodePointer(NodePointer::root()), root_mask);
+        }
+        while !node_stack.is_empty() {
+            let (node_ptr, mask) = node_stack.pop();
+            if node_ptr.0.is_leaf() {
+                if node_ptr.0.is_empty_leaf() {
+                    continue;
+                }
+                let leaf = &self.leaves[node_ptr.0.index()];
+                for lane in lanes(mask) {
+                    if let Some(hr) = leaf.hit(&rays[lane], t_min, lane_t_max[lane]) {
+                        lane_t_max[lane] = hr.t;
+                        recs[lane] = Some(hr);
+                    }
+                }
+            } else {
+                let node = &self.inners[node_ptr.0.index()];
+                let masks = node
+                    .bboxes
+                    .hit_packet(&packet, t_min, &lane_t_max, mask, self.backend);
+                let leader = &packet.infos[mask.trailing_zeros() as usize];
+                node_stack.push_children(node, &masks, leader);
+            }
+        }
+        t_max.copy_from_slice(&lane_t_max[..rays.len()]);
+    }
+    /// Hitable::is_hit_packetの、PACKET_SIZE本以下のレイに対する実装。
+    pub(super) fn is_hit_packet_chunk(
+        &self,
+        rays: &[Ray],
+        t_min: f32,
+        t_max: &[f32],
+        is_hits: &mut [bool],
+    ) {
+        let packet = RayPacket::new(rays);
+        let mut lane_t_max = [std::f32::MAX; PACKET_SIZE];
+        lane_t_max[..rays.len()].copy_from_slice(t_max);
+        let mut remaining = 0u8; // まだヒットが見つかっていないレーン
+        for (lane, &is_hit) in is_hits.iter().enumerate() {
+            if !is_hit {
+                remaining |= 1 << lane;
+            }
+        }
+        let mut node_stack = PacketNodeStack::empty();
+        node_stack.push(OBVHNodePointer(NodePointer::root()), remaining);
+        while !node_stack.is_empty() && remaining != 0 {
+            let (node_ptr, mask) = node_stack.pop();
+            let mask = mask & remaining;
+            if mask == 0 {
+                continue;
+            }
+            if node_ptr.0.is_leaf() {
+                if node_ptr.0.is_empty_leaf() {
+                    continue;
+                }
+                let leaf = &self.leaves[node_ptr.0.index()];
+                for lane in lanes(mask) {
+                    if leaf.is_hit(&rays[lane], t_min, lane_t_max[lane]) {
+                        remaining &= !(1 << lane);
+                    }
+                }
+            } else {
+                let node = &self.inners[node_ptr.0.index()];
+                let masks = node
+                    .bboxes
+                    .hit_packet(&packet, t_min, &lane_t_max, mask, self.backend);
+                let leader = &packet.infos[mask.trailing_zeros() as usize];
+                node_stack.push_children(node, &masks, leader);
+            }
+        }
+        for (lane, is_hit) in is_hits.iter_mut().enumerate() {
+            *is_hit = remaining & (1 << lane) == 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::hitable_list::HitableList;
+    use crate::hitable::obvh::{TraversalBackend, OBVH};
+    use crate::hitable::test_util;
+    use crate::hitable::Hitable;
+    use crate::ray::Ray;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
+    use std::sync::Arc;
+    /// hit_packetとis_hit_packetが1本ずつのhitとis_hitに一致することを確かめる。
+    fn assert_packet_agrees(h: &Hitable, rays: &[Ray], t_max: &[f32], context: &str) {
+        let mut packet_t_max = t_max.to_vec();
+        let mut recs = vec![None; rays.len()];
+        h.hit_packet(rays, 0.0001, &mut packet_t_max, &mut recs);
+        let mut is_hits = vec![false; rays.len()];
+        h.is_hit_packet(rays, 0.0001, t_max, &mut is_hits);
+        for (i, ray) in rays.iter().enumerate() {
+            let expected = h.hit(ray, 0.0001, t_max[i]).map(|rec| rec.t);
+            assert_eq!(expected, recs[i].map(|rec| rec.t), "{}", context);
+            assert_eq!(expected.unwrap_or(t_max[i]), packet_t_max[i], "{}", context);
+            assert_eq!(h.is_hit(ray, 0.0001, t_max[i]), is_hits[i], "{}", context);
+        }
+    }
+    #[test]
+    fn packets_agree_with_single_rays() {
+        let mut rng = XorShiftRng::seed_from_u64(7);
+        let spheres = test_util::random_spheres(&mut rng, 300, 10.0, 0.1, 1.1);
+        let mut obvh = OBVH::from_bvh(BVH::new(test_util::make_spheres(&spheres), 0.0, 1.0));
+        // 原点を共有し方向が近いレイの束（カメラレイ風）と、ばらばらなレイを混ぜる。
+        // 1003本なので、最後のパケットは3本だけになる。
+        let mut rays = Vec::new();
+        let mut t_max = Vec::new();
+        while rays.len() < 1003 {
+            let origin = test_util::random_vec(&mut rng) * 15.0;
+            let center_dir = test_util::random_vec(&mut rng);
+            for i in 0..13 {
+                let mut dir = if i < 10 {
+                    center_dir + test_util::random_vec(&mut rng) * 0.05
+                } else {
+                    test_util::random_vec(&mut rng)
+                };
+                // 軸に平行なレイも含める（inv_dirが無限大になる）。
+                if i % 5 == 0 {
+                    dir[i % 3] = 0.0;
+                }
+                rays.push(Ray::new(&origin, &dir, 0.0));
+                t_max.push(if i % 3 == 0 {
+                    std::f32::MAX
+                } else {
+                    rng.gen::<f32>() * 20.0
+                });
+            }
+        }
+        rays.truncate(1003);
+        t_max.truncate(1003);
+        let backends = [
+            TraversalBackend::Avx,
+            TraversalBackend::Sse,
+            TraversalBackend::Scalar,
+        ];
+        for backend in backends.iter().filter(|b| b.is_available()) {
+            obvh.set_backend(*backend);
+            assert_packet_agrees(&obvh, &rays, &t_max, &format!("{:?}", backend));
+        }
+        // HitableListは子のhit_packetを使う
+        let obvh: Arc<Hitable> = Arc::new(obvh);
+        let list = HitableList::new(vec![obvh.clone(), obvh]);
+        assert_packet_agrees(&list, &rays, &t_max, "HitableList");
+    }
+}
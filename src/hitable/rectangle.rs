@@ -1,5 +1,6 @@
 use crate::aabb::Aabb;
 use crate::aliases::{RandGen, Vec2, Vec3};
+use crate::export::{tessellate_rectangle, ExportMesh};
 use crate::hit_record::HitRecord;
 use crate::hitable::Hitable;
 use crate::material::Material;
@@ -61,6 +62,10 @@ impl Hitable for Rectangle {
                 point: point,
                 tex_coord: Vec2::new(u, v),
                 normal: self.normal,
+                geometric_normal: self.normal,
+                barycentric: None,
+                dpdu: self.edge_0,
+                dpdv: self.edge_1,
                 material: self.material.as_ref(),
             })
         } else {
@@ -94,4 +99,31 @@ impl Hitable for Rectangle {
             0.0
         }
     }
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        let u = rng.gen::<f32>();
+        let v = rng.gen::<f32>();
+        HitRecord {
+            t: 0.0,
+            point: self.origin + u * self.edge_0 + v * self.edge_1,
+            tex_coord: Vec2::new(u, v),
+            normal: self.normal,
+            geometric_normal: self.normal,
+            barycentric: None,
+            dpdu: self.edge_0,
+            dpdv: self.edge_1,
+            material: self.material.as_ref(),
+        }
+    }
+    fn surface_area(&self) -> f32 {
+        self.edge_0.cross(&self.edge_1).norm()
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        tessellate_rectangle(
+            mesh,
+            &self.origin,
+            &self.edge_0,
+            &self.edge_1,
+            self.material.as_ref(),
+        );
+    }
 }
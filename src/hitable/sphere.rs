@@ -1,5 +1,6 @@
 use crate::aabb::Aabb;
 use crate::aliases::{RandGen, Vec2, Vec3};
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
 use crate::hitable::Hitable;
 use crate::material::Material;
@@ -52,6 +53,25 @@ impl Sphere {
         let theta = f32::asin(p[1].min(1.0).max(-1.0));
         Vec2::new(0.5 - 0.5 * (phi / PI), 0.5 + theta / PI)
     }
+    /// (∂p/∂u, ∂p/∂v) of the parametrization by get_uv() at the point p = center + radius * normal.
+    /// At the poles, where they are not defined, returns an orthogonal frame instead.
+    pub fn get_dpduv(normal: &Vec3, radius: f32) -> (Vec3, Vec3) {
+        // p = radius * (cosθ cosφ, sinθ, cosθ sinφ), φ = π(1 - 2u), θ = π(v - 1/2)
+        let cos_theta = (normal[0] * normal[0] + normal[2] * normal[2]).sqrt();
+        if cos_theta == 0.0 {
+            let onb = Onb::build_from_w(normal);
+            return (radius * onb.u(), radius * onb.v());
+        }
+        let dpdu = 2.0 * PI * radius * Vec3::new(normal[2], 0.0, -normal[0]);
+        let dpdv = PI
+            * radius
+            * Vec3::new(
+                -normal[1] * normal[0] / cos_theta,
+                cos_theta,
+                -normal[1] * normal[2] / cos_theta,
+            );
+        (dpdu, dpdv)
+    }
 }
 
 impl Hitable for Sphere {
@@ -60,11 +80,16 @@ impl Hitable for Sphere {
             let point = ray.evaluate(t);
             let normal = (point - self.center) / self.radius;
             let uv = Sphere::get_uv(&normal);
+            let (dpdu, dpdv) = Sphere::get_dpduv(&normal, self.radius);
             HitRecord {
                 t: t,
                 point: point,
                 tex_coord: uv,
                 normal: normal,
+                geometric_normal: normal,
+                barycentric: None,
+                dpdu: dpdu,
+                dpdv: dpdv,
                 material: self.material.as_ref(),
             }
         })
@@ -94,6 +119,59 @@ impl Hitable for Sphere {
             0.0
         }
     }
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        let normal = random_in_cone(-1.0, rng);
+        let (dpdu, dpdv) = Sphere::get_dpduv(&normal, self.radius);
+        HitRecord {
+            t: 0.0,
+            point: self.center + self.radius * normal,
+            tex_coord: Sphere::get_uv(&normal),
+            normal: normal,
+            geometric_normal: normal,
+            barycentric: None,
+            dpdu: dpdu,
+            dpdv: dpdv,
+            material: self.material.as_ref(),
+        }
+    }
+    fn surface_area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        // 緯線と経線で分割し、Sphere::get_uv()と同じUVを付ける。極を含む帯は三角形1枚ずつ
+        let (segments, rings) = (mesh.sphere_segments, mesh.sphere_rings);
+        let mut grid = Vec::with_capacity((rings + 1) * (segments + 1));
+        for i in 0..=rings {
+            let v = i as f32 / rings as f32;
+            let theta = PI * (v - 0.5);
+            for j in 0..=segments {
+                let u = j as f32 / segments as f32;
+                let phi = PI * (1.0 - 2.0 * u);
+                let normal = Vec3::new(
+                    theta.cos() * phi.cos(),
+                    theta.sin(),
+                    theta.cos() * phi.sin(),
+                );
+                let point = self.center + self.radius * normal;
+                grid.push(mesh.add_vertex(&point, &normal, &Vec2::new(u, v)));
+            }
+        }
+        let material = self.material.as_ref();
+        for i in 0..rings {
+            for j in 0..segments {
+                let a = grid[i * (segments + 1) + j];
+                let b = grid[i * (segments + 1) + j + 1];
+                let c = grid[(i + 1) * (segments + 1) + j + 1];
+                let d = grid[(i + 1) * (segments + 1) + j];
+                if i != 0 {
+                    mesh.add_triangle([a, b, c], material);
+                }
+                if i != rings - 1 {
+                    mesh.add_triangle([a, c, d], material);
+                }
+            }
+        }
+    }
 }
 
 pub struct MovingSphere {
@@ -132,11 +210,16 @@ impl Hitable for MovingSphere {
             let point = ray.evaluate(t);
             let normal = (point - center) / self.radius;
             let uv = Sphere::get_uv(&normal);
+            let (dpdu, dpdv) = Sphere::get_dpduv(&normal, self.radius);
             HitRecord {
                 t: t,
                 point: point,
                 tex_coord: uv,
                 normal: normal,
+                geometric_normal: normal,
+                barycentric: None,
+                dpdu: dpdu,
+                dpdv: dpdv,
                 material: self.material.as_ref(),
             }
         })
@@ -0,0 +1,103 @@
+// 加速構造のテストで共通に使う道具。
+// 乱数で球・三角形・レイを生成し、総当たり (HitableList) の結果と比較する。
+
+use crate::aliases::Vec3;
+use crate::hitable::hitable_list::HitableList;
+use crate::hitable::sphere::Sphere;
+use crate::hitable::triangle::Triangle;
+use crate::hitable::Hitable;
+use crate::material::lambertian::Lambertian;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::texture::constant::ConstantTexture;
+use rand::prng::XorShiftRng;
+use rand::Rng;
+use std::sync::Arc;
+
+/// A white Lambertian material.
+pub fn white() -> Arc<Material> {
+    Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+        1.0, 1.0, 1.0,
+    ))))
+}
+
+/// A uniformly random vector in [-1, 1]^3.
+pub fn random_vec(rng: &mut XorShiftRng) -> Vec3 {
+    Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 2.0 - Vec3::new(1.0, 1.0, 1.0)
+}
+
+/// Centers in [-spread, spread]^3 and radii in [min_radius, max_radius) of `cnt` spheres.
+pub fn random_spheres(
+    rng: &mut XorShiftRng,
+    cnt: usize,
+    spread: f32,
+    min_radius: f32,
+    max_radius: f32,
+) -> Vec<(Vec3, f32)> {
+    (0..cnt)
+        .map(|_| {
+            let center = random_vec(rng) * spread;
+            (
+                center,
+                min_radius + rng.gen::<f32>() * (max_radius - min_radius),
+            )
+        })
+        .collect()
+}
+
+/// White spheres. The leaves are built anew on each call since acceleration structures take their ownership.
+pub fn make_spheres(spheres: &[(Vec3, f32)]) -> Vec<Sphere> {
+    let material = white();
+    spheres
+        .iter()
+        .map(|(center, radius)| Sphere::new(center, *radius, material.clone()))
+        .collect()
+}
+
+/// White triangles without vertex normals.
+pub fn make_triangles(vertices: &[[Vec3; 3]]) -> Vec<Triangle> {
+    let material = white();
+    vertices
+        .iter()
+        .map(|v| Triangle::new(v, &None, material.clone()))
+        .collect()
+}
+
+/// The reference which tests all the leaves one by one.
+pub fn brute_force<H: Hitable + 'static>(leaves: Vec<H>) -> HitableList {
+    HitableList::new(
+        leaves
+            .into_iter()
+            .map(|leaf| -> Arc<Hitable> { Arc::new(leaf) })
+            .collect(),
+    )
+}
+
+/// `cnt` rays with origins in [-spread, spread]^3 and random directions.
+pub fn random_rays(rng: &mut XorShiftRng, cnt: usize, spread: f32) -> Vec<Ray> {
+    (0..cnt)
+        .map(|_| {
+            let origin = random_vec(rng) * spread;
+            Ray::new(&origin, &random_vec(rng), 0.0)
+        })
+        .collect()
+}
+
+/// Asserts that `actual` finds the same closest hits as `expected` for every ray.
+/// * `context` - printed on failure, e.g. the traversal backend.
+pub fn assert_same_hits(expected: &Hitable, actual: &Hitable, rays: &[Ray], context: &str) {
+    for ray in rays {
+        assert_eq!(
+            expected.hit(ray, 0.0001, std::f32::MAX).map(|rec| rec.t),
+            actual.hit(ray, 0.0001, std::f32::MAX).map(|rec| rec.t),
+            "{}",
+            context
+        );
+        assert_eq!(
+            expected.is_hit(ray, 0.0001, std::f32::MAX),
+            actual.is_hit(ray, 0.0001, std::f32::MAX),
+            "{}",
+            context
+        );
+    }
+}
@@ -1,8 +1,9 @@
 use crate::aabb::Aabb;
 use crate::affine::Affine;
 use crate::aliases::{RandGen, Vec3};
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
-use crate::hitable::Hitable;
+use crate::hitable::{Hitable, TraversalStats};
 use crate::ray::Ray;
 use std::sync::Arc;
 
@@ -35,6 +36,17 @@ impl Hitable for Transform {
             .hit(&ray.get_transformed(&self.inv_transform), t_min, t_max)
             .map(|rec| rec.get_transformed(&self.transform))
     }
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        self.original
+            .hit_with_stats(&ray.get_transformed(&self.inv_transform), t_min, t_max, stats)
+            .map(|rec| rec.get_transformed(&self.transform))
+    }
     fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
         self.bbox
     }
@@ -44,4 +56,18 @@ impl Hitable for Transform {
     fn direction_density(&self, _origin: &Vec3, _dir: &Vec3) -> f32 {
         unimplemented!()
     }
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        self.original
+            .random_point_on_surface(rng)
+            .get_transformed(&self.transform)
+    }
+    fn surface_area(&self) -> f32 {
+        // Affine is a similarity transformation, which scales areas uniformly.
+        self.original.surface_area() * self.transform.determinant().abs().powf(2.0 / 3.0)
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        mesh.push_transform(&self.transform);
+        self.original.tessellate(mesh);
+        mesh.pop_transform();
+    }
 }
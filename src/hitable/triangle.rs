@@ -1,18 +1,22 @@
 use crate::aabb::Aabb;
-use crate::aliases::{RandGen, Vec2, Vec3};
+use crate::aliases::{RandGen, Vec2, Vec3, Vec4};
+use crate::export::ExportMesh;
 use crate::hit_record::HitRecord;
 use crate::hitable::Hitable;
 use crate::material::Material;
+use crate::onb::Onb;
 use crate::ray::Ray;
+use rand::Rng;
 use std::sync::Arc;
 
 pub struct Triangle {
-    vertices: [Vec3; 3],              // a,b,c
-    perpendicular_normals: [Vec3; 3], // (aから辺bcへの単位垂線ベクトル, bから辺caへの..., cから辺abへの...)
-    perpendicular_lengths: [f32; 3],  // 垂線の長さ
-    normal: Vec3,                     // 単位法線ベクトル。(b-a).cross(c-a).normalize
+    vertices: [Vec3; 3], // a,b,c
+    normal: Vec3,        // 単位法線ベクトル。(b-a).cross(c-a).normalize
     material: Arc<Material>,
     vertex_normals: Option<[Vec3; 3]>, // Normal vectors at vertices. If this is Some, the hit() returns HitRecord with linearly interpolated normal vector.
+    tex_coords: Option<[Vec2; 3]>,      // 頂点ごとのUV
+    vertex_tangents: Option<[Vec4; 3]>, // 頂点ごとの接線。wは従接線の向き（+1 or -1）
+    cull_backface: bool,                // trueなら、裏側（normalと同じ向き）から来たレイはヒットしない
 }
 
 impl Triangle {
@@ -24,45 +28,68 @@ impl Triangle {
         let a = vertices[0];
         let b = vertices[1];
         let c = vertices[2];
-        let v = (b - a).cross(&(c - a));
-        let n = v.normalize();
-        let na = v.cross(&(b - c)).normalize();
-        let nb = v.cross(&(c - a)).normalize();
-        let nc = v.cross(&(a - b)).normalize();
-        let ha = v.norm() / (b - c).norm();
-        let hb = v.norm() / (c - a).norm();
-        let hc = v.norm() / (a - b).norm();
+        let n = (b - a).cross(&(c - a)).normalize();
         Self {
             vertices: [a, b, c],
             normal: n,
-            perpendicular_normals: [na, nb, nc],
-            perpendicular_lengths: [ha, hb, hc],
             vertex_normals: *vertex_normals,
             material: material,
+            tex_coords: None,
+            vertex_tangents: None,
+            cull_backface: false,
         }
     }
-}
-
-impl Hitable for Triangle {
-    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
-        let denom = self.normal.dot(&ray.direction);
-        if denom == 0.0 {
-            return None;
-        }
-        let t = (self.vertices[0] - ray.origin).dot(&self.normal) / denom;
-        if t <= t_min || t_max <= t {
-            return None;
-        }
+    /// If this is set, the hit() interpolates the texture coordinates and calculates dpdu and dpdv from them.
+    pub fn set_tex_coords(&mut self, tex_coords: Option<[Vec2; 3]>) {
+        self.tex_coords = tex_coords;
+    }
+    /// MikkTSpace-style tangents at vertices (see TriangleMesh::generate_tangents()).
+    /// If this is set, dpdu and dpdv of the HitRecord are the interpolated tangent and bitangent.
+    pub fn set_vertex_tangents(&mut self, vertex_tangents: Option<[Vec4; 3]>) {
+        self.vertex_tangents = vertex_tangents;
+    }
+    /// If cull is true, rays coming from the back side (ray.direction.dot(normal) > 0) do not hit.
+    pub fn set_backface_culling(&mut self, cull: bool) {
+        self.cull_backface = cull;
+    }
+    pub fn vertices(&self) -> &[Vec3; 3] {
+        &self.vertices
+    }
+    pub fn vertex_normals(&self) -> &Option<[Vec3; 3]> {
+        &self.vertex_normals
+    }
+    pub fn tex_coords(&self) -> &Option<[Vec2; 3]> {
+        &self.tex_coords
+    }
+    pub fn vertex_tangents(&self) -> &Option<[Vec4; 3]> {
+        &self.vertex_tangents
+    }
+    /// Interpolated texture coordinates and (dpdu, dpdv) at the point with the barycentric coordinates.
+    fn surface_frame(&self, weights: &[f32; 3], normal: &Vec3) -> (Vec2, Vec3, Vec3) {
+        let tex_coord = match self.tex_coords {
+            Some(ref uv) => weights[0] * uv[0] + weights[1] * uv[1] + weights[2] * uv[2],
+            None => Vec2::new(0.0, 0.0),
+        };
+        let (dpdu, dpdv) = shading_derivatives(
+            &self.vertices,
+            self.tex_coords.as_ref(),
+            self.vertex_tangents.as_ref(),
+            weights,
+            normal,
+        );
+        (tex_coord, dpdu, dpdv)
+    }
+    /// 単位法線ベクトル
+    pub fn normal(&self) -> &Vec3 {
+        &self.normal
+    }
+    pub fn cull_backface(&self) -> bool {
+        self.cull_backface
+    }
+    /// The HitRecord at the intersection found by intersect_watertight().
+    /// * `weights` - the barycentric coordinates of the intersection
+    pub fn hit_record<'s>(&'s self, ray: &Ray, t: f32, weights: &[f32; 3]) -> HitRecord<'s> {
         let p = ray.evaluate(t);
-        let mut weights: [f32; 3] = [0.0, 0.0, 0.0];
-        for i in 0..3 {
-            weights[i] = 1.0
-                - (p - self.vertices[i]).dot(&self.perpendicular_normals[i])
-                    / self.perpendicular_lengths[i];
-        }
-        if weights[0] < 0.0 || weights[1] < 0.0 || weights[2] < 0.0 {
-            return None;
-        }
         let normal = if let Some(ref vertex_normals) = self.vertex_normals {
             (weights[0] * vertex_normals[0]
                 + weights[1] * vertex_normals[1]
@@ -71,13 +98,26 @@ impl Hitable for Triangle {
         } else {
             self.normal
         };
-        Some(HitRecord {
+        let (tex_coord, dpdu, dpdv) = self.surface_frame(weights, &normal);
+        HitRecord {
             t: t,
             point: p,
-            tex_coord: Vec2::new(0.0, 0.0),
+            tex_coord: tex_coord,
             normal: normal,
+            geometric_normal: self.normal,
+            barycentric: Some(Vec3::new(weights[0], weights[1], weights[2])),
+            dpdu: dpdu,
+            dpdv: dpdv,
             material: self.material.as_ref(),
-        })
+        }
+    }
+}
+
+impl Hitable for Triangle {
+    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        let (t, weights) =
+            intersect_watertight(&self.vertices, ray, t_min, t_max, self.cull_backface)?;
+        Some(self.hit_record(ray, t, &weights))
     }
     fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
         Some(Aabb::from_points(vec![
@@ -86,10 +126,337 @@ impl Hitable for Triangle {
             self.vertices[2],
         ]))
     }
+    /// Clips the edges by the plane, so that the boxes are tight for long thin triangles.
+    fn split_bounding_box(&self, bbox: &Aabb, axis: usize, position: f32) -> (Aabb, Aabb) {
+        split_triangle_bounding_box(&self.vertices, bbox, axis, position)
+    }
     fn random_direction_from(&self, _origin: &Vec3, _rng: &mut RandGen) -> Vec3 {
         unimplemented!()
     }
     fn direction_density(&self, _origin: &Vec3, _dir: &Vec3) -> f32 {
         unimplemented!()
     }
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        let r0 = rng.gen::<f32>().sqrt();
+        let r1 = rng.gen::<f32>();
+        let weights = [1.0 - r0, r0 * (1.0 - r1), r0 * r1];
+        let point = weights[0] * self.vertices[0]
+            + weights[1] * self.vertices[1]
+            + weights[2] * self.vertices[2];
+        let (tex_coord, dpdu, dpdv) = self.surface_frame(&weights, &self.normal);
+        HitRecord {
+            t: 0.0,
+            point: point,
+            tex_coord: tex_coord,
+            normal: self.normal,
+            geometric_normal: self.normal,
+            barycentric: Some(Vec3::new(weights[0], weights[1], weights[2])),
+            dpdu: dpdu,
+            dpdv: dpdv,
+            material: self.material.as_ref(),
+        }
+    }
+    fn surface_area(&self) -> f32 {
+        0.5 * (self.vertices[1] - self.vertices[0])
+            .cross(&(self.vertices[2] - self.vertices[0]))
+            .norm()
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        let normals = self.vertex_normals.unwrap_or([self.normal; 3]);
+        let tex_coords = self.tex_coords.unwrap_or([Vec2::new(0.0, 0.0); 3]);
+        let indices =
+            [0, 1, 2].map(|k| mesh.add_vertex(&self.vertices[k], &normals[k], &tex_coords[k]));
+        mesh.add_triangle(indices, self.material.as_ref());
+    }
+}
+
+/// Hitable::split_bounding_box for the triangle with the vertices.
+pub fn split_triangle_bounding_box(
+    vertices: &[Vec3; 3],
+    bbox: &Aabb,
+    axis: usize,
+    position: f32,
+) -> (Aabb, Aabb) {
+    let mut below = Aabb::empty();
+    let mut above = Aabb::empty();
+    for i in 0..3 {
+        let v0 = vertices[i];
+        let v1 = vertices[(i + 1) % 3];
+        if v0[axis] <= position {
+            below.append_point(v0);
+        }
+        if v0[axis] >= position {
+            above.append_point(v0);
+        }
+        if (v0[axis] < position && position < v1[axis])
+            || (v1[axis] < position && position < v0[axis])
+        {
+            let mut p = v0 + (v1 - v0) * ((position - v0[axis]) / (v1[axis] - v0[axis]));
+            p[axis] = position;
+            below.append_point(p);
+            above.append_point(p);
+        }
+    }
+    (below.intersect(bbox), above.intersect(bbox))
+}
+
+/// (∂p/∂u, ∂p/∂v) of the plane of the triangle, parametrized by the texture coordinates at the vertices.
+/// Returns None if the texture coordinates are degenerate.
+pub fn uv_derivatives(vertices: &[Vec3; 3], tex_coords: &[Vec2; 3]) -> Option<(Vec3, Vec3)> {
+    let e1 = vertices[1] - vertices[0];
+    let e2 = vertices[2] - vertices[0];
+    let d1 = tex_coords[1] - tex_coords[0];
+    let d2 = tex_coords[2] - tex_coords[0];
+    let det = d1[0] * d2[1] - d1[1] * d2[0];
+    if det == 0.0 || !det.is_finite() {
+        return None;
+    }
+    Some((
+        (d2[1] * e1 - d1[1] * e2) / det,
+        (d1[0] * e2 - d2[0] * e1) / det,
+    ))
+}
+
+/// dpdu and dpdv of HitRecord at the point of the triangle with the barycentric coordinates weights.
+/// * With tangents - the interpolated tangent orthogonalized to normal, and the bitangent
+///   tangent.w * normal x tangent, both normalized (the convention of MikkTSpace).
+/// * With tex_coords only - uv_derivatives().
+/// * Otherwise - an arbitrary orthonormal frame around normal.
+pub fn shading_derivatives(
+    vertices: &[Vec3; 3],
+    tex_coords: Option<&[Vec2; 3]>,
+    tangents: Option<&[Vec4; 3]>,
+    weights: &[f32; 3],
+    normal: &Vec3,
+) -> (Vec3, Vec3) {
+    if let Some(tangents) = tangents {
+        let t = weights[0] * tangents[0] + weights[1] * tangents[1] + weights[2] * tangents[2];
+        let tangent = Vec3::new(t[0], t[1], t[2]);
+        let tangent = tangent - tangent.dot(normal) * normal;
+        if tangent.norm() > 0.0 {
+            let tangent = tangent.normalize();
+            let sign = if t[3] < 0.0 { -1.0 } else { 1.0 };
+            return (tangent, sign * normal.cross(&tangent));
+        }
+    }
+    if let Some(tex_coords) = tex_coords {
+        if let Some(derivatives) = uv_derivatives(vertices, tex_coords) {
+            return derivatives;
+        }
+    }
+    let onb = Onb::build_from_w(normal);
+    (*onb.u(), *onb.v())
+}
+
+/// intersect_watertight()でレイごとに決まるせん断変換。TrianglePackでも同じ値を使う。
+pub struct RayShear {
+    pub axes: [usize; 3], // kx, ky, kz
+    pub coeffs: [f32; 3], // sx, sy, sz
+}
+
+impl RayShear {
+    pub fn new(dir: &Vec3) -> Self {
+        // レイの方向の絶対値が最大の軸をzとし、レイがz軸に平行になるようにせん断する。
+        let kz = dir.iamax();
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if dir[kz] < 0.0 {
+            // 座標系の向き（三角形の表裏）を保つ
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        RayShear {
+            axes: [kx, ky, kz],
+            coeffs: [dir[kx] / dir[kz], dir[ky] / dir[kz], 1.0 / dir[kz]],
+        }
+    }
+}
+
+/// Watertight ray-triangle intersection by Woop, Benthin and Wald (JCGT 2013).
+/// Rays through a shared edge or vertex hit at least one of the triangles sharing it.
+/// * `return` - (t, barycentric coordinates w.r.t. vertices) if t_min < t < t_max
+pub fn intersect_watertight(
+    vertices: &[Vec3; 3],
+    ray: &Ray,
+    t_min: f32,
+    t_max: f32,
+    cull_backface: bool,
+) -> Option<(f32, [f32; 3])> {
+    let RayShear {
+        axes: [kx, ky, kz],
+        coeffs: [sx, sy, sz],
+    } = RayShear::new(&ray.direction);
+    let a = vertices[0] - ray.origin;
+    let b = vertices[1] - ray.origin;
+    let c = vertices[2] - ray.origin;
+    let (ax, ay) = (a[kx] - sx * a[kz], a[ky] - sy * a[kz]);
+    let (bx, by) = (b[kx] - sx * b[kz], b[ky] - sy * b[kz]);
+    let (cx, cy) = (c[kx] - sx * c[kz], c[ky] - sy * c[kz]);
+    // せん断後のxy平面での、原点から見た各辺の符号付き面積（= 重心座標 * det）
+    let mut u = cx * by - cy * bx;
+    let mut v = ax * cy - ay * cx;
+    let mut w = bx * ay - by * ax;
+    if u == 0.0 || v == 0.0 || w == 0.0 {
+        // 辺の上では、f64で計算し直して符号を確定させる
+        let f64_cross = |px: f32, py: f32, qx: f32, qy: f32| {
+            (px as f64 * qy as f64 - py as f64 * qx as f64) as f32
+        };
+        u = f64_cross(cx, cy, bx, by);
+        v = f64_cross(ax, ay, cx, cy);
+        w = f64_cross(bx, by, ax, ay);
+    }
+    if cull_backface {
+        if u < 0.0 || v < 0.0 || w < 0.0 {
+            return None;
+        }
+    } else if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+        return None;
+    }
+    let det = u + v + w;
+    if det == 0.0 {
+        return None;
+    }
+    let t = (u * sz * a[kz] + v * sz * b[kz] + w * sz * c[kz]) / det;
+    if !(t_min < t && t < t_max) {
+        return None;
+    }
+    Some((t, [u / det, v / det, w / det]))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::hitable::triangle::Triangle;
+    use crate::hitable::triangle_mesh::{MeshTriangle, TriangleMesh};
+    use crate::hitable::Hitable;
+    use crate::material::lambertian::Lambertian;
+    use crate::material::Material;
+    use crate::obj_file::ObjFile;
+    use crate::ray::Ray;
+    use crate::texture::constant::ConstantTexture;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
+    use std::fmt::Write;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    #[test]
+    fn watertight_on_edges_and_vertices() {
+        // 面ごとに頂点を重複させた、外向きの閉じた球面メッシュ。unify_vertexで頂点を共有させる。
+        let (n_theta, n_phi) = (7, 15);
+        let point = |i: usize, j: usize| {
+            let theta = std::f32::consts::PI * i as f32 / n_theta as f32;
+            let phi = 2.0 * std::f32::consts::PI * (j % n_phi) as f32 / n_phi as f32;
+            if i == 0 || i == n_theta {
+                Vec3::new(0.0, theta.cos(), 0.0)
+            } else {
+                Vec3::new(
+                    theta.sin() * phi.cos(),
+                    theta.cos(),
+                    theta.sin() * phi.sin(),
+                )
+            }
+        };
+        let mut faces = vec![];
+        for i in 0..n_theta {
+            for j in 0..n_phi {
+                let [a, b, c, d] = [
+                    point(i, j),
+                    point(i + 1, j),
+                    point(i + 1, j + 1),
+                    point(i, j + 1),
+                ];
+                // 極ではa = dまたはb = cになるので、縮退しない方だけを使う
+                if i == n_theta - 1 {
+                    faces.push([a, d, b]);
+                } else {
+                    faces.push([a, c, b]);
+                }
+                if i != 0 && i != n_theta - 1 {
+                    faces.push([a, d, c]);
+                }
+            }
+        }
+        let mut obj = String::new();
+        for face in &faces {
+            let normal = (face[1] - face[0]).cross(&(face[2] - face[0]));
+            assert!(normal.dot(&face[0]) > 0.0);
+            for v in face {
+                writeln!(obj, "v {} {} {}", v.x, v.y, v.z).unwrap();
+            }
+        }
+        for k in 0..faces.len() {
+            writeln!(obj, "f {} {} {}", 3 * k + 1, 3 * k + 2, 3 * k + 3).unwrap();
+        }
+        let mut group = ObjFile::from_buf_reader(Cursor::new(obj))
+            .unwrap()
+            .groups
+            .remove(0);
+        group.unify_vertex();
+        let material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            1.0, 1.0, 1.0,
+        ))));
+        let mut triangles = group.to_triangles(material.clone());
+        let mut mesh = group.to_triangle_mesh(material.clone());
+        let mesh_triangles = TriangleMesh::triangles(&Arc::new(group.to_triangle_mesh(material)));
+        assert_eq!(mesh.positions().len(), (n_theta - 1) * n_phi + 2);
+        // 頂点と辺の中点を狙うレイ
+        let mut targets = mesh.positions().to_vec();
+        for face in &faces {
+            for k in 0..3 {
+                targets.push(0.5 * (face[k] + face[(k + 1) % 3]));
+            }
+        }
+        let mut rng = XorShiftRng::seed_from_u64(42);
+        let origins: Vec<Vec3> = (0..3)
+            .map(|_| {
+                Vec3::new(
+                    rng.gen_range(-0.3, 0.3),
+                    rng.gen_range(-0.3, 0.3),
+                    rng.gen_range(-0.3, 0.3),
+                )
+            })
+            .collect();
+        let rays = |outside: bool| {
+            let mut rays = vec![];
+            for origin in &origins {
+                for target in &targets {
+                    let origin = if outside {
+                        3.0 * target - origin
+                    } else {
+                        *origin
+                    };
+                    rays.push(Ray::new(&origin, &(target - origin), 0.0));
+                }
+            }
+            rays
+        };
+        let hit_cnt = |triangles: &[Triangle], mesh_triangles: &[MeshTriangle], ray: &Ray| {
+            let tri_cnt = triangles
+                .iter()
+                .filter(|tri| tri.is_hit(ray, 0.0, 10.0))
+                .count();
+            let mesh_cnt = mesh_triangles
+                .iter()
+                .filter(|tri| tri.is_hit(ray, 0.0, 10.0))
+                .count();
+            (tri_cnt, mesh_cnt)
+        };
+        // 内側からのレイはどれも穴を抜けない
+        for ray in &rays(false) {
+            let (tri_cnt, mesh_cnt) = hit_cnt(&triangles, &mesh_triangles, ray);
+            assert!(tri_cnt >= 1 && mesh_cnt >= 1);
+        }
+        // 裏面カリングでは、内側からのレイはどれもヒットせず、外側からのレイは必ずヒットする
+        for tri in &mut triangles {
+            tri.set_backface_culling(true);
+        }
+        mesh.set_backface_culling(true);
+        let mesh_triangles = TriangleMesh::triangles(&Arc::new(mesh));
+        for ray in &rays(false) {
+            assert_eq!(hit_cnt(&triangles, &mesh_triangles, ray), (0, 0));
+        }
+        for ray in &rays(true) {
+            let (tri_cnt, mesh_cnt) = hit_cnt(&triangles, &mesh_triangles, ray);
+            assert!(tri_cnt >= 1 && mesh_cnt >= 1);
+        }
+    }
 }
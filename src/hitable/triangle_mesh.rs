@@ -0,0 +1,491 @@
+// 頂点バッファ（位置・法線・UV・接線）とインデックスバッファを共有する三角形メッシュ。
+// BVH/OBVHの葉にはメッシュへの参照と三角形の番号だけを持つMeshTriangleを使い、
+// 交差判定やHitRecordに必要な値はヒットのたびにバッファから計算する。
+// Triangleは1個あたり頂点・垂線・法線・マテリアルを個別に持つので、大きなメッシュではこちらの方が数倍小さい。
+
+use crate::aabb::Aabb;
+use crate::aliases::{Mat3, RandGen, Vec2, Vec3, Vec4};
+use crate::export::ExportMesh;
+use crate::hit_record::HitRecord;
+use crate::hitable::hitable_list::HitableList;
+use crate::hitable::triangle::{
+    intersect_watertight, shading_derivatives, split_triangle_bounding_box, uv_derivatives,
+    Triangle,
+};
+use crate::hitable::Hitable;
+use crate::material::Material;
+use crate::onb::Onb;
+use crate::ray::Ray;
+use rand::Rng;
+use std::sync::Arc;
+
+/// Vertex buffers and an index buffer shared by the triangles of a mesh.
+pub struct TriangleMesh {
+    positions: Vec<Vec3>,
+    normals: Option<Vec<Vec3>>, // 頂点ごとの法線。Someならhitは補間した法線を返す
+    tex_coords: Option<Vec<Vec2>>, // 頂点ごとのUV
+    tangents: Option<Vec<Vec4>>, // 頂点ごとの接線。wは従接線の向き（+1 or -1）
+    indices: Vec<[u32; 3]>,     // 三角形ごとの頂点番号
+    material: Arc<Material>,
+    cull_backface: bool, // trueなら、裏側から来たレイはヒットしない
+}
+
+impl TriangleMesh {
+    /// Every vertex buffer given must have the same length as positions.
+    pub fn new(
+        positions: Vec<Vec3>,
+        normals: Option<Vec<Vec3>>,
+        tex_coords: Option<Vec<Vec2>>,
+        tangents: Option<Vec<Vec4>>,
+        indices: Vec<[u32; 3]>,
+        material: Arc<Material>,
+    ) -> Self {
+        let vertex_cnt = positions.len();
+        assert!(normals.as_ref().is_none_or(|n| n.len() == vertex_cnt));
+        assert!(tex_coords.as_ref().is_none_or(|t| t.len() == vertex_cnt));
+        assert!(tangents.as_ref().is_none_or(|t| t.len() == vertex_cnt));
+        assert!(indices.iter().flatten().all(|&i| (i as usize) < vertex_cnt));
+        TriangleMesh {
+            positions: positions,
+            normals: normals,
+            tex_coords: tex_coords,
+            tangents: tangents,
+            indices: indices,
+            material: material,
+            cull_backface: false,
+        }
+    }
+    /// Same as Triangle::set_backface_culling(), for all the triangles of the mesh.
+    pub fn set_backface_culling(&mut self, cull: bool) {
+        self.cull_backface = cull;
+    }
+    /// Calculates the tangents at vertices in the same way as MikkTSpace, so that normal maps baked by
+    /// other tools apply correctly: the tangent and bitangent of each triangle given by the texture coordinates
+    /// are projected to the tangent plane of the vertex normal, and summed up with the weights of corner angles.
+    /// Does nothing if the mesh has no normals or no texture coordinates.
+    pub fn generate_tangents(&mut self) {
+        let (normals, tex_coords) = match (&self.normals, &self.tex_coords) {
+            (Some(normals), Some(tex_coords)) => (normals, tex_coords),
+            _ => return,
+        };
+        let mut tangents = vec![Vec3::zeros(); self.positions.len()];
+        let mut bitangents = vec![Vec3::zeros(); self.positions.len()];
+        for idx in &self.indices {
+            let vertices = idx.map(|i| self.positions[i as usize]);
+            let uv = idx.map(|i| tex_coords[i as usize]);
+            let (dpdu, dpdv) = match uv_derivatives(&vertices, &uv) {
+                Some(derivatives) => derivatives,
+                None => continue,
+            };
+            for k in 0..3 {
+                let i = idx[k] as usize;
+                let e0 = vertices[(k + 1) % 3] - vertices[k];
+                let e1 = vertices[(k + 2) % 3] - vertices[k];
+                let angle = (e0.dot(&e1) / (e0.norm() * e1.norm()))
+                    .clamp(-1.0, 1.0)
+                    .acos();
+                if !angle.is_finite() {
+                    continue;
+                }
+                let n = normals[i];
+                let t = dpdu - dpdu.dot(&n) * n;
+                let b = dpdv - dpdv.dot(&n) * n;
+                if t.norm() > 0.0 {
+                    tangents[i] += angle * t.normalize();
+                }
+                if b.norm() > 0.0 {
+                    bitangents[i] += angle * b.normalize();
+                }
+            }
+        }
+        let tangents = normals
+            .iter()
+            .zip(tangents.iter().zip(&bitangents))
+            .map(|(n, (t, b))| {
+                let t = t - t.dot(n) * n;
+                let t = if t.norm() > 0.0 {
+                    t.normalize()
+                } else {
+                    *Onb::build_from_w(n).u()
+                };
+                // 従接線 = w * n x t
+                let w = if n.cross(&t).dot(b) < 0.0 { -1.0 } else { 1.0 };
+                Vec4::new(t[0], t[1], t[2], w)
+            })
+            .collect();
+        self.tangents = Some(tangents);
+    }
+    /// Unindexed copies of the triangles, which have the same attributes.
+    pub fn to_triangles(&self) -> Vec<Triangle> {
+        self.indices
+            .iter()
+            .map(|idx| {
+                let mut tri = Triangle::new(
+                    &idx.map(|i| self.positions[i as usize]),
+                    &self.normals.as_ref().map(|n| idx.map(|i| n[i as usize])),
+                    self.material.clone(),
+                );
+                tri.set_tex_coords(self.tex_coords.as_ref().map(|t| idx.map(|i| t[i as usize])));
+                tri.set_vertex_tangents(self.tangents.as_ref().map(|t| idx.map(|i| t[i as usize])));
+                tri.set_backface_culling(self.cull_backface);
+                tri
+            })
+            .collect()
+    }
+    /// References to all the triangles, to be used as leaves of BVH/OBVH.
+    pub fn triangles(mesh: &Arc<TriangleMesh>) -> Vec<MeshTriangle> {
+        (0..mesh.indices.len() as u32)
+            .map(|index| MeshTriangle {
+                mesh: mesh.clone(),
+                index: index,
+            })
+            .collect()
+    }
+    /// The triangles as a HitableList for Scene::light, which can sample directions to an emissive mesh
+    /// (unlike BVH/OBVH). Intersecting it tests all the triangles.
+    pub fn light(mesh: &Arc<TriangleMesh>) -> HitableList {
+        HitableList::new(
+            Self::triangles(mesh)
+                .into_iter()
+                .map(|tri| -> Arc<Hitable> { Arc::new(tri) })
+                .collect(),
+        )
+    }
+    /// The mesh transformed by x -> a x + b, keeping the orientation of the triangles,
+    /// or None if a is singular. The tangents are calculated again.
+    pub fn transformed(&self, a: &Mat3, b: &Vec3) -> Option<TriangleMesh> {
+        let normal_tr = a.try_inverse()?.transpose();
+        let mut mesh = TriangleMesh::new(
+            self.positions.iter().map(|p| a * p + b).collect(),
+            self.normals
+                .as_ref()
+                .map(|n| n.iter().map(|n| (normal_tr * n).normalize()).collect()),
+            self.tex_coords.clone(),
+            None,
+            // 鏡映なら、三角形の向きを保つために頂点の順序を入れ替える
+            if a.determinant() < 0.0 {
+                self.indices.iter().map(|i| [i[0], i[2], i[1]]).collect()
+            } else {
+                self.indices.clone()
+            },
+            self.material.clone(),
+        );
+        mesh.cull_backface = self.cull_backface;
+        mesh.generate_tangents();
+        Some(mesh)
+    }
+    pub fn triangle_cnt(&self) -> usize {
+        self.indices.len()
+    }
+    pub fn positions(&self) -> &[Vec3] {
+        &self.positions
+    }
+    pub fn normals(&self) -> Option<&[Vec3]> {
+        self.normals.as_deref()
+    }
+    pub fn tex_coords(&self) -> Option<&[Vec2]> {
+        self.tex_coords.as_deref()
+    }
+    pub fn tangents(&self) -> Option<&[Vec4]> {
+        self.tangents.as_deref()
+    }
+    pub fn material(&self) -> &Arc<Material> {
+        &self.material
+    }
+    pub fn indices(&self) -> &[[u32; 3]] {
+        &self.indices
+    }
+    /// Bytes used by the buffers.
+    pub fn buffer_bytes(&self) -> usize {
+        use std::mem::size_of;
+        self.positions.len() * size_of::<Vec3>()
+            + self
+                .normals
+                .as_ref()
+                .map_or(0, |n| n.len() * size_of::<Vec3>())
+            + self
+                .tex_coords
+                .as_ref()
+                .map_or(0, |t| t.len() * size_of::<Vec2>())
+            + self
+                .tangents
+                .as_ref()
+                .map_or(0, |t| t.len() * size_of::<Vec4>())
+            + self.indices.len() * size_of::<[u32; 3]>()
+    }
+    fn vertices(&self, index: u32) -> [Vec3; 3] {
+        let [a, b, c] = self.indices[index as usize];
+        [
+            self.positions[a as usize],
+            self.positions[b as usize],
+            self.positions[c as usize],
+        ]
+    }
+    /// 三角形indexの、重心座標がweightsである点pointでのHitRecord。
+    fn hit_record(&self, index: u32, t: f32, point: Vec3, weights: [f32; 3]) -> HitRecord<'_> {
+        let idx = self.indices[index as usize];
+        let [a, b, c] = self.vertices(index);
+        let geometric_normal = (b - a).cross(&(c - a)).normalize();
+        let normal = match self.normals {
+            Some(ref normals) => (weights[0] * normals[idx[0] as usize]
+                + weights[1] * normals[idx[1] as usize]
+                + weights[2] * normals[idx[2] as usize])
+                .normalize(),
+            None => geometric_normal,
+        };
+        let uv = self.tex_coords.as_ref().map(|t| idx.map(|i| t[i as usize]));
+        let tangents = self.tangents.as_ref().map(|t| idx.map(|i| t[i as usize]));
+        let tex_coord = match uv {
+            Some(ref uv) => weights[0] * uv[0] + weights[1] * uv[1] + weights[2] * uv[2],
+            None => Vec2::new(0.0, 0.0),
+        };
+        let (dpdu, dpdv) = shading_derivatives(
+            &[a, b, c],
+            uv.as_ref(),
+            tangents.as_ref(),
+            &weights,
+            &normal,
+        );
+        HitRecord {
+            t: t,
+            point: point,
+            tex_coord: tex_coord,
+            normal: normal,
+            geometric_normal: geometric_normal,
+            barycentric: Some(Vec3::new(weights[0], weights[1], weights[2])),
+            dpdu: dpdu,
+            dpdv: dpdv,
+            material: self.material.as_ref(),
+        }
+    }
+}
+
+/// A triangle of a TriangleMesh.
+#[derive(Clone)]
+pub struct MeshTriangle {
+    mesh: Arc<TriangleMesh>,
+    index: u32,
+}
+
+impl MeshTriangle {
+    pub fn mesh(&self) -> &TriangleMesh {
+        &self.mesh
+    }
+    pub fn index(&self) -> usize {
+        self.index as usize
+    }
+    pub fn vertices(&self) -> [Vec3; 3] {
+        self.mesh.vertices(self.index)
+    }
+}
+
+impl Hitable for MeshTriangle {
+    /// Watertight, same as Triangle.
+    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        let (t, weights) =
+            intersect_watertight(&self.vertices(), ray, t_min, t_max, self.mesh.cull_backface)?;
+        Some(
+            self.mesh
+                .hit_record(self.index, t, ray.evaluate(t), weights),
+        )
+    }
+    fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
+        Some(Aabb::from_points(self.vertices().to_vec()))
+    }
+    /// Clips the edges by the plane, so that the boxes are tight for long thin triangles.
+    fn split_bounding_box(&self, bbox: &Aabb, axis: usize, position: f32) -> (Aabb, Aabb) {
+        split_triangle_bounding_box(&self.vertices(), bbox, axis, position)
+    }
+    fn random_direction_from(&self, origin: &Vec3, rng: &mut RandGen) -> Vec3 {
+        self.random_point_on_surface(rng).point - origin
+    }
+    fn direction_density(&self, origin: &Vec3, dir: &Vec3) -> f32 {
+        match self.hit(&Ray::new(origin, dir, 0.0), 0.0, std::f32::MAX) {
+            Some(rec) => {
+                let dist_squared = (rec.point - origin).norm_squared();
+                let cosine = dir.normalize().dot(&rec.geometric_normal).abs();
+                dist_squared / (cosine * self.surface_area())
+            }
+            None => 0.0,
+        }
+    }
+    fn random_point_on_surface<'s>(&'s self, rng: &mut RandGen) -> HitRecord<'s> {
+        let r0 = rng.gen::<f32>().sqrt();
+        let r1 = rng.gen::<f32>();
+        let weights = [1.0 - r0, r0 * (1.0 - r1), r0 * r1];
+        let [a, b, c] = self.vertices();
+        let point = weights[0] * a + weights[1] * b + weights[2] * c;
+        self.mesh.hit_record(self.index, 0.0, point, weights)
+    }
+    fn surface_area(&self) -> f32 {
+        let [a, b, c] = self.vertices();
+        0.5 * (b - a).cross(&(c - a)).norm()
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        let vertices = self.vertices();
+        let index = self.mesh.indices[self.index as usize];
+        let face_normal = (vertices[1] - vertices[0]).cross(&(vertices[2] - vertices[0]));
+        let indices = [0, 1, 2].map(|k| {
+            let i = index[k] as usize;
+            let normal = self.mesh.normals.as_ref().map_or(face_normal, |n| n[i]);
+            let tex_coord = self
+                .mesh
+                .tex_coords
+                .as_ref()
+                .map_or(Vec2::new(0.0, 0.0), |t| t[i]);
+            mesh.add_vertex(&vertices[k], &normal, &tex_coord)
+        });
+        mesh.add_triangle(indices, self.mesh.material.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::{RandGen, Vec2, Vec3};
+    use crate::hit_record::HitRecord;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::obvh::OBVH;
+    use crate::hitable::test_util;
+    use crate::hitable::triangle::Triangle;
+    use crate::hitable::triangle_mesh::{MeshTriangle, TriangleMesh};
+    use crate::hitable::Hitable;
+    use crate::material::lambertian::Lambertian;
+    use crate::material::Material;
+    use crate::obj_file::ObjFile;
+    use crate::pdf::rnd_in_unit_sphere;
+    use crate::ray::Ray;
+    use crate::texture::constant::ConstantTexture;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
+    use std::f32::consts::PI;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    #[test]
+    fn mesh_agrees_with_triangles() {
+        // 四角形を含む、法線とUVつきの小さなメッシュ
+        let obj = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\nv 0.5 0.5 1\n\
+                   vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvt 0.5 0.5\n\
+                   vn 0 0 -1\nvn 0.6 0 0.8\nvn 0 0.6 0.8\n\
+                   f 4/4/1 3/3/1 2/2/1 1/1/1\n\
+                   f 1/1/2 2/2/2 5/5/2\nf 2/2/2 3/3/2 5/5/3\nf 3/3/3 4/4/3 5/5/3\nf 4/4/3 1/1/2 5/5/3\n";
+        let group = &ObjFile::from_buf_reader(Cursor::new(obj)).unwrap().groups[0];
+        let material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            1.0, 1.0, 1.0,
+        ))));
+        let mesh = Arc::new(group.to_triangle_mesh(material.clone()));
+        assert_eq!(mesh.triangle_cnt(), 6);
+        assert_eq!(mesh.positions().len(), 11); // (v, vt, vn)の異なる組の数
+        let triangles: Vec<Triangle> = TriangleMesh::triangles(&mesh)
+            .iter()
+            .map(|tri| {
+                let idx = mesh.indices()[tri.index()];
+                let normals = mesh.normals().unwrap();
+                let normals = [0, 1, 2].map(|k| normals[idx[k] as usize]);
+                Triangle::new(&tri.vertices(), &Some(normals), material.clone())
+            })
+            .collect();
+        let obvh = OBVH::from_bvh(BVH::new(TriangleMesh::triangles(&mesh), 0.0, 1.0));
+        let mut rng = XorShiftRng::seed_from_u64(3);
+        let mut hit_cnt = 0;
+        for _ in 0..1000 {
+            let origin = Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>()) * 6.0
+                - Vec3::new(2.5, 2.5, 2.5);
+            let target = Vec3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+            let ray = Ray::new(&origin, &(target - origin), 0.0);
+            let expected = triangles
+                .iter()
+                .filter_map(|tri| tri.hit(&ray, 0.0001, std::f32::MAX))
+                .fold(None, |res: Option<HitRecord>, rec| match res {
+                    Some(ref res) if rec.t >= res.t => Some(*res),
+                    _ => Some(rec),
+                });
+            let actual = obvh.hit(&ray, 0.0001, std::f32::MAX);
+            assert_eq!(expected.is_some(), actual.is_some());
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                hit_cnt += 1;
+                assert!((expected.t - actual.t).abs() < 1.0e-4);
+                assert!((expected.normal - actual.normal).norm() < 1.0e-3);
+                let bary = actual.barycentric.unwrap();
+                assert!((bary - expected.barycentric.unwrap()).norm() < 1.0e-3);
+                // UVはxy平面への射影と一致する
+                assert!(
+                    (actual.tex_coord - Vec2::new(actual.point.x, actual.point.y)).norm() < 1.0e-3
+                );
+            }
+        }
+        assert!(hit_cnt > 100);
+        // 葉は三角形ごとの頂点を持たない
+        assert!(std::mem::size_of::<MeshTriangle>() * 4 < std::mem::size_of::<Triangle>());
+    }
+    #[test]
+    fn tex_coords_and_tangents() {
+        // z=0の長方形。左半分はUVをx方向に反転している
+        let obj = "v 0 0 0\nv 2 0 0\nv 2 1 0\nv 0 1 0\nv -2 0 0\nv -2 1 0\n\
+                   vt 0 0\nvt 1 0\nvt 1 1\nvt 0 1\nvt 0 0\nvt 0 1\nvn 0 0 1\n\
+                   f 1/1/1 2/2/1 3/3/1 4/4/1\nf 5/2/1 1/5/1 4/6/1 6/3/1\n";
+        let group = &ObjFile::from_buf_reader(Cursor::new(obj)).unwrap().groups[0];
+        let material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            1.0, 1.0, 1.0,
+        ))));
+        let mesh = group.to_triangle_mesh(material.clone());
+        // 接線は+uの向き、反転した側では従接線の向きが逆になる
+        let tangents = mesh.tangents().unwrap();
+        for t in tangents {
+            assert!((t[0].abs() - 1.0).abs() < 1.0e-5);
+            assert_eq!(t[3], t[0].signum());
+        }
+        assert_eq!(tangents.iter().filter(|t| t[0] < 0.0).count(), 4);
+        let ray = |x: f32| Ray::new(&Vec3::new(x, 0.25, 1.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let triangles = group.to_triangles(material.clone());
+        for &x in &[1.5, -1.5] {
+            let rec = triangles
+                .iter()
+                .find_map(|tri| tri.hit(&ray(x), 0.0, 10.0))
+                .unwrap();
+            assert!((rec.tex_coord - Vec2::new(0.75, 0.25)).norm() < 1.0e-5);
+            assert!((rec.dpdu - Vec3::new(x.signum(), 0.0, 0.0)).norm() < 1.0e-5);
+            assert!((rec.dpdv - Vec3::new(0.0, 1.0, 0.0)).norm() < 1.0e-5);
+        }
+        // 法線がなければ接線は作らず、dpdu, dpdvはUVに対する微分そのもの
+        let obj = "v 0 0 0\nv 2 0 0\nv 2 1 0\nvt 0 0\nvt 1 0\nvt 1 1\nf 1/1 2/2 3/3\n";
+        let group = &ObjFile::from_buf_reader(Cursor::new(obj)).unwrap().groups[0];
+        let mesh = Arc::new(group.to_triangle_mesh(material));
+        assert!(mesh.tangents().is_none());
+        let triangles = TriangleMesh::triangles(&mesh);
+        let rec = triangles[0].hit(&ray(1.5), 0.0, 10.0).unwrap();
+        assert!((rec.dpdu - Vec3::new(2.0, 0.0, 0.0)).norm() < 1.0e-5);
+        assert!((rec.dpdv - Vec3::new(0.0, 1.0, 0.0)).norm() < 1.0e-5);
+    }
+    #[test]
+    fn light_direction_density() {
+        // z = 1上の正方形[-1, 1]^2を光源にし、球面上の一様な方向で密度を積分すると1になる
+        let mesh = Arc::new(TriangleMesh::new(
+            vec![
+                Vec3::new(-1.0, -1.0, 1.0),
+                Vec3::new(1.0, -1.0, 1.0),
+                Vec3::new(1.0, 1.0, 1.0),
+                Vec3::new(-1.0, 1.0, 1.0),
+            ],
+            None,
+            None,
+            None,
+            vec![[0, 1, 2], [0, 2, 3]],
+            test_util::white(),
+        ));
+        let light = TriangleMesh::light(&mesh);
+        let origin = Vec3::zeros();
+        let mut rng = RandGen::new();
+        const SAMPLE_CNT: usize = 100000;
+        let mut integral = 0.0;
+        for _ in 0..SAMPLE_CNT {
+            let dir = rnd_in_unit_sphere(&mut rng).normalize();
+            integral += light.direction_density(&origin, &dir);
+        }
+        integral *= 4.0 * PI / SAMPLE_CNT as f32;
+        assert!((integral - 1.0).abs() < 0.05, "{}", integral);
+        for _ in 0..100 {
+            let dir = light.random_direction_from(&origin, &mut rng);
+            assert!(0.0 < light.direction_density(&origin, &dir));
+        }
+    }
+}
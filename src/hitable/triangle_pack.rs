@@ -0,0 +1,563 @@
+// 最大8個の三角形をSoAで持ち、SSE/AVXでまとめて交差判定するBVH/OBVHの葉。
+// SIMDでintersect_watertightと同じ順序で同じ演算をするので、t・重心座標はTriangle::hitと一致する。
+// せん断後の符号付き面積が0（辺の上）になったレーンだけ、intersect_watertightでf64を使って計算し直す。
+// 三角形自体はパックの間で共有する1つのVecに持ち、HitRecordの作成（法線やUVの補間）に使う。
+
+use crate::aabb::Aabb;
+use crate::export::ExportMesh;
+use crate::hit_record::HitRecord;
+use crate::hitable::bvh::BVH;
+use crate::hitable::node_pointer::NodePointer;
+use crate::hitable::obvh::TraversalBackend;
+use crate::hitable::triangle::{intersect_watertight, RayShear, Triangle};
+use crate::hitable::{Hitable, TraversalStats};
+use crate::ray::Ray;
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+use std::ops::Range;
+use std::sync::Arc;
+
+/// The maximum number of triangles in a TrianglePack.
+pub const TRIANGLE_PACK_WIDTH: usize = 8;
+
+// [vertex_id][axis][lane]のSoA。AVXでは各[f32; 8]を__m256として、SSEでは前半と後半をそれぞれ__m128としてロードする。
+// 使わないレーンは頂点が全て0なので、辺の上と判定されてからレーンのマスクで除かれる。
+#[derive(Clone, Copy)]
+#[repr(C, align(32))]
+struct PackedTriangles {
+    vertices: [[[f32; TRIANGLE_PACK_WIDTH]; 3]; 3],
+    cull_backface: [u32; TRIANGLE_PACK_WIDTH], // 背面カリングするレーンは全ビットが1
+}
+
+// SIMDで求めた各レーンの交点
+struct LaneHits {
+    hits: i32,    // 第laneビット = t_min < t < t_maxでヒットしたか
+    on_edge: i32, // 第laneビット = 辺の上にあり、intersect_watertightで計算し直す必要があるか
+    t: [f32; TRIANGLE_PACK_WIDTH],
+    weights: [[f32; TRIANGLE_PACK_WIDTH]; 3], // [vertex_id][lane]。重心座標
+}
+
+/// Up to TRIANGLE_PACK_WIDTH triangles intersected together by SIMD.
+pub struct TrianglePack {
+    packed: PackedTriangles,
+    triangles: Arc<Vec<Triangle>>, // 全パックで共有する
+    range: Range<usize>,           // このパックの三角形のtrianglesでの範囲
+    backend: TraversalBackend,
+    bbox: Aabb,
+}
+
+impl TrianglePack {
+    /// * `range` - the triangles of this pack in `triangles`, at most TRIANGLE_PACK_WIDTH
+    pub fn new(triangles: Arc<Vec<Triangle>>, range: Range<usize>) -> Self {
+        assert!(!range.is_empty() && range.len() <= TRIANGLE_PACK_WIDTH);
+        let mut packed = PackedTriangles {
+            vertices: [[[0.0; TRIANGLE_PACK_WIDTH]; 3]; 3],
+            cull_backface: [0; TRIANGLE_PACK_WIDTH],
+        };
+        let mut bbox = Aabb::empty();
+        for (lane, triangle) in triangles[range.clone()].iter().enumerate() {
+            for v in 0..3 {
+                for a in 0..3 {
+                    packed.vertices[v][a][lane] = triangle.vertices()[v][a];
+                }
+            }
+            if triangle.cull_backface() {
+                packed.cull_backface[lane] = !0;
+            }
+            bbox = Aabb::unite(&bbox, &triangle.bounding_box(0.0, 0.0).unwrap());
+        }
+        TrianglePack {
+            packed: packed,
+            triangles: triangles,
+            range: range,
+            backend: TraversalBackend::detect(),
+            bbox: bbox,
+        }
+    }
+    /// Groups triangles into packs of at most pack_size (4 for SSE, 8 for AVX) triangles.
+    /// Each pack is a subtree of a BVH built by BVH::new_binned.
+    pub fn pack(
+        triangles: Vec<Triangle>,
+        pack_size: usize,
+        time_0: f32,
+        time_1: f32,
+        thread_cnt: usize,
+    ) -> Vec<TrianglePack> {
+        Self::from_bvh(
+            BVH::new_binned(triangles, time_0, time_1, thread_cnt),
+            pack_size,
+        )
+    }
+    /// Same as pack(), but groups the subtrees of a built (e.g. cached) BVH.
+    pub fn from_bvh(bvh: BVH<Triangle>, pack_size: usize) -> Vec<TrianglePack> {
+        assert!((1..=TRIANGLE_PACK_WIDTH).contains(&pack_size));
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        if bvh.inners.is_empty() {
+            groups.extend((0..bvh.leaves.len()).map(|idx| vec![idx]));
+        } else {
+            let rest = collect_subtrees(&bvh, NodePointer::root(), pack_size, &mut groups);
+            if !rest.is_empty() {
+                groups.push(rest);
+            }
+        }
+        // 各パックの三角形が連続するように並べ替える
+        let mut leaves: Vec<Option<Triangle>> = bvh.leaves.into_iter().map(Some).collect();
+        let mut triangles = Vec::with_capacity(leaves.len());
+        let mut ranges = Vec::with_capacity(groups.len());
+        for group in &groups {
+            let start = triangles.len();
+            triangles.extend(group.iter().map(|&idx| leaves[idx].take().unwrap()));
+            ranges.push(start..triangles.len());
+        }
+        let triangles = Arc::new(triangles);
+        ranges
+            .into_iter()
+            .map(|range| TrianglePack::new(triangles.clone(), range))
+            .collect()
+    }
+    pub fn triangles(&self) -> &[Triangle] {
+        &self.triangles[self.range.clone()]
+    }
+    pub fn backend(&self) -> TraversalBackend {
+        self.backend
+    }
+    /// Panics if the backend is not supported by the running CPU.
+    pub fn set_backend(&mut self, backend: TraversalBackend) {
+        assert!(
+            backend.is_available(),
+            "{:?} is not supported by this CPU",
+            backend
+        );
+        self.backend = backend;
+    }
+    /// 最も近い交点。
+    /// * `return` - (lane, t, 重心座標)
+    fn closest_hit(&self, ray: &Ray, t_min: f32, mut t_max: f32) -> Option<(usize, f32, [f32; 3])> {
+        let lanes = self.lane_hits(ray, t_min, t_max);
+        let mut res = None;
+        for (lane, triangle) in self.triangles().iter().enumerate() {
+            let hit = if lanes.on_edge & (1 << lane) != 0 {
+                intersect_watertight(
+                    triangle.vertices(),
+                    ray,
+                    t_min,
+                    t_max,
+                    triangle.cull_backface(),
+                )
+            } else if lanes.hits & (1 << lane) != 0 && lanes.t[lane] < t_max {
+                let weights = &lanes.weights;
+                Some((
+                    lanes.t[lane],
+                    [weights[0][lane], weights[1][lane], weights[2][lane]],
+                ))
+            } else {
+                None
+            };
+            if let Some((t, weights)) = hit {
+                t_max = t;
+                res = Some((lane, t, weights));
+            }
+        }
+        res
+    }
+    fn lane_hits(&self, ray: &Ray, t_min: f32, t_max: f32) -> LaneHits {
+        let mut lanes = LaneHits {
+            hits: 0,
+            on_edge: 0,
+            t: [0.0; TRIANGLE_PACK_WIDTH],
+            weights: [[0.0; TRIANGLE_PACK_WIDTH]; 3],
+        };
+        match self.backend {
+            // set_backend（またはdetect）で利用可能であることを確認済み。
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TraversalBackend::Avx => unsafe { self.lane_hits_avx(ray, t_min, t_max, &mut lanes) },
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            TraversalBackend::Sse => unsafe { self.lane_hits_sse(ray, t_min, t_max, &mut lanes) },
+            _ => self.lane_hits_scalar(ray, t_min, t_max, &mut lanes),
+        }
+        let mask = (1 << self.range.len()) - 1;
+        lanes.hits &= mask & !lanes.on_edge;
+        lanes.on_edge &= mask;
+        lanes
+    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx")]
+    unsafe fn lane_hits_avx(&self, ray: &Ray, t_min: f32, t_max: f32, lanes: &mut LaneHits) {
+        let p = &self.packed;
+        let RayShear {
+            axes: [kx, ky, kz],
+            coeffs,
+        } = RayShear::new(&ray.direction);
+        let (sx, sy, sz) = (
+            _mm256_set1_ps(coeffs[0]),
+            _mm256_set1_ps(coeffs[1]),
+            _mm256_set1_ps(coeffs[2]),
+        );
+        // 各頂点のせん断後のx, yと、せん断前のz（intersect_watertightのa[kz]など）
+        let (ox, oy, oz) = (
+            _mm256_set1_ps(ray.origin[kx]),
+            _mm256_set1_ps(ray.origin[ky]),
+            _mm256_set1_ps(ray.origin[kz]),
+        );
+        let mut xs = [_mm256_setzero_ps(); 3];
+        let mut ys = [_mm256_setzero_ps(); 3];
+        let mut zs = [_mm256_setzero_ps(); 3];
+        for v in 0..3 {
+            zs[v] = _mm256_sub_ps(_mm256_load_ps(p.vertices[v][kz].as_ptr()), oz);
+            let x = _mm256_sub_ps(_mm256_load_ps(p.vertices[v][kx].as_ptr()), ox);
+            let y = _mm256_sub_ps(_mm256_load_ps(p.vertices[v][ky].as_ptr()), oy);
+            xs[v] = _mm256_sub_ps(x, _mm256_mul_ps(sx, zs[v]));
+            ys[v] = _mm256_sub_ps(y, _mm256_mul_ps(sy, zs[v]));
+        }
+        let u = _mm256_sub_ps(_mm256_mul_ps(xs[2], ys[1]), _mm256_mul_ps(ys[2], xs[1]));
+        let v = _mm256_sub_ps(_mm256_mul_ps(xs[0], ys[2]), _mm256_mul_ps(ys[0], xs[2]));
+        let w = _mm256_sub_ps(_mm256_mul_ps(xs[1], ys[0]), _mm256_mul_ps(ys[1], xs[0]));
+        let zero = _mm256_setzero_ps();
+        let on_edge = _mm256_or_ps(
+            _mm256_or_ps(
+                _mm256_cmp_ps(u, zero, _CMP_EQ_OQ),
+                _mm256_cmp_ps(v, zero, _CMP_EQ_OQ),
+            ),
+            _mm256_cmp_ps(w, zero, _CMP_EQ_OQ),
+        );
+        let negative = _mm256_or_ps(
+            _mm256_or_ps(
+                _mm256_cmp_ps(u, zero, _CMP_LT_OQ),
+                _mm256_cmp_ps(v, zero, _CMP_LT_OQ),
+            ),
+            _mm256_cmp_ps(w, zero, _CMP_LT_OQ),
+        );
+        let positive = _mm256_or_ps(
+            _mm256_or_ps(
+                _mm256_cmp_ps(u, zero, _CMP_GT_OQ),
+                _mm256_cmp_ps(v, zero, _CMP_GT_OQ),
+            ),
+            _mm256_cmp_ps(w, zero, _CMP_GT_OQ),
+        );
+        let cull_backface = _mm256_load_ps(p.cull_backface.as_ptr() as *const f32);
+        let rejected = _mm256_and_ps(negative, _mm256_or_ps(cull_backface, positive));
+        let det = _mm256_add_ps(_mm256_add_ps(u, v), w);
+        let t = _mm256_div_ps(
+            _mm256_add_ps(
+                _mm256_add_ps(
+                    _mm256_mul_ps(_mm256_mul_ps(u, sz), zs[0]),
+                    _mm256_mul_ps(_mm256_mul_ps(v, sz), zs[1]),
+                ),
+                _mm256_mul_ps(_mm256_mul_ps(w, sz), zs[2]),
+            ),
+            det,
+        );
+        // det == 0やtがNaNのときは偽になる
+        let accepted = _mm256_and_ps(
+            _mm256_cmp_ps(det, zero, _CMP_NEQ_OQ),
+            _mm256_and_ps(
+                _mm256_cmp_ps(_mm256_set1_ps(t_min), t, _CMP_LT_OQ),
+                _mm256_cmp_ps(t, _mm256_set1_ps(t_max), _CMP_LT_OQ),
+            ),
+        );
+        lanes.hits = _mm256_movemask_ps(_mm256_andnot_ps(rejected, accepted));
+        lanes.on_edge = _mm256_movemask_ps(on_edge);
+        _mm256_storeu_ps(lanes.t.as_mut_ptr(), t);
+        for (weights, numer) in lanes.weights.iter_mut().zip(&[u, v, w]) {
+            _mm256_storeu_ps(weights.as_mut_ptr(), _mm256_div_ps(*numer, det));
+        }
+    }
+    /// レーン0..4と4..8の2回に分けて4個ずつ判定する。4個以下なら後半は飛ばす。
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "sse")]
+    unsafe fn lane_hits_sse(&self, ray: &Ray, t_min: f32, t_max: f32, lanes: &mut LaneHits) {
+        let p = &self.packed;
+        let RayShear {
+            axes: [kx, ky, kz],
+            coeffs,
+        } = RayShear::new(&ray.direction);
+        let (sx, sy, sz) = (
+            _mm_set1_ps(coeffs[0]),
+            _mm_set1_ps(coeffs[1]),
+            _mm_set1_ps(coeffs[2]),
+        );
+        let (ox, oy, oz) = (
+            _mm_set1_ps(ray.origin[kx]),
+            _mm_set1_ps(ray.origin[ky]),
+            _mm_set1_ps(ray.origin[kz]),
+        );
+        for half in 0..self.range.len().div_ceil(4) {
+            let lane = half * 4;
+            let mut xs = [_mm_setzero_ps(); 3];
+            let mut ys = [_mm_setzero_ps(); 3];
+            let mut zs = [_mm_setzero_ps(); 3];
+            for v in 0..3 {
+                zs[v] = _mm_sub_ps(_mm_load_ps(p.vertices[v][kz][lane..].as_ptr()), oz);
+                let x = _mm_sub_ps(_mm_load_ps(p.vertices[v][kx][lane..].as_ptr()), ox);
+                let y = _mm_sub_ps(_mm_load_ps(p.vertices[v][ky][lane..].as_ptr()), oy);
+                xs[v] = _mm_sub_ps(x, _mm_mul_ps(sx, zs[v]));
+                ys[v] = _mm_sub_ps(y, _mm_mul_ps(sy, zs[v]));
+            }
+            let u = _mm_sub_ps(_mm_mul_ps(xs[2], ys[1]), _mm_mul_ps(ys[2], xs[1]));
+            let v = _mm_sub_ps(_mm_mul_ps(xs[0], ys[2]), _mm_mul_ps(ys[0], xs[2]));
+            let w = _mm_sub_ps(_mm_mul_ps(xs[1], ys[0]), _mm_mul_ps(ys[1], xs[0]));
+            let zero = _mm_setzero_ps();
+            let on_edge = _mm_or_ps(
+                _mm_or_ps(_mm_cmpeq_ps(u, zero), _mm_cmpeq_ps(v, zero)),
+                _mm_cmpeq_ps(w, zero),
+            );
+            let negative = _mm_or_ps(
+                _mm_or_ps(_mm_cmplt_ps(u, zero), _mm_cmplt_ps(v, zero)),
+                _mm_cmplt_ps(w, zero),
+            );
+            let positive = _mm_or_ps(
+                _mm_or_ps(_mm_cmpgt_ps(u, zero), _mm_cmpgt_ps(v, zero)),
+                _mm_cmpgt_ps(w, zero),
+            );
+            let cull_backface = _mm_load_ps(p.cull_backface[lane..].as_ptr() as *const f32);
+            let rejected = _mm_and_ps(negative, _mm_or_ps(cull_backface, positive));
+            let det = _mm_add_ps(_mm_add_ps(u, v), w);
+            let t = _mm_div_ps(
+                _mm_add_ps(
+                    _mm_add_ps(
+                        _mm_mul_ps(_mm_mul_ps(u, sz), zs[0]),
+                        _mm_mul_ps(_mm_mul_ps(v, sz), zs[1]),
+                    ),
+                    _mm_mul_ps(_mm_mul_ps(w, sz), zs[2]),
+                ),
+                det,
+            );
+            // _mm_cmpneq_psはNaNで真になるが、そのときtもNaNなので範囲の判定で偽になる
+            let accepted = _mm_and_ps(
+                _mm_cmpneq_ps(det, zero),
+                _mm_and_ps(
+                    _mm_cmplt_ps(_mm_set1_ps(t_min), t),
+                    _mm_cmplt_ps(t, _mm_set1_ps(t_max)),
+                ),
+            );
+            lanes.hits |= _mm_movemask_ps(_mm_andnot_ps(rejected, accepted)) << lane;
+            lanes.on_edge |= _mm_movemask_ps(on_edge) << lane;
+            _mm_storeu_ps(lanes.t[lane..].as_mut_ptr(), t);
+            for (weights, numer) in lanes.weights.iter_mut().zip(&[u, v, w]) {
+                _mm_storeu_ps(weights[lane..].as_mut_ptr(), _mm_div_ps(*numer, det));
+            }
+        }
+    }
+    fn lane_hits_scalar(&self, ray: &Ray, t_min: f32, t_max: f32, lanes: &mut LaneHits) {
+        for (lane, triangle) in self.triangles().iter().enumerate() {
+            if let Some((t, weights)) = intersect_watertight(
+                triangle.vertices(),
+                ray,
+                t_min,
+                t_max,
+                triangle.cull_backface(),
+            ) {
+                lanes.hits |= 1 << lane;
+                lanes.t[lane] = t;
+                for v in 0..3 {
+                    lanes.weights[v][lane] = weights[v];
+                }
+            }
+        }
+    }
+}
+
+/// node以下の葉の添字を、pack_size個以下の部分木ごとにgroupsに追加する。
+/// * `return` - node以下の部分木全体がpack_size個以下のとき、その葉（まだgroupsに追加していない）
+fn collect_subtrees(
+    bvh: &BVH<Triangle>,
+    node_ptr: NodePointer,
+    pack_size: usize,
+    groups: &mut Vec<Vec<usize>>,
+) -> Vec<usize> {
+    if node_ptr.is_empty_leaf() {
+        return vec![];
+    }
+    if node_ptr.is_leaf() {
+        return vec![node_ptr.index()];
+    }
+    let node = &bvh.inners[node_ptr.index()];
+    let mut left = collect_subtrees(bvh, node.children[0].0, pack_size, groups);
+    let right = collect_subtrees(bvh, node.children[1].0, pack_size, groups);
+    if left.len() + right.len() <= pack_size {
+        left.extend(right);
+        return left;
+    }
+    for group in [left, right] {
+        if !group.is_empty() {
+            groups.push(group);
+        }
+    }
+    vec![]
+}
+
+impl Hitable for TrianglePack {
+    fn hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> Option<HitRecord<'s>> {
+        let (lane, t, weights) = self.closest_hit(ray, t_min, t_max)?;
+        Some(self.triangles()[lane].hit_record(ray, t, &weights))
+    }
+    // 1つのTriangleと比べられるよう、判定したレーンの数だけ数える
+    fn hit_with_stats<'s, 'r>(
+        &'s self,
+        ray: &'r Ray,
+        t_min: f32,
+        t_max: f32,
+        stats: &mut TraversalStats,
+    ) -> Option<HitRecord<'s>> {
+        stats.primitive_tests += self.triangles().len() as u32;
+        self.hit(ray, t_min, t_max)
+    }
+    fn is_hit<'s, 'r>(&'s self, ray: &'r Ray, t_min: f32, t_max: f32) -> bool {
+        let lanes = self.lane_hits(ray, t_min, t_max);
+        lanes.hits != 0
+            || self.triangles().iter().enumerate().any(|(lane, triangle)| {
+                lanes.on_edge & (1 << lane) != 0
+                    && intersect_watertight(
+                        triangle.vertices(),
+                        ray,
+                        t_min,
+                        t_max,
+                        triangle.cull_backface(),
+                    )
+                    .is_some()
+            })
+    }
+    fn bounding_box(&self, _time_0: f32, _time_1: f32) -> Option<Aabb> {
+        Some(self.bbox)
+    }
+    fn surface_area(&self) -> f32 {
+        self.triangles().iter().map(|t| t.surface_area()).sum()
+    }
+    fn tessellate(&self, mesh: &mut ExportMesh) {
+        for triangle in self.triangles() {
+            triangle.tessellate(mesh);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::obvh::{TraversalBackend, OBVH};
+    use crate::hitable::test_util::{self, random_vec};
+    use crate::hitable::triangle::Triangle;
+    use crate::hitable::triangle_pack::TrianglePack;
+    use crate::hitable::{Hitable, TraversalStats};
+    use crate::ray::Ray;
+    use itertools::iproduct;
+    use rand::prng::XorShiftRng;
+    use rand::{Rng, SeedableRng};
+    #[test]
+    fn packs_agree_with_single_triangles() {
+        let mut rng = XorShiftRng::seed_from_u64(11);
+        // 辺を共有する格子状のメッシュ（辺や頂点を通るレイが多い）と、ばらばらな三角形
+        let make_triangles = |rng: &mut XorShiftRng| {
+            let mut vertices = Vec::new();
+            let grid =
+                |i: usize, j: usize| Vec3::new(i as f32, ((i * j) % 3) as f32 * 0.3, j as f32);
+            for i in 0..8 {
+                for j in 0..8 {
+                    let (a, b) = (grid(i, j), grid(i + 1, j));
+                    let (c, d) = (grid(i + 1, j + 1), grid(i, j + 1));
+                    vertices.push([a, b, c]);
+                    vertices.push([a, c, d]);
+                }
+            }
+            for _ in 0..100 {
+                let p = random_vec(rng) * 5.0 + Vec3::new(4.0, 0.0, 4.0);
+                vertices.push([p, p + random_vec(rng), p + random_vec(rng)]);
+            }
+            let mut triangles = test_util::make_triangles(&vertices);
+            // 背面カリングもSIMDで判定する
+            for triangle in triangles.iter_mut().step_by(3) {
+                triangle.set_backface_culling(true);
+            }
+            triangles
+        };
+        let all_triangles = make_triangles(&mut XorShiftRng::seed_from_u64(12));
+        // 最も近い交点のtと重心座標
+        let brute_force = |ray: &Ray, triangles: &[Triangle]| {
+            triangles
+                .iter()
+                .filter_map(|tri| tri.hit(ray, 0.0001, std::f32::MAX))
+                .fold(None, |res: Option<(f32, Vec3)>, rec| match res {
+                    Some((t, _)) if t <= rec.t => res,
+                    _ => Some((rec.t, rec.barycentric.unwrap())),
+                })
+        };
+        let rays: Vec<Ray> = (0..1000)
+            .map(|k| {
+                let target = if k % 2 == 0 {
+                    // 格子の頂点・辺の上
+                    Vec3::new(
+                        rng.gen_range(0, 9) as f32,
+                        0.0,
+                        rng.gen_range(0, 9) as f32 + (k % 4 / 2) as f32 * rng.gen::<f32>(),
+                    )
+                } else {
+                    random_vec(&mut rng) * 5.0 + Vec3::new(4.0, 0.0, 4.0)
+                };
+                let origin = random_vec(&mut rng) * 15.0 + Vec3::new(4.0, 10.0, 4.0);
+                Ray::new(&origin, &(target - origin), 0.0)
+            })
+            .collect();
+        let expected: Vec<Option<f32>> = rays
+            .iter()
+            .map(|ray| brute_force(ray, &all_triangles).map(|(t, _)| t))
+            .collect();
+        let backends = [
+            TraversalBackend::Avx,
+            TraversalBackend::Sse,
+            TraversalBackend::Scalar,
+        ];
+        for &pack_size in [4, 8].iter() {
+            let packs = TrianglePack::pack(
+                make_triangles(&mut XorShiftRng::seed_from_u64(12)),
+                pack_size,
+                0.0,
+                1.0,
+                1,
+            );
+            let triangle_cnt: usize = packs.iter().map(|pack| pack.triangles().len()).sum();
+            assert_eq!(triangle_cnt, 8 * 8 * 2 + 100);
+            assert!(packs.len() * 2 <= triangle_cnt);
+            let mut actual = OBVH::from_bvh(BVH::new_binned(packs, 0.0, 1.0, 1));
+            for &backend in backends.iter().filter(|b| b.is_available()) {
+                for pack in actual.leaves_mut() {
+                    pack.set_backend(backend);
+                }
+                // SIMDでもTriangle::hitと同じtと重心座標になること
+                for (ray, pack) in iproduct!(rays.iter().step_by(2), actual.leaves_mut().iter()) {
+                    let expected = brute_force(ray, pack.triangles());
+                    assert_eq!(
+                        expected,
+                        pack.hit(ray, 0.0001, std::f32::MAX)
+                            .map(|rec| (rec.t, rec.barycentric.unwrap())),
+                        "{:?}, pack_size = {}",
+                        backend,
+                        pack_size
+                    );
+                    assert_eq!(
+                        expected.is_some(),
+                        pack.is_hit(ray, 0.0001, std::f32::MAX),
+                        "{:?}, pack_size = {}",
+                        backend,
+                        pack_size
+                    );
+                    // 統計はパック内の三角形ごとに数える
+                    let mut stats = TraversalStats::default();
+                    pack.hit_with_stats(ray, 0.0001, std::f32::MAX, &mut stats);
+                    assert_eq!(stats.primitive_tests, pack.triangles().len() as u32);
+                }
+                // 格子の頂点を狙うレイは、厚さ0の箱の判定の誤差でOBVH自体が取りこぼしうるので除く
+                for (ray, expected) in rays.iter().zip(&expected).skip(1).step_by(2) {
+                    assert_eq!(
+                        *expected,
+                        actual.hit(ray, 0.0001, std::f32::MAX).map(|rec| rec.t),
+                        "{:?}, pack_size = {}",
+                        backend,
+                        pack_size
+                    );
+                }
+            }
+        }
+    }
+}
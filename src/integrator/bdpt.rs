@@ -0,0 +1,588 @@
+// Bidirectional path tracing.
+// 参考：Veach, "Robust Monte Carlo Methods for Light Transport Simulation", chapter 10
+// および pbrt-v3 の BDPTIntegrator。
+// 頂点の密度はすべて面積測度で保持し、MISの重みはバランスヒューリスティックで計算する。
+
+use crate::aliases::{RandGen, Vec2, Vec3};
+use crate::hit_record::HitRecord;
+use crate::integrator::Splat;
+use crate::pdf::SingularPdf;
+use crate::ray::Ray;
+use crate::scene::Scene;
+
+const RAY_EPSILON: f32 = 0.0001;
+
+#[derive(Clone, Copy, PartialEq)]
+enum VertexType {
+    Camera,
+    Light,
+    Surface,
+}
+
+/// Which quantity is carried along a subpath.
+#[derive(Clone, Copy, PartialEq)]
+enum TransportMode {
+    Radiance,   // camera subpath
+    Importance, // light subpath
+}
+
+#[derive(Clone, Copy)]
+struct Vertex<'s> {
+    vertex_type: VertexType,
+    point: Vec3,
+    normal: Vec3, // forward direction of the camera for a Camera vertex.
+    in_dir: Vec3, // direction of the ray by which this vertex was reached. zero for end points.
+    rec: Option<HitRecord<'s>>,
+    beta: Vec3, // throughput from the end point of the subpath to this vertex.
+    is_delta: bool,
+    is_connectible: bool,
+    pdf_fwd: f32, // density (area measure) of this vertex sampled by the subpath containing it.
+    pdf_rev: f32, // density (area measure) of this vertex sampled by the opposite subpath.
+    time: f32,
+}
+
+fn zero() -> Vec3 {
+    Vec3::new(0.0, 0.0, 0.0)
+}
+
+fn is_black(v: &Vec3) -> bool {
+    v[0] <= 0.0 && v[1] <= 0.0 && v[2] <= 0.0
+}
+
+impl<'s> Vertex<'s> {
+    fn camera(point: &Vec3, forward: &Vec3, beta: &Vec3, time: f32) -> Self {
+        Vertex {
+            vertex_type: VertexType::Camera,
+            point: *point,
+            normal: *forward,
+            in_dir: zero(),
+            rec: None,
+            beta: *beta,
+            is_delta: false,
+            is_connectible: true,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            time: time,
+        }
+    }
+    fn light(rec: &HitRecord<'s>, beta: &Vec3, pdf_fwd: f32, time: f32) -> Self {
+        Vertex {
+            vertex_type: VertexType::Light,
+            point: rec.point,
+            normal: rec.normal,
+            in_dir: zero(),
+            rec: Some(*rec),
+            beta: *beta,
+            is_delta: false,
+            is_connectible: true,
+            pdf_fwd: pdf_fwd,
+            pdf_rev: 0.0,
+            time: time,
+        }
+    }
+    fn surface(rec: &HitRecord<'s>, in_dir: &Vec3, beta: &Vec3, time: f32) -> Self {
+        Vertex {
+            vertex_type: VertexType::Surface,
+            point: rec.point,
+            normal: rec.normal,
+            in_dir: *in_dir,
+            rec: Some(*rec),
+            beta: *beta,
+            is_delta: false,
+            is_connectible: false,
+            pdf_fwd: 0.0,
+            pdf_rev: 0.0,
+            time: time,
+        }
+    }
+    fn is_on_surface(&self) -> bool {
+        self.vertex_type != VertexType::Camera
+    }
+    /// Converts a density w.r.t. the solid angle at self into a density w.r.t. the area at next.
+    fn convert_density(&self, pdf_dir: f32, next: &Vertex) -> f32 {
+        let w = next.point - self.point;
+        let dist_squared = w.norm_squared();
+        if dist_squared == 0.0 {
+            return 0.0;
+        }
+        let mut pdf = pdf_dir / dist_squared;
+        if next.is_on_surface() {
+            pdf *= next.normal.dot(&w).abs() / dist_squared.sqrt();
+        }
+        pdf
+    }
+    /// BRDF at a Surface vertex for the light going out to (or coming in from) the direction out_dir.
+    /// Returns zero if the two directions are on the opposite sides of the surface,
+    /// since every non-delta material of this crate is reflective.
+    fn f_dir(&self, out_dir: &Vec3, mode: TransportMode) -> Vec3 {
+        let rec = self.rec.as_ref().unwrap();
+        // (the direction of the ray carrying outgoing light, the direction toward the incoming light)
+        let (in_ray, out_ray) = match mode {
+            TransportMode::Radiance => (self.in_dir, *out_dir),
+            TransportMode::Importance => (-out_dir, -self.in_dir),
+        };
+        if -in_ray.dot(&rec.normal) * out_ray.dot(&rec.normal) <= 0.0 {
+            return zero();
+        }
+        rec.material
+            .brdf(&in_ray, &out_ray, rec, &Vec3::new(1.0, 1.0, 1.0))
+    }
+    fn f(&self, next: &Vertex, mode: TransportMode) -> Vec3 {
+        self.f_dir(&(next.point - self.point), mode)
+    }
+    /// Density (w.r.t. the solid angle) of sampling out_dir at a Surface vertex reached along in_dir.
+    fn scatter_density(&self, in_dir: &Vec3, out_dir: &Vec3, rng: &mut RandGen) -> f32 {
+        let rec = self.rec.as_ref().unwrap();
+        let in_ray = Ray::new(&(self.point - in_dir), in_dir, self.time);
+        match rec.material.scatter(&in_ray, rec, rng) {
+            Some(scatter) => match scatter.pdf {
+                SingularPdf::Finite { ref pdf } => pdf.density(out_dir),
+                SingularPdf::Delta { .. } => 0.0,
+            },
+            None => 0.0,
+        }
+    }
+    /// Density (w.r.t. the area at next) of sampling next from self, where self is reached from prev.
+    fn density(
+        &self,
+        scene: &Scene,
+        prev: Option<&Vertex>,
+        next: &Vertex,
+        rng: &mut RandGen,
+    ) -> f32 {
+        match self.vertex_type {
+            VertexType::Light => self.light_density(next),
+            VertexType::Camera => {
+                let ray = Ray::new(&self.point, &(next.point - self.point), self.time);
+                self.convert_density(scene.camera.ray_density(&ray).1, next)
+            }
+            VertexType::Surface => {
+                if let Some(prev) = prev {
+                    let pdf_dir = self.scatter_density(
+                        &(self.point - prev.point),
+                        &(next.point - self.point),
+                        rng,
+                    );
+                    self.convert_density(pdf_dir, next)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+    /// Density (w.r.t. the area at next) of the light emitted from self toward next.
+    /// Consistent with Hitable::sample_emission.
+    fn light_density(&self, next: &Vertex) -> f32 {
+        let w = next.point - self.point;
+        let cosine = self.normal.dot(&w.normalize());
+        if cosine <= 0.0 {
+            return 0.0;
+        }
+        self.convert_density(cosine / std::f32::consts::PI, next)
+    }
+    /// Density (w.r.t. the area) of self sampled as the origin of a light subpath.
+    fn light_origin_density(&self, scene: &Scene) -> f32 {
+        match scene.light {
+            Some(ref light) => 1.0 / light.surface_area(),
+            None => 0.0,
+        }
+    }
+    /// Radiance emitted from self toward the vertex `to`.
+    fn emitted(&self, to: &Vertex) -> Vec3 {
+        match self.rec {
+            Some(ref rec) => {
+                let ray = Ray::new(&to.point, &(self.point - to.point), self.time);
+                rec.material.emitted(&ray, rec)
+            }
+            None => zero(),
+        }
+    }
+}
+
+/// Extends a subpath by tracing a ray and sampling materials repeatedly.
+/// * `pdf_dir` - density (w.r.t. the solid angle) of the direction of ray.
+/// * `max_vertices` - maximum number of vertices to be added to path.
+/// * `return` - radiance from the background reached by the subpath (always zero for light subpaths).
+fn random_walk<'s>(
+    scene: &'s Scene,
+    mut ray: Ray,
+    mut beta: Vec3,
+    pdf_dir: f32,
+    max_vertices: usize,
+    mode: TransportMode,
+    rng: &mut RandGen,
+    path: &mut Vec<Vertex<'s>>,
+) -> Vec3 {
+    debug_assert!(!path.is_empty());
+    let mut pdf_fwd = pdf_dir;
+    let mut added = 0;
+    loop {
+        let rec = match scene
+            .hitables
+            .hit(&ray, RAY_EPSILON, std::f32::MAX)
+            .map(HitRecord::shaded)
+        {
+            Some(rec) => rec,
+            None => {
+                if mode == TransportMode::Radiance {
+                    return beta.component_mul(&scene.bg.color(&ray));
+                }
+                return zero();
+            }
+        };
+        let prev = path.len() - 1;
+        let mut vertex = Vertex::surface(&rec, &ray.direction, &beta, ray.time);
+        vertex.pdf_fwd = path[prev].convert_density(pdf_fwd, &vertex);
+        let scatter = rec.material.scatter(&ray, &rec, rng);
+        if let Some(ref scatter) = scatter {
+            match scatter.pdf {
+                SingularPdf::Finite { .. } => vertex.is_connectible = true,
+                SingularPdf::Delta { .. } => vertex.is_delta = true,
+            }
+        }
+        path.push(vertex);
+        added += 1;
+        if added >= max_vertices {
+            break;
+        }
+        let scatter = match scatter {
+            Some(scatter) => scatter,
+            None => break,
+        };
+        let cur = path.len() - 1;
+        let (dir, pdf_rev) = match scatter.pdf {
+            SingularPdf::Delta { dir } => {
+                let weight =
+                    rec.material
+                        .brdf(&ray.direction, &dir, &rec, &Vec3::new(1.0, 1.0, 1.0));
+                beta = beta.component_mul(&weight);
+                pdf_fwd = 0.0;
+                (dir, 0.0)
+            }
+            SingularPdf::Finite { ref pdf } => {
+                let dir = pdf.generate(rng);
+                pdf_fwd = pdf.density(&dir);
+                if pdf_fwd <= 0.0 {
+                    break;
+                }
+                let f = path[cur].f_dir(&dir, mode);
+                let cosine = rec.normal.dot(&dir.normalize()).abs();
+                beta = beta.component_mul(&f) * (cosine / pdf_fwd);
+                let pdf_rev = path[cur].scatter_density(&(-dir), &(-ray.direction), rng);
+                (dir, pdf_rev)
+            }
+        };
+        if is_black(&beta) || !beta.norm().is_finite() {
+            break;
+        }
+        path[prev].pdf_rev = path[cur].convert_density(pdf_rev, &path[prev]);
+        ray = Ray::new(&rec.point, &dir, ray.time);
+    }
+    zero()
+}
+
+/// Whether the segment between a and b is not occluded.
+fn is_visible(scene: &Scene, a: &Vec3, b: &Vec3, time: f32) -> bool {
+    let dir = b - a;
+    let dist = dir.norm();
+    !scene.hitables.is_hit(
+        &Ray::new(a, &(dir / dist), time),
+        RAY_EPSILON,
+        dist * (1.0 - RAY_EPSILON),
+    )
+}
+
+/// Generalized geometry term including visibility.
+fn geometry(scene: &Scene, a: &Vertex, b: &Vertex) -> f32 {
+    let d = b.point - a.point;
+    let dist_squared = d.norm_squared();
+    if dist_squared == 0.0 || !is_visible(scene, &a.point, &b.point, a.time) {
+        return 0.0;
+    }
+    let d = d / dist_squared.sqrt();
+    let mut g = 1.0 / dist_squared;
+    if a.is_on_surface() {
+        g *= a.normal.dot(&d).abs();
+    }
+    if b.is_on_surface() {
+        g *= b.normal.dot(&d).abs();
+    }
+    g
+}
+
+/// MIS weight (balance heuristic) of the path made by connecting
+/// light_path[0..s] and camera_path[0..t].
+/// * `sampled` - the end point sampled in connect() when s == 1 or t == 1.
+fn mis_weight<'s>(
+    scene: &Scene,
+    light_path: &[Vertex<'s>],
+    camera_path: &[Vertex<'s>],
+    sampled: Option<Vertex<'s>>,
+    s: usize,
+    t: usize,
+    rng: &mut RandGen,
+) -> f32 {
+    if s + t == 2 || scene.light.is_none() {
+        return 1.0;
+    }
+    let remap0 = |f: f32| if f != 0.0 { f } else { 1.0 };
+    let mut light_path: Vec<Vertex<'s>> = light_path[..s].to_vec();
+    let mut camera_path: Vec<Vertex<'s>> = camera_path[..t].to_vec();
+    if s == 1 {
+        light_path[0] = sampled.unwrap();
+    } else if t == 1 {
+        camera_path[0] = sampled.unwrap();
+    }
+    // Update the reverse densities of the vertices near the connection.
+    camera_path[t - 1].is_delta = false;
+    if s > 0 {
+        light_path[s - 1].is_delta = false;
+    }
+    let pt_rev = if s > 0 {
+        let qs_minus = if s > 1 {
+            Some(&light_path[s - 2])
+        } else {
+            None
+        };
+        light_path[s - 1].density(scene, qs_minus, &camera_path[t - 1], rng)
+    } else {
+        camera_path[t - 1].light_origin_density(scene)
+    };
+    let pt_minus_rev = if t > 1 {
+        if s > 0 {
+            camera_path[t - 1].density(scene, Some(&light_path[s - 1]), &camera_path[t - 2], rng)
+        } else {
+            camera_path[t - 1].light_density(&camera_path[t - 2])
+        }
+    } else {
+        0.0
+    };
+    let qs_rev = if s > 0 {
+        let pt_minus = if t > 1 {
+            Some(&camera_path[t - 2])
+        } else {
+            None
+        };
+        camera_path[t - 1].density(scene, pt_minus, &light_path[s - 1], rng)
+    } else {
+        0.0
+    };
+    let qs_minus_rev = if s > 1 {
+        light_path[s - 1].density(scene, Some(&camera_path[t - 1]), &light_path[s - 2], rng)
+    } else {
+        0.0
+    };
+    camera_path[t - 1].pdf_rev = pt_rev;
+    if t > 1 {
+        camera_path[t - 2].pdf_rev = pt_minus_rev;
+    }
+    if s > 0 {
+        light_path[s - 1].pdf_rev = qs_rev;
+    }
+    if s > 1 {
+        light_path[s - 2].pdf_rev = qs_minus_rev;
+    }
+    // Sum up the ratios of the densities of the other strategies to the current one.
+    let mut sum_ri = 0.0;
+    let mut ri = 1.0;
+    for i in (1..t).rev() {
+        ri *= remap0(camera_path[i].pdf_rev) / remap0(camera_path[i].pdf_fwd);
+        if !camera_path[i].is_delta && !camera_path[i - 1].is_delta {
+            sum_ri += ri;
+        }
+    }
+    let mut ri = 1.0;
+    for i in (0..s).rev() {
+        ri *= remap0(light_path[i].pdf_rev) / remap0(light_path[i].pdf_fwd);
+        let is_delta_light_vertex = i > 0 && light_path[i - 1].is_delta;
+        if !light_path[i].is_delta && !is_delta_light_vertex {
+            sum_ri += ri;
+        }
+    }
+    1.0 / (1.0 + sum_ri)
+}
+
+/// Calculates the contribution of the path made by connecting light_path[0..s] and camera_path[0..t].
+/// * `return` - (weighted contribution, position on the film if t == 1)
+fn connect<'s>(
+    scene: &'s Scene,
+    light_path: &[Vertex<'s>],
+    camera_path: &[Vertex<'s>],
+    s: usize,
+    t: usize,
+    rng: &mut RandGen,
+) -> (Vec3, Option<Vec2>) {
+    let mut sampled: Option<Vertex<'s>> = None;
+    let mut film_position = None;
+    let contrib = if s == 0 {
+        // The camera subpath reaches a light by itself.
+        let pt = &camera_path[t - 1];
+        if pt.vertex_type != VertexType::Surface {
+            return (zero(), None);
+        }
+        pt.beta.component_mul(&pt.emitted(&camera_path[t - 2]))
+    } else if t == 1 {
+        // Connect the light subpath to the camera (light tracing).
+        let qs = &light_path[s - 1];
+        if !qs.is_connectible {
+            return (zero(), None);
+        }
+        let lens = match scene.camera.sample_lens_toward(&qs.point, rng) {
+            Some(lens) => lens,
+            None => return (zero(), None),
+        };
+        let beta = lens.importance / lens.density;
+        let camera_vertex = Vertex::camera(
+            &lens.lens_point,
+            &scene.camera.forward(),
+            &Vec3::new(beta, beta, beta),
+            qs.time,
+        );
+        let to_camera = (camera_vertex.point - qs.point).normalize();
+        let contrib = qs
+            .beta
+            .component_mul(&qs.f(&camera_vertex, TransportMode::Importance))
+            .component_mul(&camera_vertex.beta)
+            * qs.normal.dot(&to_camera).abs();
+        if is_black(&contrib) || !is_visible(scene, &qs.point, &camera_vertex.point, qs.time) {
+            return (zero(), None);
+        }
+        sampled = Some(camera_vertex);
+        film_position = Some(lens.film_position);
+        contrib
+    } else if s == 1 {
+        // Connect the camera subpath to a point sampled on the light (next event estimation).
+        let pt = &camera_path[t - 1];
+        if !pt.is_connectible {
+            return (zero(), None);
+        }
+        let light = scene.light.as_ref().unwrap();
+        let rec = light.random_point_on_surface(rng);
+        let w = rec.point - pt.point;
+        let dist_squared = w.norm_squared();
+        let w = w / dist_squared.sqrt();
+        let light_cosine = rec.normal.dot(&w).abs();
+        if dist_squared == 0.0 || light_cosine == 0.0 {
+            return (zero(), None);
+        }
+        let pdf_pos = 1.0 / light.surface_area();
+        let pdf = pdf_pos * dist_squared / light_cosine;
+        let emitted = rec
+            .material
+            .emitted(&Ray::new(&pt.point, &w, pt.time), &rec);
+        let light_vertex = Vertex::light(&rec, &(emitted / pdf), pdf_pos, pt.time);
+        let contrib = pt
+            .beta
+            .component_mul(&pt.f(&light_vertex, TransportMode::Radiance))
+            .component_mul(&light_vertex.beta)
+            * pt.normal.dot(&w).abs();
+        if is_black(&contrib) || !is_visible(scene, &pt.point, &rec.point, pt.time) {
+            return (zero(), None);
+        }
+        sampled = Some(light_vertex);
+        contrib
+    } else {
+        let qs = &light_path[s - 1];
+        let pt = &camera_path[t - 1];
+        if !qs.is_connectible || !pt.is_connectible {
+            return (zero(), None);
+        }
+        let contrib = qs
+            .beta
+            .component_mul(&qs.f(pt, TransportMode::Importance))
+            .component_mul(&pt.f(qs, TransportMode::Radiance))
+            .component_mul(&pt.beta);
+        if is_black(&contrib) {
+            return (zero(), None);
+        }
+        contrib * geometry(scene, qs, pt)
+    };
+    if is_black(&contrib) {
+        return (zero(), None);
+    }
+    let weight = mis_weight(scene, light_path, camera_path, sampled, s, t, rng);
+    (weight * contrib, film_position)
+}
+
+/// Calculates the radiance carried by a camera ray with bidirectional path tracing.
+/// The contributions of the strategies connecting light subpaths directly to the camera
+/// land on arbitrary pixels, so they are pushed into `splats` instead of being added to the return value.
+/// * `ray` - a ray generated by scene.camera.get_ray.
+/// * `max_depth` - maximum number of scattering events in a path.
+pub fn calc_color(
+    ray: &Ray,
+    scene: &Scene,
+    rng: &mut RandGen,
+    max_depth: usize,
+    splats: &mut Vec<Splat>,
+) -> Vec3 {
+    let mut light_out = zero();
+    // Camera subpath
+    let mut camera_path: Vec<Vertex> = Vec::with_capacity(max_depth + 2);
+    let pdf_dir = scene.camera.ray_density(ray).1;
+    if pdf_dir <= 0.0 {
+        return light_out;
+    }
+    let one = Vec3::new(1.0, 1.0, 1.0);
+    camera_path.push(Vertex::camera(
+        &ray.origin,
+        &scene.camera.forward(),
+        &one,
+        ray.time,
+    ));
+    light_out += random_walk(
+        scene,
+        *ray,
+        one,
+        pdf_dir,
+        max_depth + 1,
+        TransportMode::Radiance,
+        rng,
+        &mut camera_path,
+    );
+    // Light subpath
+    let mut light_path: Vec<Vertex> = Vec::with_capacity(max_depth + 1);
+    if let Some(ref light) = scene.light {
+        if let Some(emission) = light.sample_emission(ray.time, rng) {
+            light_path.push(Vertex::light(
+                &emission.rec,
+                &(emission.radiance / emission.pdf_pos),
+                emission.pdf_pos,
+                ray.time,
+            ));
+            let cosine = emission.rec.normal.dot(&emission.ray.direction.normalize());
+            let beta = emission.radiance * (cosine.abs() / (emission.pdf_pos * emission.pdf_dir));
+            random_walk(
+                scene,
+                emission.ray,
+                beta,
+                emission.pdf_dir,
+                max_depth,
+                TransportMode::Importance,
+                rng,
+                &mut light_path,
+            );
+        }
+    }
+    // Connect every pair of the prefixes of the subpaths.
+    for t in 1..=camera_path.len() {
+        for s in 0..=light_path.len() {
+            let depth = s as isize + t as isize - 2;
+            if (s == 1 && t == 1) || depth < 0 || depth > max_depth as isize {
+                continue;
+            }
+            let (contrib, film_position) = connect(scene, &light_path, &camera_path, s, t, rng);
+            if is_black(&contrib) || !contrib.norm().is_finite() {
+                continue;
+            }
+            match film_position {
+                Some(film_position) => splats.push(Splat {
+                    film_position: film_position,
+                    color: contrib,
+                }),
+                None => light_out += contrib,
+            }
+        }
+    }
+    light_out
+}
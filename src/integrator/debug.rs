@@ -0,0 +1,70 @@
+// ジオメトリや BVH の確認用の積分器。
+// カメラからのレイが最初に当たった点の情報を色として出力する。
+
+use crate::aliases::{RandGen, Vec3};
+use crate::hit_record::HitRecord;
+use crate::pdf::cosine::CosinePdf;
+use crate::pdf::Pdf;
+use crate::ray::Ray;
+use crate::scene::Scene;
+
+#[derive(Clone, Copy, Debug)]
+pub enum DebugMode {
+    /// Ratio of directions (weighted by cosine) not occluded within the radius.
+    AmbientOcclusion {
+        radius: f32,
+    },
+    ShadingNormal,
+    GeometricNormal,
+    Uv,
+    /// Distance from the camera, where max_distance is displayed as white.
+    Depth {
+        max_distance: f32,
+    },
+    /// Barycentric coordinates on triangles (black on the other hitables).
+    Barycentric,
+    /// Albedo of the material of the first hit.
+    Albedo,
+}
+
+/// Maps a unit vector to a color.
+fn vector_to_color(v: &Vec3) -> Vec3 {
+    0.5 * (v + Vec3::new(1.0, 1.0, 1.0))
+}
+
+/// Calculates the color of a camera ray. The background is black.
+pub fn calc_color(ray: &Ray, scene: &Scene, rng: &mut RandGen, mode: DebugMode) -> Vec3 {
+    let rec = match scene
+        .hitables
+        .hit(ray, 0.0001, std::f32::MAX)
+        .map(HitRecord::shaded)
+    {
+        Some(rec) => rec,
+        None => return Vec3::new(0.0, 0.0, 0.0),
+    };
+    match mode {
+        DebugMode::AmbientOcclusion { radius } => {
+            let normal = if rec.normal.dot(&ray.direction) > 0.0 {
+                -rec.normal
+            } else {
+                rec.normal
+            };
+            let dir = CosinePdf::new(&normal).generate(rng).normalize();
+            let occlusion_ray = Ray::new(&rec.point, &dir, ray.time);
+            if scene.hitables.is_hit(&occlusion_ray, 0.0001, radius) {
+                Vec3::new(0.0, 0.0, 0.0)
+            } else {
+                Vec3::new(1.0, 1.0, 1.0)
+            }
+        }
+        DebugMode::ShadingNormal => vector_to_color(&rec.normal),
+        DebugMode::GeometricNormal => vector_to_color(&rec.geometric_normal),
+        DebugMode::Uv => Vec3::new(rec.tex_coord[0], rec.tex_coord[1], 0.0),
+        DebugMode::Depth { max_distance } => {
+            let depth = (rec.t * ray.direction.norm() / max_distance).min(1.0);
+            Vec3::new(depth, depth, depth)
+        }
+        DebugMode::Barycentric => rec.barycentric.unwrap_or(Vec3::new(0.0, 0.0, 0.0)),
+        DebugMode::Albedo => rec.material.albedo(&rec),
+    }
+}
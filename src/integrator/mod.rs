@@ -0,0 +1,16 @@
+pub mod bdpt;
+pub mod debug;
+pub mod path_guiding;
+pub mod pssmlt;
+pub mod sppm;
+pub mod traversal_heatmap;
+
+use crate::aliases::{Vec2, Vec3};
+
+/// A contribution to the film at an arbitrary position,
+/// not necessarily to the pixel through which the camera ray was generated (e.g., light tracing).
+#[derive(Clone, Copy)]
+pub struct Splat {
+    pub film_position: Vec2, // (u, v) in the same convention as Camera::get_ray
+    pub color: Vec3,
+}
@@ -0,0 +1,482 @@
+// Path guiding by SD-tree.
+// 参考：Müller et al., "Practical Path Guiding for Efficient Light-Transport Simulation"。
+// 空間を二分木 (SpatialNode) で分割し、各葉に方向の分布を表す四分木 (DTree) を持たせる。
+// 方向は円筒座標 (cosθ, φ) により [0,1]^2 に等面積で写される。
+// 学習パス k では 2^k サンプル/ピクセルで画像をレンダリングし、
+// 前のパスで得た分布 (sampling) とマテリアルの Pdf を混合してサンプリングしつつ、
+// 入射輝度を新しい分布 (building) に記録する。パスの終わりに木を細分化する。
+
+use crate::aabb::Aabb;
+use crate::aliases::{RandGen, Vec2, Vec3};
+use crate::hit_record::HitRecord;
+use crate::next_event_estimation;
+use crate::pdf::mixture::MixturePdf;
+use crate::pdf::{Pdf, SingularPdf};
+use crate::ray::Ray;
+use crate::scene::Scene;
+use rand::Rng;
+use std::f32::consts::PI;
+
+/// Probability of sampling by the guiding distribution instead of the material's pdf.
+const GUIDING_FRACTION: f32 = 0.5;
+/// A leaf of the spatial tree is split when it records more than SPATIAL_SPLIT_FACTOR * sqrt(2^k) samples in the pass k.
+const SPATIAL_SPLIT_FACTOR: f32 = 12000.0;
+/// A quadrant of the directional tree is subdivided when it has more than this fraction of the energy.
+const DIRECTIONAL_SPLIT_FRACTION: f32 = 0.01;
+const DIRECTIONAL_MAX_DEPTH: usize = 20;
+
+/// Maps a direction to [0,1]^2 preserving areas.
+fn dir_to_square(dir: &Vec3) -> Vec2 {
+    let dir = dir.normalize();
+    let cos_theta = dir[2].min(1.0).max(-1.0);
+    let phi = dir[1].atan2(dir[0]);
+    Vec2::new(
+        ((cos_theta + 1.0) * 0.5).min(1.0).max(0.0),
+        ((phi + PI) / (2.0 * PI)).min(1.0).max(0.0),
+    )
+}
+
+fn square_to_dir(p: &Vec2) -> Vec3 {
+    let cos_theta = 2.0 * p[0] - 1.0;
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * p[1] - PI;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+fn luminance(col: &Vec3) -> f32 {
+    0.2126 * col[0] + 0.7152 * col[1] + 0.0722 * col[2]
+}
+
+#[derive(Clone, Copy)]
+struct QuadNode {
+    sums: [f32; 4],       // energies of quadrants (x + 2 * y)
+    children: [usize; 4], // 0 if the quadrant is a leaf (the root is never a child)
+}
+
+impl QuadNode {
+    fn leaf() -> Self {
+        QuadNode {
+            sums: [0.0; 4],
+            children: [0; 4],
+        }
+    }
+    fn total(&self) -> f32 {
+        self.sums.iter().sum()
+    }
+    /// The quadrant containing p and the position of p relative to the quadrant.
+    fn quadrant(p: &Vec2) -> (usize, Vec2) {
+        let x = if p[0] >= 0.5 { 1 } else { 0 };
+        let y = if p[1] >= 0.5 { 1 } else { 0 };
+        let local = Vec2::new(2.0 * p[0] - x as f32, 2.0 * p[1] - y as f32);
+        (x + 2 * y, local)
+    }
+}
+
+/// Quadtree representing a distribution of directions.
+#[derive(Clone)]
+struct DTree {
+    nodes: Vec<QuadNode>,
+    sample_cnt: usize,
+}
+
+impl DTree {
+    fn new() -> Self {
+        DTree {
+            nodes: vec![QuadNode::leaf()],
+            sample_cnt: 0,
+        }
+    }
+    fn total(&self) -> f32 {
+        self.nodes[0].total()
+    }
+    fn record(&mut self, dir: &Vec3, value: f32) {
+        self.sample_cnt += 1;
+        if !(value > 0.0 && value.is_finite()) {
+            return;
+        }
+        let mut p = dir_to_square(dir);
+        let mut idx = 0;
+        loop {
+            let (q, local) = QuadNode::quadrant(&p);
+            self.nodes[idx].sums[q] += value;
+            idx = self.nodes[idx].children[q];
+            if idx == 0 {
+                return;
+            }
+            p = local;
+        }
+    }
+    /// Density w.r.t. the solid angle.
+    fn density(&self, dir: &Vec3) -> f32 {
+        let mut p = dir_to_square(dir);
+        let mut idx = 0;
+        let mut density = 1.0 / (4.0 * PI);
+        loop {
+            let node = &self.nodes[idx];
+            let total = node.total();
+            if total <= 0.0 {
+                return 0.0;
+            }
+            let (q, local) = QuadNode::quadrant(&p);
+            density *= 4.0 * node.sums[q] / total;
+            idx = node.children[q];
+            if idx == 0 {
+                return density;
+            }
+            p = local;
+        }
+    }
+    fn generate(&self, rng: &mut RandGen) -> Vec3 {
+        let mut origin = Vec2::new(0.0, 0.0);
+        let mut size = 1.0;
+        let mut idx = 0;
+        loop {
+            let node = &self.nodes[idx];
+            let mut r = rng.gen::<f32>() * node.total();
+            let mut q = 3;
+            for i in 0..3 {
+                if r < node.sums[i] {
+                    q = i;
+                    break;
+                }
+                r -= node.sums[i];
+            }
+            size *= 0.5;
+            origin += size * Vec2::new((q % 2) as f32, (q / 2) as f32);
+            idx = node.children[q];
+            if idx == 0 {
+                let p = origin + size * Vec2::new(rng.gen::<f32>(), rng.gen::<f32>());
+                return square_to_dir(&p);
+            }
+        }
+    }
+    /// A tree with zero energies whose structure is adapted to the energies of this tree.
+    fn refined(&self) -> DTree {
+        let mut tree = DTree::new();
+        let total = self.total();
+        if total <= 0.0 {
+            return tree;
+        }
+        // (index in the new tree, energies of quadrants, the corresponding old node, depth)
+        let mut stack = vec![(0, self.nodes[0].sums, Some(0), 1)];
+        while let Some((idx, sums, old_idx, depth)) = stack.pop() {
+            for q in 0..4 {
+                if depth >= DIRECTIONAL_MAX_DEPTH || sums[q] / total <= DIRECTIONAL_SPLIT_FRACTION {
+                    continue;
+                }
+                let old_child = old_idx
+                    .map(|i: usize| self.nodes[i].children[q])
+                    .filter(|&c| c != 0);
+                let child_sums = match old_child {
+                    Some(c) => self.nodes[c].sums,
+                    None => [sums[q] / 4.0; 4],
+                };
+                let child = tree.nodes.len();
+                tree.nodes.push(QuadNode::leaf());
+                tree.nodes[idx].children[q] = child;
+                stack.push((child, child_sums, old_child, depth + 1));
+            }
+        }
+        tree
+    }
+    /// Adds the energies recorded in another tree of the same structure.
+    fn merge(&mut self, other: &DTree) {
+        debug_assert_eq!(self.nodes.len(), other.nodes.len());
+        for (node, other) in self.nodes.iter_mut().zip(other.nodes.iter()) {
+            for q in 0..4 {
+                node.sums[q] += other.sums[q];
+            }
+        }
+        self.sample_cnt += other.sample_cnt;
+    }
+}
+
+#[derive(Clone)]
+struct SpatialNode {
+    axis: usize,
+    children: Option<[usize; 2]>,
+    sampling: DTree, // the distribution learned in the previous pass
+    building: DTree, // the distribution being recorded
+}
+
+/// Spatial-directional tree, which learns the distributions of incident radiance.
+#[derive(Clone)]
+pub struct SdTree {
+    bbox: Aabb,
+    nodes: Vec<SpatialNode>,
+}
+
+/// The guiding distribution at a point.
+pub struct GuidingPdf<'a> {
+    dtree: &'a DTree,
+}
+
+impl<'a> Pdf for GuidingPdf<'a> {
+    fn density(&self, dir: &Vec3) -> f32 {
+        self.dtree.density(dir)
+    }
+    fn generate(&self, rng: &mut RandGen) -> Vec3 {
+        self.dtree.generate(rng)
+    }
+}
+
+impl SdTree {
+    pub fn new(bbox: &Aabb) -> Self {
+        SdTree {
+            bbox: *bbox,
+            nodes: vec![SpatialNode {
+                axis: 0,
+                children: None,
+                sampling: DTree::new(),
+                building: DTree::new(),
+            }],
+        }
+    }
+    fn leaf_index(&self, point: &Vec3) -> usize {
+        let mut bbox = self.bbox;
+        let mut idx = 0;
+        while let Some(children) = self.nodes[idx].children {
+            let axis = self.nodes[idx].axis;
+            let mid = 0.5 * (bbox.min[axis] + bbox.max[axis]);
+            if point[axis] < mid {
+                bbox.max[axis] = mid;
+                idx = children[0];
+            } else {
+                bbox.min[axis] = mid;
+                idx = children[1];
+            }
+        }
+        idx
+    }
+    /// The guiding distribution at the point, which is None if nothing is learned there.
+    pub fn pdf<'a>(&'a self, point: &Vec3) -> Option<GuidingPdf<'a>> {
+        let dtree = &self.nodes[self.leaf_index(point)].sampling;
+        if dtree.total() > 0.0 {
+            Some(GuidingPdf { dtree: dtree })
+        } else {
+            None
+        }
+    }
+    /// Records the incident radiance (divided by the density of its direction).
+    pub fn record(&mut self, point: &Vec3, dir: &Vec3, value: f32) {
+        let idx = self.leaf_index(point);
+        self.nodes[idx].building.record(dir, value);
+    }
+    /// Adds the records of another tree which is cloned from this tree.
+    pub fn merge(&mut self, other: &SdTree) {
+        debug_assert_eq!(self.nodes.len(), other.nodes.len());
+        for (node, other) in self.nodes.iter_mut().zip(other.nodes.iter()) {
+            if node.children.is_none() {
+                node.building.merge(&other.building);
+            }
+        }
+    }
+    /// Refines the tree at the end of the pass, and the recorded distributions will be used for sampling.
+    /// * `pass` - the index of the pass, where 2^pass samples per pixel are used.
+    pub fn refine(&mut self, pass: usize) {
+        let threshold = SPATIAL_SPLIT_FACTOR * (2.0f32.powi(pass as i32)).sqrt();
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            if let Some(children) = self.nodes[idx].children {
+                stack.extend_from_slice(&children);
+                continue;
+            }
+            if self.nodes[idx].building.sample_cnt as f32 <= threshold {
+                continue;
+            }
+            let mut child = self.nodes[idx].clone();
+            child.axis = (child.axis + 1) % 3;
+            child.building.sample_cnt /= 2;
+            let first = self.nodes.len();
+            self.nodes.push(child.clone());
+            self.nodes.push(child);
+            self.nodes[idx].children = Some([first, first + 1]);
+            self.nodes[idx].sampling = DTree::new();
+            self.nodes[idx].building = DTree::new();
+            stack.push(first);
+            stack.push(first + 1);
+        }
+        for node in &mut self.nodes {
+            if node.children.is_none() {
+                let refined = node.building.refined();
+                node.sampling = std::mem::replace(&mut node.building, refined);
+            }
+        }
+    }
+}
+
+/// Learns the SD-tree by the passes with 1, 2, 4, ... samples per pixel.
+/// The images rendered in the passes are discarded.
+pub fn train(
+    scene: &Scene,
+    nx: i32,
+    ny: i32,
+    passes: usize,
+    max_depth: i32,
+    thread_cnt: i32,
+) -> SdTree {
+    let bbox = scene
+        .hitables
+        .bounding_box(0.0, 1.0)
+        .expect("path guiding requires a bounded scene");
+    let mut tree = SdTree::new(&bbox);
+    for pass in 0..passes {
+        let spp = 1 << pass;
+        let guide = &tree;
+        let records: Vec<SdTree> = crossbeam::scope(|scope| {
+            let handles: Vec<_> = (0..thread_cnt)
+                .map(|thread_idx| {
+                    scope.spawn(move |_| {
+                        let mut rng = RandGen::new();
+                        let mut recorder = guide.clone();
+                        for j in (thread_idx..ny).step_by(thread_cnt as usize) {
+                            for i in 0..nx {
+                                for _ in 0..spp {
+                                    let u = (i as f32 + rng.gen::<f32>()) / nx as f32;
+                                    let v = (j as f32 + rng.gen::<f32>()) / ny as f32;
+                                    let ray = scene.camera.get_ray(u, v, &mut rng);
+                                    calc_color(
+                                        &ray,
+                                        scene,
+                                        &mut rng,
+                                        max_depth,
+                                        false,
+                                        guide,
+                                        Some(&mut recorder),
+                                    );
+                                }
+                            }
+                        }
+                        recorder
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+        .unwrap();
+        for record in &records {
+            tree.merge(record);
+        }
+        tree.refine(pass);
+    }
+    tree
+}
+
+/// The path tracer (crate::calc_color) which samples directions also by the guiding distribution.
+/// * `recorder` - the tree in which the incident radiances are recorded (during training).
+pub fn calc_color(
+    ray: &Ray,
+    scene: &Scene,
+    rng: &mut RandGen,
+    depth: i32,
+    is_ray_diffused: bool,
+    guide: &SdTree,
+    mut recorder: Option<&mut SdTree>,
+) -> Vec3 {
+    let mut light_out = Vec3::new(0.0, 0.0, 0.0);
+    let rec = match scene
+        .hitables
+        .hit(&ray, 0.0001, std::f32::MAX)
+        .map(HitRecord::shaded)
+    {
+        Some(rec) => rec,
+        None => return scene.bg.color(ray),
+    };
+    if !is_ray_diffused {
+        light_out += rec.material.emitted(ray, &rec);
+    }
+    if depth == 0 {
+        return light_out;
+    }
+    let scatter = match rec.material.scatter(ray, &rec, rng) {
+        Some(scatter) => scatter,
+        None => return light_out,
+    };
+    match scatter.pdf {
+        SingularPdf::Finite {
+            pdf: ref material_pdf,
+        } => {
+            next_event_estimation(ray, &rec, scene, &mut light_out, rng);
+            let guiding_pdf = guide.pdf(&rec.point);
+            let pdf = match guiding_pdf {
+                Some(ref guiding_pdf) => {
+                    MixturePdf::new(GUIDING_FRACTION, guiding_pdf, &**material_pdf)
+                }
+                None => MixturePdf::zero(&**material_pdf),
+            };
+            let dir = pdf.generate(rng);
+            let cosine = rec.normal.dot(&dir.normalize());
+            let density = pdf.density(&dir);
+            if cosine > 0.0 && density > 0.0 {
+                let out_ray = Ray::new(&rec.point, &dir, ray.time);
+                let in_light = calc_color(
+                    &out_ray,
+                    scene,
+                    rng,
+                    depth - 1,
+                    true,
+                    guide,
+                    recorder.as_deref_mut(),
+                );
+                if let Some(recorder) = recorder {
+                    recorder.record(&rec.point, &dir, luminance(&in_light) / density);
+                }
+                let brdf = rec.material.brdf(&ray.direction, &dir, &rec, &in_light);
+                light_out += (cosine / density) * brdf;
+            }
+        }
+        SingularPdf::Delta { ref dir } => {
+            let out_ray = Ray::new(&rec.point, dir, ray.time);
+            let in_light = calc_color(&out_ray, scene, rng, depth - 1, false, guide, recorder);
+            light_out += rec.material.brdf(&ray.direction, dir, &rec, &in_light)
+        }
+    };
+    light_out
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::{RandGen, Vec2, Vec3};
+    use crate::integrator::path_guiding::{square_to_dir, DTree};
+    use std::f32::consts::PI;
+    #[test]
+    fn dtree_density() {
+        const GRID: usize = 256;
+        let mut rng = RandGen::new();
+        let mut dtree = DTree::new();
+        for _ in 0..2 {
+            for _ in 0..1000 {
+                let dir = Vec3::new(0.1, 0.2, 1.0) + 0.3 * crate::pdf::rnd_in_unit_sphere(&mut rng);
+                dtree.record(&dir, 1.0);
+                dtree.record(&Vec3::new(-1.0, 0.0, 0.0), 0.1);
+            }
+            dtree = dtree.refined();
+        }
+        for _ in 0..1000 {
+            let dir = Vec3::new(0.1, 0.2, 1.0) + 0.3 * crate::pdf::rnd_in_unit_sphere(&mut rng);
+            dtree.record(&dir, 1.0);
+        }
+        // The quadtree is not deeper than log2(GRID), and the midpoint rule is exact.
+        let mut integral = 0.0f32;
+        for i in 0..GRID {
+            for j in 0..GRID {
+                let p = Vec2::new(
+                    (i as f32 + 0.5) / GRID as f32,
+                    (j as f32 + 0.5) / GRID as f32,
+                );
+                integral += dtree.density(&square_to_dir(&p));
+            }
+        }
+        integral *= 4.0 * PI / (GRID * GRID) as f32;
+        println!(
+            "[dtree_density] nodes: {}, integral: {}",
+            dtree.nodes.len(),
+            integral
+        );
+        assert!((integral - 1.0).abs() < 0.01);
+        for _ in 0..100 {
+            let dir = dtree.generate(&mut rng);
+            assert!(dtree.density(&dir) > 0.0);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+// Primary sample space Metropolis light transport.
+// 参考：Kelemen et al., "A Simple and Robust Mutation Strategy for the Metropolis Light Transport Algorithm" および pbrt-v3 の MLTIntegrator。
+// パストレーサ (crate::calc_color) が消費する乱数を PrimarySampleStream で置き換え、
+// 主標本空間（乱数列の空間）上で輝度に比例した分布に従うマルコフ連鎖を構成する。
+// 正規化定数（画像全体の平均輝度）は独立なサンプルによるブートストラップで推定する。
+
+use crate::aliases::{RandGen, Vec3};
+use crate::calc_color;
+use crate::rand_gen::PrimarySampleStream;
+use crate::scene::Scene;
+use rand::{Rng, RngCore};
+
+/// A path (generated from a primary sample) and its contribution to the film.
+#[derive(Clone, Copy)]
+struct PathSample {
+    pixel: usize, // index of the pixel in the same convention as Pssmlt::image
+    color: Vec3,
+}
+
+struct Chain {
+    stream: Option<PrimarySampleStream>, // taken out only while a mutation is evaluated
+    current: PathSample,
+}
+
+/// Renderer by primary sample space Metropolis light transport on the path tracer.
+pub struct Pssmlt<'s> {
+    scene: &'s Scene,
+    nx: usize,
+    ny: usize,
+    max_depth: i32,
+    thread_cnt: usize,
+    b: f32, // the normalization constant, i.e., the mean of scalar contributions of all paths
+    chains: Vec<Chain>,
+    film: Vec<Vec3>,
+    mutations: usize,
+}
+
+/// The scalar contribution, to which the stationary distribution of chains are proportional.
+fn scalar_contribution(color: &Vec3) -> f32 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+impl<'s> Pssmlt<'s> {
+    /// * `bootstrap_cnt` - number of independent paths used for estimating the normalization constant and choosing initial states.
+    /// * `sigma` - standard deviation of small step mutations.
+    /// * `large_step_probability` - probability of mutations which replace the whole path.
+    pub fn new(
+        scene: &'s Scene,
+        nx: usize,
+        ny: usize,
+        max_depth: i32,
+        bootstrap_cnt: usize,
+        chain_cnt: usize,
+        sigma: f32,
+        large_step_probability: f32,
+        thread_cnt: usize,
+    ) -> Self {
+        let mut pssmlt = Pssmlt {
+            scene: scene,
+            nx: nx,
+            ny: ny,
+            max_depth: max_depth,
+            thread_cnt: thread_cnt.max(1),
+            b: 0.0,
+            chains: vec![],
+            film: vec![Vec3::new(0.0, 0.0, 0.0); nx * ny],
+            mutations: 0,
+        };
+        // Bootstrapping: the stream with a seed generates the same path when it is regenerated from the seed.
+        let mut rng = RandGen::new();
+        let seed_base = rng.next_u64();
+        let seed = |idx: usize| seed_base.wrapping_add(idx as u64);
+        let chunk_size = (bootstrap_cnt + pssmlt.thread_cnt - 1) / pssmlt.thread_cnt;
+        let this = &pssmlt;
+        let weights: Vec<f32> = crossbeam::scope(|scope| {
+            let handles: Vec<_> = (0..bootstrap_cnt)
+                .step_by(chunk_size.max(1))
+                .map(|begin| {
+                    scope.spawn(move |_| {
+                        (begin..(begin + chunk_size).min(bootstrap_cnt))
+                            .map(|idx| {
+                                let stream = PrimarySampleStream::new(
+                                    seed(idx),
+                                    sigma,
+                                    large_step_probability,
+                                );
+                                scalar_contribution(&this.evaluate(stream).1.color)
+                            })
+                            .collect::<Vec<f32>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        })
+        .unwrap();
+        let weight_sum: f32 = weights.iter().sum();
+        if weight_sum <= 0.0 {
+            // Nothing is visible; the image is black.
+            return pssmlt;
+        }
+        pssmlt.b = weight_sum / bootstrap_cnt as f32;
+        // Choose initial states in proportion to the weights.
+        let cdf: Vec<f32> = weights
+            .iter()
+            .scan(0.0, |acc, w| {
+                *acc += w;
+                Some(*acc)
+            })
+            .collect();
+        for _ in 0..chain_cnt {
+            let target = rng.gen::<f32>() * weight_sum;
+            let idx = match cdf.binary_search_by(|c| c.partial_cmp(&target).unwrap()) {
+                Ok(idx) | Err(idx) => idx.min(bootstrap_cnt - 1),
+            };
+            let stream = PrimarySampleStream::new(seed(idx), sigma, large_step_probability);
+            let (stream, current) = pssmlt.evaluate(stream);
+            pssmlt.chains.push(Chain {
+                stream: Some(stream),
+                current: current,
+            });
+        }
+        pssmlt
+    }
+    /// Advances every chain so that the number of mutations increases by the number of pixels.
+    pub fn iterate(&mut self) {
+        if self.chains.is_empty() {
+            return;
+        }
+        let mutations_per_chain = (self.nx * self.ny + self.chains.len() - 1) / self.chains.len();
+        let chunk_size = (self.chains.len() + self.thread_cnt - 1) / self.thread_cnt;
+        let mut chains = std::mem::take(&mut self.chains);
+        let this = &*self;
+        let films: Vec<Vec<Vec3>> = crossbeam::scope(|scope| {
+            let handles: Vec<_> = chains
+                .chunks_mut(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move |_| {
+                        let mut rng = RandGen::new();
+                        let mut film = vec![Vec3::new(0.0, 0.0, 0.0); this.nx * this.ny];
+                        for chain in chunk.iter_mut() {
+                            for _ in 0..mutations_per_chain {
+                                this.mutate(chain, &mut film, &mut rng);
+                            }
+                        }
+                        film
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        })
+        .unwrap();
+        self.chains = chains;
+        for film in films {
+            for (sum, col) in self.film.iter_mut().zip(film) {
+                *sum += col;
+            }
+        }
+        self.mutations += mutations_per_chain * self.chains.len();
+    }
+    /// The current estimate of the image.
+    /// The pixel (i, j) (j = 0 at the top) is at the index i + j * nx.
+    pub fn image(&self) -> Vec<Vec3> {
+        if self.mutations == 0 {
+            return vec![Vec3::new(0.0, 0.0, 0.0); self.nx * self.ny];
+        }
+        let scale = self.b * (self.nx * self.ny) as f32 / self.mutations as f32;
+        self.film.iter().map(|col| col * scale).collect()
+    }
+    /// Proposes a mutation of the chain and accepts or rejects it.
+    /// Both of the current and proposed states are recorded with the weights of the expected values (Veach's "expected value" technique).
+    fn mutate(&self, chain: &mut Chain, film: &mut [Vec3], rng: &mut RandGen) {
+        let mut stream = chain.stream.take().unwrap();
+        stream.start_iteration();
+        let (mut stream, proposed) = self.evaluate(stream);
+        let current_contrib = scalar_contribution(&chain.current.color);
+        let proposed_contrib = scalar_contribution(&proposed.color);
+        let accept = if current_contrib > 0.0 {
+            (proposed_contrib / current_contrib).min(1.0)
+        } else {
+            1.0
+        };
+        if proposed_contrib > 0.0 {
+            film[proposed.pixel] += proposed.color * (accept / proposed_contrib);
+        }
+        if current_contrib > 0.0 {
+            film[chain.current.pixel] += chain.current.color * ((1.0 - accept) / current_contrib);
+        }
+        if rng.gen::<f32>() < accept {
+            stream.accept();
+            chain.current = proposed;
+        } else {
+            stream.reject();
+        }
+        chain.stream = Some(stream);
+    }
+    /// Generates a path from the primary sample and computes its contribution.
+    fn evaluate(&self, stream: PrimarySampleStream) -> (PrimarySampleStream, PathSample) {
+        let mut rng = RandGen::PrimarySample(stream);
+        let u = rng.gen::<f32>();
+        let v = rng.gen::<f32>();
+        let ray = self.scene.camera.get_ray(u, v, &mut rng);
+        let mut color = calc_color(&ray, self.scene, &mut rng, self.max_depth, false);
+        if !(color[0].is_finite() && color[1].is_finite() && color[2].is_finite()) {
+            color = Vec3::new(0.0, 0.0, 0.0);
+        }
+        let i = ((u * self.nx as f32) as usize).min(self.nx - 1);
+        let j = ((v * self.ny as f32) as usize).min(self.ny - 1);
+        let sample = PathSample {
+            pixel: i + (self.ny - j - 1) * self.nx,
+            color: color,
+        };
+        match rng {
+            RandGen::PrimarySample(stream) => (stream, sample),
+            RandGen::Thread(_) => unreachable!(),
+        }
+    }
+}
@@ -0,0 +1,375 @@
+// Stochastic progressive photon mapping.
+// 参考：Hachisuka and Jensen, "Stochastic Progressive Photon Mapping" および pbrt-v3 の SPPMIntegrator。
+// 各反復は
+// 1. カメラパス：各ピクセルについて、最初の非デルタ反射点（可視点）まで追跡する。
+// 2. 可視点をハッシュグリッドに登録する。
+// 3. フォトンパス：光源からフォトンを追跡し、近傍の可視点にフォトンの寄与を加算する。
+// 4. 各ピクセルの半径を縮小し、放射束を更新する。
+// からなる。
+
+use crate::aliases::{RandGen, Vec3};
+use crate::hit_record::HitRecord;
+use crate::next_event_estimation;
+use crate::pdf::SingularPdf;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use rand::Rng;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+const RAY_EPSILON: f32 = 0.0001;
+/// The parameter alpha in the paper, which controls how fast radii shrink.
+const RADIUS_REDUCTION: f32 = 2.0 / 3.0;
+
+/// The first non-specular point seen from a pixel in the current iteration.
+#[derive(Clone, Copy)]
+struct VisiblePoint<'s> {
+    rec: HitRecord<'s>,
+    in_dir: Vec3, // direction of the camera ray reaching rec.point
+    beta: Vec3,   // throughput from the camera to rec.point
+}
+
+#[derive(Clone, Copy)]
+struct SppmPixel<'s> {
+    radius: f32,
+    ld: Vec3, // sum of the radiances directly estimated by the camera paths
+    n: f32,   // (fractional) number of accumulated photons
+    tau: Vec3,
+    vp: Option<VisiblePoint<'s>>,
+    phi: Vec3, // flux deposited in the current iteration
+    m: u32,    // number of photons deposited in the current iteration
+}
+
+/// Renderer by stochastic progressive photon mapping.
+/// Photons are emitted from scene.light, so emitters that are not in scene.light only
+/// contribute when they are directly visible from the camera.
+pub struct Sppm<'s> {
+    scene: &'s Scene,
+    nx: usize,
+    ny: usize,
+    photons_per_iteration: usize,
+    max_depth: usize,
+    thread_cnt: usize,
+    iterations: usize,
+    pixels: Vec<SppmPixel<'s>>,
+}
+
+/// Hash grid storing the indices of pixels whose visible points are near to each cell.
+struct VisiblePointGrid {
+    origin: Vec3,
+    cell_size: f32,
+    cells: HashMap<[i32; 3], Vec<usize>>,
+}
+
+impl VisiblePointGrid {
+    fn cell_of(&self, p: &Vec3) -> [i32; 3] {
+        let rel = (p - self.origin) / self.cell_size;
+        [
+            rel[0].floor() as i32,
+            rel[1].floor() as i32,
+            rel[2].floor() as i32,
+        ]
+    }
+    fn new(pixels: &[SppmPixel]) -> Self {
+        let mut max_radius: f32 = 0.0;
+        let mut origin = Vec3::new(std::f32::INFINITY, std::f32::INFINITY, std::f32::INFINITY);
+        for pixel in pixels {
+            if let Some(ref vp) = pixel.vp {
+                max_radius = max_radius.max(pixel.radius);
+                origin = crate::util::min_vec3(&origin, &vp.rec.point);
+            }
+        }
+        let mut grid = VisiblePointGrid {
+            origin: origin,
+            cell_size: (2.0 * max_radius).max(std::f32::MIN_POSITIVE),
+            cells: HashMap::new(),
+        };
+        for (idx, pixel) in pixels.iter().enumerate() {
+            if let Some(ref vp) = pixel.vp {
+                let rad = Vec3::new(pixel.radius, pixel.radius, pixel.radius);
+                let lo = grid.cell_of(&(vp.rec.point - rad));
+                let hi = grid.cell_of(&(vp.rec.point + rad));
+                for x in lo[0]..=hi[0] {
+                    for y in lo[1]..=hi[1] {
+                        for z in lo[2]..=hi[2] {
+                            grid.cells.entry([x, y, z]).or_default().push(idx);
+                        }
+                    }
+                }
+            }
+        }
+        grid
+    }
+    fn candidates(&self, p: &Vec3) -> &[usize] {
+        match self.cells.get(&self.cell_of(p)) {
+            Some(list) => list,
+            None => &[],
+        }
+    }
+}
+
+impl<'s> Sppm<'s> {
+    /// * `initial_radius` - initial radius of the gathering disc of every pixel (in the scene's unit).
+    /// * `max_depth` - maximum number of scattering events of camera paths and photon paths.
+    pub fn new(
+        scene: &'s Scene,
+        nx: usize,
+        ny: usize,
+        initial_radius: f32,
+        photons_per_iteration: usize,
+        max_depth: usize,
+        thread_cnt: usize,
+    ) -> Self {
+        let zero = Vec3::new(0.0, 0.0, 0.0);
+        Sppm {
+            scene: scene,
+            nx: nx,
+            ny: ny,
+            photons_per_iteration: photons_per_iteration,
+            max_depth: max_depth,
+            thread_cnt: thread_cnt.max(1),
+            iterations: 0,
+            pixels: vec![
+                SppmPixel {
+                    radius: initial_radius,
+                    ld: zero,
+                    n: 0.0,
+                    tau: zero,
+                    vp: None,
+                    phi: zero,
+                    m: 0,
+                };
+                nx * ny
+            ],
+        }
+    }
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
+    /// Runs one iteration (a camera pass and a photon pass).
+    pub fn iterate(&mut self) {
+        let scene = self.scene;
+        let (nx, ny, max_depth) = (self.nx, self.ny, self.max_depth);
+        // Camera pass
+        let chunk_size = (self.pixels.len() + self.thread_cnt - 1) / self.thread_cnt;
+        crossbeam::scope(|scope| {
+            for (chunk_idx, chunk) in self.pixels.chunks_mut(chunk_size).enumerate() {
+                scope.spawn(move |_| {
+                    let mut rng = RandGen::new();
+                    for (i, pixel) in chunk.iter_mut().enumerate() {
+                        let idx = chunk_idx * chunk_size + i;
+                        let (x, y) = (idx % nx, ny - 1 - idx / nx);
+                        let u = (x as f32 + rng.gen::<f32>()) / nx as f32;
+                        let v = (y as f32 + rng.gen::<f32>()) / ny as f32;
+                        let ray = scene.camera.get_ray(u, v, &mut rng);
+                        Self::trace_camera_path(scene, &ray, max_depth, pixel, &mut rng);
+                    }
+                });
+            }
+        })
+        .unwrap();
+        // Photon pass
+        let grid = VisiblePointGrid::new(&self.pixels);
+        if let Some(ref light) = scene.light {
+            let pixels = &self.pixels;
+            let grid = &grid;
+            // 割り切れない分は先頭のスレッドに1つずつ割り振り、合計をphotons_per_iterationにする
+            let photons_per_thread = self.photons_per_iteration / self.thread_cnt;
+            let remainder = self.photons_per_iteration % self.thread_cnt;
+            let deposits: Vec<Vec<(Vec3, u32)>> = crossbeam::scope(|scope| {
+                let handles: Vec<_> = (0..self.thread_cnt)
+                    .map(|thread_idx| {
+                        let photons =
+                            photons_per_thread + if thread_idx < remainder { 1 } else { 0 };
+                        scope.spawn(move |_| {
+                            let mut rng = RandGen::new();
+                            let mut deposits = vec![(Vec3::new(0.0, 0.0, 0.0), 0u32); pixels.len()];
+                            for _ in 0..photons {
+                                let time = scene.camera.sample_time(&mut rng);
+                                if let Some(emission) = light.sample_emission(time, &mut rng) {
+                                    let cosine = emission
+                                        .rec
+                                        .normal
+                                        .dot(&emission.ray.direction.normalize())
+                                        .abs();
+                                    let beta = emission.radiance
+                                        * (cosine / (emission.pdf_pos * emission.pdf_dir));
+                                    Self::trace_photon(
+                                        scene,
+                                        pixels,
+                                        grid,
+                                        emission.ray,
+                                        beta,
+                                        max_depth,
+                                        &mut deposits,
+                                        &mut rng,
+                                    );
+                                }
+                            }
+                            deposits
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).collect()
+            })
+            .unwrap();
+            for deposit in deposits {
+                for (pixel, (phi, m)) in self.pixels.iter_mut().zip(deposit) {
+                    pixel.phi += phi;
+                    pixel.m += m;
+                }
+            }
+        }
+        // Update the statistics of pixels
+        for pixel in &mut self.pixels {
+            if pixel.m > 0 {
+                let n_new = pixel.n + RADIUS_REDUCTION * pixel.m as f32;
+                let radius_new = pixel.radius * (n_new / (pixel.n + pixel.m as f32)).sqrt();
+                let beta = pixel.vp.as_ref().unwrap().beta;
+                pixel.tau = (pixel.tau + beta.component_mul(&pixel.phi))
+                    * (radius_new * radius_new / (pixel.radius * pixel.radius));
+                pixel.n = n_new;
+                pixel.radius = radius_new;
+                pixel.phi = Vec3::new(0.0, 0.0, 0.0);
+                pixel.m = 0;
+            }
+            pixel.vp = None;
+        }
+        self.iterations += 1;
+    }
+    /// The current estimate of the image.
+    /// The pixel (i, j) (j = 0 at the top) is at the index i + j * nx.
+    pub fn image(&self) -> Vec<Vec3> {
+        let photons = (self.iterations * self.photons_per_iteration) as f32;
+        let iterations = self.iterations.max(1) as f32;
+        self.pixels
+            .iter()
+            .map(|pixel| {
+                let mut col = pixel.ld / iterations;
+                if photons > 0.0 {
+                    col += pixel.tau / (photons * PI * pixel.radius * pixel.radius);
+                }
+                col
+            })
+            .collect()
+    }
+    /// Traces a camera path until it reaches a non-specular surface, where the visible point is recorded.
+    fn trace_camera_path(
+        scene: &'s Scene,
+        ray: &Ray,
+        max_depth: usize,
+        pixel: &mut SppmPixel<'s>,
+        rng: &mut RandGen,
+    ) {
+        let mut ray = *ray;
+        let mut beta = Vec3::new(1.0, 1.0, 1.0);
+        for _ in 0..=max_depth {
+            let rec = match scene
+                .hitables
+                .hit(&ray, RAY_EPSILON, std::f32::MAX)
+                .map(HitRecord::shaded)
+            {
+                Some(rec) => rec,
+                None => {
+                    pixel.ld += beta.component_mul(&scene.bg.color(&ray));
+                    return;
+                }
+            };
+            // The emission at the diffuse surfaces are estimated by next event estimation,
+            // and it is never reached here since the path stops at the first diffuse surface.
+            pixel.ld += beta.component_mul(&rec.material.emitted(&ray, &rec));
+            let scatter = match rec.material.scatter(&ray, &rec, rng) {
+                Some(scatter) => scatter,
+                None => return,
+            };
+            match scatter.pdf {
+                SingularPdf::Finite { .. } => {
+                    let mut direct = Vec3::new(0.0, 0.0, 0.0);
+                    next_event_estimation(&ray, &rec, scene, &mut direct, rng);
+                    pixel.ld += beta.component_mul(&direct);
+                    pixel.vp = Some(VisiblePoint {
+                        rec: rec,
+                        in_dir: ray.direction,
+                        beta: beta,
+                    });
+                    return;
+                }
+                SingularPdf::Delta { dir } => {
+                    beta = rec.material.brdf(&ray.direction, &dir, &rec, &beta);
+                    ray = Ray::new(&rec.point, &dir, ray.time);
+                }
+            }
+        }
+    }
+    /// Traces a photon and deposits it to the visible points near to the surfaces it hits.
+    /// The first hits are skipped because direct lighting is estimated in the camera pass.
+    fn trace_photon(
+        scene: &Scene,
+        pixels: &[SppmPixel],
+        grid: &VisiblePointGrid,
+        mut ray: Ray,
+        mut beta: Vec3,
+        max_depth: usize,
+        deposits: &mut [(Vec3, u32)],
+        rng: &mut RandGen,
+    ) {
+        for depth in 0..max_depth {
+            let rec = match scene
+                .hitables
+                .hit(&ray, RAY_EPSILON, std::f32::MAX)
+                .map(HitRecord::shaded)
+            {
+                Some(rec) => rec,
+                None => return,
+            };
+            if depth > 0 {
+                for &idx in grid.candidates(&rec.point) {
+                    let pixel = &pixels[idx];
+                    let vp = pixel.vp.as_ref().unwrap();
+                    if (vp.rec.point - rec.point).norm_squared() > pixel.radius * pixel.radius {
+                        continue;
+                    }
+                    // Photons arriving at the back side of the visible point are not gathered.
+                    let normal = &vp.rec.normal;
+                    if vp.in_dir.dot(normal) * ray.direction.dot(normal) <= 0.0 {
+                        continue;
+                    }
+                    let f = vp
+                        .rec
+                        .material
+                        .brdf(&vp.in_dir, &(-ray.direction), &vp.rec, &beta);
+                    deposits[idx].0 += f;
+                    deposits[idx].1 += 1;
+                }
+            }
+            let scatter = match rec.material.scatter(&ray, &rec, rng) {
+                Some(scatter) => scatter,
+                None => return,
+            };
+            let (dir, beta_new) = match scatter.pdf {
+                SingularPdf::Finite { ref pdf } => {
+                    let dir = pdf.generate(rng);
+                    let density = pdf.density(&dir);
+                    if density <= 0.0 {
+                        return;
+                    }
+                    let cosine = rec.normal.dot(&dir.normalize()).abs();
+                    // The BRDF of the adjoint transport: the light comes from -ray.direction.
+                    let f = rec.material.brdf(&(-dir), &(-ray.direction), &rec, &beta);
+                    (dir, f * (cosine / density))
+                }
+                SingularPdf::Delta { dir } => {
+                    (dir, rec.material.brdf(&ray.direction, &dir, &rec, &beta))
+                }
+            };
+            // Russian roulette
+            let max_comp = |v: &Vec3| v[0].max(v[1]).max(v[2]);
+            let q = (1.0 - max_comp(&beta_new) / max_comp(&beta)).max(0.0);
+            if rng.gen::<f32>() < q {
+                return;
+            }
+            beta = beta_new / (1.0 - q);
+            ray = Ray::new(&rec.point, &dir, ray.time);
+        }
+    }
+}
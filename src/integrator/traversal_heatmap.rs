@@ -0,0 +1,212 @@
+// 加速構造（BVH, OBVH, BvhNode）の評価用。
+// 各ピクセル中心を通るカメラレイ1本ごとにトラバースの仕事量（TraversalStats）を数え、
+// 疑似カラーのヒートマップと全体の統計量を出力する。
+
+use crate::aliases::{RandGen, Vec3};
+use crate::camera::Camera;
+use crate::hitable::{Hitable, TraversalStats};
+
+/// Which counter of TraversalStats is visualized.
+#[derive(Clone, Copy, Debug)]
+pub enum TraversalCounter {
+    NodeVisits,
+    AabbTests,
+    PrimitiveTests,
+}
+
+impl TraversalCounter {
+    pub fn all() -> [TraversalCounter; 3] {
+        [
+            TraversalCounter::NodeVisits,
+            TraversalCounter::AabbTests,
+            TraversalCounter::PrimitiveTests,
+        ]
+    }
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraversalCounter::NodeVisits => "nodes",
+            TraversalCounter::AabbTests => "aabbs",
+            TraversalCounter::PrimitiveTests => "primitives",
+        }
+    }
+    fn get(&self, stats: &TraversalStats) -> u32 {
+        match self {
+            TraversalCounter::NodeVisits => stats.node_visits,
+            TraversalCounter::AabbTests => stats.aabb_tests,
+            TraversalCounter::PrimitiveTests => stats.primitive_tests,
+        }
+    }
+}
+
+/// Traversal costs of the primary rays through the pixel centers.
+pub struct TraversalHeatmap {
+    nx: usize,
+    ny: usize,
+    stats: Vec<TraversalStats>, // the pixel (i, j) (j = 0 at the top) is at the index i + j * nx
+    hit_cnt: usize,
+}
+
+/// Maps t in [0, 1] to blue -> cyan -> green -> yellow -> red.
+fn false_color(t: f32) -> Vec3 {
+    let t = t.clamp(0.0, 1.0) * 4.0;
+    let (r, g, b) = if t < 1.0 {
+        (0.0, t, 1.0)
+    } else if t < 2.0 {
+        (0.0, 1.0, 2.0 - t)
+    } else if t < 3.0 {
+        (t - 2.0, 1.0, 0.0)
+    } else {
+        (1.0, 4.0 - t, 0.0)
+    };
+    Vec3::new(r, g, b)
+}
+
+impl TraversalHeatmap {
+    /// Traces a primary ray for each pixel and counts the work done by hitable.hit_with_stats().
+    pub fn measure(
+        hitable: &Hitable,
+        camera: &Camera,
+        nx: usize,
+        ny: usize,
+        thread_cnt: usize,
+    ) -> Self {
+        let rows_per_thread = (ny + thread_cnt.max(1) - 1) / thread_cnt.max(1);
+        let results: Vec<(TraversalStats, bool)> = crossbeam::scope(|scope| {
+            let handles: Vec<_> = (0..ny)
+                .step_by(rows_per_thread.max(1))
+                .map(|begin| {
+                    scope.spawn(move |_| {
+                        let mut rng = RandGen::new();
+                        let mut results = vec![];
+                        for row in begin..(begin + rows_per_thread).min(ny) {
+                            for i in 0..nx {
+                                // row = 0 at the top.
+                                let u = (i as f32 + 0.5) / nx as f32;
+                                let v = (ny as f32 - row as f32 - 0.5) / ny as f32;
+                                let ray = camera.get_ray(u, v, &mut rng);
+                                let mut stats = TraversalStats::default();
+                                let hit = hitable
+                                    .hit_with_stats(&ray, 0.0001, std::f32::MAX, &mut stats)
+                                    .is_some();
+                                results.push((stats, hit));
+                            }
+                        }
+                        results
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|h| h.join().unwrap())
+                .collect()
+        })
+        .unwrap();
+        TraversalHeatmap {
+            nx: nx,
+            ny: ny,
+            hit_cnt: results.iter().filter(|(_, hit)| *hit).count(),
+            stats: results.into_iter().map(|(stats, _)| stats).collect(),
+        }
+    }
+    /// False-color image of the counter, where max (or the maximum over the image if None) is red.
+    /// The pixel (i, j) (j = 0 at the top) is at the index i + j * nx.
+    pub fn image(&self, counter: TraversalCounter, max: Option<f32>) -> Vec<Vec3> {
+        let max = max.unwrap_or_else(|| self.max(counter) as f32).max(1.0);
+        self.stats
+            .iter()
+            .map(|stats| false_color(counter.get(stats) as f32 / max))
+            .collect()
+    }
+    pub fn total(&self) -> TraversalStats {
+        let mut total = TraversalStats::default();
+        for stats in &self.stats {
+            total += *stats;
+        }
+        total
+    }
+    fn max(&self, counter: TraversalCounter) -> u32 {
+        self.stats.iter().map(|s| counter.get(s)).max().unwrap_or(0)
+    }
+    /// Aggregate statistics per primary ray.
+    pub fn summary(&self) -> String {
+        let ray_cnt = self.nx * self.ny;
+        let total = self.total();
+        let mut res = format!(
+            "primary rays: {} ({} hit)\n{:<12}{:>12}{:>12}{:>16}",
+            ray_cnt, self.hit_cnt, "", "mean", "max", "total"
+        );
+        for counter in TraversalCounter::all().iter() {
+            let sum = counter.get(&total);
+            res += &format!(
+                "\n{:<12}{:>12.2}{:>12}{:>16}",
+                counter.name(),
+                sum as f64 / ray_cnt.max(1) as f64,
+                self.max(*counter),
+                sum
+            );
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::camera::Camera;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::bvh_node::BvhNode;
+    use crate::hitable::hitable_list::HitableList;
+    use crate::hitable::obvh::OBVH;
+    use crate::hitable::sphere::Sphere;
+    use crate::hitable::Hitable;
+    use crate::integrator::traversal_heatmap::{TraversalCounter, TraversalHeatmap};
+    use crate::material::lambertian::Lambertian;
+    use crate::texture::constant::ConstantTexture;
+    use std::sync::Arc;
+    #[test]
+    fn traversal_stats_of_accelerators() {
+        let material = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            1.0, 1.0, 1.0,
+        ))));
+        let spheres = || -> Vec<Sphere> {
+            (0..100)
+                .map(|k| {
+                    let center = Vec3::new((k % 10) as f32 - 4.5, (k / 10) as f32 - 4.5, 0.0);
+                    Sphere::new(&center, 0.4, material.clone())
+                })
+                .collect()
+        };
+        let list = HitableList::new(
+            spheres()
+                .into_iter()
+                .map(|s| -> Arc<Hitable> { Arc::new(s) })
+                .collect(),
+        );
+        let bvh_node = BvhNode::new(list.list.clone(), 0.0, 1.0);
+        let obvh = OBVH::from_bvh(BVH::new(spheres(), 0.0, 1.0));
+        let bvh = BVH::new(spheres(), 0.0, 1.0);
+        let camera = Camera::new(
+            &Vec3::new(0.0, 0.0, 20.0),
+            &Vec3::new(0.0, 0.0, 0.0),
+            &Vec3::new(0.0, 1.0, 0.0),
+            45.0,
+            1.0,
+            0.0,
+            1.0,
+        );
+        let brute_force = TraversalHeatmap::measure(&list, &camera, 16, 16, 2);
+        assert_eq!(brute_force.total().primitive_tests, 100 * 16 * 16);
+        for accel in [&bvh as &Hitable, &obvh, &bvh_node].iter() {
+            let heatmap = TraversalHeatmap::measure(*accel, &camera, 16, 16, 2);
+            assert_eq!(heatmap.hit_cnt, brute_force.hit_cnt);
+            let total = heatmap.total();
+            assert!(total.node_visits > 0);
+            assert!(total.aabb_tests > 0);
+            assert!(total.primitive_tests * 10 < brute_force.total().primitive_tests);
+            assert_eq!(
+                heatmap.image(TraversalCounter::NodeVisits, None).len(),
+                16 * 16
+            );
+        }
+    }
+}
@@ -0,0 +1,362 @@
+// シーンファイル（glTFなど）を読み書きするための最小限のJSON。
+// 参考：https://www.rfc-editor.org/rfc/rfc8259
+// オブジェクトはキーの順序を保つため、(キー, 値)のVecとして持つ。
+
+use std::fmt;
+use std::str::FromStr;
+
+// 配列とオブジェクトの入れ子の深さの上限。再帰でスタックが溢れないようにする
+const MAX_DEPTH: usize = 512;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+#[derive(Debug)]
+pub struct Error {
+    pub offset: usize, // byte offset in the text
+    pub message: String,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "byte {}: {}", self.offset, self.message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, Error> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+            depth: 0,
+        };
+        let value = parser.value()?;
+        parser.skip_whitespace();
+        if parser.pos != parser.text.len() {
+            return Err(parser.error("Unexpected characters after the value."));
+        }
+        Ok(value)
+    }
+    /// The value of the key if self is an object.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(members) => members.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Json::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+    pub fn as_f32(&self) -> Option<f32> {
+        self.as_f64().map(|x| x as f32)
+    }
+    /// Some if self is a non-negative integer.
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(x) if 0.0 <= *x && x.fract() == 0.0 => Some(*x as usize),
+            _ => None,
+        }
+    }
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+    pub fn as_array(&self) -> Option<&[Json]> {
+        match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        }
+    }
+    /// The numbers of self if self is an array of numbers.
+    pub fn as_f32_array(&self) -> Option<Vec<f32>> {
+        self.as_array()?.iter().map(Json::as_f32).collect()
+    }
+}
+
+/// Compact JSON text. Non-finite numbers are written as null.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(x) if x.is_finite() => write!(f, "{}", x),
+            Json::Number(_) => write!(f, "null"),
+            Json::String(s) => write_string(f, s),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    depth: usize, // of the arrays and objects containing pos
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> Error {
+        Error {
+            offset: self.pos,
+            message: message.to_string(),
+        }
+    }
+    fn skip_whitespace(&mut self) {
+        while self.pos < self.text.len() && b" \t\r\n".contains(&self.text[self.pos]) {
+            self.pos += 1;
+        }
+    }
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.pos).cloned()
+    }
+    fn expect(&mut self, c: u8) -> Result<(), Error> {
+        if self.peek() != Some(c) {
+            return Err(self.error(&format!("'{}' is expected.", c as char)));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+    fn keyword(&mut self, word: &str, value: Json) -> Result<Json, Error> {
+        if !self.text[self.pos..].starts_with(word.as_bytes()) {
+            return Err(self.error("Invalid value."));
+        }
+        self.pos += word.len();
+        Ok(value)
+    }
+    fn value(&mut self) -> Result<Json, Error> {
+        match self.peek() {
+            None => Err(self.error("Unexpected end of text.")),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(c @ b'[') | Some(c @ b'{') => {
+                if MAX_DEPTH <= self.depth {
+                    return Err(self.error("Too deeply nested."));
+                }
+                self.depth += 1;
+                let value = if c == b'[' {
+                    self.array()
+                } else {
+                    self.object()
+                };
+                self.depth -= 1;
+                value
+            }
+            Some(_) => self.number(),
+        }
+    }
+    fn array(&mut self) -> Result<Json, Error> {
+        self.pos += 1; // '['
+        let mut values = Vec::new();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Json::Array(values));
+        }
+        loop {
+            values.push(self.value()?);
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                _ => break,
+            }
+        }
+        self.expect(b']')?;
+        Ok(Json::Array(values))
+    }
+    fn object(&mut self) -> Result<Json, Error> {
+        self.pos += 1; // '{'
+        let mut members = Vec::new();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Json::Object(members));
+        }
+        loop {
+            if self.peek() != Some(b'"') {
+                return Err(self.error("A key is expected."));
+            }
+            let key = self.string()?;
+            self.expect(b':')?;
+            members.push((key, self.value()?));
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                _ => break,
+            }
+        }
+        self.expect(b'}')?;
+        Ok(Json::Object(members))
+    }
+    fn number(&mut self) -> Result<Json, Error> {
+        let start = self.pos;
+        while self.pos < self.text.len() && b"+-0123456789.eE".contains(&self.text[self.pos]) {
+            self.pos += 1;
+        }
+        // 数値の文字はASCIIなので、必ずUTF-8として正しい
+        let s = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        match f64::from_str(s) {
+            Ok(x) if !s.is_empty() => Ok(Json::Number(x)),
+            _ => {
+                self.pos = start;
+                Err(self.error("Invalid value."))
+            }
+        }
+    }
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok());
+        match digits {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => Err(self.error("Invalid \\u escape.")),
+        }
+    }
+    fn string(&mut self) -> Result<String, Error> {
+        self.pos += 1; // '"'
+        let mut bytes = Vec::new();
+        loop {
+            let c = match self.text.get(self.pos) {
+                Some(&c) => c,
+                None => return Err(self.error("Unterminated string.")),
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = match self.text.get(self.pos) {
+                        Some(&c) => c,
+                        None => return Err(self.error("Unterminated string.")),
+                    };
+                    self.pos += 1;
+                    let c = match escaped {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            // サロゲートペア
+                            if (0xD800..0xDC00).contains(&code)
+                                && self.text[self.pos..].starts_with(b"\\u")
+                            {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                code = 0x10000
+                                    + ((code - 0xD800) << 10)
+                                    + (low.wrapping_sub(0xDC00) & 0x3FF);
+                            }
+                            std::char::from_u32(code).unwrap_or('\u{FFFD}')
+                        }
+                        _ => return Err(self.error("Invalid escape.")),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                _ => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.error("Invalid UTF-8."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::json::Json;
+    #[test]
+    fn parse_values() {
+        let json = Json::parse(
+            r#" { "a": [1, -2.5e1, true, null], "b": {"c": "x\"é\ud83d\ude00\n"}, "d": [] } "#,
+        )
+        .unwrap();
+        assert_eq!(json.get("a").unwrap().as_array().unwrap().len(), 4);
+        assert_eq!(
+            json.get("a").unwrap().as_array().unwrap()[1].as_f64(),
+            Some(-25.0)
+        );
+        assert_eq!(
+            json.get("a").unwrap().as_array().unwrap()[0].as_usize(),
+            Some(1)
+        );
+        assert_eq!(
+            json.get("b").unwrap().get("c").unwrap().as_str(),
+            Some("x\"é😀\n")
+        );
+        assert_eq!(json.get("d"), Some(&Json::Array(Vec::new())));
+        assert!(json.get("e").is_none());
+        assert_eq!(Json::parse("[1, 2").unwrap_err().offset, 5);
+        assert!(Json::parse("{\"a\" 1}").is_err());
+        assert!(Json::parse("1 2").is_err());
+        assert_eq!(Json::parse(&json.to_string()).unwrap(), json);
+    }
+    #[test]
+    fn nesting_limit() {
+        let nested = |depth: usize| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Json::parse(&nested(512)).is_ok());
+        assert_eq!(Json::parse(&nested(513)).unwrap_err().offset, 512);
+        // スタックを溢れさせずにエラーになる
+        assert!(Json::parse(&"[{\"a\":".repeat(100000)).is_err());
+    }
+}
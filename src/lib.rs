@@ -1,70 +1,134 @@
 pub mod aabb;
+pub mod accel_cache;
 pub mod affine;
 pub mod aliases;
 pub mod background;
 pub mod camera;
+pub mod emission_record;
+pub mod export;
+pub mod gltf_file;
 pub mod hit_record;
 pub mod hitable;
+pub mod integrator;
+pub mod json;
 pub mod material;
+pub mod mtl_file;
 pub mod obj_file;
 pub mod onb;
+pub mod pbrt_file;
+pub mod ply_file;
 pub mod pdf;
+pub mod rand_gen;
 pub mod ray;
 pub mod scatter_record;
 pub mod scene;
+pub mod stl_file;
 pub mod texture;
 pub mod util;
 
 use crate::aliases::{RandGen, Vec3};
 use crate::hit_record::HitRecord;
+use crate::hitable::obvh::packet::PACKET_SIZE;
 use crate::pdf::hitable::HitablePdf;
 use crate::pdf::{Pdf, SingularPdf};
 use crate::ray::Ray;
+use crate::scatter_record::ScatterRecord;
 use crate::scene::Scene;
 
-pub fn next_event_estimation(
+/// A shadow ray toward a point on scene.light sampled by next event estimation.
+struct LightSample {
+    shadow_ray: Ray,
+    t_max: f32,         // the shadow ray must not hit anything before the light
+    contribution: Vec3, // added to light_out if the shadow ray is not occluded
+}
+
+fn sample_light(
     ray: &Ray,
     rec: &HitRecord,
     scene: &Scene,
-    light_out: &mut Vec3,
     rng: &mut RandGen,
-) {
-    if scene.light.is_none() {
-        return;
-    }
-    let light = &**(scene.light.as_ref().unwrap());
+) -> Option<LightSample> {
+    let light = &**(scene.light.as_ref()?);
     let pdf = HitablePdf::new(light, &rec.point);
     let dir = pdf.generate(rng);
     let shadow_ray = Ray::new(&rec.point, &dir, ray.time);
-    let light_hit_rec = light.hit(&shadow_ray, 0.0, std::f32::MAX);
     // このhitは無駄な計算である。dirはpdf.generateで作ったものなので、
     // 必ずhitし、その衝突点も衝突時刻もわかっているはず。
     // ・衝突点はemitを計算するために必要。
     // ・衝突時刻は「シャドウレイを遮るものがないか？」を計算するために必要。
     // 一方で、無駄な計算を含むものの、単純で堅牢で拡張性の高い実装であるとは思えるので、変えるかどうか悩む。
-    if light_hit_rec.is_none() {
-        return;
-    }
-    let light_hit_rec = light_hit_rec.as_ref().unwrap();
-    if scene.hitables.is_hit(
-        &shadow_ray,
-        0.0001,
-        light_hit_rec.t - std::f32::MIN_POSITIVE,
-    ) {
-        return;
-    }
+    let light_hit_rec = light.hit(&shadow_ray, 0.0, std::f32::MAX)?.shaded();
     let cosine = rec.normal.dot(&dir.normalize());
     if cosine <= 0.0 {
-        return;
+        return None;
     }
     let density = pdf.density(&dir);
     if density <= 0.0 {
         // Mathematically Prob(density == 0.0) is zero (but occurres sometimes),
         // and therefore just ignoring such cases to avoid Inf is harmless.
-        return;
+        return None;
     }
     let emitted = light_hit_rec.material.emitted(&shadow_ray, &light_hit_rec);
-    *light_out += (cosine / density) * rec.material.brdf(&ray.direction, &dir, rec, &emitted);
+    Some(LightSample {
+        shadow_ray: shadow_ray,
+        t_max: light_hit_rec.t - std::f32::MIN_POSITIVE,
+        contribution: (cosine / density) * rec.material.brdf(&ray.direction, &dir, rec, &emitted),
+    })
+}
+
+pub fn next_event_estimation(
+    ray: &Ray,
+    rec: &HitRecord,
+    scene: &Scene,
+    light_out: &mut Vec3,
+    rng: &mut RandGen,
+) {
+    if let Some(sample) = sample_light(ray, rec, scene, rng) {
+        if !scene
+            .hitables
+            .is_hit(&sample.shadow_ray, 0.0001, sample.t_max)
+        {
+            *light_out += sample.contribution;
+        }
+    }
+}
+
+/// next_event_estimation for each (rays[i], recs[i]), where the shadow rays are traced as a packet.
+/// recs[i] == None is skipped. At most PACKET_SIZE rays.
+pub fn next_event_estimation_packet(
+    rays: &[Ray],
+    recs: &[Option<&HitRecord>],
+    scene: &Scene,
+    light_outs: &mut [Vec3],
+    rng: &mut RandGen,
+) {
+    assert!(rays.len() <= PACKET_SIZE);
+    let mut indices = [0usize; PACKET_SIZE];
+    let mut contributions = [Vec3::new(0.0, 0.0, 0.0); PACKET_SIZE];
+    let mut shadow_rays = [Ray::new(&Vec3::zeros(), &Vec3::zeros(), 0.0); PACKET_SIZE];
+    let mut t_max = [0.0f32; PACKET_SIZE];
+    let mut cnt = 0;
+    for (i, rec) in recs.iter().enumerate() {
+        if let Some(sample) = rec.and_then(|rec| sample_light(&rays[i], rec, scene, rng)) {
+            indices[cnt] = i;
+            contributions[cnt] = sample.contribution;
+            shadow_rays[cnt] = sample.shadow_ray;
+            t_max[cnt] = sample.t_max;
+            cnt += 1;
+        }
+    }
+    let mut occluded = [false; PACKET_SIZE];
+    scene.hitables.is_hit_packet(
+        &shadow_rays[..cnt],
+        0.0001,
+        &t_max[..cnt],
+        &mut occluded[..cnt],
+    );
+    for k in 0..cnt {
+        if !occluded[k] {
+            light_outs[indices[k]] += contributions[k];
+        }
+    }
 }
 
 pub fn calc_color(
@@ -75,7 +139,7 @@ pub fn calc_color(
     is_ray_diffused: bool,
 ) -> Vec3 {
     let mut light_out = Vec3::new(0.0, 0.0, 0.0);
-    let rec = scene.hitables.hit(&ray, 0.0001, std::f32::MAX);
+    let rec = scene.hitables.hit(&ray, 0.0001, std::f32::MAX).map(HitRecord::shaded);
     if rec.is_none() {
         light_out += scene.bg.color(ray);
         return light_out;
@@ -92,12 +156,80 @@ pub fn calc_color(
         return light_out;
     }
     let scatter = scatter.as_ref().unwrap();
+    if let SingularPdf::Finite { .. } = scatter.pdf {
+        // NEE (Next Event Estimation)
+        next_event_estimation(ray, rec, scene, &mut light_out, rng);
+    }
+    light_out += calc_scattered_color(ray, rec, scatter, scene, rng, depth);
+    light_out
+}
+
+/// calc_color for camera rays (is_ray_diffused = false) traced together.
+/// The first hits and the shadow rays of NEE at them are traced as packets, and the rest of the paths one by one.
+/// * `rays` - at most PACKET_SIZE rays.
+/// * `light_outs` - output; light_outs[i] is the color of rays[i].
+pub fn calc_color_packet(
+    rays: &[Ray],
+    scene: &Scene,
+    rng: &mut RandGen,
+    depth: i32,
+    light_outs: &mut [Vec3],
+) {
+    assert!(rays.len() <= PACKET_SIZE);
+    let n = rays.len();
+    let mut t_max = [std::f32::MAX; PACKET_SIZE];
+    let mut recs: [Option<HitRecord>; PACKET_SIZE] = [None; PACKET_SIZE];
+    scene
+        .hitables
+        .hit_packet(rays, 0.0001, &mut t_max[..n], &mut recs[..n]);
+    for rec in &mut recs[..n] {
+        *rec = rec.map(HitRecord::shaded);
+    }
+    let mut scatters: [Option<ScatterRecord>; PACKET_SIZE] = Default::default();
+    for i in 0..n {
+        light_outs[i] = Vec3::new(0.0, 0.0, 0.0);
+        match recs[i] {
+            None => light_outs[i] += scene.bg.color(&rays[i]),
+            Some(ref rec) => {
+                light_outs[i] += rec.material.emitted(&rays[i], rec);
+                if depth > 0 {
+                    scatters[i] = rec.material.scatter(&rays[i], rec, rng);
+                }
+            }
+        }
+    }
+    // NEE (Next Event Estimation)
+    let mut nee_recs: [Option<&HitRecord>; PACKET_SIZE] = [None; PACKET_SIZE];
+    for i in 0..n {
+        if let Some(ScatterRecord {
+            pdf: SingularPdf::Finite { .. },
+        }) = scatters[i]
+        {
+            nee_recs[i] = recs[i].as_ref();
+        }
+    }
+    next_event_estimation_packet(rays, &nee_recs[..n], scene, light_outs, rng);
+    for i in 0..n {
+        if let (Some(ref rec), Some(ref scatter)) = (recs[i], &scatters[i]) {
+            light_outs[i] += calc_scattered_color(&rays[i], rec, scatter, scene, rng, depth);
+        }
+    }
+}
+
+/// The contribution of the scattered ray except NEE.
+fn calc_scattered_color(
+    ray: &Ray,
+    rec: &HitRecord,
+    scatter: &ScatterRecord,
+    scene: &Scene,
+    rng: &mut RandGen,
+    depth: i32,
+) -> Vec3 {
+    let mut light_out = Vec3::new(0.0, 0.0, 0.0);
     match scatter.pdf {
         SingularPdf::Finite {
             pdf: ref material_pdf,
         } => {
-            // NEE (Next Event Estimation)
-            next_event_estimation(ray, rec, scene, &mut light_out, rng);
             let dir = material_pdf.generate(rng);
             let cosine = rec.normal.dot(&dir.normalize());
             if cosine > 0.0 {
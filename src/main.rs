@@ -2,7 +2,13 @@ mod scenes;
 
 use crate::scenes::ScenesType;
 use rand::prelude::Rng;
-use ray::aliases::Vec3;
+use ray::aliases::{RandGen, Vec2, Vec3};
+use ray::export::ExportMesh;
+use ray::integrator::debug::DebugMode;
+use ray::integrator::path_guiding::SdTree;
+use ray::integrator::traversal_heatmap::{TraversalCounter, TraversalHeatmap};
+use ray::integrator::Splat;
+use ray::ray::Ray;
 use ray::scene::Scene;
 use ray::util::duration_to_secs;
 use std::path::Path;
@@ -26,10 +32,32 @@ impl ColorSum {
             sum: vec![Vec3::new(0.0, 0.0, 0.0); (nx as usize) * (ny as usize)],
         }
     }
+    pub fn from_mean(nx: i32, ny: i32, count: i32, mean: &[Vec3]) -> Self {
+        ColorSum {
+            nx: nx,
+            ny: ny,
+            count: count,
+            sum: mean.iter().map(|col| col * count as f32).collect(),
+        }
+    }
     pub fn replace_zero(&mut self) -> ColorSum {
         let (x, y) = (self.nx, self.ny);
         std::mem::replace(self, ColorSum::zero(x, y))
     }
+    /// Adds a contribution at a position on the film.
+    pub fn add_splat(&mut self, splat: &Splat) {
+        let idx = self.pixel_index(&splat.film_position);
+        self.sum[idx] += splat.color;
+    }
+    fn pixel_index(&self, film_position: &Vec2) -> usize {
+        let i = ((film_position[0] * self.nx as f32) as i32)
+            .min(self.nx - 1)
+            .max(0);
+        let j = ((film_position[1] * self.ny as f32) as i32)
+            .min(self.ny - 1)
+            .max(0);
+        (i + (self.ny - j - 1) * self.nx) as usize
+    }
     pub fn add(&mut self, rhs: ColorSum) {
         debug_assert_eq!((self.nx, self.ny), (rhs.nx, rhs.ny));
         self.count += rhs.count;
@@ -70,29 +98,136 @@ impl ColorSum {
     }
 }
 
+/// Integrators selected by the command line arguments.
+#[derive(Clone, Debug)]
+enum IntegratorType {
+    PathTracing,
+    Bdpt,
+    PathGuiding,
+    Sppm,
+    Pssmlt,
+    Debug(DebugMode),
+    TraversalHeatmap { max: Option<f32> },
+    Export { path: String }, // レンダリングせずにシーンを書き出す
+}
+
+const USAGE: &'static str = "Usage: ray [INTEGRATOR]
+INTEGRATOR:
+    pt (default), bdpt, guiding, sppm, pssmlt,
+    ao [RADIUS], normal, geometric-normal, uv, depth [MAX_DISTANCE], barycentric, albedo,
+    heatmap [MAX_COUNT], export [PATH(.obj or .gltf)]";
+
+impl IntegratorType {
+    fn from_args(args: &[String]) -> Result<Self, String> {
+        let param = |idx: usize, default: f32| -> Result<f32, String> {
+            match args.get(idx) {
+                Some(arg) => arg
+                    .parse::<f32>()
+                    .map_err(|_| format!("Invalid parameter: {}", arg)),
+                None => Ok(default),
+            }
+        };
+        let name = match args.get(0) {
+            Some(name) => name.as_str(),
+            None => return Ok(IntegratorType::PathTracing),
+        };
+        Ok(match name {
+            "pt" => IntegratorType::PathTracing,
+            "bdpt" => IntegratorType::Bdpt,
+            "guiding" => IntegratorType::PathGuiding,
+            "sppm" => IntegratorType::Sppm,
+            "pssmlt" => IntegratorType::Pssmlt,
+            "ao" => IntegratorType::Debug(DebugMode::AmbientOcclusion {
+                radius: param(1, 1.0)?,
+            }),
+            "normal" => IntegratorType::Debug(DebugMode::ShadingNormal),
+            "geometric-normal" => IntegratorType::Debug(DebugMode::GeometricNormal),
+            "uv" => IntegratorType::Debug(DebugMode::Uv),
+            "depth" => IntegratorType::Debug(DebugMode::Depth {
+                max_distance: param(1, 100.0)?,
+            }),
+            "barycentric" => IntegratorType::Debug(DebugMode::Barycentric),
+            "albedo" => IntegratorType::Debug(DebugMode::Albedo),
+            "heatmap" => IntegratorType::TraversalHeatmap {
+                max: match args.get(1) {
+                    Some(_) => Some(param(1, 0.0)?),
+                    None => None,
+                },
+            },
+            "export" => IntegratorType::Export {
+                path: args
+                    .get(1)
+                    .cloned()
+                    .unwrap_or_else(|| "debug_images/scene.obj".to_string()),
+            },
+            _ => return Err(format!("Unknown integrator: {}", name)),
+        })
+    }
+}
+
+/// Integrators calculating colors of camera rays, used in trace_rays.
+#[derive(Clone, Copy)]
+enum RayIntegrator<'a> {
+    PathTracing,
+    Bdpt,
+    PathGuiding(&'a SdTree),
+    Debug(DebugMode),
+}
+
 fn trace_rays(
     nx: i32,
     ny: i32,
     ns: i32,
     scene: &Scene,
+    integrator: RayIntegrator,
     report_interval: i32,
     tx: Sender<ColorSum>,
 ) {
-    let mut rng = rand::prelude::thread_rng();
+    let mut rng = RandGen::new();
     let mut color_sum = ColorSum::zero(nx, ny);
+    let mut splats: Vec<Splat> = Vec::new();
     let report = |result: &mut ColorSum| {
         tx.send(result.replace_zero()).unwrap();
     };
     for _ in 0..ns {
-        for i in 0..nx {
-            for j in 0..ny {
-                let u = (i as f32 + rng.gen::<f32>()) / nx as f32;
-                let v = (j as f32 + rng.gen::<f32>()) / ny as f32;
-                let ray = scene.camera.get_ray(u, v, &mut rng);
-                let col = ray::calc_color(&ray, scene, &mut rng, 50 /* depth */, false);
-                let idx = (i + (ny - j - 1) * nx) as usize;
-                color_sum.sum[idx] += col;
+        match integrator {
+            RayIntegrator::PathTracing => {
+                trace_ray_packets(nx, ny, scene, &mut rng, &mut color_sum)
             }
+            RayIntegrator::Bdpt => trace_pixels(
+                nx,
+                ny,
+                scene,
+                &mut rng,
+                &mut color_sum,
+                &mut splats,
+                |ray, rng, splats| {
+                    ray::integrator::bdpt::calc_color(ray, scene, rng, 10 /* depth */, splats)
+                },
+            ),
+            RayIntegrator::PathGuiding(guide) => trace_pixels(
+                nx,
+                ny,
+                scene,
+                &mut rng,
+                &mut color_sum,
+                &mut splats,
+                |ray, rng, _| {
+                    ray::integrator::path_guiding::calc_color(
+                        ray, scene, rng, 50, /* depth */
+                        false, guide, None,
+                    )
+                },
+            ),
+            RayIntegrator::Debug(mode) => trace_pixels(
+                nx,
+                ny,
+                scene,
+                &mut rng,
+                &mut color_sum,
+                &mut splats,
+                |ray, rng, _| ray::integrator::debug::calc_color(ray, scene, rng, mode),
+            ),
         }
         color_sum.count += 1;
         if color_sum.count % report_interval == 0 {
@@ -102,55 +237,94 @@ fn trace_rays(
     report(&mut color_sum);
 }
 
-fn main() {
-    let start_time = Instant::now();
-    const IMAGE_WIDTH: i32 = 200;
-    const IMAGE_HEIGHT: i32 = 200;
-    let aspect = IMAGE_WIDTH as f32 / IMAGE_HEIGHT as f32;
-    const RAYS_PER_PIXEL: i32 = 1000;
-    const THREAD_CNT: i32 = 4;
-    const REPORT_INTERVAL: i32 = 200;
-    const FILE_PATH_PREFIX: &'static str = "debug_images/image_";
-    if get_output_dir_if_exists(Path::new(FILE_PATH_PREFIX)).is_none() {
-        println!(
-            "Wrong FILE_NAME (directory not exist): {}",
-            FILE_PATH_PREFIX
-        );
-        std::process::exit(1);
-    }
-    println!(
-        "FILE_PATH_PREFIX: {}, IMAGE_WIDTH: {}, IMAGE_HEIGHT: {}, RAYS_PER_PIXEL: {}, THREAD_CNT: {}",
-        FILE_PATH_PREFIX, IMAGE_WIDTH, IMAGE_HEIGHT, RAYS_PER_PIXEL, THREAD_CNT
-    );
-    if RAYS_PER_PIXEL % THREAD_CNT != 0 {
-        println!("RAYS_PER_PIXEL must divide THREAD_CNT.");
-        std::process::exit(1);
+/// One sample per pixel by calc_color, which may add splats (e.g., light tracing in BDPT).
+fn trace_pixels<F>(
+    nx: i32,
+    ny: i32,
+    scene: &Scene,
+    rng: &mut RandGen,
+    color_sum: &mut ColorSum,
+    splats: &mut Vec<Splat>,
+    mut calc_color: F,
+) where
+    F: FnMut(&Ray, &mut RandGen, &mut Vec<Splat>) -> Vec3,
+{
+    for i in 0..nx {
+        for j in 0..ny {
+            let u = (i as f32 + rng.gen::<f32>()) / nx as f32;
+            let v = (j as f32 + rng.gen::<f32>()) / ny as f32;
+            let ray = scene.camera.get_ray(u, v, rng);
+            let col = calc_color(&ray, rng, splats);
+            let idx = (i + (ny - j - 1) * nx) as usize;
+            color_sum.sum[idx] += col;
+            for splat in splats.drain(..) {
+                color_sum.add_splat(&splat);
+            }
+        }
     }
-    if REPORT_INTERVAL % THREAD_CNT != 0 {
-        println!("REPORT_INTERVAL must divide THREAD_CNT.");
-        std::process::exit(1);
+}
+
+/// One sample per pixel by path tracing, where the camera rays of PACKET_W x PACKET_H pixels are traced together.
+fn trace_ray_packets(nx: i32, ny: i32, scene: &Scene, rng: &mut RandGen, color_sum: &mut ColorSum) {
+    const PACKET_W: i32 = 4;
+    const PACKET_H: i32 = 2;
+    const N: usize = (PACKET_W * PACKET_H) as usize;
+    let mut pixels = [(0, 0); N];
+    let mut rays = [Ray::new(&Vec3::zeros(), &Vec3::zeros(), 0.0); N];
+    let mut cols = [Vec3::zeros(); N];
+    for i0 in (0..nx).step_by(PACKET_W as usize) {
+        for j0 in (0..ny).step_by(PACKET_H as usize) {
+            let mut cnt = 0;
+            for i in i0..(i0 + PACKET_W).min(nx) {
+                for j in j0..(j0 + PACKET_H).min(ny) {
+                    let u = (i as f32 + rng.gen::<f32>()) / nx as f32;
+                    let v = (j as f32 + rng.gen::<f32>()) / ny as f32;
+                    pixels[cnt] = (i, j);
+                    rays[cnt] = scene.camera.get_ray(u, v, rng);
+                    cnt += 1;
+                }
+            }
+            ray::calc_color_packet(
+                &rays[..cnt],
+                scene,
+                rng,
+                50, /* depth */
+                &mut cols[..cnt],
+            );
+            for (&(i, j), col) in pixels[..cnt].iter().zip(&cols[..cnt]) {
+                let idx = (i + (ny - j - 1) * nx) as usize;
+                color_sum.sum[idx] += col;
+            }
+        }
     }
-    // let scene = scenes::get(ScenesType::CornellBox, aspect);
-    // let scene = scenes::get(ScenesType::ManySpheres, aspect);
-    let scene = scenes::get(ScenesType::Teapot, aspect);
-    // let scene = scenes::get(ScenesType::Menger, aspect);
-    // let scene = scenes::get(ScenesType::JerusalemCube, aspect);
-    let scene_time = duration_to_secs(&start_time.elapsed());
-    println!("Scene constructed. ({:.3} secs elapsed)", scene_time);
-    let rays_per_thread = RAYS_PER_PIXEL / THREAD_CNT;
+}
+
+fn render_by_tracing_rays(
+    scene: &Scene,
+    integrator: RayIntegrator,
+    nx: i32,
+    ny: i32,
+    rays_per_pixel: i32,
+    thread_cnt: i32,
+    report_interval: i32,
+    file_path_prefix: &str,
+    start_time: &Instant,
+) {
+    let rays_per_thread = rays_per_pixel / thread_cnt;
     crossbeam::scope(|scope| {
         let (tx, cx) = channel::<ColorSum>();
         let mut opt_tx = Some(tx);
         let mut threads: Vec<crossbeam::thread::ScopedJoinHandle<()>> = Vec::new();
-        for _ in 0..THREAD_CNT {
+        for _ in 0..thread_cnt {
             let tx = opt_tx.as_ref().unwrap().clone();
             let th = scope.spawn(|_| {
                 trace_rays(
-                    IMAGE_WIDTH,
-                    IMAGE_HEIGHT,
+                    nx,
+                    ny,
                     rays_per_thread,
-                    &scene,
-                    REPORT_INTERVAL / THREAD_CNT,
+                    scene,
+                    integrator,
+                    report_interval / thread_cnt,
                     tx,
                 );
             });
@@ -160,15 +334,15 @@ fn main() {
                        // than save_thread.join() while save_thread waits until every tx is destructed,
                        // and therefore causes deadlock.
         let save_thread = scope.spawn(move |_| {
-            let mut current = ColorSum::zero(IMAGE_WIDTH, IMAGE_HEIGHT);
+            let mut current = ColorSum::zero(nx, ny);
             let mut cnt = 0;
             loop {
                 if let Ok(res) = cx.recv() {
                     current.add(res);
                     cnt += 1;
-                    if cnt % THREAD_CNT == 0 {
+                    if cnt % thread_cnt == 0 {
                         let elapsed_time = start_time.elapsed();
-                        current.save_png(FILE_PATH_PREFIX, &elapsed_time);
+                        current.save_png(file_path_prefix, &elapsed_time);
                     }
                 } else {
                     break;
@@ -181,6 +355,229 @@ fn main() {
         save_thread.join().unwrap();
     })
     .unwrap();
+}
+
+/// Renders by stochastic progressive photon mapping, which refines the whole image in each iteration.
+fn render_sppm(
+    scene: &Scene,
+    nx: i32,
+    ny: i32,
+    iterations: i32,
+    initial_radius: f32,
+    thread_cnt: i32,
+    report_interval: i32,
+    file_path_prefix: &str,
+    start_time: &Instant,
+) {
+    let mut sppm = ray::integrator::sppm::Sppm::new(
+        scene,
+        nx as usize,
+        ny as usize,
+        initial_radius,
+        (nx * ny) as usize, /* photons per iteration */
+        10,                 /* depth */
+        thread_cnt as usize,
+    );
+    for i in 1..=iterations {
+        sppm.iterate();
+        if i % report_interval == 0 || i == iterations {
+            // i is the number of camera rays per pixel
+            let color_sum = ColorSum::from_mean(nx, ny, i, &sppm.image());
+            color_sum.save_png(file_path_prefix, &start_time.elapsed());
+        }
+    }
+}
+
+/// Renders by primary sample space Metropolis light transport on the path tracer.
+fn render_pssmlt(
+    scene: &Scene,
+    nx: i32,
+    ny: i32,
+    mutations_per_pixel: i32,
+    thread_cnt: i32,
+    report_interval: i32,
+    file_path_prefix: &str,
+    start_time: &Instant,
+) {
+    let mut pssmlt = ray::integrator::pssmlt::Pssmlt::new(
+        scene,
+        nx as usize,
+        ny as usize,
+        50,                 /* depth */
+        (nx * ny) as usize, /* bootstrap paths */
+        1000,               /* chains */
+        0.01,               /* sigma */
+        0.3,                /* large step probability */
+        thread_cnt as usize,
+    );
+    for i in 1..=mutations_per_pixel {
+        pssmlt.iterate();
+        if i % report_interval == 0 || i == mutations_per_pixel {
+            let color_sum = ColorSum::from_mean(nx, ny, i, &pssmlt.image());
+            color_sum.save_png(file_path_prefix, &start_time.elapsed());
+        }
+    }
+}
+
+/// Counts the work of traversing the hitables for a primary ray per pixel,
+/// saves the counters as heatmaps and prints the aggregate statistics.
+fn render_traversal_heatmap(
+    scene: &Scene,
+    nx: i32,
+    ny: i32,
+    max: Option<f32>,
+    thread_cnt: i32,
+    file_path_prefix: &str,
+    start_time: &Instant,
+) {
+    let heatmap = TraversalHeatmap::measure(
+        &*scene.hitables,
+        &scene.camera,
+        nx as usize,
+        ny as usize,
+        thread_cnt as usize,
+    );
+    for counter in TraversalCounter::all().iter() {
+        let color_sum = ColorSum::from_mean(nx, ny, 1, &heatmap.image(*counter, max));
+        color_sum.save_png(
+            &format!("{}heatmap_{}_", file_path_prefix, counter.name()),
+            &start_time.elapsed(),
+        );
+    }
+    println!("{}", heatmap.summary());
+}
+
+/// Tessellates the hitables of the scene and writes them as glTF if the extension of path is .gltf, otherwise OBJ+MTL.
+fn export_scene(scene: &Scene, path: &str) {
+    let mesh = ExportMesh::from_hitable(&*scene.hitables);
+    let path = Path::new(path);
+    let result = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("gltf") => mesh.write_gltf(path),
+        _ => mesh.write_obj(path),
+    };
+    match result {
+        Ok(()) => println!(
+            "Exported {} triangles to {} ({} hitables skipped).",
+            mesh.triangle_cnt(),
+            path.display(),
+            mesh.skipped
+        ),
+        Err(e) => println!("Failed to export {}: {}", path.display(), e),
+    }
+}
+
+fn main() {
+    let start_time = Instant::now();
+    const IMAGE_WIDTH: i32 = 200;
+    const IMAGE_HEIGHT: i32 = 200;
+    let aspect = IMAGE_WIDTH as f32 / IMAGE_HEIGHT as f32;
+    const RAYS_PER_PIXEL: i32 = 1000;
+    const THREAD_CNT: i32 = 4;
+    const REPORT_INTERVAL: i32 = 200;
+    const FILE_PATH_PREFIX: &'static str = "debug_images/image_";
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let integrator = match IntegratorType::from_args(&args) {
+        Ok(integrator) => integrator,
+        Err(msg) => {
+            println!("{}\n{}", msg, USAGE);
+            std::process::exit(1);
+        }
+    };
+    if get_output_dir_if_exists(Path::new(FILE_PATH_PREFIX)).is_none() {
+        println!(
+            "Wrong FILE_NAME (directory not exist): {}",
+            FILE_PATH_PREFIX
+        );
+        std::process::exit(1);
+    }
+    println!(
+        "FILE_PATH_PREFIX: {}, IMAGE_WIDTH: {}, IMAGE_HEIGHT: {}, RAYS_PER_PIXEL: {}, THREAD_CNT: {}, INTEGRATOR: {:?}",
+        FILE_PATH_PREFIX, IMAGE_WIDTH, IMAGE_HEIGHT, RAYS_PER_PIXEL, THREAD_CNT, integrator
+    );
+    if RAYS_PER_PIXEL % THREAD_CNT != 0 {
+        println!("RAYS_PER_PIXEL must divide THREAD_CNT.");
+        std::process::exit(1);
+    }
+    if REPORT_INTERVAL % THREAD_CNT != 0 {
+        println!("REPORT_INTERVAL must divide THREAD_CNT.");
+        std::process::exit(1);
+    }
+    // let scene = scenes::get(ScenesType::CornellBox, aspect);
+    // let scene = scenes::get(ScenesType::ManySpheres, aspect);
+    let scene = scenes::get(ScenesType::Teapot, aspect);
+    // let scene = scenes::get(ScenesType::TeapotForest, aspect);
+    // let scene = scenes::get(ScenesType::Menger, aspect);
+    // let scene = scenes::get(ScenesType::JerusalemCube, aspect);
+    let scene_time = duration_to_secs(&start_time.elapsed());
+    println!("Scene constructed. ({:.3} secs elapsed)", scene_time);
+    if let IntegratorType::Export { ref path } = integrator {
+        export_scene(&scene, path);
+        return;
+    }
+    let render_by_tracing_rays = |integrator: RayIntegrator| {
+        render_by_tracing_rays(
+            &scene,
+            integrator,
+            IMAGE_WIDTH,
+            IMAGE_HEIGHT,
+            RAYS_PER_PIXEL,
+            THREAD_CNT,
+            REPORT_INTERVAL,
+            FILE_PATH_PREFIX,
+            &start_time,
+        )
+    };
+    match integrator {
+        IntegratorType::PathTracing => render_by_tracing_rays(RayIntegrator::PathTracing),
+        IntegratorType::Bdpt => render_by_tracing_rays(RayIntegrator::Bdpt),
+        IntegratorType::PathGuiding => {
+            let guide = ray::integrator::path_guiding::train(
+                &scene,
+                IMAGE_WIDTH,
+                IMAGE_HEIGHT,
+                8,  /* passes */
+                50, /* depth */
+                THREAD_CNT,
+            );
+            println!(
+                "SD-tree trained. ({:.3} secs elapsed)",
+                duration_to_secs(&start_time.elapsed())
+            );
+            render_by_tracing_rays(RayIntegrator::PathGuiding(&guide));
+        }
+        IntegratorType::Debug(mode) => render_by_tracing_rays(RayIntegrator::Debug(mode)),
+        IntegratorType::Sppm => render_sppm(
+            &scene,
+            IMAGE_WIDTH,
+            IMAGE_HEIGHT,
+            1000, /* iterations */
+            0.05, /* initial radius */
+            THREAD_CNT,
+            10, /* report interval */
+            FILE_PATH_PREFIX,
+            &start_time,
+        ),
+        IntegratorType::Pssmlt => render_pssmlt(
+            &scene,
+            IMAGE_WIDTH,
+            IMAGE_HEIGHT,
+            RAYS_PER_PIXEL, /* mutations per pixel */
+            THREAD_CNT,
+            REPORT_INTERVAL,
+            FILE_PATH_PREFIX,
+            &start_time,
+        ),
+        IntegratorType::TraversalHeatmap { max } => render_traversal_heatmap(
+            &scene,
+            IMAGE_WIDTH,
+            IMAGE_HEIGHT,
+            max,
+            THREAD_CNT,
+            FILE_PATH_PREFIX,
+            &start_time,
+        ),
+        IntegratorType::Export { .. } => unreachable!(),
+    }
     let elapsed = duration_to_secs(&start_time.elapsed());
     println!(
         "Completed. ({:.3} secs elapsed, {:.3} secs for rendering)",
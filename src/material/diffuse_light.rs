@@ -30,4 +30,7 @@ impl Material for DiffuseLight {
     fn brdf(&self, _in_ray: &Vec3, _out_ray: &Vec3, _rec: &HitRecord, _in_light: &Vec3) -> Vec3 {
         panic!("brdf called for Metal.")
     }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.emit.value(&rec.tex_coord, &rec.point)
+    }
 }
@@ -5,7 +5,10 @@ use crate::pdf::rnd_in_unit_sphere;
 use crate::pdf::SingularPdf;
 use crate::ray::Ray;
 use crate::scatter_record::ScatterRecord;
+use crate::texture::constant::ConstantTexture;
+use crate::texture::Texture;
 use rand::Rng;
+use std::sync::Arc;
 
 /// * `n` - must be normalized
 pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
@@ -27,13 +30,22 @@ pub fn refract(v: &Vec3, n: &Vec3, r: f32) -> Option<Vec3> {
 pub struct Glass {
     pub ref_idx: f32,
     pub fuziness: f32,
+    pub tint: Arc<Texture>, // the color of the transmitted light (reflection is not tinted)
 }
 
 impl Glass {
     pub fn new(ref_idx: f32, fuziness: f32) -> Self {
+        Self::with_tint(
+            ref_idx,
+            fuziness,
+            Arc::new(ConstantTexture::rgb(1.0, 1.0, 1.0)),
+        )
+    }
+    pub fn with_tint(ref_idx: f32, fuziness: f32, tint: Arc<Texture>) -> Self {
         Glass {
             ref_idx: ref_idx,
             fuziness: fuziness,
+            tint: tint,
         }
     }
 }
@@ -84,7 +96,17 @@ impl Material for Glass {
             })
         }
     }
-    fn brdf(&self, _in_ray: &Vec3, _out_ray: &Vec3, _rec: &HitRecord, in_light: &Vec3) -> Vec3 {
-        *in_light
+    fn brdf(&self, in_ray: &Vec3, out_ray: &Vec3, rec: &HitRecord, in_light: &Vec3) -> Vec3 {
+        // 屈折した光だけに色を付ける
+        if in_ray.dot(&rec.normal) * out_ray.dot(&rec.normal) > 0.0 {
+            self.tint
+                .value(&rec.tex_coord, &rec.point)
+                .component_mul(in_light)
+        } else {
+            *in_light
+        }
+    }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.tint.value(&rec.tex_coord, &rec.point)
     }
 }
@@ -31,4 +31,7 @@ impl Material for Lambertian {
     fn brdf(&self, _in_ray: &Vec3, _out_ray: &Vec3, rec: &HitRecord, in_light: &Vec3) -> Vec3 {
         ((1.0 / PI) * self.albedo.value(&rec.tex_coord, &rec.point)).component_mul(in_light)
     }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(&rec.tex_coord, &rec.point)
+    }
 }
@@ -39,6 +39,13 @@ impl LBP {
     }
 }
 
+/// The exponent approximately equivalent to the GGX roughness (perceptual, i.e., α = roughness^2)
+/// by 2 / α^2 - 2 (Walter et al. 2007).
+pub fn exponent_from_roughness(roughness: f32) -> i32 {
+    let alpha = (roughness * roughness).max(1.0e-3);
+    (2.0 / (alpha * alpha) - 2.0).round().clamp(1.0, 10000.0) as i32
+}
+
 impl Material for LBP {
     fn scatter(&self, ray: &Ray, rec: &HitRecord, _rng: &mut RandGen) -> Option<ScatterRecord> {
         let pdf = MixturePdfBox {
@@ -73,4 +80,7 @@ impl Material for LBP {
                 .component_mul(in_light);
         diffuse + specular
     }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.diffuse_coef.value(&rec.tex_coord, &rec.point)
+    }
 }
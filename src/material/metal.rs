@@ -5,16 +5,22 @@ use crate::pdf::rnd_in_unit_sphere;
 use crate::pdf::SingularPdf;
 use crate::ray::Ray;
 use crate::scatter_record::ScatterRecord;
+use crate::texture::constant::ConstantTexture;
+use crate::texture::Texture;
+use std::sync::Arc;
 
 pub struct Metal {
-    pub albedo: Vec3,
+    pub albedo: Arc<Texture>,
     pub fuzziness: f32,
 }
 
 impl Metal {
     pub fn new(albedo: &Vec3, fuzziness: f32) -> Self {
+        Self::with_texture(Arc::new(ConstantTexture::new(albedo)), fuzziness)
+    }
+    pub fn with_texture(albedo: Arc<Texture>, fuzziness: f32) -> Self {
         Metal {
-            albedo: *albedo,
+            albedo: albedo,
             fuzziness: fuzziness,
         }
     }
@@ -36,7 +42,12 @@ impl Material for Metal {
             pdf: SingularPdf::Delta { dir: reflected },
         })
     }
-    fn brdf(&self, _ray: &Vec3, _scattered: &Vec3, _rec: &HitRecord, in_light: &Vec3) -> Vec3 {
-        self.albedo.component_mul(in_light)
+    fn brdf(&self, _ray: &Vec3, _scattered: &Vec3, rec: &HitRecord, in_light: &Vec3) -> Vec3 {
+        self.albedo
+            .value(&rec.tex_coord, &rec.point)
+            .component_mul(in_light)
+    }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.albedo.value(&rec.tex_coord, &rec.point)
     }
 }
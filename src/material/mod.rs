@@ -3,6 +3,7 @@ pub mod glass;
 pub mod lambertian;
 pub mod lbp;
 pub mod metal;
+pub mod normal_map;
 pub mod phong;
 
 use crate::aliases::{RandGen, Vec3};
@@ -22,4 +23,14 @@ pub trait Material: Send + Sync {
     /// * `in_ray` - the direction (not normalized) of the incoming ray carrying outgoing light.
     /// * `out_ray` - the direction (not normalized) of the outgoing ray carrying incoming light.
     fn brdf(&self, in_ray: &Vec3, out_ray: &Vec3, rec: &HitRecord, in_light: &Vec3) -> Vec3;
+    /// The color of the surface (e.g., the diffuse reflectance) for debugging.
+    fn albedo(&self, _rec: &HitRecord) -> Vec3 {
+        Vec3::new(1.0, 1.0, 1.0)
+    }
+    /// The HitRecord with the shading normal of this material (e.g., perturbed by a normal map).
+    /// Integrators call this (by HitRecord::shaded) once at each hit, and pass the result to the other methods
+    /// and use its normal for the cosine terms.
+    fn shading_record<'a>(&self, rec: &HitRecord<'a>) -> HitRecord<'a> {
+        *rec
+    }
 }
@@ -0,0 +1,61 @@
+use crate::aliases::{RandGen, Vec3};
+use crate::hit_record::HitRecord;
+use crate::material::Material;
+use crate::ray::Ray;
+use crate::scatter_record::ScatterRecord;
+use crate::texture::Texture;
+use std::sync::Arc;
+
+/// Replaces the shading normal by a tangent-space normal map in shading_record(), and delegates to the base material.
+/// The map is in the common convention (MikkTSpace, +y of the map = +v), where rgb = (n + 1) / 2 in the frame
+/// (dpdu, dpdv, normal) of HitRecord.
+pub struct NormalMap {
+    base: Arc<Material>,
+    map: Arc<Texture>,
+    strength: f32, // 法線マップのxy成分にかける係数（MTLの-bm）
+}
+
+impl NormalMap {
+    pub fn new(base: Arc<Material>, map: Arc<Texture>, strength: f32) -> Self {
+        NormalMap {
+            base: base,
+            map: map,
+            strength: strength,
+        }
+    }
+    fn perturb<'a>(&self, rec: &HitRecord<'a>) -> HitRecord<'a> {
+        let c = 2.0 * self.map.value(&rec.tex_coord, &rec.point) - Vec3::new(1.0, 1.0, 1.0);
+        let normal = (self.strength * c[0] * rec.dpdu.normalize()
+            + self.strength * c[1] * rec.dpdv.normalize()
+            + c[2] * rec.normal)
+            .normalize();
+        // 接線が縮退している場合や、裏返った法線は使わない
+        let cos = normal.dot(&rec.normal);
+        if cos.is_nan() || cos <= 0.0 {
+            return *rec;
+        }
+        HitRecord {
+            normal: normal,
+            ..*rec
+        }
+    }
+}
+
+impl Material for NormalMap {
+    // recはshading_record()で法線を置き換え済み
+    fn scatter(&self, ray: &Ray, rec: &HitRecord, rng: &mut RandGen) -> Option<ScatterRecord> {
+        self.base.scatter(ray, rec, rng)
+    }
+    fn emitted(&self, ray_in: &Ray, rec: &HitRecord) -> Vec3 {
+        self.base.emitted(ray_in, rec)
+    }
+    fn brdf(&self, in_ray: &Vec3, out_ray: &Vec3, rec: &HitRecord, in_light: &Vec3) -> Vec3 {
+        self.base.brdf(in_ray, out_ray, rec, in_light)
+    }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.base.albedo(rec)
+    }
+    fn shading_record<'a>(&self, rec: &HitRecord<'a>) -> HitRecord<'a> {
+        self.base.shading_record(&self.perturb(rec))
+    }
+}
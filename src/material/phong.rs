@@ -75,4 +75,7 @@ impl Material for Phong {
             * in_light;
         diffuse + specular
     }
+    fn albedo(&self, rec: &HitRecord) -> Vec3 {
+        self.k_diffuse * self.diffuse_texture.value(&rec.tex_coord, &rec.point)
+    }
 }
@@ -0,0 +1,477 @@
+// .objのmtllibで参照されるマテリアルライブラリ(.mtl)。
+// 参考：
+// http://paulbourke.net/dataformats/mtl/
+// PBR拡張(Pr, Pm, Ke, norm): http://exocortex.com/blog/extending_wavefront_mtl_to_support_pbr
+// マテリアルは次の順に決める。
+//   Keが0でない -> DiffuseLight
+//   illumが屈折・透過を表す(4, 6, 7, 9) -> Glass（Tfがあればその色、なければKdかmap_Kdを屈折光の色にする）
+//   Pm >= 0.5 -> Metal（Kdかmap_Kdを反射率にする）
+//   Ks, map_Ks, Prのどれかがあり、illumがハイライトなしでない -> LBP
+//   それ以外 -> Lambertian
+// d < 1（Tr > 0）は葉などの部分的な被覆を表すことが多いので、illumが透過を表さなければ無視して警告を出す。
+// map_Bumpまたはnormは接空間の法線マップとして、NormalMapで包む。
+
+use crate::aliases::Vec3;
+use crate::material::diffuse_light::DiffuseLight;
+use crate::material::glass::Glass;
+use crate::material::lambertian::Lambertian;
+use crate::material::lbp::{exponent_from_roughness, LBP};
+use crate::material::metal::Metal;
+use crate::material::normal_map::NormalMap;
+use crate::material::Material;
+use crate::obj_file::Error;
+use crate::texture::constant::ConstantTexture;
+use crate::texture::image::ImageTexture;
+use crate::texture::Texture;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+pub struct MtlFile {
+    pub materials: Vec<MtlMaterial>,
+    pub warnings: Vec<String>, // "line: message" of ignored statements and options
+}
+
+/// A texture map statement such as "map_Kd -bm 0.5 file.png". Only -bm of the options is used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MtlMap {
+    pub path: PathBuf, // relative to the .mtl file
+    pub bump_multiplier: f32,
+}
+
+/// A newmtl block. Values not given in the file have the defaults of the format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MtlMaterial {
+    pub name: String,
+    pub diffuse: Vec3,                // Kd
+    pub specular: Vec3,               // Ks
+    pub exponent: f32,                // Ns
+    pub emission: Vec3,               // Ke
+    pub ior: Option<f32>,             // Ni
+    pub dissolve: f32,                // d, or 1 - Tr
+    pub transmission: Option<Vec3>,   // Tf
+    pub illum: Option<u32>,           // illumination model
+    pub roughness: Option<f32>,       // Pr
+    pub metallic: Option<f32>,        // Pm
+    pub diffuse_map: Option<MtlMap>,  // map_Kd
+    pub specular_map: Option<MtlMap>, // map_Ks
+    pub normal_map: Option<MtlMap>,   // map_Bump, bump, or norm
+}
+
+impl MtlMaterial {
+    fn new(name: &str) -> Self {
+        MtlMaterial {
+            name: name.to_string(),
+            diffuse: Vec3::new(0.8, 0.8, 0.8),
+            specular: Vec3::new(0.0, 0.0, 0.0),
+            exponent: 1.0,
+            emission: Vec3::new(0.0, 0.0, 0.0),
+            ior: None,
+            dissolve: 1.0,
+            transmission: None,
+            illum: None,
+            roughness: None,
+            metallic: None,
+            diffuse_map: None,
+            specular_map: None,
+            normal_map: None,
+        }
+    }
+    /// Converts to a Material. Texture maps are loaded from dir, sharing the ones already in textures.
+    pub fn to_material(
+        &self,
+        dir: &Path,
+        textures: &mut HashMap<PathBuf, Arc<Texture>>,
+    ) -> Result<Arc<Material>, Error> {
+        if self.emission != Vec3::new(0.0, 0.0, 0.0) {
+            return Ok(Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
+                &self.emission,
+            )))));
+        }
+        let roughness = self.roughness.unwrap_or(0.0);
+        let mut load = |map: &Option<MtlMap>, constant: &Vec3| -> Result<Arc<Texture>, Error> {
+            match map {
+                Some(map) => Self::load_texture(&dir.join(&map.path), textures),
+                None => Ok(Arc::new(ConstantTexture::new(constant))),
+            }
+        };
+        // map_Kdがあれば、Kdを掛けずにそのまま使う（多くのエクスポーターの想定）
+        let diffuse = load(&self.diffuse_map, &self.diffuse)?;
+        if self.is_transparent() {
+            let tint: Arc<Texture> = match self.transmission {
+                Some(ref tf) => Arc::new(ConstantTexture::new(tf)),
+                None => diffuse,
+            };
+            return Ok(Arc::new(Glass::with_tint(
+                self.ior.unwrap_or(1.5),
+                roughness,
+                tint,
+            )));
+        }
+        let material: Arc<Material> = if 0.5 <= self.metallic.unwrap_or(0.0) {
+            Arc::new(Metal::with_texture(diffuse, roughness))
+        } else if self.illum.is_none_or(|illum| 2 <= illum)
+            && (self.specular != Vec3::new(0.0, 0.0, 0.0)
+                || self.specular_map.is_some()
+                || self.roughness.is_some())
+        {
+            // 非金属のPBRマテリアルのKsは、誘電体の典型的な反射率
+            let specular =
+                if self.specular == Vec3::new(0.0, 0.0, 0.0) && self.specular_map.is_none() {
+                    Vec3::new(0.04, 0.04, 0.04)
+                } else {
+                    self.specular
+                };
+            let specular = load(&self.specular_map, &specular)?;
+            Arc::new(LBP::new(
+                diffuse,
+                specular,
+                self.blinn_phong_exponent(),
+                0.0,
+            ))
+        } else {
+            Arc::new(Lambertian::new(diffuse))
+        };
+        match self.normal_map {
+            Some(ref map) => Ok(Arc::new(NormalMap::new(
+                material,
+                Self::load_texture(&dir.join(&map.path), textures)?,
+                map.bump_multiplier,
+            ))),
+            None => Ok(material),
+        }
+    }
+    /// Whether illum is one of the models with refraction or transparency (4, 6, 7, 9).
+    fn is_transparent(&self) -> bool {
+        self.illum
+            .is_some_and(|illum| [4, 6, 7, 9].contains(&illum))
+    }
+    /// Ns, or the exponent equivalent to Pr.
+    fn blinn_phong_exponent(&self) -> i32 {
+        match self.roughness {
+            Some(roughness) => exponent_from_roughness(roughness),
+            None => self.exponent.round().clamp(1.0, 10000.0) as i32,
+        }
+    }
+    fn load_texture(
+        path: &Path,
+        textures: &mut HashMap<PathBuf, Arc<Texture>>,
+    ) -> Result<Arc<Texture>, Error> {
+        if let Some(texture) = textures.get(path) {
+            return Ok(texture.clone());
+        }
+        let texture: Arc<Texture> =
+            Arc::new(ImageTexture::open(path).map_err(|e| Error::Image(path.to_path_buf(), e))?);
+        textures.insert(path.to_path_buf(), texture.clone());
+        Ok(texture)
+    }
+}
+
+impl MtlFile {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Self::from_buf_reader(BufReader::new(File::open(path)?))
+    }
+    pub fn from_buf_reader(reader: impl BufRead) -> Result<Self, Error> {
+        let mut materials: Vec<MtlMaterial> = Vec::new();
+        let mut warnings: Vec<String> = Vec::new();
+        let mut dissolve_lines: Vec<Option<usize>> = Vec::new(); // of d or Tr in each material
+        for (i, line) in reader.lines().enumerate() {
+            let line = line?;
+            let line_no = i + 1;
+            let content = match line.find('#') {
+                Some(i) => &line[..i],
+                None => &line,
+            };
+            let mut columns = content.split_whitespace();
+            let statement = match columns.next() {
+                Some(statement) => statement,
+                None => continue,
+            };
+            let args: Vec<&str> = columns.collect();
+            if statement == "newmtl" {
+                if args.len() != 1 {
+                    return Err(Error::Parse(line_no, "Invalid newmtl.".to_string()));
+                }
+                materials.push(MtlMaterial::new(args[0]));
+                dissolve_lines.push(None);
+                continue;
+            }
+            let material = match materials.last_mut() {
+                Some(material) => material,
+                None => {
+                    return Err(Error::Parse(
+                        line_no,
+                        format!("'{}' before newmtl.", statement),
+                    ));
+                }
+            };
+            match statement {
+                "Kd" => material.diffuse = parse_color(&args, line_no)?,
+                "Ks" => material.specular = parse_color(&args, line_no)?,
+                "Ke" => material.emission = parse_color(&args, line_no)?,
+                "Ns" => material.exponent = parse_scalar(&args, line_no)?,
+                "Ni" => material.ior = Some(parse_scalar(&args, line_no)?),
+                "d" => {
+                    material.dissolve = match args[..] {
+                        // 視線と面の角度で変わる不透明度は扱わず、一様なdとみなす
+                        ["-halo", d] => {
+                            warnings.push(format!("{}: 'd -halo' is treated as 'd'.", line_no));
+                            parse_f32(d, line_no)?
+                        }
+                        _ => parse_scalar(&args, line_no)?,
+                    };
+                    *dissolve_lines.last_mut().unwrap() = Some(line_no);
+                }
+                "Tr" => {
+                    material.dissolve = 1.0 - parse_scalar(&args, line_no)?;
+                    *dissolve_lines.last_mut().unwrap() = Some(line_no);
+                }
+                "Tf" => material.transmission = Some(parse_color(&args, line_no)?),
+                "Pr" => material.roughness = Some(parse_scalar(&args, line_no)?),
+                "Pm" => material.metallic = Some(parse_scalar(&args, line_no)?),
+                "illum" => {
+                    material.illum = Some(match args[..] {
+                        [illum] => u32::from_str(illum).map_err(|e| {
+                            Error::Parse(line_no, format!("Invalid illum '{}': {}.", illum, e))
+                        })?,
+                        _ => return Err(Error::Parse(line_no, "Invalid illum.".to_string())),
+                    })
+                }
+                "map_Kd" => material.diffuse_map = Some(parse_map(&args, line_no)?),
+                "map_Ks" => material.specular_map = Some(parse_map(&args, line_no)?),
+                "map_Bump" | "map_bump" | "bump" | "norm" => {
+                    material.normal_map = Some(parse_map(&args, line_no)?)
+                }
+                // 環境光、他のマップ、PBR拡張の残りは使わない
+                "Ka" | "sharpness" | "map_Ka" | "map_Ns" | "map_d" | "map_Ke" | "map_Pr"
+                | "map_Pm" | "disp" | "decal" | "refl" | "Ps" | "Pc" | "Pcr" | "aniso"
+                | "anisor" | "map_Ps" => {}
+                // 独自拡張などは読み飛ばす
+                _ => warnings.push(format!("{}: Unknown statement '{}'.", line_no, statement)),
+            }
+        }
+        for (material, line) in materials.iter().zip(dissolve_lines) {
+            match line {
+                Some(line_no) if material.dissolve < 1.0 && !material.is_transparent() => warnings
+                    .push(format!(
+                        "{}: 'd' without illum 4, 6, 7 or 9 is ignored.",
+                        line_no
+                    )),
+                _ => {}
+            }
+        }
+        Ok(MtlFile {
+            materials: materials,
+            warnings: warnings,
+        })
+    }
+    /// Converts all the materials. Texture maps are loaded from dir (the directory of the .mtl file).
+    pub fn to_materials(
+        &self,
+        dir: &Path,
+        textures: &mut HashMap<PathBuf, Arc<Texture>>,
+    ) -> Result<HashMap<String, Arc<Material>>, Error> {
+        let mut materials = HashMap::new();
+        for material in &self.materials {
+            materials.insert(material.name.clone(), material.to_material(dir, textures)?);
+        }
+        Ok(materials)
+    }
+}
+
+fn parse_f32(column: &str, line_no: usize) -> Result<f32, Error> {
+    f32::from_str(column)
+        .map_err(|e| Error::Parse(line_no, format!("Invalid number '{}': {}.", column, e)))
+}
+
+fn parse_scalar(args: &[&str], line_no: usize) -> Result<f32, Error> {
+    match args {
+        [x] => parse_f32(x, line_no),
+        _ => Err(Error::Parse(line_no, "Expected a number.".to_string())),
+    }
+}
+
+/// "r g b", or "r" meaning "r r r". Spectral (spectral file.rfl) and CIEXYZ (xyz x y z) colors are not supported.
+fn parse_color(args: &[&str], line_no: usize) -> Result<Vec3, Error> {
+    match args {
+        [r] => {
+            let r = parse_f32(r, line_no)?;
+            Ok(Vec3::new(r, r, r))
+        }
+        [r, g, b] => Ok(Vec3::new(
+            parse_f32(r, line_no)?,
+            parse_f32(g, line_no)?,
+            parse_f32(b, line_no)?,
+        )),
+        [kind, ..] if *kind == "spectral" || *kind == "xyz" => {
+            Err(Error::Unsupported(line_no, kind.to_string()))
+        }
+        _ => Err(Error::Parse(line_no, "Invalid color.".to_string())),
+    }
+}
+
+/// "[-option args...] file". The file name is the last column.
+fn parse_map(args: &[&str], line_no: usize) -> Result<MtlMap, Error> {
+    let path = match args.last() {
+        Some(path) => PathBuf::from(path),
+        None => return Err(Error::Parse(line_no, "No file name.".to_string())),
+    };
+    let bump_multiplier = match args.iter().position(|&a| a == "-bm") {
+        Some(i) if i + 2 < args.len() => parse_f32(args[i + 1], line_no)?,
+        Some(_) => return Err(Error::Parse(line_no, "Invalid -bm.".to_string())),
+        None => 1.0,
+    };
+    Ok(MtlMap {
+        path: path,
+        bump_multiplier: bump_multiplier,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::{Vec2, Vec3};
+    use crate::hit_record::HitRecord;
+    use crate::material::lambertian::Lambertian;
+    use crate::material::Material;
+    use crate::mtl_file::MtlFile;
+    use crate::obj_file::{Error, ObjFile};
+    use crate::ray::Ray;
+    use crate::texture::constant::ConstantTexture;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    #[test]
+    fn import_materials() {
+        let dir = std::env::temp_dir().join(format!("ray-mtl-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("tex")).unwrap();
+        image::RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0]))
+            .save(dir.join("tex/red.png"))
+            .unwrap();
+        image::RgbImage::from_pixel(2, 2, image::Rgb([192, 128, 255]))
+            .save(dir.join("tex/tilted.png"))
+            .unwrap();
+        let mtl = "# comment\n\
+                   newmtl light\n\
+                   Kd 0 0 0\n\
+                   Ke 4 3 2\n\
+                   newmtl glass\n\
+                   Ni 1.33\n\
+                   Tr 0.9\n\
+                   d -halo 0.1\n\
+                   illum 7\n\
+                   Tf 0.5 1 1\n\
+                   newmtl textured\n\
+                   Ka 0.1\n\
+                   Kd 0.2 0.3 0.4\n\
+                   Ks 0.5\n\
+                   Ns 100\n\
+                   illum 2\n\
+                   map_Kd -o 0 0 tex/red.png\n\
+                   map_Bump -bm 0.5 tex/tilted.png\n\
+                   newmtl pbr\n\
+                   Kd 0.9 0.8 0.7\n\
+                   Pr 0.5\n\
+                   Pm 1\n\
+                   map_Kd tex/red.png\n\
+                   vendor_ext 1 2\n\
+                   newmtl leaf\n\
+                   Kd 0.1 0.6 0.1\n\
+                   d 0.99\n";
+        fs::write(dir.join("a.mtl"), mtl).unwrap();
+        let parsed = MtlFile::from_buf_reader(Cursor::new(mtl)).unwrap();
+        let names: Vec<&str> = parsed.materials.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["light", "glass", "textured", "pbr", "leaf"]);
+        assert_eq!(parsed.materials[0].emission, Vec3::new(4.0, 3.0, 2.0));
+        assert_eq!(parsed.materials[1].ior, Some(1.33));
+        assert!((parsed.materials[1].dissolve - 0.1).abs() < 1.0e-6);
+        assert_eq!(
+            parsed.materials[1].transmission,
+            Some(Vec3::new(0.5, 1.0, 1.0))
+        );
+        let textured = &parsed.materials[2];
+        assert_eq!(textured.specular, Vec3::new(0.5, 0.5, 0.5));
+        assert_eq!(textured.illum, Some(2));
+        assert_eq!(
+            textured.diffuse_map.as_ref().unwrap().path,
+            PathBuf::from("tex/red.png")
+        );
+        assert_eq!(textured.normal_map.as_ref().unwrap().bump_multiplier, 0.5);
+        assert_eq!(parsed.materials[3].roughness, Some(0.5));
+        assert_eq!(parsed.warnings.len(), 3);
+        assert!(parsed.warnings[0].starts_with("8: "));
+        assert!(parsed.warnings[1].contains("vendor_ext"));
+        // illumが透過を表さないdは被覆率として無視する
+        assert!(parsed.warnings[2].starts_with("27: "));
+        match MtlFile::from_buf_reader(Cursor::new("Kd 1 1 1\n")) {
+            Err(Error::Parse(1, _)) => {}
+            _ => panic!("Kd before newmtl must be an error."),
+        }
+
+        let obj = "mtllib a.mtl\n\
+                   v 0 0 0\nv 1 0 0\nv 0 1 0\n\
+                   usemtl light\nf 1 2 3\n\
+                   usemtl textured\nf 1 2 3\n\
+                   usemtl pbr\nf 1 2 3\n\
+                   usemtl glass\nf 1 2 3\n\
+                   usemtl leaf\nf 1 2 3\n\
+                   usemtl unknown\nf 1 2 3\n";
+        fs::write(dir.join("a.obj"), obj).unwrap();
+        let default_material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(
+            ConstantTexture::rgb(0.0, 1.0, 0.0),
+        )));
+        let meshes = ObjFile::load_meshes(&dir.join("a.obj"), default_material).unwrap();
+        assert_eq!(meshes.len(), 6);
+
+        let materials = ObjFile::from_file(&dir.join("a.obj"))
+            .unwrap()
+            .load_materials(&dir)
+            .unwrap();
+        let record = |material: &Arc<Material>| {
+            let rec = HitRecord {
+                t: 1.0,
+                point: Vec3::new(0.0, 0.0, 0.0),
+                tex_coord: Vec2::new(0.5, 0.5),
+                normal: Vec3::new(0.0, 0.0, 1.0),
+                geometric_normal: Vec3::new(0.0, 0.0, 1.0),
+                barycentric: None,
+                dpdu: Vec3::new(1.0, 0.0, 0.0),
+                dpdv: Vec3::new(0.0, 1.0, 0.0),
+                material: material.as_ref(),
+            };
+            let ray = Ray::new(&Vec3::new(0.0, 0.0, 1.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+            (
+                material.emitted(&ray, &rec),
+                material.albedo(&rec),
+                rec.shaded().normal,
+            )
+        };
+        assert_eq!(record(&materials["light"]).0, Vec3::new(4.0, 3.0, 2.0));
+        // map_KdはKdを置き換える
+        let albedo = record(&materials["textured"]).1;
+        assert!((albedo - Vec3::new(1.0, 0.0, 0.0)).norm() < 1.0e-3);
+        let albedo = record(&materials["pbr"]).1;
+        assert!((albedo - Vec3::new(1.0, 0.0, 0.0)).norm() < 1.0e-3);
+        // Tfは屈折光の色になる
+        assert_eq!(record(&materials["glass"]).1, Vec3::new(0.5, 1.0, 1.0));
+        assert_eq!(record(&materials["leaf"]).1, Vec3::new(0.1, 0.6, 0.1));
+        // 法線マップは、積分器が使うshading_recordの法線を+u側に傾ける
+        let normal = record(&materials["textured"]).2;
+        let expected = Vec3::new(0.5 * (2.0 * 192.0 / 255.0 - 1.0), 0.0, 1.0).normalize();
+        assert!((normal - expected).norm() < 1.0e-2, "{:?}", normal);
+        assert_eq!(record(&materials["pbr"]).2, Vec3::new(0.0, 0.0, 1.0));
+
+        fs::remove_file(dir.join("tex/red.png")).unwrap();
+        match ObjFile::from_file(&dir.join("a.obj"))
+            .unwrap()
+            .load_materials(&dir)
+        {
+            Err(Error::Image(path, _)) => assert_eq!(path, dir.join("tex/red.png")),
+            _ => panic!("A missing texture must be an error."),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
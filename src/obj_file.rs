@@ -1,51 +1,74 @@
 // 参考：
 // https://www.hiramine.com/programming/3dmodelfileformat/objfileformat.html
+// http://paulbourke.net/dataformats/obj/
+// v, vt, vnはファイル全体で通し番号を持ち、全てのグループの面から参照できる。
+// そのため頂点の配列(ObjVertexPool)はグループ間で共有し、面は読み込み時に解決した0始まりの番号を持つ。
 
 use crate::aliases::{Vec2, Vec3};
 use crate::hitable::triangle::Triangle;
+use crate::hitable::triangle_mesh::TriangleMesh;
 use crate::hitable::Hitable;
 use crate::material::Material;
-use crate::util::HashVec3;
+use crate::mtl_file::MtlFile;
+use crate::util::unify_points;
 use std::collections::HashMap;
-use std::collections::VecDeque;
 use std::f32;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::BufRead;
 use std::io::BufReader;
-use std::num;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
 
 pub struct ObjMaterialFile {
-    #[allow(dead_code)]
     name: String,
 }
 
+impl ObjMaterialFile {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
 pub struct ObjFile {
-    pub material_file: Option<ObjMaterialFile>,
+    pub material_files: Vec<ObjMaterialFile>,
     pub groups: Vec<ObjGroup>,
 }
 
-pub struct ObjGroup {
-    name: Option<String>,
-    #[allow(dead_code)]
-    material: Option<ObjMaterial>,
+/// Vertex attributes of a file, which are referred by the faces of all the groups.
+#[derive(Clone, Default)]
+pub struct ObjVertexPool {
     vertices: Vec<Vec3>,
     tex_coords: Vec<Vec2>,
     normals: Vec<Vec3>,
+}
+
+/// Faces sharing the same group name, object name and material.
+/// A new ObjGroup begins at each g, o and usemtl statement.
+pub struct ObjGroup {
+    name: Option<String>,
+    object: Option<String>,
+    material: Option<ObjMaterial>,
+    pool: Arc<ObjVertexPool>, // 変更するときはlocal_pool()で、このグループが参照する頂点だけのpoolにする
     faces: Vec<ObjFace>,
 }
 
+#[derive(Clone)]
 pub struct ObjMaterial {
-    #[allow(dead_code)]
     name: String,
 }
 
+impl ObjMaterial {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Indices to ObjVertexPool.
 pub struct ObjVertex {
     vertex: usize,
-    #[allow(dead_code)]
     tex_coord: Option<usize>,
     normal: Option<usize>,
 }
@@ -53,40 +76,113 @@ pub struct ObjVertex {
 pub struct ObjFace(Vec<ObjVertex>);
 
 impl ObjFace {
-    pub fn vertex_indices_for_triangle(&self, triangle_idx: usize) -> (usize, usize, usize) {
-        (
-            self.0[0].vertex,
-            self.0[triangle_idx + 1].vertex,
-            self.0[triangle_idx + 2].vertex,
-        )
+    /// Decomposes the polygon into triangles. See triangulate_polygon().
+    /// * `return` - triangles as indices of the vertices of this face
+    pub fn triangulate(&self, vertices: &[Vec3]) -> Vec<[usize; 3]> {
+        let points: Vec<Vec3> = self.0.iter().map(|v| vertices[v.vertex]).collect();
+        triangulate_polygon(&points)
     }
 }
 
-// ToDo: implement Error trait. use custom_error! macro.
+/// Decomposes a planar polygon into triangles by ear clipping, so that non-convex polygons are also correct.
+/// The triangles have the same orientation as the polygon.
+/// Degenerate or self-intersecting parts, where no ear is found, are decomposed into a triangle fan.
+/// * `return` - triangles as indices of points
+pub fn triangulate_polygon(points: &[Vec3]) -> Vec<[usize; 3]> {
+    let n = points.len();
+    if n < 3 {
+        return vec![];
+    }
+    let mut triangles = Vec::with_capacity(n - 2);
+    let mut remaining: Vec<usize> = (0..n).collect();
+    // Newell法で求めた法線の成分が最大の軸を落として平面に射影し、反時計回りになるように向きを揃える
+    let mut normal = Vec3::zeros();
+    for i in 0..n {
+        let (p, q) = (points[i], points[(i + 1) % n]);
+        normal += Vec3::new(
+            (p.y - q.y) * (p.z + q.z),
+            (p.z - q.z) * (p.x + q.x),
+            (p.x - q.x) * (p.y + q.y),
+        );
+    }
+    let axis = normal.iamax();
+    if 3 < n && normal[axis] != 0.0 {
+        let (ax, ay) = ((axis + 1) % 3, (axis + 2) % 3);
+        let sign = normal[axis].signum();
+        let projected: Vec<Vec2> = points
+            .iter()
+            .map(|p| Vec2::new(p[ax], sign * p[ay]))
+            .collect();
+        let cross =
+            |o: &Vec2, a: &Vec2, b: &Vec2| (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x);
+        while remaining.len() > 3 {
+            let m = remaining.len();
+            let is_ear = |k: usize| {
+                let (a, b, c) = (
+                    remaining[(k + m - 1) % m],
+                    remaining[k],
+                    remaining[(k + 1) % m],
+                );
+                let (pa, pb, pc) = (&projected[a], &projected[b], &projected[c]);
+                if cross(pa, pb, pc) <= 0.0 {
+                    return false;
+                }
+                // 他の頂点が三角形の内部（境界を含む）にあれば耳ではない
+                remaining.iter().all(|&i| {
+                    let p = &projected[i];
+                    p == pa
+                        || p == pb
+                        || p == pc
+                        || cross(pa, pb, p) < 0.0
+                        || cross(pb, pc, p) < 0.0
+                        || cross(pc, pa, p) < 0.0
+                })
+            };
+            match (0..m).find(|&k| is_ear(k)) {
+                Some(k) => {
+                    triangles.push([
+                        remaining[(k + m - 1) % m],
+                        remaining[k],
+                        remaining[(k + 1) % m],
+                    ]);
+                    remaining.remove(k);
+                }
+                None => break,
+            }
+        }
+    }
+    for i in 1..remaining.len() - 1 {
+        triangles.push([remaining[0], remaining[i], remaining[i + 1]]);
+    }
+    triangles
+}
+
 #[derive(Debug)]
 pub enum Error {
     IO(io::Error),
-    Parse(String),
-    Unsupported(String),
-    ParseFloat(num::ParseFloatError),
-    ParseInt(num::ParseIntError),
+    Parse(usize, String), // (line number, message). A line continued by '\' has the number of its first line.
+    Unsupported(usize, String), // (line number, statement), e.g., free-form curves and surfaces
+    Image(PathBuf, image::ImageError), // failed to load a texture map
 }
 
-impl From<std::io::Error> for Error {
-    fn from(io_e: io::Error) -> Self {
-        Error::IO(io_e)
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "{}", e),
+            Error::Parse(line, message) => write!(f, "line {}: {}", line, message),
+            Error::Unsupported(line, statement) => {
+                write!(f, "line {}: '{}' is not supported.", line, statement)
+            }
+            Error::Image(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
     }
 }
 
-impl From<num::ParseFloatError> for Error {
-    fn from(pfe: num::ParseFloatError) -> Self {
-        Error::ParseFloat(pfe)
-    }
-}
+impl std::error::Error for Error {}
 
-impl From<num::ParseIntError> for Error {
-    fn from(pie: num::ParseIntError) -> Self {
-        Error::ParseInt(pie)
+impl From<std::io::Error> for Error {
+    fn from(io_e: io::Error) -> Self {
+        Error::IO(io_e)
     }
 }
 
@@ -94,284 +190,406 @@ impl ObjFile {
     pub fn from_file(path: &Path) -> Result<Self, Error> {
         Self::from_buf_reader(BufReader::new(File::open(path)?))
     }
-    pub fn from_buf_reader(reader: impl BufRead) -> Result<Self, Error> {
-        let mut obj_file = ObjFile {
-            material_file: None,
-            groups: Vec::new(),
-        };
-        let mut lines: VecDeque<String> = VecDeque::new();
-        for line in reader.lines() {
-            lines.push_back(line?);
+    /// Loads an .obj file with the material libraries, and converts each group to a mesh
+    /// with the material given by usemtl. Groups without a known material have default_material.
+    pub fn load_meshes(
+        path: &Path,
+        default_material: Arc<Material>,
+    ) -> Result<Vec<TriangleMesh>, Error> {
+        let obj = Self::from_file(path)?;
+        let materials = obj.load_materials(path.parent().unwrap_or_else(|| Path::new("")))?;
+        Ok(obj
+            .groups
+            .iter()
+            .map(|group| {
+                let material = group
+                    .material_name()
+                    .and_then(|name| materials.get(name))
+                    .unwrap_or(&default_material);
+                group.to_triangle_mesh(material.clone())
+            })
+            .collect())
+    }
+    /// Reads the material libraries given by mtllib, which are relative to dir (the directory of the .obj file).
+    pub fn load_materials(&self, dir: &Path) -> Result<HashMap<String, Arc<Material>>, Error> {
+        let mut textures = HashMap::new();
+        let mut materials = HashMap::new();
+        for file in &self.material_files {
+            let path = dir.join(file.name());
+            let mtl = MtlFile::from_file(&path)?;
+            for warning in &mtl.warnings {
+                println!("[ObjFile] {}:{}", path.display(), warning);
+            }
+            materials.extend(mtl.to_materials(path.parent().unwrap(), &mut textures)?);
         }
+        Ok(materials)
+    }
+    /// Reads the file line by line.
+    pub fn from_buf_reader(mut reader: impl BufRead) -> Result<Self, Error> {
+        let mut material_files = Vec::new();
+        let mut groups = Vec::new();
+        let mut pool = ObjVertexPool::default();
+        let mut current = ObjGroup::empty();
+        let mut line = String::new();
+        let mut next_line_no = 1;
         loop {
-            if lines.is_empty() {
-                return Ok(obj_file);
+            let line_no = next_line_no;
+            if !Self::read_line(&mut reader, &mut line, &mut next_line_no)? {
+                break;
             }
-            let line = lines.front().unwrap().clone();
-            if line.is_empty() {
-                lines.pop_front();
-            } else if line.starts_with("#") {
-                lines.pop_front();
-            } else if line.starts_with("mtllib ") {
-                if obj_file.material_file.is_some() {
-                    return Err(Error::Parse("Found two mtllib definitions.".to_string()));
-                } else {
-                    obj_file.material_file = Some(ObjMaterialFile {
-                        name: Self::parse_matlib_line(line)?,
+            let content = match line.find('#') {
+                Some(i) => &line[..i],
+                None => &line,
+            };
+            let mut columns = content.split_whitespace();
+            let statement = match columns.next() {
+                Some(statement) => statement,
+                None => continue,
+            };
+            let args: Vec<&str> = columns.collect();
+            match statement {
+                // "v x y z w"の他に、"v x y z r g b"（頂点カラー）もよく使われる
+                "v" => pool.vertices.push(parse_vec3(&args, &[3, 4, 6], line_no)?),
+                "vt" => {
+                    if args.is_empty() || 3 < args.len() {
+                        return Err(Error::Parse(line_no, "Invalid tex_coord.".to_string()));
+                    }
+                    let v = if args.len() == 1 {
+                        0.0
+                    } else {
+                        parse_f32(args[1], line_no)?
+                    };
+                    pool.tex_coords
+                        .push(Vec2::new(parse_f32(args[0], line_no)?, v));
+                }
+                "vn" => pool.normals.push(parse_vec3(&args, &[3], line_no)?),
+                "f" | "fo" => current.faces.push(parse_face(&args, &pool, line_no)?),
+                "g" => {
+                    current.split(&mut groups);
+                    current.name = if args.is_empty() {
+                        None
+                    } else {
+                        Some(args.join(" "))
+                    };
+                }
+                "o" => {
+                    current.split(&mut groups);
+                    current.object = Some(args.join(" "));
+                    current.name = None;
+                }
+                "usemtl" => {
+                    if args.len() != 1 {
+                        return Err(Error::Parse(line_no, "Invalid usemtl.".to_string()));
+                    }
+                    current.split(&mut groups);
+                    current.material = Some(ObjMaterial {
+                        name: args[0].to_string(),
                     });
-                    lines.pop_front();
                 }
-            } else if line.starts_with("g ")
-                || line.starts_with("usemtl ")
-                || line.starts_with("v ")
-                || line.starts_with("vt ")
-                || line.starts_with("vn ")
-                || line.starts_with("f ")
-            {
-                obj_file.groups.push(ObjGroup::parse(&mut lines)?);
-            } else {
-                return Err(Error::Parse(format!("Unexpected line:\n{}.", line)));
+                "mtllib" => {
+                    if args.is_empty() {
+                        return Err(Error::Parse(line_no, "Invalid mtllib.".to_string()));
+                    }
+                    material_files.extend(args.iter().map(|name| ObjMaterialFile {
+                        name: name.to_string(),
+                    }));
+                }
+                // 法線はvnかset_smooth_normals()で与えるので、スムージンググループは使わない
+                "s" | "mg" => {}
+                // 線、点、自由曲線・曲面のパラメータ空間の頂点は描画しない
+                "l" | "p" | "vp" => {}
+                // 表示・レンダリング属性
+                "bevel" | "c_interp" | "d_interp" | "lod" | "maplib" | "usemap" | "shadow_obj"
+                | "trace_obj" | "ctech" | "stech" => {}
+                "cstype" | "deg" | "bmat" | "step" | "curv" | "curv2" | "surf" | "parm"
+                | "trim" | "hole" | "scrv" | "sp" | "end" | "con" => {
+                    return Err(Error::Unsupported(line_no, statement.to_string()));
+                }
+                _ => {
+                    return Err(Error::Parse(
+                        line_no,
+                        format!("Unknown statement '{}'.", statement),
+                    ));
+                }
             }
         }
+        current.split(&mut groups);
+        let pool = Arc::new(pool);
+        for group in &mut groups {
+            group.pool = pool.clone();
+        }
+        Ok(ObjFile {
+            material_files: material_files,
+            groups: groups,
+        })
     }
-    fn parse_matlib_line(line: String) -> Result<String, Error> {
-        let columns: Vec<&str> = line.split_whitespace().collect();
-        if columns.len() != 2 {
-            Err(Error::Parse(format!(
-                "Invalid matlib definition:\n{}.",
-                line
-            )))
-        } else {
-            Ok(columns[1].to_string())
+    /// Reads a line into buf, joining the following lines if it ends with '\'.
+    /// Returns false at the end of the file.
+    fn read_line(
+        reader: &mut impl BufRead,
+        buf: &mut String,
+        line_no: &mut usize,
+    ) -> io::Result<bool> {
+        buf.clear();
+        loop {
+            if reader.read_line(buf)? == 0 {
+                return Ok(!buf.is_empty());
+            }
+            *line_no += 1;
+            buf.truncate(buf.trim_end().len());
+            if !buf.ends_with('\\') {
+                return Ok(true);
+            }
+            buf.pop();
+            buf.push(' ');
         }
     }
 }
 
+fn parse_f32(column: &str, line_no: usize) -> Result<f32, Error> {
+    f32::from_str(column)
+        .map_err(|e| Error::Parse(line_no, format!("Invalid number '{}': {}.", column, e)))
+}
+
+/// Parses the first three of args, whose length must be one of lens.
+fn parse_vec3(args: &[&str], lens: &[usize], line_no: usize) -> Result<Vec3, Error> {
+    if !lens.contains(&args.len()) {
+        return Err(Error::Parse(
+            line_no,
+            format!("Expected {:?} numbers, found {}.", lens, args.len()),
+        ));
+    }
+    Ok(Vec3::new(
+        parse_f32(args[0], line_no)?,
+        parse_f32(args[1], line_no)?,
+        parse_f32(args[2], line_no)?,
+    ))
+}
+
+fn parse_face(args: &[&str], pool: &ObjVertexPool, line_no: usize) -> Result<ObjFace, Error> {
+    if args.len() < 3 {
+        return Err(Error::Parse(
+            line_no,
+            "A face must have at least 3 vertices.".to_string(),
+        ));
+    }
+    let mut face = ObjFace(Vec::with_capacity(args.len()));
+    for column in args {
+        face.0.push(parse_vertex_column(column, pool, line_no)?);
+    }
+    Ok(face)
+}
+
+/// "v", "v/vt", "v//vn" or "v/vt/vn"
+fn parse_vertex_column(
+    column: &str,
+    pool: &ObjVertexPool,
+    line_no: usize,
+) -> Result<ObjVertex, Error> {
+    let indices: Vec<&str> = column.split('/').collect();
+    if 3 < indices.len() {
+        return Err(Error::Parse(
+            line_no,
+            format!("Invalid vertex definition '{}'.", column),
+        ));
+    }
+    let optional_index = |k: usize, len: usize| match indices.get(k) {
+        Some(index) if !index.is_empty() => resolve_index(index, len, line_no).map(Some),
+        _ => Ok(None),
+    };
+    Ok(ObjVertex {
+        vertex: resolve_index(indices[0], pool.vertices.len(), line_no)?,
+        tex_coord: optional_index(1, pool.tex_coords.len())?,
+        normal: optional_index(2, pool.normals.len())?,
+    })
+}
+
+/// Converts an index of .obj format, which starts from 1 or is negative (relative to the end),
+/// to the 0-based index of the list with len elements defined so far.
+fn resolve_index(index: &str, len: usize, line_no: usize) -> Result<usize, Error> {
+    let i = isize::from_str(index)
+        .map_err(|e| Error::Parse(line_no, format!("Invalid index '{}': {}.", index, e)))?;
+    let resolved = if i < 0 { len as isize + i } else { i - 1 };
+    if i == 0 || resolved < 0 || len as isize <= resolved {
+        Err(Error::Parse(
+            line_no,
+            format!("Index {} is out of range of {} elements.", i, len),
+        ))
+    } else {
+        Ok(resolved as usize)
+    }
+}
+
 impl ObjGroup {
     fn empty() -> Self {
         ObjGroup {
             name: None,
+            object: None,
             material: None,
-            vertices: Vec::new(),
-            tex_coords: Vec::new(),
-            normals: Vec::new(),
+            pool: Arc::default(),
             faces: Vec::new(),
         }
     }
-    pub fn unify_vertex(&mut self) {
-        let mut new_vertices: Vec<Vec3> = vec![];
-        new_vertices.reserve(self.vertices.len());
-        let mut vec_to_idx = HashMap::<HashVec3, usize>::new();
-        vec_to_idx.reserve(self.vertices.len());
-        for v in &self.vertices {
-            if vec_to_idx.contains_key(&HashVec3 { 0: *v }) {
-                continue;
-            }
-            let idx = new_vertices.len();
-            new_vertices.push(*v);
-            vec_to_idx.insert(HashVec3 { 0: *v }, idx);
-        }
-        for f in &mut self.faces {
-            for v in &mut f.0 {
-                let new_idx = vec_to_idx[&HashVec3 {
-                    0: self.vertices[v.vertex],
-                }];
-                v.vertex = new_idx;
-            }
+    /// If self has faces, moves them to groups as a finished group.
+    /// The names and the material are kept for the following faces.
+    fn split(&mut self, groups: &mut Vec<ObjGroup>) {
+        if self.faces.is_empty() {
+            return;
         }
-        self.vertices = new_vertices;
+        let next = ObjGroup {
+            name: self.name.clone(),
+            object: self.object.clone(),
+            material: self.material.clone(),
+            ..Self::empty()
+        };
+        groups.push(std::mem::replace(self, next));
     }
-    fn parse(lines: &mut VecDeque<String>) -> Result<Self, Error> {
-        let mut grp = Self::empty();
-        if lines.is_empty() {
-            return Ok(grp);
-        }
-        if lines.front().unwrap().starts_with("g ") {
-            grp.name = Some(Self::parse_group_line(&lines.front().unwrap())?);
-            lines.pop_front();
-        }
-        loop {
-            if lines.is_empty() {
-                return Ok(grp);
-            }
-            let line = lines.front().unwrap();
-            if line.is_empty() {
-                lines.pop_front();
-            } else if line.starts_with("# ") {
-                lines.pop_front();
-            } else if line.starts_with("g ") {
-                return Ok(grp);
-            } else if line.starts_with("v ") {
-                grp.vertices.push(Self::parse_vec3_line(line)?);
-                lines.pop_front();
-            } else if line.starts_with("vt ") {
-                grp.tex_coords.push(Self::parse_vec2_line(line)?);
-                lines.pop_front();
-            } else if line.starts_with("vn ") {
-                grp.normals.push(Self::parse_vec3_line(line)?);
-                lines.pop_front();
-            } else if line.starts_with("f ") {
-                grp.faces.push(Self::parse_face_line(line)?);
-                lines.pop_front();
-            } else {
-                return Err(Error::Parse(format!("Unexpected line:\n{}.", line)));
-            }
-        }
+    /// Name given by g.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
     }
-    fn parse_group_line(line: &String) -> Result<String, Error> {
-        let columns: Vec<&str> = line.split_whitespace().collect();
-        if columns.len() != 2 {
-            Err(Error::Parse(format!(
-                "Invalid group definition:\n{}.",
-                line
-            )))
-        } else {
-            Ok(columns[1].to_string())
-        }
+    /// Name given by o.
+    pub fn object_name(&self) -> Option<&str> {
+        self.object.as_deref()
     }
-    fn parse_vec3_line(line: &String) -> Result<Vec3, Error> {
-        let columns: Vec<&str> = line.split_whitespace().collect();
-        if columns.len() != 4 {
-            Err(Error::Parse(format!("Invalid line:\n{}.", line)))
-        } else {
-            Ok(Vec3::new(
-                f32::from_str(columns[1])?,
-                f32::from_str(columns[2])?,
-                f32::from_str(columns[3])?,
-            ))
-        }
+    /// Name given by usemtl.
+    pub fn material_name(&self) -> Option<&str> {
+        self.material.as_ref().map(|m| m.name())
     }
-    fn parse_vec2_line(line: &String) -> Result<Vec2, Error> {
-        let columns: Vec<&str> = line.split_whitespace().collect();
-        if columns.len() != 3 {
-            Err(Error::Parse(format!("Invalid line:\n{}.", line)))
-        } else {
-            Ok(Vec2::new(
-                f32::from_str(columns[1])?,
-                f32::from_str(columns[2])?,
-            ))
-        }
+    pub fn face_cnt(&self) -> usize {
+        self.faces.len()
     }
-    fn parse_face_line(line: &String) -> Result<ObjFace, Error> {
-        let columns: Vec<&str> = line.split_whitespace().collect();
-        if columns.len() <= 1 {
-            return Err(Error::Parse(format!("Invalid line:\n{}.", line)));
-        }
-        let mut face = ObjFace { 0: Vec::new() };
-        for i in 1..columns.len() {
-            face.0
-                .push(Self::parse_vertex_column(columns[i].to_string())?);
-        }
-        Ok(face)
-    }
-    fn parse_vertex_column(column: String) -> Result<ObjVertex, Error> {
-        let indices: Vec<&str> = column.split('/').collect();
-        if indices.len() == 1 {
-            Ok(ObjVertex {
-                vertex: usize::from_str(indices[0])? - 1, // .obj format indexes vertices starting from 1
-                tex_coord: None,
-                normal: None,
-            })
-        } else if indices.len() == 2 {
-            Ok(ObjVertex {
-                vertex: usize::from_str(indices[0])? - 1,
-                tex_coord: Some(usize::from_str(indices[1])? - 1),
-                normal: None,
-            })
-        } else if indices.len() == 3 {
-            if indices[1].is_empty() {
-                Ok(ObjVertex {
-                    vertex: usize::from_str(indices[0])? - 1,
-                    tex_coord: None,
-                    normal: Some(usize::from_str(indices[2])? - 1),
-                })
-            } else {
-                Ok(ObjVertex {
-                    vertex: usize::from_str(indices[0])? - 1,
-                    tex_coord: Some(usize::from_str(indices[1])? - 1),
-                    normal: Some(usize::from_str(indices[2])? - 1),
-                })
+    /// 他のグループとpoolを共有していれば、facesが参照する属性だけを持つpoolに置き換えてから返す。
+    /// faceの添字は新しいpoolでの添字になる。
+    fn local_pool<'a>(
+        pool: &'a mut Arc<ObjVertexPool>,
+        faces: &mut [ObjFace],
+    ) -> &'a mut ObjVertexPool {
+        if Arc::get_mut(pool).is_none() {
+            let shared = &*pool;
+            let mut local = ObjVertexPool::default();
+            let mut vertex_idx = HashMap::<usize, usize>::new();
+            let mut tex_coord_idx = HashMap::<usize, usize>::new();
+            let mut normal_idx = HashMap::<usize, usize>::new();
+            for v in faces.iter_mut().flat_map(|f| &mut f.0) {
+                v.vertex = *vertex_idx.entry(v.vertex).or_insert_with(|| {
+                    local.vertices.push(shared.vertices[v.vertex]);
+                    local.vertices.len() - 1
+                });
+                if let Some(ref mut tex_coord) = v.tex_coord {
+                    *tex_coord = *tex_coord_idx.entry(*tex_coord).or_insert_with(|| {
+                        local.tex_coords.push(shared.tex_coords[*tex_coord]);
+                        local.tex_coords.len() - 1
+                    });
+                }
+                if let Some(ref mut normal) = v.normal {
+                    *normal = *normal_idx.entry(*normal).or_insert_with(|| {
+                        local.normals.push(shared.normals[*normal]);
+                        local.normals.len() - 1
+                    });
+                }
             }
-        } else {
-            Err(Error::Parse(format!(
-                "Invalid vertex definition: {}.",
-                column
-            )))
+            *pool = Arc::new(local);
         }
+        Arc::get_mut(pool).unwrap()
     }
-    pub fn to_triangles_ref(&self, material: Arc<Material>) -> Vec<Arc<Hitable>> {
-        // ToDo: コピペ
-        let mut tris: Vec<Arc<Hitable>> = Vec::new();
-        for face in &self.faces {
-            if face.0.len() != 3 {
-                // ToDo: decompose to triangle.
-                panic!("This group has a polygon which is not a triangle.");
+    pub fn unify_vertex(&mut self) {
+        let pool = Self::local_pool(&mut self.pool, &mut self.faces);
+        let (new_vertices, new_idx) = unify_points(&pool.vertices);
+        pool.vertices = new_vertices;
+        for f in &mut self.faces {
+            for v in &mut f.0 {
+                v.vertex = new_idx[v.vertex];
             }
-            let vertices = [
-                self.vertices[face.0[0].vertex],
-                self.vertices[face.0[1].vertex],
-                self.vertices[face.0[2].vertex],
-            ];
-            let normals = (|| -> Option<[Vec3; 3]> {
-                Some([
-                    self.normals[face.0[0].normal?],
-                    self.normals[face.0[1].normal?],
-                    self.normals[face.0[2].normal?],
-                ])
-            })();
-            tris.push(Arc::new(Triangle::new(
-                &vertices,
-                &normals,
-                material.clone(),
-                // Glass::boxed(2.0, 0.0),
-            )));
         }
-        tris
     }
+    pub fn to_triangles_ref(&self, material: Arc<Material>) -> Vec<Arc<Hitable>> {
+        self.to_triangles(material)
+            .into_iter()
+            .map(|tri| -> Arc<Hitable> { Arc::new(tri) })
+            .collect()
+    }
+    /// Same triangles as to_triangle_mesh(), not sharing the vertices.
     pub fn to_triangles(&self, material: Arc<Material>) -> Vec<Triangle> {
-        let mut tris: Vec<Triangle> = Vec::new();
+        self.to_triangle_mesh(material).to_triangles()
+    }
+    /// Converts to an indexed mesh. Vertices sharing the same position, texture coordinates and normal
+    /// are unified into one vertex of the mesh. Polygons are decomposed by ObjFace::triangulate().
+    /// If all the vertices have texture coordinates and normals, the tangents are also generated.
+    pub fn to_triangle_mesh(&self, material: Arc<Material>) -> TriangleMesh {
+        let pool = &self.pool;
+        let mut positions: Vec<Vec3> = Vec::new();
+        let mut tex_coords: Vec<Vec2> = Vec::new();
+        let mut normals: Vec<Vec3> = Vec::new();
+        let mut indices: Vec<[u32; 3]> = Vec::new();
+        let mut vertex_to_idx = HashMap::<(usize, Option<usize>, Option<usize>), u32>::new();
+        // 全ての頂点がUV（法線）を持つときだけ、メッシュにUV（法線）のバッファを持たせる
+        let has_tex_coords = self
+            .faces
+            .iter()
+            .flat_map(|f| &f.0)
+            .all(|v| v.tex_coord.is_some());
+        let has_normals = self
+            .faces
+            .iter()
+            .flat_map(|f| &f.0)
+            .all(|v| v.normal.is_some());
         for face in &self.faces {
-            if face.0.len() != 3 {
-                // ToDo: decompose to triangle.
-                panic!("This group has a polygon which is not a triangle.");
+            let mut face_indices = Vec::with_capacity(face.0.len());
+            for v in &face.0 {
+                let key = (v.vertex, v.tex_coord, v.normal);
+                let idx = *vertex_to_idx.entry(key).or_insert_with(|| {
+                    positions.push(pool.vertices[v.vertex]);
+                    if has_tex_coords {
+                        tex_coords.push(pool.tex_coords[v.tex_coord.unwrap()]);
+                    }
+                    if has_normals {
+                        normals.push(pool.normals[v.normal.unwrap()]);
+                    }
+                    (positions.len() - 1) as u32
+                });
+                face_indices.push(idx);
+            }
+            for tri in face.triangulate(&pool.vertices) {
+                indices.push(tri.map(|k| face_indices[k]));
             }
-            let vertices = [
-                self.vertices[face.0[0].vertex],
-                self.vertices[face.0[1].vertex],
-                self.vertices[face.0[2].vertex],
-            ];
-            let normals = (|| -> Option<[Vec3; 3]> {
-                Some([
-                    self.normals[face.0[0].normal?],
-                    self.normals[face.0[1].normal?],
-                    self.normals[face.0[2].normal?],
-                ])
-            })();
-            tris.push(Triangle::new(
-                &vertices,
-                &normals,
-                material.clone(),
-                // Glass::boxed(2.0, 0.0),
-            ));
         }
-        tris
+        let mut mesh = TriangleMesh::new(
+            positions,
+            if has_normals { Some(normals) } else { None },
+            if has_tex_coords {
+                Some(tex_coords)
+            } else {
+                None
+            },
+            None,
+            indices,
+            material,
+        );
+        mesh.generate_tangents();
+        mesh
     }
+    /// Replaces the normals by the averages of the face normals around each vertex.
     pub fn set_smooth_normals(&mut self) {
+        let pool = Self::local_pool(&mut self.pool, &mut self.faces);
         let mut normals_at_vtx: Vec<Vec<Vec3>> = vec![];
-        normals_at_vtx.reserve(self.vertices.len());
-        for _ in 0..self.vertices.len() {
+        normals_at_vtx.reserve(pool.vertices.len());
+        for _ in 0..pool.vertices.len() {
             normals_at_vtx.push(vec![]);
         }
         for face in &self.faces {
-            for triangle_idx in 0..face.0.len() - 2 {
-                let normal = self.calc_normal_to_face(&face, triangle_idx);
-                let (ai, bi, ci) = face.vertex_indices_for_triangle(triangle_idx);
+            for tri in face.triangulate(&pool.vertices) {
+                let [ai, bi, ci] = tri.map(|k| face.0[k].vertex);
+                let a = pool.vertices[ai];
+                let b = pool.vertices[bi];
+                let c = pool.vertices[ci];
+                let normal = (b - a).cross(&(c - a)).normalize();
                 for vi in &[ai, bi, ci] {
                     normals_at_vtx[*vi].push(normal);
                 }
             }
         }
-        let mut normal_at_vtx: Vec<Vec3> = normals_at_vtx
+        let normal_at_vtx: Vec<Vec3> = normals_at_vtx
             .iter()
             .map(|vecs| {
                 if vecs.len() == 0 {
@@ -381,20 +599,135 @@ impl ObjGroup {
                 }
             })
             .collect();
-        let new_idx_begin = self.normals.len();
-        self.normals.append(&mut normal_at_vtx);
+        // poolはこのグループだけのものなので、元の法線は捨ててよい
+        pool.normals = normal_at_vtx;
         for face in &mut self.faces {
             for vtx in &mut face.0 {
-                vtx.normal = Some(new_idx_begin + vtx.vertex);
+                vtx.normal = Some(vtx.vertex);
             }
         }
     }
-    fn calc_normal_to_face(&self, face: &ObjFace, triangle_idx: usize) -> Vec3 {
-        debug_assert!(triangle_idx < face.0.len() - 2);
-        let (ai, bi, ci) = face.vertex_indices_for_triangle(triangle_idx);
-        let a = self.vertices[ai];
-        let b = self.vertices[bi];
-        let c = self.vertices[ci];
-        (b - a).cross(&(c - a)).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::material::lambertian::Lambertian;
+    use crate::material::Material;
+    use crate::obj_file::{triangulate_polygon, Error, ObjFile};
+    use crate::texture::constant::ConstantTexture;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    #[test]
+    fn parse_groups_and_triangulate() {
+        let obj = "mtllib a.mtl b.mtl\n\
+                   o object # comment\n\
+                   v 0 0 0\nv 2 0 0\nv 2 2 0\nv 1 2 0\nv 1 1 0\nv 0 1 0 \\\n\
+                   \n\
+                   vt 0 0\nvn 0 0 1\nvp 0.5\ns 1\nmg 1 0.5\n\
+                   g first\nusemtl red\n\
+                   f 1 2 3 4 5 6\nl 1 2\n\
+                   usemtl blue\n\
+                   f -6/-1/-1 -5/1/1 -1/1/1\n\
+                   g second\n\
+                   v 0 0 1\n\
+                   f 1 2 \\\n\
+                   7\n";
+        let obj = ObjFile::from_buf_reader(Cursor::new(obj)).unwrap();
+        let names: Vec<_> = obj.material_files.iter().map(|f| f.name()).collect();
+        assert_eq!(names, ["a.mtl", "b.mtl"]);
+        let groups: Vec<_> = obj
+            .groups
+            .iter()
+            .map(|g| (g.object_name(), g.name(), g.material_name(), g.face_cnt()))
+            .collect();
+        assert_eq!(
+            groups,
+            [
+                (Some("object"), Some("first"), Some("red"), 1),
+                (Some("object"), Some("first"), Some("blue"), 1),
+                (Some("object"), Some("second"), Some("blue"), 1),
+            ]
+        );
+        let material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            1.0, 1.0, 1.0,
+        ))));
+        // L字型の6角形は4つの三角形になり、面積と向きが保たれる
+        let mesh = obj.groups[0].to_triangle_mesh(material.clone());
+        assert_eq!(mesh.triangle_cnt(), 4);
+        let area: f32 = mesh
+            .indices()
+            .iter()
+            .map(|idx| {
+                let [a, b, c] = idx.map(|i| mesh.positions()[i as usize]);
+                let n = (b - a).cross(&(c - a));
+                assert!(n.z >= 0.0);
+                0.5 * n.z
+            })
+            .sum();
+        assert!((area - 3.0).abs() < 1.0e-5);
+        // 負の番号と、グループをまたいだ頂点の参照
+        let mesh = obj.groups[1].to_triangle_mesh(material.clone());
+        assert_eq!(mesh.positions()[2], Vec3::new(0.0, 1.0, 0.0));
+        assert!(mesh.normals().is_some() && mesh.tex_coords().is_some());
+        let mesh = obj.groups[2].to_triangle_mesh(material);
+        assert_eq!(mesh.positions()[2], Vec3::new(0.0, 0.0, 1.0));
+        // 凹多角形の頂点が三角形に含まれないように切る
+        let polygon = [(0.0, 0.0), (4.0, 0.0), (4.0, 4.0), (2.0, 1.0), (0.0, 4.0)]
+            .map(|(x, y)| Vec3::new(x, 0.0, y));
+        let triangles = triangulate_polygon(&polygon);
+        assert_eq!(triangles.len(), 3);
+        assert!(triangles.iter().all(|tri| tri.contains(&3)));
+        // エラーには行番号がつく
+        for (obj, line) in &[
+            ("v 0 0 0\nv 0 \\\n 0 0\n\nf 1 2 3\n", 5),
+            ("v 0 0 0\nf 1 1 0\n", 2),
+            ("v 0 0 0\nf -2 1 1\n", 2),
+            ("\n\nv 0 0 x\n", 3),
+            ("# comment\nfoo\n", 2),
+        ] {
+            match ObjFile::from_buf_reader(Cursor::new(*obj)) {
+                Err(Error::Parse(l, _)) => assert_eq!(l, *line),
+                _ => panic!("An invalid file is accepted:\n{}", obj),
+            }
+        }
+        match ObjFile::from_buf_reader(Cursor::new("v 0 0 0\ncurv 0 1 1\n")) {
+            Err(Error::Unsupported(2, _)) => {}
+            _ => panic!("Free-form curves are not supported."),
+        }
+    }
+    #[test]
+    fn preprocess_groups_sharing_the_pool() {
+        // 2つのグループが大きなpoolを共有し、1つ目のグループには同じ位置の頂点が2つある
+        let mut obj = String::new();
+        for k in 0..1000 {
+            obj += &format!("v {} 0 0\nv {} 1 0\nv {} 0 1\n", k, k, k);
+        }
+        obj += "v 0 0 0\nvn 0 0 1\n\
+                g first\nf 1//1 2//1 3//1\nf 3001 3 4\n\
+                g second\nf 5 6 7\n";
+        let mut obj = ObjFile::from_buf_reader(Cursor::new(obj)).unwrap();
+        let shared = obj.groups[1].pool.clone();
+        obj.groups[0].unify_vertex();
+        obj.groups[0].set_smooth_normals();
+        // 共有していた頂点全体ではなく、グループが使う頂点だけを持つ
+        let pool = &obj.groups[0].pool;
+        assert_eq!(pool.vertices.len(), 4);
+        assert_eq!(pool.normals.len(), 4);
+        assert!(pool.tex_coords.is_empty());
+        assert!(Arc::ptr_eq(&shared, &obj.groups[1].pool));
+        let material: Arc<Material> = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            1.0, 1.0, 1.0,
+        ))));
+        let mesh = obj.groups[0].to_triangle_mesh(material.clone());
+        assert_eq!(mesh.positions().len(), 4);
+        assert_eq!(
+            mesh.normals().unwrap()[0],
+            Vec3::new(1.0, 1.0, 0.0).normalize()
+        );
+        // 他のグループはそのまま
+        let mesh = obj.groups[1].to_triangle_mesh(material);
+        assert_eq!(mesh.positions()[0], Vec3::new(1.0, 1.0, 0.0));
+        assert!(mesh.normals().is_none());
     }
 }
@@ -0,0 +1,1306 @@
+// pbrt-v3のシーン記述(.pbrt)の一部を読み、Sceneを作る。
+// 参考：https://pbrt.org/fileformat-v3
+// 対応するもの：
+//   LookAt, Translate, Scale, Rotate, Transform, ConcatTransform, Identity, CoordinateSystem, CoordSysTransform,
+//   Camera "perspective", Film, Sampler, WorldBegin/End, AttributeBegin/End, TransformBegin/End, Include,
+//   Shape "sphere"/"trianglemesh"/"plymesh", Material "matte"/"plastic"/"metal"/"mirror"/"glass",
+//   MakeNamedMaterial, NamedMaterial, Texture "imagemap"/"constant", ReverseOrientation,
+//   LightSource "point"/"spot"/"distant"/"infinite", AreaLightSource "diffuse", ObjectBegin/End, ObjectInstance
+// 対応しない命令やパラメータは警告にして読み飛ばし、pbrtの命令でないものはエラーにする。
+// ・pbrtは左手系なので、カメラ座標系が（世界座標で）右手系なら、カメラの左右方向にシーンを鏡映して同じ画像になるようにする。
+// ・点光源とスポットライトはgltf_fileと同じく小さな球光源で近似する。無限遠の光源は背景にする。
+// ・面光源は（球もメッシュも）BVHには入れず、Scene::lightとしてNEEの対象にする。
+// ・オブジェクトの形状は共有するBLASにまとめ、ObjectInstanceの変換が相似変換ならInstanceとしてTlasに入れる。
+//   そうでなければメッシュに変換を焼き込む。オブジェクトの中の光源は無視する。
+
+use crate::affine::Affine;
+use crate::aliases::{Mat3, Mat4, Vec2, Vec3};
+use crate::background::{AmbientLight, Background, DirectionalLight, WeightedBg};
+use crate::camera::Camera;
+use crate::gltf_file::POINT_LIGHT_RADIUS;
+use crate::hitable::bvh::BVH;
+use crate::hitable::hitable_list::HitableList;
+use crate::hitable::instance::{Instance, Tlas};
+use crate::hitable::obvh::OBVH;
+use crate::hitable::sphere::Sphere;
+use crate::hitable::triangle_mesh::{MeshTriangle, TriangleMesh};
+use crate::hitable::Hitable;
+use crate::material::diffuse_light::DiffuseLight;
+use crate::material::glass::Glass;
+use crate::material::lambertian::Lambertian;
+use crate::material::lbp::{exponent_from_roughness, LBP};
+use crate::material::metal::Metal;
+use crate::material::Material;
+use crate::ply_file;
+use crate::ply_file::PlyFile;
+use crate::scene::Scene;
+use crate::texture::constant::ConstantTexture;
+use crate::texture::image::ImageTexture;
+use crate::texture::Texture;
+use nalgebra as na;
+use std::collections::HashMap;
+use std::f32::consts::PI;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A scene read from a pbrt file with the settings of the image.
+pub struct PbrtFile {
+    pub scene: Scene,
+    pub width: usize,                     // Film xresolution
+    pub height: usize,                    // Film yresolution
+    pub samples_per_pixel: Option<usize>, // Sampler pixelsamples
+    pub warnings: Vec<String>,            // ignored directives and parameters
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(PathBuf, io::Error),
+    Parse(PathBuf, usize, String), // (file, line number, message)
+    Ply(PathBuf, ply_file::Error),
+    Image(PathBuf, image::ImageError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IO(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Parse(path, line, message) => {
+                write!(f, "{}:{}: {}", path.display(), line, message)
+            }
+            Error::Ply(path, e) => write!(f, "{}: {}", path.display(), e),
+            Error::Image(path, e) => write!(f, "{}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Number(f32),
+    Open,
+    Close,
+}
+
+/// A token with the index of the file and the line number.
+type Located = (Token, usize, usize);
+
+fn tokenize(text: &str, file: usize, path: &Path) -> Result<Vec<Located>, Error> {
+    let mut tokens = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line_no = i + 1;
+        let mut chars = line.char_indices().peekable();
+        while let Some(&(start, c)) = chars.peek() {
+            match c {
+                '#' => break,
+                '[' | ']' => {
+                    chars.next();
+                    tokens.push((
+                        if c == '[' { Token::Open } else { Token::Close },
+                        file,
+                        line_no,
+                    ));
+                }
+                '"' => {
+                    chars.next();
+                    let mut s = String::new();
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, '\\')) => match chars.next() {
+                                Some((_, 'n')) => s.push('\n'),
+                                Some((_, 't')) => s.push('\t'),
+                                Some((_, c)) => s.push(c),
+                                None => break,
+                            },
+                            Some((_, c)) => s.push(c),
+                            None => {
+                                return Err(Error::Parse(
+                                    path.to_path_buf(),
+                                    line_no,
+                                    "Unterminated string.".to_string(),
+                                ))
+                            }
+                        }
+                    }
+                    tokens.push((Token::Str(s), file, line_no));
+                }
+                c if c.is_whitespace() => {
+                    chars.next();
+                }
+                _ => {
+                    let mut end = start;
+                    while let Some(&(i, c)) = chars.peek() {
+                        if c.is_whitespace() || "[]\"#".contains(c) {
+                            break;
+                        }
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+                    let word = &line[start..end];
+                    tokens.push((
+                        match f32::from_str(word) {
+                            Ok(x) => Token::Number(x),
+                            Err(_) => Token::Ident(word.to_string()),
+                        },
+                        file,
+                        line_no,
+                    ));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+#[derive(Clone, Debug)]
+enum Value {
+    Number(f32),
+    Str(String),
+    Bool(bool),
+}
+
+/// A parameter such as "float radius" [1].
+struct Param {
+    ty: String,
+    name: String,
+    values: Vec<Value>,
+}
+
+struct Params(Vec<Param>);
+
+impl Params {
+    fn find(&self, name: &str) -> Option<&Param> {
+        self.0.iter().find(|p| p.name == name)
+    }
+    fn floats(&self, name: &str) -> Option<Vec<f32>> {
+        self.find(name)?
+            .values
+            .iter()
+            .map(|v| match v {
+                Value::Number(x) => Some(*x),
+                _ => None,
+            })
+            .collect()
+    }
+    fn float(&self, name: &str, default: f32) -> f32 {
+        match self.floats(name) {
+            Some(ref x) if x.len() == 1 => x[0],
+            _ => default,
+        }
+    }
+    fn string(&self, name: &str) -> Option<&str> {
+        match self.find(name)?.values.first() {
+            Some(Value::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+    fn bool(&self, name: &str, default: bool) -> bool {
+        match self.find(name).and_then(|p| p.values.first()) {
+            Some(Value::Bool(b)) => *b,
+            Some(Value::Str(s)) => s == "true",
+            _ => default,
+        }
+    }
+    fn vec3s(&self, name: &str) -> Option<Vec<Vec3>> {
+        let x = self.floats(name)?;
+        if x.len() % 3 != 0 {
+            return None;
+        }
+        Some(x.chunks(3).map(|v| Vec3::new(v[0], v[1], v[2])).collect())
+    }
+    fn vec3(&self, name: &str, default: Vec3) -> Vec3 {
+        match self.vec3s(name) {
+            Some(ref v) if v.len() == 1 => v[0],
+            _ => default,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct GraphicsState {
+    ctm: Mat4, // object to world (in pbrt's coordinates)
+    material: Arc<Material>,
+    area_light: Option<Vec3>, // radiance
+    reverse_orientation: bool,
+}
+
+struct CameraParams {
+    camera_to_world: Mat4,
+    fov: f32,
+    lens_radius: f32,
+    focus_dist: f32,
+}
+
+fn split(m: &Mat4) -> (Mat3, Vec3) {
+    (
+        Mat3::new(
+            m[(0, 0)],
+            m[(0, 1)],
+            m[(0, 2)],
+            m[(1, 0)],
+            m[(1, 1)],
+            m[(1, 2)],
+            m[(2, 0)],
+            m[(2, 1)],
+            m[(2, 2)],
+        ),
+        Vec3::new(m[(0, 3)], m[(1, 3)], m[(2, 3)]),
+    )
+}
+
+fn from_linear(a: &Mat3, b: &Vec3) -> Mat4 {
+    Mat4::new(
+        a[(0, 0)],
+        a[(0, 1)],
+        a[(0, 2)],
+        b[0],
+        a[(1, 0)],
+        a[(1, 1)],
+        a[(1, 2)],
+        b[1],
+        a[(2, 0)],
+        a[(2, 1)],
+        a[(2, 2)],
+        b[2],
+        0.0,
+        0.0,
+        0.0,
+        1.0,
+    )
+}
+
+/// The world to camera matrix of "LookAt eye look up".
+fn look_at(eye: &Vec3, look: &Vec3, up: &Vec3) -> Option<Mat4> {
+    let dir = (look - eye).normalize();
+    let right = up.normalize().cross(&dir);
+    if right.norm() == 0.0 || !right.norm().is_finite() {
+        return None;
+    }
+    let right = right.normalize();
+    let new_up = dir.cross(&right);
+    let camera_to_world = from_linear(&Mat3::from_columns(&[right, new_up, dir]), eye);
+    camera_to_world.try_inverse()
+}
+
+/// The shapes defined by ObjectBegin/End in the coordinates of the instances.
+#[derive(Default)]
+struct Object {
+    meshes: Vec<Arc<TriangleMesh>>,
+    spheres: Vec<Arc<Hitable>>,
+    blas: Option<Arc<Hitable>>, // of meshes and spheres, None if empty
+}
+
+struct Parser {
+    files: Vec<PathBuf>,
+    tokens: Vec<Located>,
+    pos: usize,
+    warnings: Vec<String>,
+    state: GraphicsState,
+    state_stack: Vec<GraphicsState>,
+    transform_stack: Vec<Mat4>,
+    coordinate_systems: HashMap<String, Mat4>,
+    named_materials: HashMap<String, Arc<Material>>,
+    textures: HashMap<String, Arc<Texture>>,
+    camera: Option<CameraParams>,
+    width: usize,
+    height: usize,
+    samples_per_pixel: Option<usize>,
+    flip: Mat4, // 左手系を右手系にするための鏡映
+    triangles: Vec<MeshTriangle>,
+    spheres: Vec<Arc<Hitable>>,
+    lights: Vec<Arc<Hitable>>,
+    backgrounds: Vec<Box<Background>>,
+    object: Option<(String, Object)>, // the object being defined
+    objects: HashMap<String, Arc<Object>>,
+    instances: Vec<Instance<Hitable>>,
+}
+
+impl PbrtFile {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let text = fs::read_to_string(path).map_err(|e| Error::IO(path.to_path_buf(), e))?;
+        Self::parse(&text, path)
+    }
+    /// Parses text as the contents of path, which is used for the relative paths of the files it refers to.
+    pub fn parse(text: &str, path: &Path) -> Result<Self, Error> {
+        let mut parser = Parser {
+            files: vec![path.to_path_buf()],
+            tokens: tokenize(text, 0, path)?,
+            pos: 0,
+            warnings: Vec::new(),
+            state: GraphicsState {
+                ctm: Mat4::identity(),
+                material: Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+                    0.5, 0.5, 0.5,
+                )))),
+                area_light: None,
+                reverse_orientation: false,
+            },
+            state_stack: Vec::new(),
+            transform_stack: Vec::new(),
+            coordinate_systems: HashMap::new(),
+            named_materials: HashMap::new(),
+            textures: HashMap::new(),
+            camera: None,
+            width: 1280,
+            height: 720,
+            samples_per_pixel: None,
+            flip: Mat4::identity(),
+            triangles: Vec::new(),
+            spheres: Vec::new(),
+            lights: Vec::new(),
+            backgrounds: Vec::new(),
+            object: None,
+            objects: HashMap::new(),
+            instances: Vec::new(),
+        };
+        while parser.pos < parser.tokens.len() {
+            parser.directive()?;
+        }
+        parser.build()
+    }
+}
+
+impl Parser {
+    fn error<T>(&self, message: &str) -> Result<T, Error> {
+        let (file, line) = match self.tokens.get(self.pos.min(self.tokens.len().max(1) - 1)) {
+            Some(&(_, file, line)) => (file, line),
+            None => (0, 0),
+        };
+        Err(Error::Parse(
+            self.files[file].clone(),
+            line,
+            message.to_string(),
+        ))
+    }
+    fn warn(&mut self, message: String) {
+        let (file, line) = match self.tokens.get(self.pos.saturating_sub(1)) {
+            Some(&(_, file, line)) => (file, line),
+            None => (0, 0),
+        };
+        self.warnings.push(format!(
+            "{}:{}: {}",
+            self.files[file].display(),
+            line,
+            message
+        ));
+    }
+    fn dir(&self) -> PathBuf {
+        let file = self
+            .tokens
+            .get(self.pos.saturating_sub(1))
+            .map_or(0, |t| t.1);
+        self.files[file]
+            .parent()
+            .unwrap_or_else(|| Path::new(""))
+            .to_path_buf()
+    }
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.0)
+    }
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|t| t.0.clone());
+        self.pos += 1;
+        token
+    }
+    fn string(&mut self) -> Result<String, Error> {
+        match self.next() {
+            Some(Token::Str(s)) => Ok(s),
+            _ => self.error("A string is expected."),
+        }
+    }
+    /// n numbers, which may be enclosed in brackets.
+    fn numbers(&mut self, n: usize) -> Result<Vec<f32>, Error> {
+        let bracket = self.peek() == Some(&Token::Open);
+        if bracket {
+            self.pos += 1;
+        }
+        let mut x = Vec::with_capacity(n);
+        for _ in 0..n {
+            match self.next() {
+                Some(Token::Number(v)) => x.push(v),
+                _ => return self.error(&format!("{} numbers are expected.", n)),
+            }
+        }
+        if bracket && self.next() != Some(Token::Close) {
+            return self.error("']' is expected.");
+        }
+        Ok(x)
+    }
+    fn params(&mut self) -> Result<Params, Error> {
+        let mut params = Vec::new();
+        while let Some(Token::Str(decl)) = self.peek().cloned() {
+            let words: Vec<&str> = decl.split_whitespace().collect();
+            if words.len() != 2 {
+                break;
+            }
+            self.pos += 1;
+            let mut values = Vec::new();
+            let bracket = self.peek() == Some(&Token::Open);
+            if bracket {
+                self.pos += 1;
+            }
+            loop {
+                match self.peek().cloned() {
+                    Some(Token::Number(x)) => values.push(Value::Number(x)),
+                    Some(Token::Str(s)) => values.push(Value::Str(s)),
+                    Some(Token::Ident(ref s)) if s == "true" || s == "false" => {
+                        values.push(Value::Bool(s == "true"))
+                    }
+                    Some(Token::Close) if bracket => {
+                        self.pos += 1;
+                        break;
+                    }
+                    _ if bracket => return self.error("']' is expected."),
+                    _ => return self.error("A parameter value is expected."),
+                }
+                self.pos += 1;
+                if !bracket {
+                    break;
+                }
+            }
+            params.push(Param {
+                ty: words[0].to_string(),
+                name: words[1].to_string(),
+                values: values,
+            });
+        }
+        Ok(Params(params))
+    }
+    /// An RGB parameter, or a texture of type "texture name" [...].
+    fn spectrum_texture(&mut self, params: &Params, name: &str, default: Vec3) -> Arc<Texture> {
+        if let Some(param) = params.find(name) {
+            if param.ty == "texture" {
+                let texture = params
+                    .string(name)
+                    .and_then(|t| self.textures.get(t))
+                    .cloned();
+                match texture {
+                    Some(texture) => return texture,
+                    None => self.warn(format!("Unknown texture for {}.", name)),
+                }
+            }
+        }
+        Arc::new(ConstantTexture::new(&self.spectrum(params, name, default)))
+    }
+    fn spectrum(&mut self, params: &Params, name: &str, default: Vec3) -> Vec3 {
+        let param = match params.find(name) {
+            Some(param) => param,
+            None => return default,
+        };
+        match param.ty.as_str() {
+            "rgb" | "color" => match params.vec3s(name) {
+                Some(ref v) if v.len() == 1 => return v[0],
+                _ => self.warn(format!("Invalid {}.", name)),
+            },
+            "texture" => {}
+            ty => self.warn(format!(
+                "Spectrum type '{}' of {} is not supported.",
+                ty, name
+            )),
+        }
+        default
+    }
+    fn directive(&mut self) -> Result<(), Error> {
+        let name = match self.next() {
+            Some(Token::Ident(name)) => name,
+            _ => {
+                self.pos -= 1;
+                return self.error("A directive is expected.");
+            }
+        };
+        match name.as_str() {
+            "Identity" => self.state.ctm = Mat4::identity(),
+            "Translate" => {
+                let v = self.numbers(3)?;
+                self.state.ctm *= Mat4::new_translation(&Vec3::new(v[0], v[1], v[2]));
+            }
+            "Scale" => {
+                let v = self.numbers(3)?;
+                self.state.ctm *= Mat4::new_nonuniform_scaling(&Vec3::new(v[0], v[1], v[2]));
+            }
+            "Rotate" => {
+                let v = self.numbers(4)?;
+                let axis = Vec3::new(v[1], v[2], v[3]);
+                if axis.norm() > 0.0 {
+                    let rotation = na::Rotation3::new(axis.normalize() * v[0].to_radians());
+                    self.state.ctm *= from_linear(rotation.matrix(), &Vec3::zeros());
+                }
+            }
+            "LookAt" => {
+                let v = self.numbers(9)?;
+                let vec = |i: usize| Vec3::new(v[i], v[i + 1], v[i + 2]);
+                match look_at(&vec(0), &vec(3), &vec(6)) {
+                    Some(m) => self.state.ctm *= m,
+                    None => self.warn("Degenerate LookAt is ignored.".to_string()),
+                }
+            }
+            "Transform" | "ConcatTransform" => {
+                // 列優先
+                let m = Mat4::from_column_slice(&self.numbers(16)?);
+                if name == "Transform" {
+                    self.state.ctm = m;
+                } else {
+                    self.state.ctm *= m;
+                }
+            }
+            "CoordinateSystem" => {
+                let name = self.string()?;
+                self.coordinate_systems.insert(name, self.state.ctm);
+            }
+            "CoordSysTransform" => {
+                let name = self.string()?;
+                match self.coordinate_systems.get(&name) {
+                    Some(m) => self.state.ctm = *m,
+                    None => self.warn(format!("Unknown coordinate system '{}'.", name)),
+                }
+            }
+            "Camera" => {
+                let ty = self.string()?;
+                let params = self.params()?;
+                let camera_to_world = match self.state.ctm.try_inverse() {
+                    Some(m) => m,
+                    None => return self.error("The camera transformation is singular."),
+                };
+                self.coordinate_systems
+                    .insert("camera".to_string(), camera_to_world);
+                if ty != "perspective" {
+                    self.warn(format!(
+                        "Camera '{}' is replaced by a perspective camera.",
+                        ty
+                    ));
+                }
+                self.camera = Some(CameraParams {
+                    camera_to_world: camera_to_world,
+                    fov: params.float("fov", 90.0),
+                    lens_radius: params.float("lensradius", 0.0),
+                    focus_dist: params.float("focaldistance", 1.0e6),
+                });
+            }
+            "Film" => {
+                self.string()?;
+                let params = self.params()?;
+                self.width = params.float("xresolution", 1280.0) as usize;
+                self.height = params.float("yresolution", 720.0) as usize;
+            }
+            "Sampler" => {
+                self.string()?;
+                let params = self.params()?;
+                if params.find("pixelsamples").is_some() {
+                    self.samples_per_pixel = Some(params.float("pixelsamples", 16.0) as usize);
+                }
+            }
+            "Integrator" | "PixelFilter" | "Accelerator" | "MakeNamedMedium" => {
+                self.string()?;
+                self.params()?;
+                self.warn(format!("{} is ignored.", name));
+            }
+            "ColorSpace" | "MediumInterface" => {
+                while let Some(Token::Str(_)) = self.peek() {
+                    self.pos += 1;
+                }
+                self.warn(format!("{} is ignored.", name));
+            }
+            "Option" => {
+                self.params()?;
+                self.warn(format!("{} is ignored.", name));
+            }
+            "Attribute" => {
+                self.string()?;
+                self.params()?;
+                self.warn(format!("{} is ignored.", name));
+            }
+            // 動きのある変換には対応せず、全ての変換を開始時刻の変換とみなす
+            "ActiveTransform" => {
+                match self.next() {
+                    Some(Token::Ident(ref time))
+                        if time == "All" || time == "StartTime" || time == "EndTime" => {}
+                    _ => return self.error("All, StartTime or EndTime is expected."),
+                }
+                self.warn(format!("{} is ignored.", name));
+            }
+            "TransformTimes" => {
+                self.numbers(2)?;
+                self.warn(format!("{} is ignored.", name));
+            }
+            "WorldBegin" => {
+                self.state.ctm = Mat4::identity();
+                self.coordinate_systems
+                    .insert("world".to_string(), Mat4::identity());
+                self.flip = self.handedness_flip();
+            }
+            "WorldEnd" => {}
+            "AttributeBegin" => self.state_stack.push(self.state.clone()),
+            "AttributeEnd" => match self.state_stack.pop() {
+                Some(state) => self.state = state,
+                None => return self.error("Unmatched AttributeEnd."),
+            },
+            "TransformBegin" => self.transform_stack.push(self.state.ctm),
+            "TransformEnd" => match self.transform_stack.pop() {
+                Some(ctm) => self.state.ctm = ctm,
+                None => return self.error("Unmatched TransformEnd."),
+            },
+            "ReverseOrientation" => {
+                self.state.reverse_orientation = !self.state.reverse_orientation
+            }
+            "Include" | "Import" => {
+                let path = self.dir().join(self.string()?);
+                let text = fs::read_to_string(&path).map_err(|e| Error::IO(path.clone(), e))?;
+                let tokens = tokenize(&text, self.files.len(), &path)?;
+                self.files.push(path);
+                let rest = self.tokens.split_off(self.pos);
+                self.tokens.extend(tokens);
+                self.tokens.extend(rest);
+            }
+            "Material" => {
+                let ty = self.string()?;
+                let params = self.params()?;
+                self.state.material = self.material(&ty, &params);
+            }
+            "MakeNamedMaterial" => {
+                let name = self.string()?;
+                let params = self.params()?;
+                let ty = params.string("type").unwrap_or("matte").to_string();
+                let material = self.material(&ty, &params);
+                self.named_materials.insert(name, material);
+            }
+            "NamedMaterial" => {
+                let name = self.string()?;
+                match self.named_materials.get(&name) {
+                    Some(material) => self.state.material = material.clone(),
+                    None => self.warn(format!("Unknown material '{}'.", name)),
+                }
+            }
+            "Texture" => {
+                let name = self.string()?;
+                let ty = self.string()?;
+                let class = self.string()?;
+                let params = self.params()?;
+                self.texture(name, &ty, &class, &params)?;
+            }
+            "AreaLightSource" => {
+                let ty = self.string()?;
+                let params = self.params()?;
+                if ty != "diffuse" {
+                    self.warn(format!("AreaLightSource '{}' is not supported.", ty));
+                }
+                let radiance = params.float("scale", 1.0)
+                    * self.spectrum(&params, "L", Vec3::new(1.0, 1.0, 1.0));
+                self.state.area_light = Some(radiance);
+            }
+            "LightSource" => {
+                let ty = self.string()?;
+                let params = self.params()?;
+                if self.object.is_some() {
+                    self.warn("LightSource in an object is ignored.".to_string());
+                } else {
+                    self.light_source(&ty, &params);
+                }
+            }
+            "Shape" => {
+                let ty = self.string()?;
+                let params = self.params()?;
+                self.shape(&ty, &params)?;
+            }
+            "ObjectBegin" => {
+                let name = self.string()?;
+                if self.object.is_some() {
+                    return self.error("Nested ObjectBegin.");
+                }
+                self.state_stack.push(self.state.clone());
+                self.object = Some((name, Object::default()));
+            }
+            "ObjectEnd" => {
+                let (name, mut object) = match self.object.take() {
+                    Some(object) => object,
+                    None => return self.error("Unmatched ObjectEnd."),
+                };
+                let mut blas = object.spheres.clone();
+                let leaves: Vec<MeshTriangle> = object
+                    .meshes
+                    .iter()
+                    .flat_map(TriangleMesh::triangles)
+                    .collect();
+                if !leaves.is_empty() {
+                    blas.push(Arc::new(OBVH::from_bvh(BVH::new_binned(
+                        leaves, 0.0, 0.0, 1,
+                    ))));
+                }
+                if !blas.is_empty() {
+                    object.blas = Some(Arc::new(HitableList::new(blas)));
+                }
+                self.objects.insert(name, Arc::new(object));
+                match self.state_stack.pop() {
+                    Some(state) => self.state = state,
+                    None => return self.error("Unmatched ObjectEnd."),
+                }
+            }
+            "ObjectInstance" => {
+                let name = self.string()?;
+                if self.object.is_some() {
+                    return self.error("ObjectInstance in an object is not supported.");
+                }
+                match self.objects.get(&name).cloned() {
+                    Some(object) => self.add_instance(&object),
+                    None => self.warn(format!("Unknown object '{}'.", name)),
+                }
+            }
+            _ => {
+                self.pos -= 1;
+                return self.error(&format!("Unknown directive '{}'.", name));
+            }
+        }
+        Ok(())
+    }
+    /// The reflection of the world making the camera coordinate system left-handed.
+    fn handedness_flip(&self) -> Mat4 {
+        let camera_to_world = self
+            .camera
+            .as_ref()
+            .map_or(Mat4::identity(), |c| c.camera_to_world);
+        let (a, origin) = split(&camera_to_world);
+        if a.determinant() < 0.0 {
+            return Mat4::identity();
+        }
+        let forward = a * Vec3::new(0.0, 0.0, 1.0);
+        let up = a * Vec3::new(0.0, 1.0, 0.0);
+        let n = forward.cross(&up).normalize();
+        // originを通り、nに垂直な平面に関する鏡映
+        let reflection = Mat3::identity() - 2.0 * n * n.transpose();
+        from_linear(&reflection, &(origin - reflection * origin))
+    }
+    /// The transformation of shapes to the world, or to the instances in an object.
+    fn to_world(&self) -> Mat4 {
+        if self.object.is_some() {
+            self.state.ctm
+        } else {
+            self.flip * self.state.ctm
+        }
+    }
+    fn material(&mut self, ty: &str, params: &Params) -> Arc<Material> {
+        // remaproughness == falseなら、roughnessはα（= perceptual roughnessの2乗）
+        let roughness = |name: &str, default: f32| {
+            let r = params.float(name, default);
+            if params.bool("remaproughness", true) {
+                r
+            } else {
+                r.sqrt()
+            }
+        };
+        match ty {
+            "matte" => Arc::new(Lambertian::new(self.spectrum_texture(
+                params,
+                "Kd",
+                Vec3::new(0.5, 0.5, 0.5),
+            ))),
+            "plastic" => {
+                let diffuse = self.spectrum_texture(params, "Kd", Vec3::new(0.25, 0.25, 0.25));
+                let specular = self.spectrum_texture(params, "Ks", Vec3::new(0.25, 0.25, 0.25));
+                Arc::new(LBP::new(
+                    diffuse,
+                    specular,
+                    exponent_from_roughness(roughness("roughness", 0.1)),
+                    0.0,
+                ))
+            }
+            "metal" => {
+                // 垂直入射のフレネル反射率。既定値は銅
+                let eta = self.spectrum(params, "eta", Vec3::new(0.2, 0.924, 1.102));
+                let k = self.spectrum(params, "k", Vec3::new(3.912, 2.452, 2.142));
+                let albedo = Vec3::from_fn(|i, _| {
+                    ((eta[i] - 1.0).powi(2) + k[i] * k[i]) / ((eta[i] + 1.0).powi(2) + k[i] * k[i])
+                });
+                let r = match (params.find("uroughness"), params.find("vroughness")) {
+                    (Some(_), Some(_)) => {
+                        0.5 * (roughness("uroughness", 0.01) + roughness("vroughness", 0.01))
+                    }
+                    _ => roughness("roughness", 0.01),
+                };
+                Arc::new(Metal::new(&albedo, r))
+            }
+            "mirror" => Arc::new(Metal::new(
+                &self.spectrum(params, "Kr", Vec3::new(0.9, 0.9, 0.9)),
+                0.0,
+            )),
+            "glass" => {
+                let eta = match params.find("eta") {
+                    Some(_) => params.float("eta", 1.5),
+                    None => params.float("index", 1.5),
+                };
+                Arc::new(Glass::new(eta, 0.0))
+            }
+            _ => {
+                self.warn(format!("Material '{}' is replaced by matte.", ty));
+                Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+                    0.5, 0.5, 0.5,
+                ))))
+            }
+        }
+    }
+    fn texture(
+        &mut self,
+        name: String,
+        ty: &str,
+        class: &str,
+        params: &Params,
+    ) -> Result<(), Error> {
+        if ty == "float" {
+            self.warn(format!("Float texture '{}' is not supported.", name));
+            return Ok(());
+        }
+        let texture: Arc<Texture> = match class {
+            "imagemap" => {
+                let path = self.dir().join(params.string("filename").unwrap_or(""));
+                match ImageTexture::open(&path) {
+                    Ok(texture) => Arc::new(texture),
+                    Err(e) => return Err(Error::Image(path, e)),
+                }
+            }
+            "constant" => Arc::new(ConstantTexture::new(&self.spectrum(
+                params,
+                "value",
+                Vec3::new(1.0, 1.0, 1.0),
+            ))),
+            _ => {
+                self.warn(format!("Texture '{}' is not supported.", class));
+                return Ok(());
+            }
+        };
+        self.textures.insert(name, texture);
+        Ok(())
+    }
+    fn light_source(&mut self, ty: &str, params: &Params) {
+        let (a, b) = split(&self.to_world());
+        let scale = params.float("scale", 1.0);
+        match ty {
+            "point" | "spot" => {
+                if ty == "spot" {
+                    self.warn("The cone of the spot light is ignored.".to_string());
+                }
+                // 光度Iの点光源を、放射輝度I / (πr^2)の球で近似する
+                let intensity = scale * self.spectrum(params, "I", Vec3::new(1.0, 1.0, 1.0));
+                let r = POINT_LIGHT_RADIUS;
+                let center = a * params.vec3("from", Vec3::zeros()) + b;
+                let light = Arc::new(Sphere::new(
+                    &center,
+                    r,
+                    Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
+                        &(intensity / (PI * r * r)),
+                    )))),
+                ));
+                self.lights.push(light);
+            }
+            "distant" => {
+                let radiance = scale * self.spectrum(params, "L", Vec3::new(1.0, 1.0, 1.0));
+                let from = params.vec3("from", Vec3::zeros());
+                let to = params.vec3("to", Vec3::new(0.0, 0.0, 1.0));
+                self.backgrounds.push(Box::new(DirectionalLight::new(
+                    &(a * (from - to)),
+                    &radiance,
+                )));
+            }
+            "infinite" => {
+                if params.find("mapname").is_some() {
+                    self.warn("The environment map of the infinite light is ignored.".to_string());
+                }
+                let radiance = scale * self.spectrum(params, "L", Vec3::new(1.0, 1.0, 1.0));
+                self.backgrounds
+                    .push(Box::new(AmbientLight::new(&radiance)));
+            }
+            _ => self.warn(format!("LightSource '{}' is not supported.", ty)),
+        }
+    }
+    fn shape(&mut self, ty: &str, params: &Params) -> Result<(), Error> {
+        if self.state.area_light.is_some() && self.object.is_some() {
+            self.warn("AreaLightSource in an object is ignored.".to_string());
+            self.state.area_light = None;
+        }
+        let material: Arc<Material> = match self.state.area_light {
+            Some(ref radiance) => {
+                Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(radiance))))
+            }
+            None => self.state.material.clone(),
+        };
+        let world = self.to_world();
+        match ty {
+            "sphere" => {
+                for unsupported in &["zmin", "zmax", "phimax"] {
+                    if params.find(unsupported).is_some() {
+                        self.warn(format!("{} of sphere is ignored.", unsupported));
+                    }
+                }
+                let (a, b) = split(&world);
+                if Affine::similarity(&a, &b).is_none() {
+                    self.warn("A non-uniformly scaled sphere is approximated.".to_string());
+                }
+                let radius = params.float("radius", 1.0) * a.determinant().abs().cbrt();
+                let sphere: Arc<Hitable> = Arc::new(Sphere::new(&b, radius, material));
+                if let Some((_, ref mut object)) = self.object {
+                    object.spheres.push(sphere);
+                } else if self.state.area_light.is_some() {
+                    self.lights.push(sphere);
+                } else {
+                    self.spheres.push(sphere);
+                }
+            }
+            "trianglemesh" => {
+                let positions = match params.vec3s("P") {
+                    Some(positions) => positions,
+                    None => return self.error("trianglemesh requires P."),
+                };
+                let indices = match params.floats("indices") {
+                    Some(ref i)
+                        if i.len() % 3 == 0 && i.iter().all(|&x| 0.0 <= x && x.fract() == 0.0) =>
+                    {
+                        i.chunks(3)
+                            .map(|t| [t[0] as u32, t[1] as u32, t[2] as u32])
+                            .collect()
+                    }
+                    None if positions.len() == 3 => vec![[0, 1, 2]],
+                    _ => return self.error("Invalid indices of trianglemesh."),
+                };
+                let normals = params.vec3s("N");
+                let tex_coords = match params.floats("uv").or_else(|| params.floats("st")) {
+                    Some(ref uv) if uv.len() % 2 != 0 => {
+                        return self.error("Invalid uv of trianglemesh.")
+                    }
+                    uv => uv.map(|uv| {
+                        uv.chunks(2)
+                            .map(|uv| Vec2::new(uv[0], uv[1]))
+                            .collect::<Vec<_>>()
+                    }),
+                };
+                self.add_mesh(&world, positions, normals, tex_coords, indices, material)?;
+            }
+            "plymesh" => {
+                let path = self.dir().join(params.string("filename").unwrap_or(""));
+                let ply = PlyFile::from_file(&path).map_err(|e| Error::Ply(path, e))?;
+                let indices = ply.triangulate();
+                self.add_mesh(
+                    &world,
+                    ply.positions,
+                    ply.normals,
+                    ply.tex_coords,
+                    indices,
+                    material,
+                )?;
+            }
+            _ => self.warn(format!("Shape '{}' is not supported.", ty)),
+        }
+        Ok(())
+    }
+    /// Transforms the mesh to the world, keeping the orientation of the triangles.
+    fn add_mesh(
+        &mut self,
+        world: &Mat4,
+        positions: Vec<Vec3>,
+        normals: Option<Vec<Vec3>>,
+        tex_coords: Option<Vec<Vec2>>,
+        mut indices: Vec<[u32; 3]>,
+        material: Arc<Material>,
+    ) -> Result<(), Error> {
+        let vertex_cnt = positions.len();
+        if normals.as_ref().is_some_and(|n| n.len() != vertex_cnt)
+            || tex_coords.as_ref().is_some_and(|t| t.len() != vertex_cnt)
+            || indices.iter().flatten().any(|&i| vertex_cnt <= i as usize)
+        {
+            return self.error("Inconsistent mesh.");
+        }
+        if self.state.reverse_orientation {
+            for t in indices.iter_mut() {
+                t.swap(1, 2);
+            }
+        }
+        let (a, b) = split(world);
+        let mesh = TriangleMesh::new(positions, normals, tex_coords, None, indices, material);
+        let mesh = match mesh.transformed(&a, &b) {
+            Some(mesh) => mesh,
+            None => return Ok(()),
+        };
+        if let Some((_, ref mut object)) = self.object {
+            object.meshes.push(Arc::new(mesh));
+        } else if self.state.area_light.is_some() {
+            self.lights
+                .push(Arc::new(TriangleMesh::light(&Arc::new(mesh))));
+        } else {
+            self.triangles
+                .extend(TriangleMesh::triangles(&Arc::new(mesh)));
+        }
+        Ok(())
+    }
+    fn add_instance(&mut self, object: &Object) {
+        let blas = match object.blas {
+            Some(ref blas) => blas,
+            None => return,
+        };
+        let (a, b) = split(&self.to_world());
+        match Affine::similarity(&a, &b) {
+            Some(tr) => self
+                .instances
+                .push(Instance::new(blas.clone(), &tr, None, 0.0, 0.0)),
+            None => {
+                for mesh in &object.meshes {
+                    if let Some(mesh) = mesh.transformed(&a, &b) {
+                        self.triangles
+                            .extend(TriangleMesh::triangles(&Arc::new(mesh)));
+                    }
+                }
+                if !object.spheres.is_empty() {
+                    self.warn(
+                        "Spheres in a non-uniformly scaled instance are ignored.".to_string(),
+                    );
+                }
+            }
+        }
+    }
+    fn build(self) -> Result<PbrtFile, Error> {
+        if !self.state_stack.is_empty() || !self.transform_stack.is_empty() {
+            return self.error("Unmatched AttributeBegin or TransformBegin.");
+        }
+        let mut hitables: Vec<Arc<Hitable>> = Vec::new();
+        if !self.triangles.is_empty() {
+            hitables.push(Arc::new(OBVH::from_bvh(BVH::new_binned(
+                self.triangles,
+                0.0,
+                0.0,
+                1,
+            ))));
+        }
+        if !self.instances.is_empty() {
+            hitables.push(Arc::new(Tlas::new(self.instances, 0.0, 0.0)));
+        }
+        hitables.extend(self.spheres.iter().cloned());
+        hitables.extend(self.lights.iter().cloned());
+        let light: Option<Arc<Hitable>> = match self.lights.len() {
+            0 => None,
+            1 => Some(self.lights[0].clone()),
+            _ => Some(Arc::new(HitableList::new(self.lights))),
+        };
+        let aspect = self.width as f32 / self.height as f32;
+        let camera = match self.camera {
+            Some(ref c) => {
+                let (a, origin) = split(&c.camera_to_world);
+                let forward = a * Vec3::new(0.0, 0.0, 1.0);
+                let up = a * Vec3::new(0.0, 1.0, 0.0);
+                // fovは画像の短い方の辺の画角
+                let vfov = if aspect < 1.0 {
+                    2.0 * ((0.5 * c.fov.to_radians()).tan() / aspect)
+                        .atan()
+                        .to_degrees()
+                } else {
+                    c.fov
+                };
+                Camera::new(
+                    &origin,
+                    &(origin + forward),
+                    &up,
+                    vfov,
+                    aspect,
+                    c.lens_radius,
+                    c.focus_dist,
+                )
+            }
+            None => Camera::new(
+                &Vec3::zeros(),
+                &Vec3::new(0.0, 0.0, 1.0),
+                &Vec3::new(0.0, 1.0, 0.0),
+                90.0,
+                aspect,
+                0.0,
+                1.0,
+            ),
+        };
+        let mut backgrounds = self.backgrounds.into_iter();
+        let first: Box<Background> = match backgrounds.next() {
+            Some(bg) => bg,
+            None => Box::new(AmbientLight::new(&Vec3::zeros())),
+        };
+        let bg = backgrounds.fold(first, |a, b| Box::new(WeightedBg::new(1.0, a, 1.0, b)));
+        Ok(PbrtFile {
+            scene: Scene {
+                hitables: Arc::new(HitableList::new(hitables)),
+                light: light,
+                camera: camera,
+                bg: Arc::from(bg),
+            },
+            width: self.width,
+            height: self.height,
+            samples_per_pixel: self.samples_per_pixel,
+            warnings: self.warnings,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::pbrt_file::{Error, PbrtFile};
+    use crate::ray::Ray;
+    use std::fs;
+    use std::path::Path;
+    #[test]
+    fn import_scene() {
+        let dir = std::env::temp_dir().join(format!("ray-pbrt-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("included.pbrt"),
+            "MakeNamedMaterial \"red\" \"string type\" \"matte\" \"rgb Kd\" [0.8 0 0]\n",
+        )
+        .unwrap();
+        let pbrt = r#"
+LookAt 0 0 5  0 0 0  0 1 0 # カメラ
+Camera "perspective" "float fov" [45]
+Film "image" "integer xresolution" [200] "integer yresolution" [100]
+Sampler "halton" "integer pixelsamples" 8
+Integrator "path"
+WorldBegin
+LightSource "point" "point from" [0 3 0] "rgb I" [10 10 10]
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [4 4 4]
+  Translate 0 5 0
+  Shape "sphere" "float radius" 0.5
+AttributeEnd
+AttributeBegin
+  AreaLightSource "diffuse" "rgb L" [2 2 2]
+  Translate 10 0 -3
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0]
+AttributeEnd
+Include "included.pbrt"
+AttributeBegin
+  NamedMaterial "red"
+  Translate 1.5 0 0
+  Scale 0.5 0.5 1
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0]
+AttributeEnd
+Shape "cylinder"
+WorldEnd
+"#;
+        let file = PbrtFile::parse(pbrt, &dir.join("scene.pbrt")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((file.width, file.height), (200, 100));
+        assert_eq!(file.samples_per_pixel, Some(8));
+        assert!(file.warnings.iter().any(|w| w.contains("Integrator")));
+        assert!(file.warnings.iter().any(|w| w.contains("cylinder")));
+        let scene = &file.scene;
+        let hit = |x: f32| {
+            let ray = Ray::new(&Vec3::new(x, 0.4, 5.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+            scene
+                .hitables
+                .hit(&ray, 0.0, std::f32::MAX)
+                .map(|rec| (rec.t, rec.material.albedo(&rec)))
+        };
+        // pbrtの画像で右（カメラの+x = 世界の-x）にある面は、鏡映されて世界の-xに来る
+        let (t, albedo) = hit(-1.5).unwrap();
+        assert!((t - 5.0).abs() < 1.0e-4);
+        assert_eq!(albedo, Vec3::new(0.8, 0.0, 0.0));
+        assert!(hit(1.5).is_none());
+        assert!(hit(-1.5 + 0.6).is_none());
+        // 面光源の球と点光源
+        let ray = Ray::new(&Vec3::new(0.0, 0.0, 0.0), &Vec3::new(0.0, 1.0, 0.0), 0.0);
+        let rec = scene
+            .light
+            .as_ref()
+            .unwrap()
+            .hit(&ray, 0.0, std::f32::MAX)
+            .unwrap();
+        assert!((rec.t - 2.99).abs() < 1.0e-4);
+        let ray = Ray::new(&Vec3::new(1.0, 5.0, 0.0), &Vec3::new(-1.0, 0.0, 0.0), 0.0);
+        let rec = scene
+            .light
+            .as_ref()
+            .unwrap()
+            .hit(&ray, 0.0, std::f32::MAX)
+            .unwrap();
+        assert!((rec.t - 0.5).abs() < 1.0e-4);
+        assert_eq!(rec.material.emitted(&ray, &rec), Vec3::new(4.0, 4.0, 4.0));
+        // メッシュの面光源（鏡映されて世界の-xに来る）
+        let ray = Ray::new(&Vec3::new(-10.0, 0.0, 0.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+        let rec = scene
+            .light
+            .as_ref()
+            .unwrap()
+            .hit(&ray, 0.0, std::f32::MAX)
+            .unwrap();
+        assert!((rec.t - 3.0).abs() < 1.0e-4);
+        assert_eq!(rec.material.emitted(&ray, &rec), Vec3::new(2.0, 2.0, 2.0));
+        assert!((scene.camera.forward() - Vec3::new(0.0, 0.0, -1.0)).norm() < 1.0e-4);
+
+        let file = PbrtFile::parse(
+            "ActiveTransform EndTime\nTransformTimes 0 1\nWorldBegin\n",
+            &dir.join("a.pbrt"),
+        )
+        .unwrap();
+        assert_eq!(file.warnings.len(), 2);
+        match PbrtFile::parse(
+            "WorldBegin\n\nShape \"sphere\"\nFoo 1\n",
+            &dir.join("a.pbrt"),
+        ) {
+            Err(Error::Parse(_, 4, _)) => {}
+            _ => panic!("An unknown directive must be an error."),
+        }
+        let mesh = "WorldBegin\nShape \"trianglemesh\" \"point P\" [0 0 0  1 0 0  0 1 0]";
+        match PbrtFile::parse(
+            &format!("{} \"float uv\" [0 0 1 0 0]\n", mesh),
+            &dir.join("a.pbrt"),
+        ) {
+            Err(Error::Parse(_, 2, _)) => {}
+            _ => panic!("An odd number of uv must be an error."),
+        }
+        for indices in &["[0 1 -1]", "[0 1 1.5]"] {
+            match PbrtFile::parse(
+                &format!("{} \"integer indices\" {}\n", mesh, indices),
+                &dir.join("a.pbrt"),
+            ) {
+                Err(Error::Parse(_, 2, _)) => {}
+                _ => panic!("Index {} must be an error.", indices),
+            }
+        }
+    }
+    #[test]
+    fn object_instances() {
+        let pbrt = r#"
+WorldBegin
+ObjectBegin "quad"
+  Translate 0 0 -1
+  Shape "trianglemesh" "integer indices" [0 1 2 0 2 3]
+    "point P" [-1 -1 0  1 -1 0  1 1 0  -1 1 0]
+ObjectEnd
+AttributeBegin
+  Translate 3 0 0
+  ObjectInstance "quad"
+AttributeEnd
+AttributeBegin
+  Translate -3 0 0
+  Scale 1 2 1
+  ObjectInstance "quad"
+AttributeEnd
+ObjectInstance "unknown"
+WorldEnd
+"#;
+        let file = PbrtFile::parse(pbrt, Path::new("scene.pbrt")).unwrap();
+        assert_eq!(file.warnings.len(), 1);
+        let hit = |x: f32, y: f32| {
+            let ray = Ray::new(&Vec3::new(x, y, 5.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+            file.scene
+                .hitables
+                .hit(&ray, 0.0, std::f32::MAX)
+                .map(|rec| rec.t)
+        };
+        // オブジェクトの定義そのものは描かれない
+        assert!(hit(0.0, 0.0).is_none());
+        // 鏡映されて世界の-xに来るインスタンス
+        assert!((hit(-3.0, 0.9).unwrap() - 6.0).abs() < 1.0e-4);
+        assert!(hit(-3.0, 1.1).is_none());
+        // 非一様スケールのインスタンス（焼き込み）
+        assert!((hit(3.0, 1.9).unwrap() - 6.0).abs() < 1.0e-4);
+        assert!(hit(3.0, 2.1).is_none());
+    }
+}
@@ -43,7 +43,7 @@ impl Pdf for BlinnPhongPdf {
 
 #[cfg(test)]
 mod tests {
-    use crate::aliases::Vec3;
+    use crate::aliases::{RandGen, Vec3};
     use crate::pdf::blinnphong::BlinnPhongPdf;
     use crate::pdf::random_in_cone;
     use crate::pdf::Pdf;
@@ -52,7 +52,7 @@ mod tests {
     fn blinn_phong_pdf_density() {
         const SAMPLE_CNT: usize = 10000;
         const EXPONENT: i32 = 100;
-        let mut rng = rand::thread_rng();
+        let mut rng = RandGen::new();
         let ray_in = Vec3::new(-1.0, 0.0, -1.0);
         let pdf = BlinnPhongPdf::new(EXPONENT, &Vec3::new(0.0, 0.0, 1.0), &ray_in);
         let mut integral = 0.0f32;
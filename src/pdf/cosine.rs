@@ -62,7 +62,7 @@ impl Pdf for CosineNPdf {
 
 #[cfg(test)]
 mod tests {
-    use crate::aliases::Vec3;
+    use crate::aliases::{RandGen, Vec3};
     use crate::pdf::cosine::CosineNPdf;
     use crate::pdf::cosine::CosinePdf;
     use crate::pdf::random_in_cone;
@@ -71,7 +71,7 @@ mod tests {
     #[test]
     fn cosine_pdf_density() {
         const SAMPLE_CNT: usize = 10000;
-        let mut rng = rand::thread_rng();
+        let mut rng = RandGen::new();
         let pdf = CosinePdf::new(&Vec3::new(0.0, 0.0, 1.0));
         let mut integral = 0.0f32;
         for _ in 0..SAMPLE_CNT {
@@ -87,7 +87,7 @@ mod tests {
     fn cosn_pdf_density() {
         const SAMPLE_CNT: usize = 10000;
         const MAX_EXPONENT: usize = 10;
-        let mut rng = rand::thread_rng();
+        let mut rng = RandGen::new();
         for e in 1..=MAX_EXPONENT {
             let pdf = CosineNPdf::new(&Vec3::new(0.0, 0.0, 1.0), e as i32);
             let mut integral = 0.0f32;
@@ -0,0 +1,656 @@
+// Stanford Triangle Format (.ply)。
+// 参考：
+// http://paulbourke.net/dataformats/ply/
+// ascii、binary_little_endian、binary_big_endianのいずれにも対応する。
+// vertex要素のx, y, z, nx, ny, nz, u, v（s, t、texture_u, texture_vも可）, red, green, blue（alphaは無視）と、
+// face要素のvertex_indices（vertex_indexも可）を読む。他の要素やプロパティは読み飛ばす。
+
+use crate::aliases::{Vec2, Vec3};
+use crate::hitable::triangle::Triangle;
+use crate::hitable::triangle_mesh::TriangleMesh;
+use crate::material::Material;
+use crate::obj_file::triangulate_polygon;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// ヘッダーの要素数が壊れていても巨大な確保をしないように、事前に確保するのはここまでにする
+const MAX_RESERVE: usize = 1 << 24;
+
+/// A polygon mesh read from a PLY file. Faces may have any number of vertices.
+pub struct PlyFile {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>,
+    pub tex_coords: Option<Vec<Vec2>>,
+    pub colors: Option<Vec<Vec3>>, // in [0, 1]. Integer components are divided by their maximum values
+    pub faces: Vec<Vec<u32>>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    Header(usize, String),       // (line number, message)
+    Data(String, usize, String), // (element name, element index, message)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "{}", e),
+            Error::Header(line, message) => write!(f, "header line {}: {}", line, message),
+            Error::Data(element, i, message) => write!(f, "{} {}: {}", element, i, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(io_e: io::Error) -> Self {
+        Error::IO(io_e)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Ascii,
+    BinaryLittleEndian,
+    BinaryBigEndian,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ScalarType {
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Float32,
+    Float64,
+}
+
+impl ScalarType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "char" | "int8" => Some(ScalarType::Int8),
+            "uchar" | "uint8" => Some(ScalarType::UInt8),
+            "short" | "int16" => Some(ScalarType::Int16),
+            "ushort" | "uint16" => Some(ScalarType::UInt16),
+            "int" | "int32" => Some(ScalarType::Int32),
+            "uint" | "uint32" => Some(ScalarType::UInt32),
+            "float" | "float32" => Some(ScalarType::Float32),
+            "double" | "float64" => Some(ScalarType::Float64),
+            _ => None,
+        }
+    }
+    /// The value meaning the full intensity when used as a color component.
+    fn color_max(self) -> f64 {
+        match self {
+            ScalarType::Int8 => 127.0,
+            ScalarType::UInt8 => 255.0,
+            ScalarType::Int16 => 32767.0,
+            ScalarType::UInt16 => 65535.0,
+            ScalarType::Int32 => 2147483647.0,
+            ScalarType::UInt32 => 4294967295.0,
+            ScalarType::Float32 | ScalarType::Float64 => 1.0,
+        }
+    }
+}
+
+enum PropertyType {
+    Scalar(ScalarType),
+    List(ScalarType, ScalarType), // (type of the count, type of the items)
+}
+
+struct Property {
+    name: String,
+    ty: PropertyType,
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>,
+}
+
+impl Element {
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        self.properties
+            .iter()
+            .position(|p| names.contains(&p.name.as_str()))
+    }
+}
+
+/// Values of the properties of an element. A scalar property is a list of length 1.
+type Values = Vec<Vec<f64>>;
+
+struct DataReader<R: BufRead> {
+    reader: R,
+    format: Format,
+    line: String, // asciiで読んだ1行（1要素）
+}
+
+impl<R: BufRead> DataReader<R> {
+    fn read_element(&mut self, element: &Element, values: &mut Values) -> Result<(), String> {
+        match self.format {
+            Format::Ascii => self.read_ascii(element, values),
+            _ => self.read_binary(element, values).map_err(|e| e.to_string()),
+        }
+    }
+    fn read_ascii(&mut self, element: &Element, values: &mut Values) -> Result<(), String> {
+        // asciiでは、1行に1要素が書かれている
+        loop {
+            self.line.clear();
+            if self
+                .reader
+                .read_line(&mut self.line)
+                .map_err(|e| e.to_string())?
+                == 0
+            {
+                return Err("Unexpected end of file.".to_string());
+            }
+            if !self.line.trim().is_empty() {
+                break;
+            }
+        }
+        let mut columns = self.line.split_whitespace();
+        let mut next = || -> Result<f64, String> {
+            let column = columns.next().ok_or("Too few values.")?;
+            f64::from_str(column).map_err(|e| format!("Invalid number '{}': {}.", column, e))
+        };
+        for (property, value) in element.properties.iter().zip(values.iter_mut()) {
+            value.clear();
+            match property.ty {
+                PropertyType::Scalar(_) => value.push(next()?),
+                PropertyType::List(_, _) => {
+                    let cnt = next()?;
+                    if cnt < 0.0 || cnt.fract() != 0.0 {
+                        return Err(format!("Invalid list length {}.", cnt));
+                    }
+                    for _ in 0..cnt as usize {
+                        value.push(next()?);
+                    }
+                }
+            }
+        }
+        if columns.next().is_some() {
+            return Err("Too many values.".to_string());
+        }
+        Ok(())
+    }
+    fn read_binary(&mut self, element: &Element, values: &mut Values) -> io::Result<()> {
+        for (property, value) in element.properties.iter().zip(values.iter_mut()) {
+            value.clear();
+            match property.ty {
+                PropertyType::Scalar(ty) => value.push(self.read_scalar(ty)?),
+                PropertyType::List(cnt_ty, ty) => {
+                    let cnt = self.read_scalar(cnt_ty)?;
+                    if cnt < 0.0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Invalid list length {}.", cnt),
+                        ));
+                    }
+                    for _ in 0..cnt as usize {
+                        value.push(self.read_scalar(ty)?);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn read_scalar(&mut self, ty: ScalarType) -> io::Result<f64> {
+        macro_rules! read {
+            ($t:ty) => {{
+                let mut bytes = [0; std::mem::size_of::<$t>()];
+                self.reader.read_exact(&mut bytes)?;
+                (if self.format == Format::BinaryLittleEndian {
+                    <$t>::from_le_bytes(bytes)
+                } else {
+                    <$t>::from_be_bytes(bytes)
+                }) as f64
+            }};
+        }
+        Ok(match ty {
+            ScalarType::Int8 => read!(i8),
+            ScalarType::UInt8 => read!(u8),
+            ScalarType::Int16 => read!(i16),
+            ScalarType::UInt16 => read!(u16),
+            ScalarType::Int32 => read!(i32),
+            ScalarType::UInt32 => read!(u32),
+            ScalarType::Float32 => read!(f32),
+            ScalarType::Float64 => read!(f64),
+        })
+    }
+}
+
+fn parse_header(reader: &mut impl BufRead) -> Result<(Format, Vec<Element>), Error> {
+    let mut format = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut line = String::new();
+    for line_no in 1.. {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::Header(line_no, "No end_header.".to_string()));
+        }
+        let columns: Vec<&str> = line.split_whitespace().collect();
+        let error = |message: &str| Err(Error::Header(line_no, message.to_string()));
+        if line_no == 1 {
+            if columns[..] != ["ply"] {
+                return error("Not a PLY file.");
+            }
+            continue;
+        }
+        match columns[..] {
+            [] | ["comment", ..] | ["obj_info", ..] => {}
+            ["format", name, version] => {
+                if version != "1.0" {
+                    return error(&format!("Unsupported version {}.", version));
+                }
+                format = Some(match name {
+                    "ascii" => Format::Ascii,
+                    "binary_little_endian" => Format::BinaryLittleEndian,
+                    "binary_big_endian" => Format::BinaryBigEndian,
+                    _ => return error(&format!("Unknown format {}.", name)),
+                });
+            }
+            ["element", name, count] => elements.push(Element {
+                name: name.to_string(),
+                count: match usize::from_str(count) {
+                    Ok(count) => count,
+                    Err(_) => return error(&format!("Invalid element count {}.", count)),
+                },
+                properties: Vec::new(),
+            }),
+            ["property", ..] => {
+                let ty = match columns[1..] {
+                    ["list", cnt_ty, ty, _] => ScalarType::from_name(cnt_ty).and_then(|cnt_ty| {
+                        Some(PropertyType::List(cnt_ty, ScalarType::from_name(ty)?))
+                    }),
+                    [ty, _] => ScalarType::from_name(ty).map(PropertyType::Scalar),
+                    _ => return error("Invalid property."),
+                };
+                let ty = match ty {
+                    Some(ty) => ty,
+                    None => return error("Unknown property type."),
+                };
+                match elements.last_mut() {
+                    Some(element) => element.properties.push(Property {
+                        name: columns.last().unwrap().to_string(),
+                        ty: ty,
+                    }),
+                    None => return error("Property before element."),
+                }
+            }
+            ["end_header"] => break,
+            _ => return error(&format!("Unknown header line '{}'.", line.trim())),
+        }
+    }
+    match format {
+        Some(format) => Ok((format, elements)),
+        None => Err(Error::Header(1, "No format.".to_string())),
+    }
+}
+
+impl PlyFile {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Self::from_buf_reader(BufReader::new(File::open(path)?))
+    }
+    pub fn from_buf_reader(mut reader: impl BufRead) -> Result<Self, Error> {
+        let (format, elements) = parse_header(&mut reader)?;
+        let mut reader = DataReader {
+            reader: reader,
+            format: format,
+            line: String::new(),
+        };
+        let mut ply = PlyFile {
+            positions: Vec::new(),
+            normals: None,
+            tex_coords: None,
+            colors: None,
+            faces: Vec::new(),
+        };
+        for element in &elements {
+            let mut values: Values = vec![Vec::new(); element.properties.len()];
+            let scalar = |names: &[&str]| -> Option<usize> {
+                let i = element.find(names)?;
+                match element.properties[i].ty {
+                    PropertyType::Scalar(_) => Some(i),
+                    PropertyType::List(_, _) => None,
+                }
+            };
+            let triple = |names: [&[&str]; 3]| -> Option<[usize; 3]> {
+                Some([scalar(names[0])?, scalar(names[1])?, scalar(names[2])?])
+            };
+            let position = triple([&["x"], &["y"], &["z"]]);
+            let normal = triple([&["nx"], &["ny"], &["nz"]]);
+            let tex_coord = scalar(&["u", "s", "texture_u"]).zip(scalar(&["v", "t", "texture_v"]));
+            let color = triple([&["red", "r"], &["green", "g"], &["blue", "b"]]);
+            let color_max = color.map(|c| {
+                c.map(|i| match element.properties[i].ty {
+                    PropertyType::Scalar(ty) => ty.color_max(),
+                    PropertyType::List(_, _) => unreachable!(),
+                })
+            });
+            let indices = element
+                .find(&["vertex_indices", "vertex_index"])
+                .filter(|&i| match element.properties[i].ty {
+                    PropertyType::List(_, _) => true,
+                    PropertyType::Scalar(_) => false,
+                });
+            let is_vertex = element.name == "vertex";
+            let is_face = element.name == "face";
+            if is_vertex {
+                if position.is_none() {
+                    return Err(Error::Data(
+                        element.name.clone(),
+                        0,
+                        "No x, y, z.".to_string(),
+                    ));
+                }
+                let capacity = element.count.min(MAX_RESERVE);
+                ply.positions.reserve(capacity);
+                if normal.is_some() {
+                    ply.normals = Some(Vec::with_capacity(capacity));
+                }
+                if tex_coord.is_some() {
+                    ply.tex_coords = Some(Vec::with_capacity(capacity));
+                }
+                if color.is_some() {
+                    ply.colors = Some(Vec::with_capacity(capacity));
+                }
+            }
+            if is_face && indices.is_none() {
+                return Err(Error::Data(
+                    element.name.clone(),
+                    0,
+                    "No vertex_indices.".to_string(),
+                ));
+            }
+            let vec3 = |values: &Values, idx: [usize; 3]| {
+                Vec3::new(
+                    values[idx[0]][0] as f32,
+                    values[idx[1]][0] as f32,
+                    values[idx[2]][0] as f32,
+                )
+            };
+            for i in 0..element.count {
+                reader
+                    .read_element(element, &mut values)
+                    .map_err(|message| Error::Data(element.name.clone(), i, message))?;
+                if is_vertex {
+                    ply.positions.push(vec3(&values, position.unwrap()));
+                    if let (Some(normals), Some(idx)) = (ply.normals.as_mut(), normal) {
+                        normals.push(vec3(&values, idx));
+                    }
+                    if let (Some(tex_coords), Some(idx)) = (ply.tex_coords.as_mut(), tex_coord) {
+                        tex_coords
+                            .push(Vec2::new(values[idx.0][0] as f32, values[idx.1][0] as f32));
+                    }
+                    if let (Some(colors), Some(idx), Some(max)) =
+                        (ply.colors.as_mut(), color, color_max)
+                    {
+                        colors.push(Vec3::new(
+                            (values[idx[0]][0] / max[0]) as f32,
+                            (values[idx[1]][0] / max[1]) as f32,
+                            (values[idx[2]][0] / max[2]) as f32,
+                        ));
+                    }
+                } else if is_face {
+                    let face = &values[indices.unwrap()];
+                    if face.len() < 3 {
+                        return Err(Error::Data(
+                            element.name.clone(),
+                            i,
+                            "Less than 3 vertices.".to_string(),
+                        ));
+                    }
+                    // 頂点数との比較は全ての頂点を読んでから行う
+                    if let Some(v) = face
+                        .iter()
+                        .find(|&&v| !(0.0 <= v && v <= u32::MAX as f64 && v.fract() == 0.0))
+                    {
+                        return Err(Error::Data(
+                            element.name.clone(),
+                            i,
+                            format!("Invalid vertex index {}.", v),
+                        ));
+                    }
+                    ply.faces.push(face.iter().map(|&v| v as u32).collect());
+                }
+            }
+        }
+        let vertex_cnt = ply.positions.len();
+        for (i, face) in ply.faces.iter().enumerate() {
+            if let Some(v) = face.iter().find(|&&v| vertex_cnt <= v as usize) {
+                return Err(Error::Data(
+                    "face".to_string(),
+                    i,
+                    format!("Invalid vertex index {}.", v),
+                ));
+            }
+        }
+        Ok(ply)
+    }
+    /// Triangles of the faces, which are triangulated by ear clipping.
+    pub fn triangulate(&self) -> Vec<[u32; 3]> {
+        let mut triangles = Vec::with_capacity(self.faces.len());
+        for face in &self.faces {
+            if face.len() == 3 {
+                triangles.push([face[0], face[1], face[2]]);
+                continue;
+            }
+            let points: Vec<Vec3> = face.iter().map(|&v| self.positions[v as usize]).collect();
+            for tri in triangulate_polygon(&points) {
+                triangles.push(tri.map(|k| face[k]));
+            }
+        }
+        triangles
+    }
+    /// Converts to a mesh, to be used as leaves of BVH/OBVH by TriangleMesh::triangles().
+    /// The vertex colors are not used.
+    pub fn to_triangle_mesh(&self, material: Arc<Material>) -> TriangleMesh {
+        let mut mesh = TriangleMesh::new(
+            self.positions.clone(),
+            self.normals.clone(),
+            self.tex_coords.clone(),
+            None,
+            self.triangulate(),
+            material,
+        );
+        mesh.generate_tangents();
+        mesh
+    }
+    /// Converts to triangles, each of which has the material made from the average of its vertex colors.
+    /// Returns None if the vertices have no colors.
+    pub fn to_colored_triangles<F>(&self, material: F) -> Option<Vec<Triangle>>
+    where
+        F: Fn(&Vec3) -> Arc<Material>,
+    {
+        let colors = self.colors.as_ref()?;
+        Some(
+            self.triangulate()
+                .iter()
+                .map(|idx| {
+                    let color = idx.iter().map(|&i| colors[i as usize]).sum::<Vec3>() / 3.0;
+                    let mut tri = Triangle::new(
+                        &idx.map(|i| self.positions[i as usize]),
+                        &self.normals.as_ref().map(|n| idx.map(|i| n[i as usize])),
+                        material(&color),
+                    );
+                    tri.set_tex_coords(
+                        self.tex_coords.as_ref().map(|t| idx.map(|i| t[i as usize])),
+                    );
+                    tri
+                })
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::{Vec2, Vec3};
+    use crate::material::lambertian::Lambertian;
+    use crate::ply_file::{Error, PlyFile};
+    use crate::texture::constant::ConstantTexture;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    #[test]
+    fn read_ascii_and_binary() {
+        // 四角形と五角形
+        let positions = [
+            [0.0f32, 0.0, 0.0],
+            [1.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [0.0, 1.0, 0.0],
+            [2.0, 0.0, 0.0],
+            [2.0, 1.0, 0.0],
+            [1.5, 2.0, 0.0],
+        ];
+        let faces: [&[i32]; 2] = [&[0, 1, 2, 3], &[1, 4, 5, 6, 2]];
+        let header = |format: &str| {
+            format!(
+                "ply\nformat {} 1.0\ncomment test\n\
+                 element vertex 7\n\
+                 property float x\nproperty float y\nproperty float z\n\
+                 property double nz\nproperty double nx\nproperty double ny\n\
+                 property float s\nproperty float t\n\
+                 property uchar red\nproperty uchar green\nproperty uchar blue\n\
+                 element face 2\nproperty list uchar int vertex_indices\n\
+                 element edge 1\nproperty int vertex1\nproperty int vertex2\n\
+                 end_header\n",
+                format
+            )
+        };
+        let mut ascii = header("ascii");
+        for p in &positions {
+            ascii += &format!(
+                "{} {} {} 1 0 0 {} {} 255 {} 0\n",
+                p[0],
+                p[1],
+                p[2],
+                p[0] / 2.0,
+                p[1] / 2.0,
+                (p[0] * 100.0) as u8
+            );
+        }
+        for f in &faces {
+            ascii += &format!(
+                "{} {}\n",
+                f.len(),
+                f.iter()
+                    .map(|i| i.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        ascii += "0 1\n";
+        let binary = |little_endian: bool| {
+            let mut data = header(if little_endian {
+                "binary_little_endian"
+            } else {
+                "binary_big_endian"
+            })
+            .into_bytes();
+            macro_rules! push {
+                ($x:expr) => {
+                    if little_endian {
+                        data.extend_from_slice(&$x.to_le_bytes())
+                    } else {
+                        data.extend_from_slice(&$x.to_be_bytes())
+                    }
+                };
+            }
+            for p in &positions {
+                for &x in p {
+                    push!(x);
+                }
+                for &n in &[1.0f64, 0.0, 0.0] {
+                    push!(n);
+                }
+                push!(p[0] / 2.0);
+                push!(p[1] / 2.0);
+                for &c in &[255u8, (p[0] * 100.0) as u8, 0] {
+                    push!(c);
+                }
+            }
+            for f in &faces {
+                push!(f.len() as u8);
+                for &i in f.iter() {
+                    push!(i);
+                }
+            }
+            push!(0i32);
+            push!(1i32);
+            data
+        };
+        for data in &[ascii.into_bytes(), binary(true), binary(false)] {
+            let ply = PlyFile::from_buf_reader(Cursor::new(data)).unwrap();
+            assert_eq!(ply.positions.len(), 7);
+            assert_eq!(ply.positions[6], Vec3::new(1.5, 2.0, 0.0));
+            assert_eq!(ply.normals.as_ref().unwrap()[3], Vec3::new(0.0, 0.0, 1.0));
+            assert_eq!(ply.tex_coords.as_ref().unwrap()[5], Vec2::new(1.0, 0.5));
+            assert_eq!(
+                ply.colors.as_ref().unwrap()[1],
+                Vec3::new(1.0, 100.0 / 255.0, 0.0)
+            );
+            assert_eq!(ply.faces, vec![vec![0, 1, 2, 3], vec![1, 4, 5, 6, 2]]);
+            let triangles = ply.triangulate();
+            assert_eq!(triangles.len(), 5);
+            // 三角形分割しても面積は変わらない
+            let area: f32 = triangles
+                .iter()
+                .map(|t| {
+                    let p = t.map(|i| ply.positions[i as usize]);
+                    (p[1] - p[0])
+                        .cross(&(p[2] - p[0]))
+                        .dot(&Vec3::new(0.0, 0.0, 0.5))
+                })
+                .sum();
+            assert!((area - 2.5).abs() < 1.0e-5);
+            let material = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+                0.5, 0.5, 0.5,
+            ))));
+            assert_eq!(ply.to_triangle_mesh(material).triangle_cnt(), 5);
+            let colored = ply
+                .to_colored_triangles(|c| {
+                    Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(c))))
+                })
+                .unwrap();
+            assert_eq!(colored.len(), 5);
+        }
+
+        let invalid = "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\nproperty float y\nproperty float z\n\
+                       element face 1\nproperty list uchar int vertex_indices\nend_header\n0 0 0\n3 0 0 1\n";
+        match PlyFile::from_buf_reader(Cursor::new(invalid)) {
+            Err(Error::Data(ref element, 0, _)) if element == "face" => {}
+            _ => panic!("An out of range index must be an error."),
+        }
+        for index in &["-1", "0.5"] {
+            let invalid = invalid.replace("3 0 0 1", &format!("3 0 0 {}", index));
+            match PlyFile::from_buf_reader(Cursor::new(invalid)) {
+                Err(Error::Data(ref element, 0, _)) if element == "face" => {}
+                _ => panic!("Vertex index {} must be an error.", index),
+            }
+        }
+        // ヘッダーの要素数が大きすぎても、確保せずにデータの不足をエラーにする
+        match PlyFile::from_buf_reader(Cursor::new(
+            invalid.replace("element vertex 1", "element vertex 4000000000"),
+        )) {
+            Err(Error::Data(ref element, 1, _)) if element == "vertex" => {}
+            _ => panic!("Missing vertices must be an error."),
+        }
+        match PlyFile::from_buf_reader(Cursor::new(
+            "ply\nformat ascii 1.0\nelement vertex 1\nproperty float x\n",
+        )) {
+            Err(Error::Header(5, _)) => {}
+            _ => panic!("A header without end_header must be an error."),
+        }
+    }
+}
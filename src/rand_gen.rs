@@ -0,0 +1,188 @@
+use rand::distributions::StandardNormal;
+use rand::prelude::ThreadRng;
+use rand::prng::XorShiftRng;
+use rand::{Error, Rng, RngCore, SeedableRng};
+
+/// The source of random numbers consumed by integrators, materials and pdfs.
+/// Usually it is the thread-local generator, but it can also be a stream of primary samples
+/// which can be replayed and mutated (used by PSSMLT).
+pub enum RandGen {
+    Thread(ThreadRng),
+    PrimarySample(PrimarySampleStream),
+}
+
+impl RandGen {
+    pub fn new() -> Self {
+        RandGen::Thread(rand::thread_rng())
+    }
+}
+
+impl Default for RandGen {
+    fn default() -> Self {
+        RandGen::new()
+    }
+}
+
+impl RngCore for RandGen {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            RandGen::Thread(rng) => rng.next_u32(),
+            RandGen::PrimarySample(stream) => (stream.next_sample() * 4294967296.0) as u32,
+        }
+    }
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            RandGen::Thread(rng) => rng.next_u64(),
+            RandGen::PrimarySample(stream) => {
+                (stream.next_sample() * 18446744073709551616.0) as u64
+            }
+        }
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            RandGen::Thread(rng) => rng.fill_bytes(dest),
+            RandGen::PrimarySample(_) => {
+                for chunk in dest.chunks_mut(4) {
+                    let bytes = self.next_u32().to_le_bytes();
+                    let len = chunk.len();
+                    chunk.copy_from_slice(&bytes[..len]);
+                }
+            }
+        }
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy)]
+struct PrimarySample {
+    value: f64,
+    last_modified: u64,
+    backup_value: f64,
+    backup_modified: u64,
+}
+
+/// A sequence of uniform random numbers in [0, 1), i.e., a point in the primary sample space,
+/// which is mutated lazily as in Kelemen et al., "A Simple and Robust Mutation Strategy for the Metropolis Light Transport Algorithm".
+/// The same seed reproduces the same sequence, and a rejected mutation can be undone.
+pub struct PrimarySampleStream {
+    rng: XorShiftRng,
+    sigma: f64,
+    large_step_probability: f32,
+    samples: Vec<PrimarySample>,
+    index: usize,
+    iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+}
+
+impl PrimarySampleStream {
+    /// * `sigma` - standard deviation of a small step mutation.
+    /// * `large_step_probability` - probability that a mutation replaces every sample by a new one.
+    pub fn new(seed: u64, sigma: f32, large_step_probability: f32) -> Self {
+        PrimarySampleStream {
+            rng: XorShiftRng::seed_from_u64(seed),
+            sigma: sigma as f64,
+            large_step_probability: large_step_probability,
+            samples: vec![],
+            index: 0,
+            iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+        }
+    }
+    /// Begins a new mutation. The samples are mutated when they are used.
+    pub fn start_iteration(&mut self) {
+        self.iteration += 1;
+        self.large_step = self.rng.gen::<f32>() < self.large_step_probability;
+        self.index = 0;
+    }
+    /// Keeps the mutated samples.
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.iteration;
+        }
+    }
+    /// Restores the samples before the last mutation.
+    pub fn reject(&mut self) {
+        for sample in &mut self.samples {
+            if sample.last_modified == self.iteration {
+                sample.value = sample.backup_value;
+                sample.last_modified = sample.backup_modified;
+            }
+        }
+        self.iteration -= 1;
+    }
+    fn next_sample(&mut self) -> f64 {
+        if self.index >= self.samples.len() {
+            // A sample used for the first time is regarded as generated at the last large step.
+            // (Otherwise, e.g., rejection sampling loops would receive samples near to zero forever.)
+            let value = self.rng.gen::<f64>();
+            self.samples.push(PrimarySample {
+                value: value,
+                last_modified: self.last_large_step_iteration,
+                backup_value: value,
+                backup_modified: self.last_large_step_iteration,
+            });
+        }
+        let sample = &mut self.samples[self.index];
+        self.index += 1;
+        // Samples which are not used since the last large step are regenerated.
+        if sample.last_modified < self.last_large_step_iteration {
+            sample.value = self.rng.gen::<f64>();
+            sample.last_modified = self.last_large_step_iteration;
+        }
+        sample.backup_value = sample.value;
+        sample.backup_modified = sample.last_modified;
+        if self.large_step {
+            sample.value = self.rng.gen::<f64>();
+        } else {
+            // Small steps applied (iteration - last_modified) times are a single gaussian step.
+            let step_cnt = (self.iteration - sample.last_modified) as f64;
+            let normal: f64 = self.rng.sample(StandardNormal);
+            sample.value += normal * self.sigma * step_cnt.sqrt();
+            sample.value -= sample.value.floor();
+        }
+        sample.last_modified = self.iteration;
+        sample.value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rand_gen::{PrimarySampleStream, RandGen};
+    use rand::Rng;
+    fn draw(rng: &mut RandGen, cnt: usize) -> Vec<f32> {
+        (0..cnt).map(|_| rng.gen::<f32>()).collect()
+    }
+    fn values(rng: &RandGen) -> Vec<f64> {
+        match rng {
+            RandGen::PrimarySample(stream) => stream.samples.iter().map(|s| s.value).collect(),
+            RandGen::Thread(_) => unreachable!(),
+        }
+    }
+    #[test]
+    fn primary_sample_replay_and_reject() {
+        const SAMPLE_CNT: usize = 20;
+        let mut rng = RandGen::PrimarySample(PrimarySampleStream::new(7, 0.01, 0.3));
+        let first = draw(&mut rng, SAMPLE_CNT);
+        let mut same_seed = RandGen::PrimarySample(PrimarySampleStream::new(7, 0.01, 0.3));
+        assert_eq!(first, draw(&mut same_seed, SAMPLE_CNT));
+        for i in 0..100 {
+            let before = values(&rng);
+            if let RandGen::PrimarySample(ref mut stream) = rng {
+                stream.start_iteration();
+            }
+            // The mutated path may use more samples than before.
+            let mutated = draw(&mut rng, SAMPLE_CNT + i);
+            assert!(mutated.iter().all(|&x| 0.0 <= x && x < 1.0));
+            assert_ne!(before[..SAMPLE_CNT], values(&rng)[..SAMPLE_CNT]);
+            if let RandGen::PrimarySample(ref mut stream) = rng {
+                stream.reject();
+            }
+            assert_eq!(before[..], values(&rng)[..before.len()]);
+        }
+    }
+}
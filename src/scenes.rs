@@ -3,6 +3,7 @@ mod jerusalem_cube;
 mod manyspheres;
 mod menger;
 mod teapot;
+mod teapot_forest;
 
 use ray::scene::Scene;
 
@@ -11,6 +12,7 @@ pub enum ScenesType {
     CornellBox,
     ManySpheres,
     Teapot,
+    TeapotForest,
     Menger,
     JerusalemCube,
 }
@@ -20,6 +22,7 @@ pub fn get(scene_type: ScenesType, aspect_ratio: f32) -> Scene {
         ScenesType::CornellBox => self::cornellbox::scene(aspect_ratio),
         ScenesType::ManySpheres => self::manyspheres::scene(aspect_ratio),
         ScenesType::Teapot => self::teapot::scene(aspect_ratio),
+        ScenesType::TeapotForest => self::teapot_forest::scene(aspect_ratio),
         ScenesType::Menger => self::menger::scene(aspect_ratio),
         ScenesType::JerusalemCube => self::jerusalem_cube::scene(aspect_ratio),
     }
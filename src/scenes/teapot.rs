@@ -1,3 +1,4 @@
+use ray::accel_cache::{BuildParams, Builder, MeshCache};
 use ray::aliases::Vec3;
 use ray::background::AmbientLight;
 use ray::camera::Camera;
@@ -6,6 +7,7 @@ use ray::hitable::hitable_list::HitableList;
 use ray::hitable::obvh::OBVH;
 use ray::hitable::rectangle::Rectangle;
 use ray::hitable::sphere::Sphere;
+use ray::hitable::triangle_pack::{TrianglePack, TRIANGLE_PACK_WIDTH};
 use ray::hitable::Hitable;
 use ray::material::diffuse_light::DiffuseLight;
 use ray::material::glass::Glass;
@@ -61,18 +63,32 @@ pub fn scene(aspect_ratio: f32) -> Scene {
         0.5,
     )))));
     let _glass = Arc::new(Glass::new(2.2, 0.0));
-    let teapot = &mut ObjFile::from_file(Path::new("res/teapot.obj"))
-        .unwrap()
-        .groups[0];
-    teapot.unify_vertex();
-    teapot.set_smooth_normals();
-    // let teapot = Arc::new(BvhNode::new(
-    //     teapot.to_triangles_ref(lambert.clone()),
-    //     0.0,
-    //     1.0,
-    // ));
-    let teapot = BVH::new(teapot.to_triangles(lambert.clone()), 0.0, 1.0);
-    let teapot = Arc::new(OBVH::from_bvh(teapot));
+    let teapot_cache = MeshCache::new(
+        Path::new("res/cache"),
+        Path::new("res/teapot.obj"),
+        "unify_vertex, set_smooth_normals",
+        BuildParams {
+            builder: Builder::Binned,
+            time_0: 0.0,
+            time_1: 1.0,
+        },
+    )
+    .unwrap();
+    let teapot = teapot_cache.bvh_or_build(lambert.clone(), 4, || {
+        let teapot = &mut ObjFile::from_file(Path::new("res/teapot.obj"))
+            .unwrap()
+            .groups[0];
+        teapot.unify_vertex();
+        teapot.set_smooth_normals();
+        teapot.to_triangles(lambert.clone())
+    });
+    // キャッシュしたBVHの部分木ごとに三角形をSIMDでまとめて判定する
+    let teapot = Arc::new(OBVH::from_bvh(BVH::new_binned(
+        TrianglePack::from_bvh(teapot, TRIANGLE_PACK_WIDTH),
+        0.0,
+        1.0,
+        4,
+    )));
     // let bunny = &mut ObjFile::from_file(Path::new("res/bunny.obj"))
     //     .unwrap()
     //     .groups[0];
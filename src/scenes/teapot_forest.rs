@@ -0,0 +1,138 @@
+use ray::accel_cache::{BuildParams, Builder, MeshCache};
+use ray::affine::Affine;
+use ray::aliases::Vec3;
+use ray::background::AmbientLight;
+use ray::camera::Camera;
+use ray::hitable::bvh::BVH;
+use ray::hitable::hitable_list::HitableList;
+use ray::hitable::instance::{Instance, Tlas};
+use ray::hitable::obvh::OBVH;
+use ray::hitable::rectangle::Rectangle;
+use ray::hitable::sphere::Sphere;
+use ray::hitable::triangle_pack::{TrianglePack, TRIANGLE_PACK_WIDTH};
+use ray::hitable::Hitable;
+use ray::material::diffuse_light::DiffuseLight;
+use ray::material::lambertian::Lambertian;
+use ray::material::Material;
+use ray::obj_file::ObjFile;
+use ray::scene::Scene;
+use ray::texture::constant::ConstantTexture;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Teapots on a N x N grid. Only one teapot BVH is built and shared by all the instances.
+pub fn scene(aspect_ratio: f32) -> Scene {
+    const N: usize = 64;
+    const PITCH: f32 = 8.0;
+    let mut objs = Vec::<Arc<Hitable>>::new();
+    const L: f32 = N as f32 * PITCH;
+    objs.push(Arc::new(Rectangle::new(
+        &Vec3::new(-L, 0.0, -L),
+        &Vec3::new(0.0, 0.0, 2.0 * L),
+        &Vec3::new(2.0 * L, 0.0, 0.0),
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(&Vec3::new(
+            0.5, 0.5, 0.5,
+        ))))),
+        0.1,
+    ))); // floor
+    let light_power = 10.0;
+    let light = Arc::new(Sphere::new(
+        &Vec3::new(0.0, 100.0, 0.0),
+        30.0,
+        Arc::new(DiffuseLight::new(Arc::new(ConstantTexture::new(
+            &Vec3::new(light_power, light_power, light_power),
+        )))),
+    ));
+    objs.push(light.clone()); // light
+    let lambert = Arc::new(Lambertian::new(Arc::new(ConstantTexture::new(&Vec3::new(
+        232.0 / 255.0,
+        200.0 / 255.0,
+        0.5,
+    )))));
+    let teapot_cache = MeshCache::new(
+        Path::new("res/cache"),
+        Path::new("res/teapot.obj"),
+        "unify_vertex, set_smooth_normals",
+        BuildParams {
+            builder: Builder::Binned,
+            time_0: 0.0,
+            time_1: 1.0,
+        },
+    )
+    .unwrap();
+    let teapot = teapot_cache.bvh_or_build(lambert.clone(), 4, || {
+        let teapot = &mut ObjFile::from_file(Path::new("res/teapot.obj"))
+            .unwrap()
+            .groups[0];
+        teapot.unify_vertex();
+        teapot.set_smooth_normals();
+        teapot.to_triangles(lambert.clone())
+    });
+    // キャッシュしたBVHの部分木ごとに三角形をSIMDでまとめて判定する
+    let teapot = Arc::new(OBVH::from_bvh(BVH::new_binned(
+        TrianglePack::from_bvh(teapot, TRIANGLE_PACK_WIDTH),
+        0.0,
+        1.0,
+        4,
+    )));
+    let palette: Vec<Arc<Material>> = vec![
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            0.8, 0.3, 0.3,
+        )))),
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            0.3, 0.8, 0.3,
+        )))),
+        Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+            0.3, 0.3, 0.8,
+        )))),
+    ];
+    let mut instances = Vec::with_capacity(N * N);
+    for i in 0..N {
+        for j in 0..N {
+            let pos = Vec3::new(
+                (i as f32 - N as f32 * 0.5) * PITCH,
+                0.0,
+                (j as f32 - N as f32 * 0.5) * PITCH,
+            );
+            let angle = (i * 7 + j * 13) as f32; // 適当に回転させる
+            let rotation = Affine::rotation(&Vec3::new(0.0, angle, 0.0), &Vec3::new(0.0, 0.0, 0.0));
+            let instance = Instance::new(
+                teapot.clone(),
+                &Affine::translate(&pos).compose(&rotation),
+                // 4つに1つは元のマテリアルのまま
+                if (i + j) % 4 == 0 {
+                    None
+                } else {
+                    Some(palette[(i + j) % palette.len()].clone())
+                },
+                0.0,
+                1.0,
+            );
+            instances.push(instance);
+        }
+    }
+    objs.push(Arc::new(Tlas::new(instances, 0.0, 1.0)));
+    let objs = Arc::new(HitableList::new(objs));
+    let look_from = Vec3::new(0.0, 40.0, 120.0);
+    let look_at = Vec3::new(0.0, 0.0, 0.0);
+    let dist_to_focus = 100.0;
+    let vfov = 40.0;
+    let camera = Camera::new_time(
+        &look_from,
+        &look_at,
+        &Vec3::new(0.0, 1.0, 0.0),
+        vfov,
+        aspect_ratio,
+        0.0, // lens_radius
+        dist_to_focus,
+        0.0, // time_0
+        1.0, // time_1
+    );
+    let bg = Arc::new(AmbientLight::new(&Vec3::new(0.0, 0.0, 0.0)));
+    Scene {
+        hitables: objs,
+        light: Some(light),
+        camera: camera,
+        bg: bg,
+    }
+}
@@ -0,0 +1,340 @@
+// STL (.stl)。CADから出力される三角形の集まり。
+// 参考：
+// http://paulbourke.net/dataformats/stl/
+// ・バイナリは80バイトのヘッダ、三角形の数（u32）、三角形ごとに法線・3頂点（f32 x 12）と属性（u16）。
+//   "solid"で始まるバイナリもあるので、ファイルサイズが三角形の数と合えばバイナリとみなす。
+// ・面の向きは頂点の順序（反時計回りが表）で決め、ファイル中の法線は使わない。
+// ・頂点は三角形ごとに別々に読むので、共有するにはunify_vertex()を呼ぶ。
+
+use crate::aliases::Vec3;
+use crate::hitable::triangle_mesh::TriangleMesh;
+use crate::material::Material;
+use crate::obj_file::triangulate_polygon;
+use crate::util::{unify_points, HashVec3};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// A triangle mesh read from a STL file.
+pub struct StlFile {
+    pub positions: Vec<Vec3>,
+    pub normals: Option<Vec<Vec3>>, // 頂点ごとの法線。set_crease_normals()で作る
+    pub indices: Vec<[u32; 3]>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    Parse(usize, String), // (line number, message) of ASCII STL
+    Binary(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IO(e) => write!(f, "{}", e),
+            Error::Parse(line, message) => write!(f, "line {}: {}", line, message),
+            Error::Binary(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(io_e: io::Error) -> Self {
+        Error::IO(io_e)
+    }
+}
+
+impl StlFile {
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        Self::from_buf_reader(BufReader::new(File::open(path)?))
+    }
+    /// Reads ASCII or binary STL.
+    pub fn from_buf_reader(mut reader: impl BufRead) -> Result<Self, Error> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if data.len() >= 84 {
+            let cnt = u32::from_le_bytes([data[80], data[81], data[82], data[83]]) as usize;
+            if data.len() == 84 + 50 * cnt {
+                return Ok(Self::parse_binary(&data[84..], cnt));
+            }
+        }
+        if data.trim_ascii_start().starts_with(b"solid") {
+            Self::parse_ascii(&String::from_utf8_lossy(&data))
+        } else {
+            Err(Error::Binary(format!(
+                "The size {} bytes does not match the number of triangles.",
+                data.len()
+            )))
+        }
+    }
+    fn parse_binary(data: &[u8], cnt: usize) -> Self {
+        let f32_at = |offset: usize| {
+            let b = &data[offset..offset + 4];
+            f32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        };
+        let mut positions = Vec::with_capacity(3 * cnt);
+        for i in 0..cnt {
+            // 先頭12バイトの法線は使わない
+            for k in 0..3 {
+                let offset = 50 * i + 12 + 12 * k;
+                positions.push(Vec3::new(
+                    f32_at(offset),
+                    f32_at(offset + 4),
+                    f32_at(offset + 8),
+                ));
+            }
+        }
+        let indices = (0..cnt as u32)
+            .map(|i| [3 * i, 3 * i + 1, 3 * i + 2])
+            .collect();
+        StlFile {
+            positions: positions,
+            normals: None,
+            indices: indices,
+        }
+    }
+    fn parse_ascii(text: &str) -> Result<Self, Error> {
+        let mut positions = Vec::new();
+        let mut indices = Vec::new();
+        let mut polygon: Vec<Vec3> = Vec::new();
+        for (line_no, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l)) {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                None | Some("solid") | Some("endsolid") | Some("outer") | Some("endloop") => {}
+                Some("facet") => polygon.clear(),
+                Some("vertex") => {
+                    let mut p = Vec3::zeros();
+                    for i in 0..3 {
+                        let token = tokens.next().ok_or_else(|| {
+                            Error::Parse(line_no, "A vertex needs 3 coordinates.".to_string())
+                        })?;
+                        p[i] = f32::from_str(token).map_err(|e| {
+                            Error::Parse(line_no, format!("Invalid number '{}': {}.", token, e))
+                        })?;
+                    }
+                    polygon.push(p);
+                }
+                Some("endfacet") => {
+                    if polygon.len() < 3 {
+                        return Err(Error::Parse(
+                            line_no,
+                            "A facet needs at least 3 vertices.".to_string(),
+                        ));
+                    }
+                    // 多角形は仕様外だが、書き出すツールもあるので三角形に分割する
+                    let base = positions.len() as u32;
+                    for tri in triangulate_polygon(&polygon) {
+                        indices.push(tri.map(|k| base + k as u32));
+                    }
+                    positions.append(&mut polygon);
+                }
+                Some(keyword) => {
+                    return Err(Error::Parse(
+                        line_no,
+                        format!("Unknown keyword '{}'.", keyword),
+                    ))
+                }
+            }
+        }
+        Ok(StlFile {
+            positions: positions,
+            normals: None,
+            indices: indices,
+        })
+    }
+    pub fn triangle_cnt(&self) -> usize {
+        self.indices.len()
+    }
+    /// Unifies the vertices with the same position (and the same normal if self has normals),
+    /// in the same way as ObjGroup::unify_vertex().
+    pub fn unify_vertex(&mut self) {
+        let (positions, position_idx) = unify_points(&self.positions);
+        let (normals, normal_idx) = match self.normals {
+            Some(ref normals) => unify_points(normals),
+            None => (Vec::new(), vec![0; self.positions.len()]),
+        };
+        let mut new_positions = Vec::new();
+        let mut new_normals = Vec::new();
+        let mut key_to_idx = HashMap::<(usize, usize), u32>::new();
+        let new_idx: Vec<u32> = position_idx
+            .iter()
+            .zip(&normal_idx)
+            .map(|(&p, &n)| {
+                *key_to_idx.entry((p, n)).or_insert_with(|| {
+                    new_positions.push(positions[p]);
+                    if !normals.is_empty() {
+                        new_normals.push(normals[n]);
+                    }
+                    (new_positions.len() - 1) as u32
+                })
+            })
+            .collect();
+        for tri in &mut self.indices {
+            *tri = tri.map(|i| new_idx[i as usize]);
+        }
+        self.positions = new_positions;
+        if self.normals.is_some() {
+            self.normals = Some(new_normals);
+        }
+    }
+    /// Sets the normals at vertices to the averages of the normals of the adjacent triangles, like
+    /// ObjGroup::set_smooth_normals(). However, only the triangles whose normals make an angle up to
+    /// crease_angle (in degrees) with that of the triangle are averaged, so that hard edges stay sharp.
+    /// The vertices on the hard edges are split. Triangles are adjacent if they share a position,
+    /// which does not need unify_vertex().
+    pub fn set_crease_normals(&mut self, crease_angle: f32) {
+        let cos_crease = crease_angle.to_radians().cos();
+        let (unified, position_idx) = unify_points(&self.positions);
+        // 縮退した三角形の法線はNaNになり、平均には含めない
+        let face_normals: Vec<Vec3> = self
+            .indices
+            .iter()
+            .map(|tri| {
+                let [a, b, c] = tri.map(|i| self.positions[i as usize]);
+                (b - a).cross(&(c - a)).normalize()
+            })
+            .collect();
+        let mut faces_at_vtx: Vec<Vec<usize>> = vec![Vec::new(); unified.len()];
+        for (f, tri) in self.indices.iter().enumerate() {
+            for &i in tri {
+                faces_at_vtx[position_idx[i as usize]].push(f);
+            }
+        }
+        let mut positions = Vec::new();
+        let mut normals = Vec::new();
+        let mut key_to_idx = HashMap::<(u32, HashVec3), u32>::new();
+        let old_positions = &self.positions;
+        for (f, tri) in self.indices.iter_mut().enumerate() {
+            let face_normal = face_normals[f];
+            let is_degenerate = !face_normal.iter().all(|x| x.is_finite());
+            *tri = tri.map(|i| {
+                let normal = faces_at_vtx[position_idx[i as usize]]
+                    .iter()
+                    .map(|&g| face_normals[g])
+                    .filter(|n| n.iter().all(|x| x.is_finite()))
+                    .filter(|n| is_degenerate || face_normal.dot(n) >= cos_crease)
+                    .sum::<Vec3>()
+                    .normalize();
+                let normal = if normal.iter().all(|x| x.is_finite()) {
+                    normal
+                } else if !is_degenerate {
+                    face_normal
+                } else {
+                    Vec3::new(0.0, 0.0, 1.0)
+                };
+                *key_to_idx.entry((i, HashVec3(normal))).or_insert_with(|| {
+                    positions.push(old_positions[i as usize]);
+                    normals.push(normal);
+                    (positions.len() - 1) as u32
+                })
+            });
+        }
+        self.positions = positions;
+        self.normals = Some(normals);
+    }
+    /// Converts to a mesh, to be used as leaves of BVH/OBVH by TriangleMesh::triangles().
+    pub fn to_triangle_mesh(&self, material: Arc<Material>) -> TriangleMesh {
+        TriangleMesh::new(
+            self.positions.clone(),
+            self.normals.clone(),
+            None,
+            None,
+            self.indices.clone(),
+            material,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aliases::Vec3;
+    use crate::hitable::bvh::BVH;
+    use crate::hitable::triangle_mesh::TriangleMesh;
+    use crate::hitable::Hitable;
+    use crate::material::lambertian::Lambertian;
+    use crate::ray::Ray;
+    use crate::stl_file::StlFile;
+    use crate::texture::constant::ConstantTexture;
+    use std::io::Cursor;
+    use std::sync::Arc;
+    #[test]
+    fn read_ascii_and_binary() {
+        // 単位立方体の6面を、向かい合う頂点を対角線とする2枚の三角形ずつで表す
+        let mut triangles: Vec<[Vec3; 3]> = Vec::new();
+        for axis in 0..3 {
+            for &side in &[0.0, 1.0] {
+                let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+                let corner = |a: f32, b: f32| {
+                    let mut p = Vec3::zeros();
+                    p[axis] = side;
+                    p[u] = a;
+                    p[v] = b;
+                    p
+                };
+                let mut quad = [
+                    corner(0.0, 0.0),
+                    corner(1.0, 0.0),
+                    corner(1.0, 1.0),
+                    corner(0.0, 1.0),
+                ];
+                if side == 0.0 {
+                    quad.reverse();
+                }
+                triangles.push([quad[0], quad[1], quad[2]]);
+                triangles.push([quad[0], quad[2], quad[3]]);
+            }
+        }
+        let mut ascii = "solid cube\n".to_string();
+        let mut binary = vec![b's'; 80]; // "solid"で始まるヘッダ
+        binary.extend_from_slice(&(triangles.len() as u32).to_le_bytes());
+        for tri in &triangles {
+            ascii += "  facet normal 0 0 0\n    outer loop\n";
+            binary.extend_from_slice(&[0; 12]);
+            for p in tri {
+                ascii += &format!("      vertex {} {} {}\n", p[0], p[1], p[2]);
+                for x in p.iter() {
+                    binary.extend_from_slice(&x.to_le_bytes());
+                }
+            }
+            ascii += "    endloop\n  endfacet\n";
+            binary.extend_from_slice(&[0; 2]);
+        }
+        ascii += "endsolid cube\n";
+
+        for data in &[ascii.as_bytes(), &binary] {
+            let stl = StlFile::from_buf_reader(Cursor::new(data)).unwrap();
+            assert_eq!(stl.triangle_cnt(), 12);
+            assert_eq!(stl.positions.len(), 36);
+            // 折れ角90度は、閾値60度では分かれ、閾値120度では分かれない
+            let mut creased = StlFile::from_buf_reader(Cursor::new(data)).unwrap();
+            creased.set_crease_normals(60.0);
+            creased.unify_vertex();
+            assert_eq!(creased.positions.len(), 24);
+            let mut smooth = StlFile::from_buf_reader(Cursor::new(data)).unwrap();
+            smooth.unify_vertex();
+            assert_eq!(smooth.positions.len(), 8);
+            smooth.set_crease_normals(120.0);
+            assert_eq!(smooth.positions.len(), 8);
+
+            let material = Arc::new(Lambertian::new(Arc::new(ConstantTexture::rgb(
+                1.0, 1.0, 1.0,
+            ))));
+            let mesh = Arc::new(creased.to_triangle_mesh(material));
+            let bvh = BVH::new(TriangleMesh::triangles(&mesh), 0.0, 1.0);
+            let ray = Ray::new(&Vec3::new(0.3, 0.4, 2.0), &Vec3::new(0.0, 0.0, -1.0), 0.0);
+            let rec = bvh.hit(&ray, 0.0, std::f32::MAX).unwrap();
+            assert!((rec.t - 1.0).abs() < 1.0e-5);
+            assert!((rec.normal - Vec3::new(0.0, 0.0, 1.0)).norm() < 1.0e-5);
+        }
+        assert!(StlFile::from_buf_reader(Cursor::new("solid\nfacet\nvertex 0 0\n")).is_err());
+        assert!(StlFile::from_buf_reader(Cursor::new(vec![0; 90])).is_err());
+    }
+}
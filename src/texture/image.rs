@@ -12,7 +12,14 @@ pub struct ImageTexture {
 
 impl ImageTexture {
     pub fn new(path: &Path) -> Self {
-        let img = image::open(path).unwrap();
+        Self::open(path).unwrap()
+    }
+    /// Same as new(), but returns the error instead of panicking.
+    pub fn open(path: &Path) -> image::ImageResult<Self> {
+        Ok(Self::from_image(&image::open(path)?))
+    }
+    /// From an image already decoded (e.g., by image::load_from_memory).
+    pub fn from_image(img: &image::DynamicImage) -> Self {
         let width = img.width() as usize;
         let height = img.height() as usize;
         let mut data = vec![0; width * height * 3];
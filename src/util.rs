@@ -1,5 +1,6 @@
 use crate::aliases::{Mat4, Vec2, Vec3};
 use std::cmp::Eq;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::mem::transmute;
 use std::time::Duration;
@@ -12,10 +13,12 @@ pub fn zipwith_vec3(lhs: &Vec3, rhs: &Vec3, zipper: impl Fn(f32, f32) -> f32) ->
     )
 }
 
+#[inline]
 pub fn min_vec3(lhs: &Vec3, rhs: &Vec3) -> Vec3 {
     zipwith_vec3(lhs, rhs, f32::min)
 }
 
+#[inline]
 pub fn max_vec3(lhs: &Vec3, rhs: &Vec3) -> Vec3 {
     zipwith_vec3(lhs, rhs, f32::max)
 }
@@ -37,7 +40,7 @@ pub fn proj_transform(mat: &Mat4, pt: &Vec3) -> Vec3 {
 }
 
 pub fn duration_to_secs(dur: &Duration) -> f64 {
-    dur.as_secs() as f64 + dur.subsec_millis() as f64 * 1.0e-3 + dur.subsec_nanos() as f64 * 1.0e-9
+    dur.as_secs() as f64 + dur.subsec_nanos() as f64 * 1.0e-9
 }
 
 pub fn compare_vec3_le(lhs: &Vec3, rhs: &Vec3) -> bool {
@@ -87,3 +90,20 @@ impl Hash for HashVec3 {
 
 // lift PartialEq to Eq
 impl Eq for HashVec3 {}
+
+/// Unifies the equal points into one.
+/// Returns the distinct points in the order of appearance, and the index in them of each point.
+pub fn unify_points(points: &[Vec3]) -> (Vec<Vec3>, Vec<usize>) {
+    let mut unified: Vec<Vec3> = Vec::with_capacity(points.len());
+    let mut vec_to_idx = HashMap::<HashVec3, usize>::with_capacity(points.len());
+    let indices = points
+        .iter()
+        .map(|p| {
+            *vec_to_idx.entry(HashVec3(*p)).or_insert_with(|| {
+                unified.push(*p);
+                unified.len() - 1
+            })
+        })
+        .collect();
+    (unified, indices)
+}